@@ -0,0 +1,92 @@
+//! Instructions per second of the CPU core, run with `cargo bench`.
+//!
+//! One workload is a loop of everyday instructions on a bare 64 KiB bus, to
+//! measure the core alone. The other is nestest on the NES bus in cycle
+//! accurate mode, where the PPU is clocked along with the CPU.
+
+use std::time::{Duration, Instant};
+
+use nise::common::bus::Bus;
+use nise::nes::asm::assemble;
+use nise::nes::bus::NiseBus;
+use nise::nes::cpu::{Nise6502, Variant};
+use nise::nes::rom::Rom;
+
+// How long to run each workload for
+const DURATION: Duration = Duration::from_secs(3);
+
+// Copies, sums and shifts a page, through subroutines, forever
+const LOOP: &str = "
+    .org $8000
+    reset:  ldx #$FF
+            txs
+            lda #$00
+            sta $10
+            lda #$03
+            sta $11
+    loop:   jsr copy
+            jsr sum
+            inc $12
+            jmp loop
+    copy:   ldy #$00
+    @next:  lda $8000,y
+            sta ($10),y
+            iny
+            bne @next
+            rts
+    sum:    ldx #$00
+            clc
+            lda #$00
+    @next:  adc $0300,x
+            rol $13
+            eor $0301,x
+            inx
+            bne @next
+            sta $14
+            rts
+    .org $FFFC
+    .word reset
+";
+
+// Steps `cpu` until `DURATION` is up, in runs of `batch` steps made by
+// `run`, and prints how many instructions that came to
+fn measure<B: Bus>(name: &str, cpu: &mut Nise6502<B>, batch: u64, run: impl Fn(&mut Nise6502<B>)) {
+    let start = Instant::now();
+    let mut instructions = 0;
+    while start.elapsed() < DURATION {
+        run(cpu);
+        instructions += batch;
+    }
+    let seconds = start.elapsed().as_secs_f64();
+    println!(
+        "{:<10} {:>12} instructions in {:.2} s: {:>7.2} million per second",
+        name,
+        instructions,
+        seconds,
+        instructions as f64 / seconds / 1e6
+    );
+}
+
+fn main() {
+    let assembly = assemble(Variant::Ricoh2A03, LOOP).unwrap();
+    let mut cpu = Nise6502::new(assembly.flat_bus());
+    cpu.reset();
+    measure("flat bus", &mut cpu, 10_000, |cpu| {
+        for _ in 0..10_000 {
+            cpu.step();
+        }
+    });
+
+    // The first 8,900 instructions of nestest, before it reaches the APU
+    let rom = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/nestest.nes")).unwrap();
+    let rom = Rom::new(&rom).unwrap();
+    let mut cpu = Nise6502::new(NiseBus::new(rom));
+    measure("nestest", &mut cpu, 8_900, |cpu| {
+        cpu.set_cycle_accurate(true);
+        cpu.reset();
+        cpu.set_pc(0xC000);
+        for _ in 0..8_900 {
+            cpu.step();
+        }
+    });
+}
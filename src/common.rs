@@ -1,3 +1,4 @@
+pub mod bus;
 pub mod graphics;
 
 pub fn to_u16(l: u8, h: u8) -> u16 {
@@ -0,0 +1,165 @@
+/// A 6502 memory bus. The CPU performs exactly one `read` or `write` per
+/// cycle, and in cycle accurate mode calls `tick` before each of them.
+pub trait Bus {
+    fn read(&mut self, address: u16) -> u8;
+
+    fn write(&mut self, address: u16, data: u8);
+
+    /// Reads without side effects, for debuggers and trace output.
+    fn peek(&self, address: u16) -> u8;
+
+    /// Writes on behalf of a debugger. Unlike `write`, this may patch ROM.
+    fn poke(&mut self, address: u16, data: u8) {
+        self.write(address, data)
+    }
+
+    /// Where the byte at `address` is in PRG ROM, if ROM is mapped there.
+    fn prg_offset(&self, _address: u16) -> Option<u32> {
+        None
+    }
+
+    /// Where a byte of PRG ROM can be seen by the CPU, if its bank is
+    /// mapped in at the moment.
+    fn prg_address(&self, _offset: u32) -> Option<u16> {
+        None
+    }
+
+    /// Notes how an instruction used the byte at `address`, for code/data
+    /// logging. Only called once the CPU is told to `set_log_usage`.
+    fn log_usage(&mut self, _address: u16, _usage: Usage) {}
+
+    /// The scanline and dot that the video chip is at, if there is one, for
+    /// traces.
+    fn video_position(&self) -> Option<(usize, usize)> {
+        None
+    }
+
+    /// A copy of everything on the bus, for the debugger to go back to with
+    /// `load_state`. Buses that return `None` cannot be run backwards.
+    fn save_state(&self) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        None
+    }
+
+    /// Goes back to a state made by `save_state`.
+    fn load_state(&mut self, _state: &Self)
+    where
+        Self: Sized,
+    {
+    }
+
+    /// Clocks everything else on the bus for one CPU cycle.
+    fn tick(&mut self) {}
+
+    /// Takes the transfer another bus master is waiting to make, if any. The
+    /// CPU asks before each read it makes, and is halted until it is done.
+    fn take_dma(&mut self) -> Option<Dma> {
+        None
+    }
+
+    /// Hands the byte that a `Dma::Dmc` fetched to the DMC.
+    fn dmc_fetched(&mut self, _value: u8) {}
+
+    /// Level of the NMI input driven by devices on the bus.
+    fn nmi_line(&self) -> bool {
+        false
+    }
+
+    /// Level of the IRQ input driven by devices on the bus.
+    fn irq_line(&self) -> bool {
+        false
+    }
+}
+
+/// A transfer that halts the CPU while it takes over the bus, through the
+/// 2A03's RDY input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dma {
+    /// Sprite DMA: copies the page at `$XX00` to PPU OAM through $2004, one
+    /// byte every two cycles.
+    Oam(u8),
+    /// Fetches a byte of DMC sample from an address.
+    Dmc(u16),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// How the CPU used a byte of memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Usage {
+    /// Executed, as an opcode or operand.
+    Code,
+    /// Executed after being jumped to through a pointer.
+    IndirectCode,
+    /// Read by an instruction, or as an interrupt vector.
+    Data,
+    /// Read through a pointer, as by `LDA ($12),Y`.
+    IndirectData,
+    /// Fetched by the DMC as sample data.
+    Pcm,
+}
+
+/// A single read or write made by the CPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BusAccess {
+    pub address: u16,
+    pub value: u8,
+    pub kind: AccessKind,
+}
+
+/// A machine that is nothing but 64 KiB of RAM, for running raw 6502
+/// binaries such as test suites.
+#[derive(Clone)]
+pub struct FlatBus {
+    memory: Box<[u8; 0x10000]>,
+}
+
+impl FlatBus {
+    pub fn new() -> Self {
+        Self {
+            memory: Box::new([0; 0x10000]),
+        }
+    }
+
+    /// Copies `data` into memory starting at `address`, wrapping around the
+    /// end of the address space.
+    pub fn load(&mut self, address: u16, data: &[u8]) {
+        for (offset, byte) in data.iter().enumerate() {
+            self.memory[address.wrapping_add(offset as u16) as usize] = *byte;
+        }
+    }
+}
+
+impl Default for FlatBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bus for FlatBus {
+    fn read(&mut self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        self.memory[address as usize] = data;
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    fn save_state(&self) -> Option<Self> {
+        Some(self.clone())
+    }
+
+    fn load_state(&mut self, state: &Self) {
+        self.memory.copy_from_slice(&state.memory[..]);
+    }
+}
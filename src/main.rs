@@ -1,13 +1,126 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
 #[cfg(feature = "nestest")]
-use nise::nes::{bus::NiseBus, cpu::Nise6502, rom::Rom};
+use nise::nes::trace::{TraceFormat, TraceWriter};
+use nise::nes::{
+    bus::NiseBus, cpu::Nise6502, dap::DapServer, debugger::Debugger, gdb::GdbStub,
+    monitor::Monitor, rom::Rom,
+};
+
+const USAGE: &str = "usage: nise monitor <rom.nes> [script] [--run]
+       nise gdb <rom.nes> [port]
+       nise dap [port]";
+
+const DEFAULT_GDB_PORT: u16 = 6502;
 
 fn main() {
-    #[cfg(feature = "nestest")]
-    {
-        let nesdata = std::fs::read("./nestest.nes").expect("Unable to read rom!");
-        let rom = Rom::new(&nesdata).unwrap();
-        let bus = NiseBus::new(rom);
-        let mut nes = Nise6502::new(bus);
-        let _ = nes.nestest();
+    let args = std::env::args().collect::<Vec<_>>();
+    match args.get(1).map(String::as_str) {
+        Some("monitor") => monitor(&args[2..]),
+        Some("gdb") => gdb(&args[2..]),
+        Some("dap") => dap(&args[2..]),
+        Some(_) => usage(),
+        None => {
+            #[cfg(feature = "nestest")]
+            {
+                let nesdata = std::fs::read("./nestest.nes").expect("Unable to read rom!");
+                let rom = Rom::new(&nesdata).unwrap();
+                let bus = NiseBus::new(rom);
+                let mut nes = Nise6502::new(bus);
+                let log = std::fs::File::create("output.log").expect("Unable to write log!");
+                let tracer = TraceWriter::new(std::io::BufWriter::new(log), TraceFormat::Nestest);
+                nes.set_cycle_accurate(true);
+                nes.reset();
+                nes.set_pc(0xC000);
+                nes.set_tracer(Some(Box::new(tracer)));
+                while nes.cycles() < 40000 {
+                    nes.step();
+                }
+                // Flushes the log
+                nes.set_tracer(None);
+            }
+        }
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(1);
+}
+
+// Powers on the machine with the ROM at `path` inserted, stopped at reset
+fn load(path: Option<&String>) -> Debugger<NiseBus> {
+    let Some(path) = path else { usage() };
+    let nesdata = std::fs::read(path).expect("Unable to read rom!");
+    let rom = Rom::new(&nesdata).expect("Not an iNES rom!");
+    let banks = rom.prg_rom.len() / 0x4000;
+    let mut cpu = Nise6502::new(NiseBus::new(rom));
+    cpu.set_cycle_accurate(true);
+    cpu.reset();
+
+    // Labels kept beside the ROM, as ld65, FCEUX or Mesen would save them
+    let nl = |suffix: &str| PathBuf::from(format!("{}.{}.nl", path, suffix));
+    let mut labels = vec![
+        Path::new(path).with_extension("dbg"),
+        Path::new(path).with_extension("mlb"),
+        nl("ram"),
+    ];
+    labels.extend((0..banks).map(|bank| nl(&bank.to_string())));
+    for file in labels.iter().filter(|file| file.exists()) {
+        if let Err(error) = cpu.symbols_mut().load(file) {
+            eprintln!("{}", error);
+        }
+    }
+    Debugger::new(cpu)
+}
+
+// Runs the commands in the script, if any, then attaches to the machine and
+// takes commands typed on stdin. The machine runs straight away with --run,
+// or when the script leaves the monitor with x.
+fn monitor(args: &[String]) {
+    let mut run = args.iter().any(|arg| arg == "--run");
+    let args = args
+        .iter()
+        .filter(|arg| *arg != "--run")
+        .collect::<Vec<_>>();
+    let mut monitor = Monitor::new(load(args.first().copied()));
+    let mut stdout = std::io::stdout();
+    if let Some(script) = args.get(1) {
+        let script = std::fs::File::open(script).expect("Unable to read script!");
+        if !monitor
+            .run(std::io::BufReader::new(script), &mut stdout, true)
+            .unwrap()
+        {
+            if monitor.has_quit() {
+                return;
+            }
+            run = true;
+        }
+        writeln!(stdout).unwrap();
+    }
+    monitor.attach(std::io::stdin(), &mut stdout, run).unwrap();
+}
+
+fn gdb(args: &[String]) {
+    let mut stub = GdbStub::new(load(args.first()));
+    let port = match args.get(1) {
+        Some(port) => port.parse().unwrap_or_else(|_| usage()),
+        None => DEFAULT_GDB_PORT,
+    };
+    eprintln!("Waiting for a GDB client on 127.0.0.1:{}", port);
+    stub.listen(("127.0.0.1", port)).unwrap();
+}
+
+// Editors launch the ROM themselves, over stdio unless a port is given
+fn dap(args: &[String]) {
+    let mut server = DapServer::new();
+    match args.first() {
+        Some(port) => {
+            let port: u16 = port.parse().unwrap_or_else(|_| usage());
+            eprintln!("Waiting for a DAP client on 127.0.0.1:{}", port);
+            server.listen(("127.0.0.1", port)).unwrap();
+        }
+        None => server.serve(std::io::stdin(), std::io::stdout()).unwrap(),
     }
 }
@@ -1,4 +1,17 @@
+pub mod asm;
 pub mod bus;
+pub mod cdl;
 pub mod cpu;
+pub mod dap;
+pub mod dbginfo;
+pub mod debugger;
+pub mod disasm;
+pub mod gdb;
+pub mod history;
+pub mod hooks;
+pub mod monitor;
 pub mod ppu;
+pub mod profiler;
 pub mod rom;
+pub mod symbols;
+pub mod trace;
@@ -0,0 +1,712 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use crate::common::bus::FlatBus;
+use crate::nes::cpu::Variant;
+use crate::nes::disasm::{opcodes, AddressingMode, Opcode};
+use AddressingMode::*;
+
+/// A run of bytes assembled from one `.org` onwards.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+}
+
+/// The output of the assembler.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Assembly {
+    pub segments: Vec<Segment>,
+    /// Every label and constant. Cheap local labels are listed as
+    /// `global@local`.
+    pub symbols: BTreeMap<String, u16>,
+}
+
+impl Assembly {
+    /// Lays the segments out over `length` bytes starting at `address`, with
+    /// `fill` in the gaps. Bytes outside the window are dropped.
+    pub fn image(&self, address: u16, length: usize, fill: u8) -> Vec<u8> {
+        let mut image = vec![fill; length];
+        for segment in &self.segments {
+            for (offset, byte) in segment.bytes.iter().enumerate() {
+                let index = (segment.address as usize + offset).wrapping_sub(address as usize);
+                if index < length {
+                    image[index] = *byte;
+                }
+            }
+        }
+        image
+    }
+
+    /// Loads the segments into 64 KiB of RAM.
+    pub fn flat_bus(&self) -> FlatBus {
+        let mut bus = FlatBus::new();
+        for segment in &self.segments {
+            bus.load(segment.address, &segment.bytes);
+        }
+        bus
+    }
+
+    /// Builds an NROM iNES file with one or two 16 KiB banks of code, mapped
+    /// to the top of the address space, and blank CHR ROM.
+    pub fn ines(&self, prg_banks: u8) -> Vec<u8> {
+        assert!(matches!(prg_banks, 1 | 2), "NROM has one or two PRG banks");
+        let prg_length = prg_banks as usize * 0x4000;
+        let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, prg_banks, 1, 0, 0];
+        rom.resize(16, 0);
+        rom.extend(self.image((0x10000 - prg_length) as u16, prg_length, 0xFF));
+        rom.resize(16 + prg_length + 0x2000, 0);
+        rom
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AsmError {
+    /// 1-based line number in the source.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// Assembles `source` for `variant`. The syntax is a subset of ca65's:
+/// labels (`name:`, and cheap locals `@name:`), constants (`name = expr`),
+/// `.org`, `.byte`, `.word` and `.res`, and expressions with the usual
+/// operators plus `<` and `>` for the low and high byte. Forward references
+/// are assembled as absolute addresses; `a:` forces that for known ones.
+pub fn assemble(variant: Variant, source: &str) -> Result<Assembly, AsmError> {
+    let mut assembler = Assembler {
+        table: opcodes(variant),
+        symbols: HashMap::new(),
+        modes: HashMap::new(),
+        segments: vec![],
+        final_pass: false,
+        pc: 0,
+        scope: String::new(),
+    };
+    for final_pass in [false, true] {
+        assembler.final_pass = final_pass;
+        assembler.pc = 0;
+        assembler.scope.clear();
+        assembler.segments = vec![Segment {
+            address: 0,
+            bytes: vec![],
+        }];
+        for (index, line) in source.lines().enumerate() {
+            assembler.line(index, line).map_err(|message| AsmError {
+                line: index + 1,
+                message,
+            })?;
+        }
+    }
+    let Assembler {
+        symbols, segments, ..
+    } = assembler;
+    Ok(Assembly {
+        segments: segments
+            .into_iter()
+            .filter(|segment| !segment.bytes.is_empty())
+            .collect(),
+        symbols: symbols
+            .into_iter()
+            .map(|(name, value)| (name, value as u16))
+            .collect(),
+    })
+}
+
+// Alternative names for unofficial opcodes, as used by other assemblers
+const ALIASES: [(&str, &str); 9] = [
+    ("ASR", "ALR"),
+    ("SBX", "AXS"),
+    ("DCM", "DCP"),
+    ("ISC", "ISB"),
+    ("INS", "ISB"),
+    ("KIL", "JAM"),
+    ("ANE", "XAA"),
+    ("AHX", "SHA"),
+    ("LAR", "LAS"),
+];
+
+// The syntactic shape of an operand, before a mode is picked for it
+enum Form {
+    None,
+    Accumulator,
+    Immediate(Option<i64>),
+    IndexedIndirect(Option<i64>),
+    IndirectIndexed(Option<i64>),
+    Indirect(Option<i64>),
+    Direct(Option<i64>),
+    DirectX(Option<i64>),
+    DirectY(Option<i64>),
+    ZeroPageRelative(Option<i64>, Option<i64>),
+}
+
+struct Assembler {
+    table: &'static [Opcode; 256],
+    symbols: HashMap<String, i64>,
+    // The mode picked for each instruction in the first pass, so that the
+    // second pass lays out the same addresses
+    modes: HashMap<usize, AddressingMode>,
+    segments: Vec<Segment>,
+    final_pass: bool,
+    pc: u32,
+    scope: String,
+}
+
+impl Assembler {
+    fn line(&mut self, index: usize, line: &str) -> Result<(), String> {
+        let mut text = strip_comment(line).trim();
+
+        // Labels, of which there may be several
+        while let Some(end) = text.find(':') {
+            let name = text[..end].trim();
+            if !is_identifier(name) {
+                break;
+            }
+            if !name.starts_with('@') {
+                self.scope = name.to_string();
+            }
+            let name = self.qualify(name);
+            self.define(name, self.pc as i64)?;
+            text = text[end + 1..].trim();
+        }
+        if text.is_empty() {
+            return Ok(());
+        }
+
+        let (word, rest) = match text.find(|c: char| c.is_whitespace() || c == '=') {
+            Some(end) => (&text[..end], text[end..].trim()),
+            None => (text, ""),
+        };
+        if let Some(value) = rest.strip_prefix('=') {
+            if !is_identifier(word) {
+                return Err(format!("invalid symbol name `{}`", word));
+            }
+            let value = self.expression(value)?;
+            let name = self.qualify(word);
+            return match value {
+                Some(value) => self.define(name, value),
+                None => Ok(()),
+            };
+        }
+
+        if let Some(directive) = word.strip_prefix('.') {
+            self.directive(&directive.to_lowercase(), rest)
+        } else {
+            self.instruction(index, &word.to_uppercase(), rest)
+        }
+    }
+
+    fn directive(&mut self, directive: &str, operands: &str) -> Result<(), String> {
+        match directive {
+            "org" => {
+                let address = self.known(operands)?;
+                if !(0..=0xFFFF).contains(&address) {
+                    return Err(format!("origin ${:X} is out of range", address));
+                }
+                self.pc = address as u32;
+                self.segments.push(Segment {
+                    address: address as u16,
+                    bytes: vec![],
+                });
+            }
+            "byte" | "db" => {
+                for operand in split_operands(operands) {
+                    if let Some(string) = operand.strip_prefix('"') {
+                        let string = string.strip_suffix('"').ok_or("unterminated string")?;
+                        for byte in string.bytes() {
+                            self.emit(byte)?;
+                        }
+                    } else {
+                        let value = self.expression(operand)?;
+                        self.emit_byte(value)?;
+                    }
+                }
+            }
+            "word" | "dw" | "addr" => {
+                for operand in split_operands(operands) {
+                    let value = self.expression(operand)?;
+                    self.emit_word(value)?;
+                }
+            }
+            "res" => {
+                let operands = split_operands(operands);
+                let count = self.known(operands.first().ok_or("missing count")?)?;
+                let fill = match operands.get(1) {
+                    Some(fill) => self.expression(fill)?,
+                    None => Some(0),
+                };
+                for _ in 0..count {
+                    self.emit_byte(fill)?;
+                }
+            }
+            _ => return Err(format!("unknown directive `.{}`", directive)),
+        }
+        Ok(())
+    }
+
+    fn instruction(&mut self, index: usize, mnemonic: &str, operand: &str) -> Result<(), String> {
+        let mnemonic = ALIASES
+            .iter()
+            .find(|(alias, _)| *alias == mnemonic)
+            .map_or(mnemonic, |(_, name)| name);
+        if !self.table.iter().any(|entry| entry.mnemonic == mnemonic) {
+            return Err(format!("unknown instruction `{}`", mnemonic));
+        }
+
+        let (forced_absolute, operand) = match operand.get(..2) {
+            Some("a:" | "A:") => (true, &operand[2..]),
+            _ => (false, operand),
+        };
+        let form = self.form(operand)?;
+        let mode = match self.modes.get(&index) {
+            Some(mode) if self.final_pass => *mode,
+            _ => {
+                let mode = self.pick_mode(mnemonic, &form, forced_absolute)?;
+                self.modes.insert(index, mode);
+                mode
+            }
+        };
+        let opcode = self
+            .opcode(mnemonic, mode)
+            .ok_or_else(|| format!("`{}` has no {:?} mode", mnemonic, mode))?;
+        self.emit(opcode)?;
+
+        match form {
+            Form::None | Form::Accumulator => {}
+            Form::ZeroPageRelative(address, target) => {
+                self.emit_byte(address)?;
+                self.emit_branch(target)?;
+            }
+            Form::Immediate(value)
+            | Form::IndexedIndirect(value)
+            | Form::IndirectIndexed(value)
+            | Form::Indirect(value)
+            | Form::Direct(value)
+            | Form::DirectX(value)
+            | Form::DirectY(value) => {
+                if mode == Relative {
+                    self.emit_branch(value)?
+                } else if mode.operand_length() == 1 {
+                    self.emit_byte(value)?
+                } else {
+                    self.emit_word(value)?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Takes the first mode the instruction has, skipping zero page modes when
+    // the address is unknown or too big, unless there is no alternative
+    fn pick_mode(
+        &self,
+        mnemonic: &str,
+        form: &Form,
+        forced_absolute: bool,
+    ) -> Result<AddressingMode, String> {
+        let fits = |value: &Option<i64>| !forced_absolute && matches!(value, Some(0..=0xFF));
+        let candidates: &[(AddressingMode, bool)] = match form {
+            Form::None => &[(Implied, false), (Accumulator, false)],
+            Form::Accumulator => &[(Accumulator, false)],
+            Form::Immediate(_) => &[(Immediate, false)],
+            Form::IndexedIndirect(_) => {
+                &[(IndexedIndirect, true), (AbsoluteIndexedIndirect, false)]
+            }
+            Form::IndirectIndexed(_) => &[(IndirectIndexed, true)],
+            Form::Indirect(_) => &[(ZeroPageIndirect, true), (Indirect, false)],
+            Form::Direct(_) => &[(Relative, false), (ZeroPage, true), (Absolute, false)],
+            Form::DirectX(_) => &[(ZeroPageX, true), (AbsoluteX, false)],
+            Form::DirectY(_) => &[(ZeroPageY, true), (AbsoluteY, false)],
+            Form::ZeroPageRelative(..) => &[(ZeroPageRelative, false)],
+        };
+        let value = match form {
+            Form::IndexedIndirect(value)
+            | Form::IndirectIndexed(value)
+            | Form::Indirect(value)
+            | Form::Direct(value)
+            | Form::DirectX(value)
+            | Form::DirectY(value) => *value,
+            _ => None,
+        };
+        let available =
+            |(mode, _): &&(AddressingMode, bool)| self.opcode(mnemonic, *mode).is_some();
+        candidates
+            .iter()
+            .filter(available)
+            .find(|(_, zero_page)| !zero_page || fits(&value))
+            .or_else(|| candidates.iter().find(available))
+            .map(|(mode, _)| *mode)
+            .ok_or_else(|| format!("`{}` does not take this operand", mnemonic))
+    }
+
+    // Prefers the documented opcode where an unofficial one does the same
+    fn opcode(&self, mnemonic: &str, mode: AddressingMode) -> Option<u8> {
+        let matches = |entry: &&Opcode| entry.mnemonic == mnemonic && entry.mode == mode;
+        let position = |unofficial: bool| {
+            self.table
+                .iter()
+                .position(|entry| matches(&entry) && entry.unofficial == unofficial)
+        };
+        position(false)
+            .or_else(|| position(true))
+            .map(|code| code as u8)
+    }
+
+    fn form(&self, operand: &str) -> Result<Form, String> {
+        let operand = operand.trim();
+        if operand.is_empty() {
+            return Ok(Form::None);
+        }
+        if operand.eq_ignore_ascii_case("a") {
+            return Ok(Form::Accumulator);
+        }
+        if let Some(value) = operand.strip_prefix('#') {
+            return Ok(Form::Immediate(self.expression(value)?));
+        }
+
+        if operand.starts_with('(') {
+            let close = matching_paren(operand).ok_or("unbalanced parentheses")?;
+            let inner = &operand[1..close];
+            let after = operand[close + 1..].trim();
+            if let Some(base) = strip_index(inner, 'X') {
+                if after.is_empty() {
+                    return Ok(Form::IndexedIndirect(self.expression(base)?));
+                }
+            } else if after.is_empty() {
+                return Ok(Form::Indirect(self.expression(inner)?));
+            } else if strip_index(after, 'Y') == Some("") {
+                return Ok(Form::IndirectIndexed(self.expression(inner)?));
+            }
+            // Otherwise the parentheses just group an expression
+        }
+
+        if let Some(base) = strip_index(operand, 'X') {
+            return Ok(Form::DirectX(self.expression(base)?));
+        }
+        if let Some(base) = strip_index(operand, 'Y') {
+            return Ok(Form::DirectY(self.expression(base)?));
+        }
+        match split_operands(operand)[..] {
+            [value] => Ok(Form::Direct(self.expression(value)?)),
+            [address, target] => Ok(Form::ZeroPageRelative(
+                self.expression(address)?,
+                self.expression(target)?,
+            )),
+            _ => Err("too many operands".to_string()),
+        }
+    }
+
+    fn emit(&mut self, byte: u8) -> Result<(), String> {
+        if self.pc > 0xFFFF {
+            return Err("code runs past $FFFF".to_string());
+        }
+        self.pc += 1;
+        if self.final_pass {
+            self.segments.last_mut().unwrap().bytes.push(byte);
+        }
+        Ok(())
+    }
+
+    fn emit_byte(&mut self, value: Option<i64>) -> Result<(), String> {
+        let value = self.resolve(value)?;
+        if !(-0x80..=0xFF).contains(&value) {
+            return Err(format!("${:X} does not fit in a byte", value));
+        }
+        self.emit(value as u8)
+    }
+
+    fn emit_word(&mut self, value: Option<i64>) -> Result<(), String> {
+        let value = self.resolve(value)?;
+        if !(-0x8000..=0xFFFF).contains(&value) {
+            return Err(format!("${:X} does not fit in a word", value));
+        }
+        self.emit(value as u8)?;
+        self.emit((value >> 8) as u8)
+    }
+
+    // The offset is relative to the end of the instruction, which is the
+    // byte after this one
+    fn emit_branch(&mut self, target: Option<i64>) -> Result<(), String> {
+        let offset = self.resolve(target)? - (self.pc as i64 + 1);
+        if self.final_pass && !(-0x80..=0x7F).contains(&offset) {
+            return Err(format!("branch target is {} bytes away", offset));
+        }
+        self.emit(offset as u8)
+    }
+
+    // Unknown values are only allowed in the first pass, as placeholders
+    fn resolve(&self, value: Option<i64>) -> Result<i64, String> {
+        match value {
+            Some(value) => Ok(value),
+            None if !self.final_pass => Ok(0),
+            None => Err("undefined symbol".to_string()),
+        }
+    }
+
+    // For values that decide the layout, which must not depend on later lines
+    fn known(&self, text: &str) -> Result<i64, String> {
+        self.expression(text)?
+            .ok_or_else(|| "value must be defined before use".to_string())
+    }
+
+    fn define(&mut self, name: String, value: i64) -> Result<(), String> {
+        match self.symbols.insert(name.clone(), value) {
+            Some(old) if !self.final_pass && old != value => {
+                Err(format!("`{}` is already defined", name))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn qualify(&self, name: &str) -> String {
+        if name.starts_with('@') {
+            format!("{}{}", self.scope, name)
+        } else {
+            name.to_string()
+        }
+    }
+
+    fn expression(&self, text: &str) -> Result<Option<i64>, String> {
+        let mut parser = Parser {
+            assembler: self,
+            text: text.as_bytes(),
+            position: 0,
+        };
+        let value = parser.expression(0)?;
+        parser.skip_whitespace();
+        if parser.position != text.len() {
+            return Err(format!("unexpected `{}`", &text[parser.position..]));
+        }
+        Ok(value)
+    }
+}
+
+// Binary operators from loosest to tightest binding
+const OPERATORS: [&[&str]; 5] = [&["|"], &["^"], &["&"], &["<<", ">>"], &["+", "-"]];
+const PRODUCT_OPERATORS: [&str; 2] = ["*", "/"];
+
+struct Parser<'a> {
+    assembler: &'a Assembler,
+    text: &'a [u8],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn expression(&mut self, level: usize) -> Result<Option<i64>, String> {
+        let operators: &[&str] = match level {
+            0..=4 => OPERATORS[level],
+            5 => &PRODUCT_OPERATORS,
+            _ => return self.unary(),
+        };
+        let mut value = self.expression(level + 1)?;
+        'outer: loop {
+            self.skip_whitespace();
+            for operator in operators {
+                if self.text[self.position..].starts_with(operator.as_bytes()) {
+                    self.position += operator.len();
+                    let rhs = self.expression(level + 1)?;
+                    value = match (value, rhs) {
+                        (Some(lhs), Some(rhs)) => Some(apply(operator, lhs, rhs)?),
+                        _ => None,
+                    };
+                    continue 'outer;
+                }
+            }
+            return Ok(value);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Option<i64>, String> {
+        self.skip_whitespace();
+        let Some(&c) = self.text.get(self.position) else {
+            return Err("missing value".to_string());
+        };
+        let op: fn(i64) -> Option<i64> = match c {
+            b'-' => i64::checked_neg,
+            b'~' => |v| Some(!v),
+            b'<' => |v| Some(v & 0xFF),
+            b'>' => |v| Some((v >> 8) & 0xFF),
+            b'+' => Some,
+            _ => return self.primary(),
+        };
+        self.position += 1;
+        match self.unary()? {
+            Some(value) => op(value).map(Some).ok_or_else(|| "overflow".to_string()),
+            None => Ok(None),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Option<i64>, String> {
+        let rest = &self.text[self.position..];
+        match rest[0] {
+            b'(' => {
+                self.position += 1;
+                let value = self.expression(0)?;
+                self.skip_whitespace();
+                if self.text.get(self.position) != Some(&b')') {
+                    return Err("missing `)`".to_string());
+                }
+                self.position += 1;
+                Ok(value)
+            }
+            b'*' => {
+                self.position += 1;
+                Ok(Some(self.assembler.pc as i64))
+            }
+            b'\'' => match rest {
+                [_, c, b'\'', ..] => {
+                    self.position += 3;
+                    Ok(Some(*c as i64))
+                }
+                _ => Err("invalid character constant".to_string()),
+            },
+            b'$' => self.number(1, 16),
+            b'%' => self.number(1, 2),
+            b'0'..=b'9' => self.number(0, 10),
+            _ => {
+                let length = rest
+                    .iter()
+                    .position(|c| !is_identifier_char(*c as char))
+                    .unwrap_or(rest.len());
+                let name = std::str::from_utf8(&rest[..length]).unwrap();
+                if !is_identifier(name) {
+                    return Err(format!("unexpected `{}`", rest[0] as char));
+                }
+                self.position += length;
+                Ok(self
+                    .assembler
+                    .symbols
+                    .get(&self.assembler.qualify(name))
+                    .copied())
+            }
+        }
+    }
+
+    fn number(&mut self, prefix: usize, radix: u32) -> Result<Option<i64>, String> {
+        self.position += prefix;
+        let start = self.position;
+        while self
+            .text
+            .get(self.position)
+            .is_some_and(|c| (*c as char).is_digit(radix))
+        {
+            self.position += 1;
+        }
+        let digits = std::str::from_utf8(&self.text[start..self.position]).unwrap();
+        i64::from_str_radix(digits, radix)
+            .map(Some)
+            .map_err(|_| "invalid number".to_string())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .text
+            .get(self.position)
+            .is_some_and(|c| c.is_ascii_whitespace())
+        {
+            self.position += 1;
+        }
+    }
+}
+
+fn apply(operator: &str, lhs: i64, rhs: i64) -> Result<i64, String> {
+    let value = match operator {
+        "|" => Some(lhs | rhs),
+        "^" => Some(lhs ^ rhs),
+        "&" => Some(lhs & rhs),
+        "<<" => Some(lhs << (rhs & 63)),
+        ">>" => Some(lhs >> (rhs & 63)),
+        "+" => lhs.checked_add(rhs),
+        "-" => lhs.checked_sub(rhs),
+        "*" => lhs.checked_mul(rhs),
+        "/" if rhs == 0 => return Err("division by zero".to_string()),
+        "/" => lhs.checked_div(rhs),
+        _ => return Err(format!("unknown operator `{}`", operator)),
+    };
+    value.ok_or_else(|| "overflow".to_string())
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '@'
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '@' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &line[..index],
+            _ => {}
+        }
+    }
+    line
+}
+
+// Splits on commas outside of strings, character constants and parentheses
+fn split_operands(text: &str) -> Vec<&str> {
+    let mut operands = vec![];
+    let (mut depth, mut quoted, mut start) = (0, false, 0);
+    let bytes = text.as_bytes();
+    let mut index = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'"' => quoted = !quoted,
+            b'\'' if !quoted => index += 2,
+            b'(' if !quoted => depth += 1,
+            b')' if !quoted => depth -= 1,
+            b',' if !quoted && depth == 0 => {
+                operands.push(text[start..index].trim());
+                start = index + 1;
+            }
+            _ => {}
+        }
+        index += 1;
+    }
+    let last = text[start.min(text.len())..].trim();
+    if !last.is_empty() || !operands.is_empty() {
+        operands.push(last);
+    }
+    operands
+}
+
+// `base,X` becomes `base`
+fn strip_index(text: &str, register: char) -> Option<&str> {
+    match split_operands(text)[..] {
+        [base, index] if index.eq_ignore_ascii_case(&register.to_string()) => Some(base),
+        _ => None,
+    }
+}
+
+fn matching_paren(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (index, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(index);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
@@ -1,3 +1,8 @@
+use std::path::Path;
+
+use crate::common::bus::{Bus, Dma, Usage};
+use crate::nes::cdl::{self, CodeDataLog};
+use crate::nes::hooks::MemoryHooks;
 use crate::nes::ppu::NisePPU;
 use crate::nes::rom::four_screen_mirrored_addr;
 use crate::nes::rom::horizontal_mirrored_addr;
@@ -9,6 +14,13 @@ pub struct NiseBus {
     memory: [u8; 2048],
     ppu: NisePPU,
     prg_rom: Vec<u8>,
+    // Code/data log flags for each byte of PRG ROM, while logging
+    prg_log: Option<Vec<u8>>,
+    ppu_hooks: MemoryHooks,
+    // DMA waiting for the CPU to halt, and the last byte the DMC fetched
+    oam_dma: Option<u8>,
+    dmc_dma: Option<u16>,
+    dmc_sample: Option<u8>,
 }
 
 impl NiseBus {
@@ -24,26 +36,112 @@ impl NiseBus {
             memory: [0; 2048],
             ppu,
             prg_rom,
+            prg_log: None,
+            ppu_hooks: MemoryHooks::new(),
+            oam_dma: None,
+            dmc_dma: None,
+            dmc_sample: None,
+        }
+    }
+
+    pub fn ppu(&self) -> &NisePPU {
+        &self.ppu
+    }
+
+    /// Hooks on the PPU address space, $0000-$3FFF. They only see the CPU's
+    /// reads and writes through PPUDATA, the nametable byte read behind a
+    /// palette entry included, as the PPU does not render yet.
+    pub fn ppu_hooks(&self) -> &MemoryHooks {
+        &self.ppu_hooks
+    }
+
+    pub fn ppu_hooks_mut(&mut self) -> &mut MemoryHooks {
+        &mut self.ppu_hooks
+    }
+
+    /// Has the DMC fetch the sample byte at `address`, which halts the CPU
+    /// for three or four cycles. There is no APU yet to ask for samples.
+    pub fn request_dmc_fetch(&mut self, address: u16) {
+        self.dmc_dma = Some(address);
+    }
+
+    /// The byte fetched by the last DMC DMA.
+    pub fn dmc_sample(&self) -> Option<u8> {
+        self.dmc_sample
+    }
+
+    /// Starts logging how each byte of PRG and CHR ROM is used, unless a log
+    /// is already being kept. The CPU has to `set_log_usage` too.
+    pub fn start_code_data_log(&mut self) {
+        if self.prg_log.is_none() {
+            let log = CodeDataLog::new(self.prg_rom.len(), self.ppu.chr_rom().len());
+            self.set_code_data_log(log);
+        }
+    }
+
+    /// Carries on logging from a `.cdl` file made for this ROM.
+    pub fn load_code_data_log(&mut self, path: impl AsRef<Path>) -> Result<(), String> {
+        let log = CodeDataLog::load(path, self.prg_rom.len(), self.ppu.chr_rom().len())?;
+        self.set_code_data_log(log);
+        Ok(())
+    }
+
+    pub fn stop_code_data_log(&mut self) -> Option<CodeDataLog> {
+        let log = self.code_data_log();
+        self.prg_log = None;
+        self.ppu.chr_log = None;
+        log
+    }
+
+    /// A copy of the log kept since `start_code_data_log`, if any.
+    pub fn code_data_log(&self) -> Option<CodeDataLog> {
+        Some(CodeDataLog {
+            prg: self.prg_log.clone()?,
+            chr: self.ppu.chr_log.clone()?,
+        })
+    }
+
+    fn set_code_data_log(&mut self, log: CodeDataLog) {
+        self.prg_log = Some(log.prg);
+        self.ppu.chr_log = Some(log.chr);
+    }
+
+    fn read_prg_rom(&self, mut addr: u16) -> u8 {
+        addr -= 0x8000;
+        if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
+            //mirror if needed
+            addr %= 0x4000;
+        }
+        self.prg_rom[addr as usize]
+    }
+}
+
+impl Bus for NiseBus {
+    fn tick(&mut self) {
+        for _ in 0..3 {
+            self.ppu.clock();
         }
     }
 
-    // TODO: IMPLEMENT PPU REGISTER ACCESS
-    pub fn read(&mut self, address: u16) -> u8 {
+    fn nmi_line(&self) -> bool {
+        self.ppu.nmi_line()
+    }
+
+    fn read(&mut self, address: u16) -> u8 {
         match address {
             0x0000..=0x1fff => {
-                let mirrored_addr = address & 0b0011_11111111;
+                let mirrored_addr = address & 0b0111_11111111;
                 self.memory[mirrored_addr as usize]
             }
             0x2000..=0x3FFF => {
                 let mirrored_addr = address & 0x0007;
                 match mirrored_addr {
-                    0 | 1 | 3 | 5 | 6 => panic!("Attempt to read to write-only PPU register!"),
-                    2 => {
-                        self.ppu.w = 0;
-                        self.ppu.ppustatus
-                    }
-                    4 => self.ppu.oamdata,
-                    7 => self.ppu.ppudata,
+                    // Indexed writes do a dummy read first, so reads of
+                    // write-only registers are legal and return open bus
+                    0 | 1 | 3 | 5 | 6 => 0,
+                    2 => self.ppu.read_status(),
+                    4 => self.ppu.read_oam(),
+                    7 => self.ppu.read_data(&mut self.ppu_hooks),
                     _ => panic!("Invalid mirrored address?"),
                 }
             }
@@ -51,44 +149,129 @@ impl NiseBus {
             _ => 0,
         }
     }
-    pub fn write(&mut self, address: u16, data: u8) {
+
+    fn write(&mut self, address: u16, data: u8) {
         match address {
             0x0000..=0x1fff => {
-                let mirrored_addr = address & 0b0011_11111111;
-                self.memory[mirrored_addr as usize] = data as u8;
+                let mirrored_addr = address & 0b0111_11111111;
+                self.memory[mirrored_addr as usize] = data;
             }
             0x2000..=0x3FFF => {
                 let mirrored_addr = address & 0x0007;
                 match mirrored_addr {
-                    2 => panic!("Attempt to write to read-only PPU register!"),
-                    0 => self.ppu.ppuctrl = data,
+                    // PPUSTATUS is read-only; a write only fills the PPU's
+                    // open bus latch, which isn't modelled
+                    2 => {}
+                    0 => self.ppu.write_ctrl(data),
                     1 => self.ppu.ppumask = data,
                     3 => self.ppu.oamaddr = data,
-                    4 => self.ppu.oamdata = data,
-                    5 => {
-                        self.ppu.w ^= 1;
-                        self.ppu.ppuscroll = data
-                    }
-                    6 => {
-                        self.ppu.w ^= 1;
-                        self.ppu.ppuaddr = data
-                    }
-                    7 => self.ppu.ppudata = data,
+                    4 => self.ppu.write_oam(data),
+                    5 => self.ppu.write_scroll(data),
+                    6 => self.ppu.write_addr(data),
+                    7 => self.ppu.write_data(data, &mut self.ppu_hooks),
                     _ => panic!("Invalid mirrored address?"),
                 }
             }
-            0x8000..=0xFFFF => {
-                panic!("Attempt to write to Cartridge ROM space")
+            0x4014 => {
+                self.ppu.oamdma = data;
+                self.oam_dma = Some(data);
             }
+            // NROM has no registers, so writes to ROM are lost
+            0x8000..=0xFFFF => {}
             _ => {}
         }
     }
-    fn read_prg_rom(&self, mut addr: u16) -> u8 {
-        addr -= 0x8000;
-        if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
-            //mirror if needed
-            addr = addr % 0x4000;
+
+    fn peek(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x1fff => self.memory[(address & 0b0111_11111111) as usize],
+            0x2000..=0x3FFF => match address & 0x0007 {
+                2 => self.ppu.ppustatus,
+                4 => self.ppu.read_oam(),
+                7 => self.ppu.peek_data(),
+                _ => 0,
+            },
+            0x8000..=0xFFFF => self.read_prg_rom(address),
+            _ => 0,
+        }
+    }
+
+    fn prg_offset(&self, address: u16) -> Option<u32> {
+        match address {
+            0x8000..=0xFFFF => Some((address as u32 - 0x8000) % self.prg_rom.len() as u32),
+            _ => None,
+        }
+    }
+
+    // A single 16 KiB bank appears twice, and the upper copy has the vectors
+    fn prg_address(&self, offset: u32) -> Option<u16> {
+        match self.prg_rom.len() as u32 {
+            0x4000 if offset < 0x4000 => Some(0xC000 + offset as u16),
+            length if offset < length => Some(0x8000 + offset as u16),
+            _ => None,
+        }
+    }
+
+    // Code/data logs are a record of the whole session, and hooks belong to
+    // the tools that added them, not to the machine, so they are left out
+    fn save_state(&self) -> Option<Self> {
+        let mut ppu = self.ppu.clone();
+        ppu.chr_log = None;
+        Some(Self {
+            memory: self.memory,
+            ppu,
+            prg_rom: self.prg_rom.clone(),
+            prg_log: None,
+            ppu_hooks: MemoryHooks::new(),
+            oam_dma: self.oam_dma,
+            dmc_dma: self.dmc_dma,
+            dmc_sample: self.dmc_sample,
+        })
+    }
+
+    fn load_state(&mut self, state: &Self) {
+        let chr_log = self.ppu.chr_log.take();
+        self.memory = state.memory;
+        self.ppu = state.ppu.clone();
+        self.ppu.chr_log = chr_log;
+        self.prg_rom.clone_from(&state.prg_rom);
+        self.oam_dma = state.oam_dma;
+        self.dmc_dma = state.dmc_dma;
+        self.dmc_sample = state.dmc_sample;
+    }
+
+    fn take_dma(&mut self) -> Option<Dma> {
+        match self.oam_dma.take() {
+            Some(page) => Some(Dma::Oam(page)),
+            None => self.dmc_dma.take().map(Dma::Dmc),
+        }
+    }
+
+    fn dmc_fetched(&mut self, value: u8) {
+        self.dmc_sample = Some(value);
+    }
+
+    fn video_position(&self) -> Option<(usize, usize)> {
+        Some((self.ppu.scanline(), self.ppu.dot()))
+    }
+
+    fn log_usage(&mut self, address: u16, usage: Usage) {
+        let offset = self.prg_offset(address);
+        if let (Some(log), Some(offset)) = (&mut self.prg_log, offset) {
+            log[offset as usize] |= cdl::prg_flags(usage, address);
+        }
+    }
+
+    fn poke(&mut self, address: u16, data: u8) {
+        match address {
+            // Writing the PPU registers moves the PPU's latches and address,
+            // and writing $4014 starts a DMA, so a debugger can't edit them
+            0x2000..=0x3FFF | 0x4014 => {}
+            0x8000..=0xFFFF => {
+                let length = self.prg_rom.len();
+                self.prg_rom[(address as usize - 0x8000) % length] = data;
+            }
+            _ => self.write(address, data),
         }
-        self.prg_rom[addr as usize]
     }
 }
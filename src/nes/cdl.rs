@@ -0,0 +1,92 @@
+use std::path::Path;
+
+use crate::common::bus::Usage;
+
+// Flags of a PRG ROM byte, as FCEUX lays them out: xPdcAADC, where AA is the
+// 8 KiB window of $8000-$FFFF it was last seen through
+pub const CODE: u8 = 0x01;
+pub const DATA: u8 = 0x02;
+pub const INDIRECT_CODE: u8 = 0x10;
+pub const INDIRECT_DATA: u8 = 0x20;
+pub const PCM: u8 = 0x40;
+
+// Flags of a CHR ROM byte
+pub const RENDERED: u8 = 0x01;
+pub const READ: u8 = 0x02;
+
+/// A code/data log: how each byte of PRG and CHR ROM has been used, saved as
+/// an FCEUX `.cdl` file of a flag byte for each byte of PRG ROM, then each
+/// byte of CHR ROM.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CodeDataLog {
+    pub prg: Vec<u8>,
+    pub chr: Vec<u8>,
+}
+
+impl CodeDataLog {
+    pub fn new(prg_length: usize, chr_length: usize) -> Self {
+        Self {
+            prg: vec![0; prg_length],
+            chr: vec![0; chr_length],
+        }
+    }
+
+    /// Splits the contents of a `.cdl` file, which has to be made for ROM of
+    /// these sizes.
+    pub fn from_bytes(bytes: &[u8], prg_length: usize, chr_length: usize) -> Result<Self, String> {
+        if bytes.len() != prg_length + chr_length {
+            return Err(format!(
+                "{} bytes long, but the ROM needs {}",
+                bytes.len(),
+                prg_length + chr_length
+            ));
+        }
+        let (prg, chr) = bytes.split_at(prg_length);
+        Ok(Self {
+            prg: prg.to_vec(),
+            chr: chr.to_vec(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [&self.prg[..], &self.chr[..]].concat()
+    }
+
+    pub fn load(
+        path: impl AsRef<Path>,
+        prg_length: usize,
+        chr_length: usize,
+    ) -> Result<Self, String> {
+        let path = path.as_ref();
+        let error = |error: String| format!("{}: {}", path.display(), error);
+        let bytes = std::fs::read(path).map_err(|e| error(e.to_string()))?;
+        Self::from_bytes(&bytes, prg_length, chr_length).map_err(error)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_bytes()).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// How many bytes of PRG ROM have any of `flags` set.
+    pub fn prg_count(&self, flags: u8) -> usize {
+        self.prg.iter().filter(|&&byte| byte & flags != 0).count()
+    }
+
+    /// How many bytes of CHR ROM have any of `flags` set.
+    pub fn chr_count(&self, flags: u8) -> usize {
+        self.chr.iter().filter(|&&byte| byte & flags != 0).count()
+    }
+}
+
+/// The flags for a PRG ROM byte used by the CPU at `address`.
+pub(crate) fn prg_flags(usage: Usage, address: u16) -> u8 {
+    let flags = match usage {
+        Usage::Code => CODE,
+        Usage::IndirectCode => CODE | INDIRECT_CODE,
+        Usage::Data => DATA,
+        Usage::IndirectData => DATA | INDIRECT_DATA,
+        Usage::Pcm => PCM,
+    };
+    flags | ((address >> 11) & 0x0C) as u8
+}
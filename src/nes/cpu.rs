@@ -1,23 +1,44 @@
+use crate::common::bus::{AccessKind, Bus, BusAccess, Dma, Usage};
 use crate::common::to_u16;
 use crate::nes::bus::NiseBus;
-#[cfg(feature = "nestest")]
-use log::debug;
-use log::warn;
-
-#[cfg(feature = "nestest")]
+use crate::nes::disasm::{opcodes, AddressingMode};
+use crate::nes::history::{CallStack, Executed, Frame, FrameKind, History};
+use crate::nes::hooks::{ExecuteHook, HookId, MemoryHooks};
+use crate::nes::profiler::Profiler;
+use crate::nes::symbols::Symbols;
+use crate::nes::trace::Tracer;
+use std::fmt;
+
+// Instructions with an operand address that they do not read from
+const NON_READING: [&str; 11] = [
+    "STA", "STX", "STY", "STZ", "SAX", "SHA", "SHX", "SHY", "TAS", "JMP", "JSR",
+];
+
+/// A snapshot of the programmer visible registers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub struct Nise6502State {
-    pc: u16,
-    s: u8,
-    p: u8,
-    a: u8,
-    x: u8,
-    y: u8,
-    cycle_count: u32,
+    pub pc: u16,
+    pub s: u8,
+    pub p: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    /// Total number of CPU cycles executed so far.
+    pub cycles: u64,
 }
 
-#[cfg(feature = "nestest")]
-impl From<&mut Nise6502> for Nise6502State {
-    fn from(cpu: &mut Nise6502) -> Self {
+impl Nise6502State {
+    pub fn flag(&self, flag: Flag) -> bool {
+        self.p & flag.mask() != 0
+    }
+
+    pub fn set_flag(&mut self, flag: Flag, set: bool) {
+        self.p = (self.p & !flag.mask()) | if set { flag.mask() } else { 0 };
+    }
+}
+
+impl<B: Bus> From<&Nise6502<B>> for Nise6502State {
+    fn from(cpu: &Nise6502<B>) -> Self {
         Self {
             pc: cpu.pc,
             s: cpu.s,
@@ -25,355 +46,1082 @@ impl From<&mut Nise6502> for Nise6502State {
             a: cpu.a,
             x: cpu.x,
             y: cpu.y,
-            cycle_count: cpu.cycle_count,
+            cycles: cpu.cycles,
+        }
+    }
+}
+
+/// The hardware interrupts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    Nmi,
+    Irq,
+}
+
+/// The bits of the status register P. Bit 5 has no flag behind it and always
+/// reads as 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flag {
+    Carry,
+    Zero,
+    InterruptDisable,
+    Decimal,
+    /// Only exists in the copy of P pushed by BRK and PHP.
+    Break,
+    Overflow,
+    Negative,
+}
+
+impl Flag {
+    pub fn mask(self) -> u8 {
+        match self {
+            Flag::Carry => 0b0000_0001,
+            Flag::Zero => 0b0000_0010,
+            Flag::InterruptDisable => 0b0000_0100,
+            Flag::Decimal => 0b0000_1000,
+            Flag::Break => 0b0001_0000,
+            Flag::Overflow => 0b0100_0000,
+            Flag::Negative => 0b1000_0000,
         }
     }
 }
 
-pub struct Nise6502 {
+/// The members of the 6502 family the core can behave as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Variant {
+    /// The NES CPU: an NMOS 6502 whose decimal mode is disconnected.
+    Ricoh2A03,
+    /// A stock NMOS 6502, with BCD arithmetic.
+    Nmos6502,
+    /// The WDC 65C02, with its extra instructions, bug fixes and timing.
+    Wdc65C02,
+}
+
+/// What became of the instruction run by `step`, or in progress at `tick`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepStatus {
+    /// It executed, or an interrupt was serviced, or WAI left the CPU
+    /// waiting for one.
+    Running,
+    /// A JAM opcode, or the 65C02's STP, at `address` halted the CPU. It
+    /// stays halted, ignoring interrupts, until reset.
+    Jammed { address: u16 },
+    /// The opcode at `address` is not implemented. It was skipped as a
+    /// single byte NOP.
+    Unimplemented { address: u16, opcode: u8 },
+}
+
+impl fmt::Display for StepStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StepStatus::Running => write!(f, "running"),
+            StepStatus::Jammed { address } => write!(f, "jammed at {:04X}", address),
+            StepStatus::Unimplemented { address, opcode } => {
+                write!(f, "unimplemented opcode {:02X} at {:04X}", opcode, address)
+            }
+        }
+    }
+}
+
+pub struct Nise6502<B: Bus = NiseBus> {
     pc: u16,
     s: u8,
     p: u8,
     a: u8,
     x: u8,
     y: u8,
-    bus: NiseBus,
+    bus: B,
+    variant: Variant,
+    opcode: u8,
+    // Set by the 65C02's WAI, and by STP and the NMOS JAM opcodes to where
+    // they halted the CPU
+    waiting: bool,
+    stopped: Option<u16>,
+    status: StepStatus,
     cycle_count: u32,
+    cycles: u64,
+    cycle_accurate: bool,
+    nmi_line: bool,
+    prev_nmi_line: bool,
+    nmi_pending: bool,
+    prev_nmi_pending: bool,
+    irq_line: bool,
+    irq_pending: bool,
+    prev_irq_pending: bool,
+    interrupt_serviced: Option<Interrupt>,
+    record_accesses: bool,
+    accesses: Vec<BusAccess>,
+    log_usage: bool,
+    // The operand address of the last instruction, and whether it jumped
+    // through a pointer
+    effective_address: u16,
+    jumped_indirect: bool,
+    symbols: Symbols,
+    call_stack: CallStack,
+    history: History,
+    profiler: Option<Profiler>,
+    tracer: Option<Box<dyn Tracer<B> + Send>>,
+    hooks: MemoryHooks,
+    execute_hooks: Vec<(HookId, u16, ExecuteHook<B>)>,
+}
+
+/// The CPU and everything on its bus at one moment, as the debugger saves it
+/// to run backwards. Symbols, the profiler, the tracer and hooks are not
+/// part of it.
+pub struct Snapshot<B> {
+    state: Nise6502State,
+    opcode: u8,
+    waiting: bool,
+    stopped: Option<u16>,
+    status: StepStatus,
+    cycle_count: u32,
+    nmi_line: bool,
+    prev_nmi_line: bool,
+    nmi_pending: bool,
+    prev_nmi_pending: bool,
+    irq_line: bool,
+    irq_pending: bool,
+    prev_irq_pending: bool,
+    interrupt_serviced: Option<Interrupt>,
+    accesses: Vec<BusAccess>,
+    call_stack: CallStack,
+    history: History,
+    bus: B,
+}
+
+impl<B> Snapshot<B> {
+    pub fn cycles(&self) -> u64 {
+        self.state.cycles
+    }
 }
 
 struct Operand {
-    value: u8,
     address: u16,
 }
 
-#[cfg(feature = "nestest")]
-fn setup_nestest_logger() -> Result<(), fern::InitError> {
-    std::fs::write("output.log", "")?;
-    fern::Dispatch::new()
-        //        .format(|out, message, record| {
-        //            out.finish(format_args!(
-        //                "[{} {} {}] {}",
-        //                humantime::format_rfc3339_seconds(SystemTime::now()),
-        //                record.level(),
-        //                record.target(),
-        //                message
-        //            ))
-        //        })
-        .format(|out, message, _| out.finish(format_args!("{}", message)))
-        .level(log::LevelFilter::Debug)
-        .chain(fern::log_file("output.log")?)
-        .apply()?;
-    Ok(())
-}
+// An opcode's addressing mode and operation
+type Operation<B> = (
+    fn(&mut Nise6502<B>) -> Operand,
+    fn(&mut Nise6502<B>, Operand),
+);
 
-impl Nise6502 {
-    pub fn new(bus: NiseBus) -> Self {
-        let cpu = Self {
+impl<B: Bus> Nise6502<B> {
+    pub fn new(bus: B) -> Self {
+        Self {
             pc: 0,
-            s: 0xFD,
+            s: 0,
             p: 0x24,
             a: 0,
             x: 0,
             y: 0,
             bus,
+            variant: Variant::Ricoh2A03,
+            opcode: 0,
+            waiting: false,
+            stopped: None,
+            status: StepStatus::Running,
             cycle_count: 0,
-        };
-        cpu
+            cycles: 0,
+            cycle_accurate: false,
+            nmi_line: false,
+            prev_nmi_line: false,
+            nmi_pending: false,
+            prev_nmi_pending: false,
+            irq_line: false,
+            irq_pending: false,
+            prev_irq_pending: false,
+            interrupt_serviced: None,
+            record_accesses: false,
+            accesses: vec![],
+            log_usage: false,
+            effective_address: 0,
+            jumped_indirect: false,
+            symbols: Symbols::new(),
+            call_stack: CallStack::default(),
+            history: History::default(),
+            profiler: None,
+            tracer: None,
+            hooks: MemoryHooks::new(),
+            execute_hooks: vec![],
+        }
     }
 
-    #[cfg(feature = "nestest")]
-    pub fn nestest(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        setup_nestest_logger()?;
-        self.pc = 0xc000;
-        for _ in 0..40000 {
-            self.tick();
-        }
-        Ok(())
+    /// Drives the NMI input. NMI is edge triggered: only a transition from
+    /// released to asserted latches an interrupt, which is then serviced at the
+    /// next instruction boundary regardless of the I flag.
+    pub fn set_nmi(&mut self, asserted: bool) {
+        self.nmi_line = asserted;
+    }
+
+    /// Drives the IRQ input. IRQ is level triggered: it is serviced at every
+    /// instruction boundary for as long as it stays asserted and I is clear.
+    pub fn set_irq(&mut self, asserted: bool) {
+        self.irq_line = asserted;
+    }
+
+    /// The interrupt whose entry sequence the last `step` ran instead of an
+    /// instruction, if any.
+    pub fn interrupt_serviced(&self) -> Option<Interrupt> {
+        self.interrupt_serviced
+    }
+
+    /// Keeps a list of the bus accesses made by each `step`, dummy accesses
+    /// included, for debuggers.
+    pub fn set_record_accesses(&mut self, enabled: bool) {
+        self.record_accesses = enabled;
+        self.accesses.clear();
+    }
+
+    /// Tells the bus which bytes each instruction ran from and read, and
+    /// which interrupt vectors were fetched, through `Bus::log_usage`.
+    pub fn set_log_usage(&mut self, enabled: bool) {
+        self.log_usage = enabled;
+    }
+
+    /// The bus accesses made by the last `step`, in order. Empty unless
+    /// recording is enabled.
+    pub fn accesses(&self) -> &[BusAccess] {
+        &self.accesses
+    }
+
+    pub fn variant(&self) -> Variant {
+        self.variant
+    }
+
+    pub fn set_variant(&mut self, variant: Variant) {
+        self.variant = variant;
+    }
+
+    /// In cycle accurate mode the CPU clocks the bus once before every one of
+    /// its bus accesses, so PPU and mapper state advance in step with the
+    /// instruction instead of catching up afterwards. Drive the CPU with
+    /// `step` and do not clock the bus separately.
+    pub fn set_cycle_accurate(&mut self, enabled: bool) {
+        self.cycle_accurate = enabled;
+    }
+
+    /// Total number of CPU cycles executed so far.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn set_cycles(&mut self, cycles: u64) {
+        self.cycles = cycles;
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    /// Moves execution to `pc`, as a jump would.
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    pub fn a(&self) -> u8 {
+        self.a
+    }
+
+    pub fn set_a(&mut self, a: u8) {
+        self.a = a;
+    }
+
+    pub fn x(&self) -> u8 {
+        self.x
+    }
+
+    pub fn set_x(&mut self, x: u8) {
+        self.x = x;
+    }
+
+    pub fn y(&self) -> u8 {
+        self.y
+    }
+
+    pub fn set_y(&mut self, y: u8) {
+        self.y = y;
+    }
+
+    /// The stack pointer, an offset into page $01.
+    pub fn s(&self) -> u8 {
+        self.s
+    }
+
+    pub fn set_s(&mut self, s: u8) {
+        self.s = s;
+    }
+
+    /// The status register. See `Flag` for its bits.
+    pub fn p(&self) -> u8 {
+        self.p
+    }
+
+    pub fn set_p(&mut self, p: u8) {
+        self.p = p;
+    }
+
+    pub fn flag(&self, flag: Flag) -> bool {
+        self.p & flag.mask() != 0
+    }
+
+    pub fn set_flag(&mut self, flag: Flag, set: bool) {
+        self.p = (self.p & !flag.mask()) | if set { flag.mask() } else { 0 };
+    }
+
+    pub fn state(&self) -> Nise6502State {
+        self.into()
+    }
+
+    /// Loads every register, and the cycle counter, from `state`.
+    pub fn set_state(&mut self, state: &Nise6502State) {
+        self.pc = state.pc;
+        self.s = state.s;
+        self.p = state.p;
+        self.a = state.a;
+        self.x = state.x;
+        self.y = state.y;
+        self.cycles = state.cycles;
+    }
+
+    /// Saves the CPU and its bus, if the bus can save its state.
+    pub fn snapshot(&self) -> Option<Snapshot<B>> {
+        Some(Snapshot {
+            state: self.state(),
+            opcode: self.opcode,
+            waiting: self.waiting,
+            stopped: self.stopped,
+            status: self.status,
+            cycle_count: self.cycle_count,
+            nmi_line: self.nmi_line,
+            prev_nmi_line: self.prev_nmi_line,
+            nmi_pending: self.nmi_pending,
+            prev_nmi_pending: self.prev_nmi_pending,
+            irq_line: self.irq_line,
+            irq_pending: self.irq_pending,
+            prev_irq_pending: self.prev_irq_pending,
+            interrupt_serviced: self.interrupt_serviced,
+            accesses: self.accesses.clone(),
+            call_stack: self.call_stack.clone(),
+            history: self.history.clone(),
+            bus: self.bus.save_state()?,
+        })
+    }
+
+    /// Goes back to the moment `snapshot` was taken. Running on from there
+    /// does the same as it did then, so long as nothing outside the bus
+    /// drives the CPU differently.
+    pub fn restore(&mut self, snapshot: &Snapshot<B>) {
+        self.set_state(&snapshot.state);
+        self.opcode = snapshot.opcode;
+        self.waiting = snapshot.waiting;
+        self.stopped = snapshot.stopped;
+        self.status = snapshot.status;
+        self.cycle_count = snapshot.cycle_count;
+        self.nmi_line = snapshot.nmi_line;
+        self.prev_nmi_line = snapshot.prev_nmi_line;
+        self.nmi_pending = snapshot.nmi_pending;
+        self.prev_nmi_pending = snapshot.prev_nmi_pending;
+        self.irq_line = snapshot.irq_line;
+        self.irq_pending = snapshot.irq_pending;
+        self.prev_irq_pending = snapshot.prev_irq_pending;
+        self.interrupt_serviced = snapshot.interrupt_serviced;
+        self.accesses.clone_from(&snapshot.accesses);
+        self.call_stack.clone_from(&snapshot.call_stack);
+        self.history.clone_from(&snapshot.history);
+        self.bus.load_state(&snapshot.bus);
+    }
+
+    pub fn bus(&self) -> &B {
+        &self.bus
+    }
+
+    /// The bus, for loading memory and poking devices. Accesses made through
+    /// it take no CPU cycles.
+    pub fn bus_mut(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    /// Labels for traces and debuggers to show addresses by.
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    pub fn symbols_mut(&mut self) -> &mut Symbols {
+        &mut self.symbols
+    }
+
+    /// Names `address` after the closest label, as in `ReadJoypad+3`.
+    pub fn describe(&self, address: u16) -> Option<String> {
+        self.symbols.describe(&self.bus, address)
+    }
+
+    /// The subroutines and interrupt handlers entered and not yet returned
+    /// from, as followed through JSR, RTS, BRK, RTI and interrupts.
+    pub fn call_stack(&self) -> &CallStack {
+        &self.call_stack
     }
 
-    #[cfg(feature = "nestest")]
-    fn nestest_dbgprint(
+    /// The last instructions executed, oldest first.
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    /// Keeps the last `len` instructions executed in `history`, or none if
+    /// `len` is zero.
+    pub fn set_history_len(&mut self, len: usize) {
+        self.history = History::new(len);
+    }
+
+    /// Starts counting the cycles spent on each instruction, in each
+    /// subroutine and interrupt handler, and in each frame, afresh.
+    pub fn start_profiling(&mut self) {
+        self.profiler = Some(Profiler::new());
+    }
+
+    pub fn stop_profiling(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    // Carries on with a profiler set aside by `stop_profiling`
+    pub(crate) fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Shows each instruction to `tracer` before it executes, in place of
+    /// the tracer attached before, which is returned.
+    pub fn set_tracer(
         &mut self,
-        old_state: Nise6502State,
-        name: &str,
-        fetch: &str,
-        operand: &Operand,
-    ) {
-        let operand_bytecode = match fetch {
-            "zpa" | "zpx" | "zpy" | "imm" | "idx" | "idy" | "idy_w" | "rel" => {
-                format!("{:02X}   ", self.read(old_state.pc))
-            }
-            "abs" | "aby" | "abx" | "abx_w" | "aby_w" | "ind" => format!(
-                "{:02X} {:02X}",
-                self.read(old_state.pc),
-                self.read(old_state.pc + 1)
-            ),
-            _ => "     ".to_string(),
-        };
+        tracer: Option<Box<dyn Tracer<B> + Send>>,
+    ) -> Option<Box<dyn Tracer<B> + Send>> {
+        std::mem::replace(&mut self.tracer, tracer)
+    }
 
-        let disassembly = match fetch {
-            "abs" => match name {
-                "jmp" | "jsr" => {
-                    format!("${:04X}", operand.address)
-                }
-                _ => format!("${:04X} = {:02X}", operand.address, operand.value),
-            },
-            "abx" | "abx_w" | "aby" | "aby_w" => format!(
-                "${:02X}{:02X},{} @ {:04X} = {:02X}",
-                self.read(old_state.pc + 1),
-                self.read(old_state.pc),
-                match fetch {
-                    "abx" | "abx_w" => "X",
-                    _ => "Y",
-                },
-                operand.address,
-                operand.value
-            ),
-            "ind" => format!("#${:02X}", operand.value),
-            "imm" => {
-                format!("#${:02X}", operand.value)
-            }
-            "zpa" => {
-                format!("${:02X} = {:02X}", self.read(old_state.pc), operand.value)
-            }
-            "zpx" | "zpy" => {
-                format!(
-                    "${:02X},{} @ {:02X} = {:02X}",
-                    self.read(old_state.pc),
-                    match fetch {
-                        "zpx" => "X",
-                        _ => "Y",
-                    },
-                    operand.address,
-                    operand.value
+    /// Hooks on the reads, writes and opcode fetches the CPU makes. Debugger
+    /// peeks and pokes go around them.
+    pub fn hooks(&self) -> &MemoryHooks {
+        &self.hooks
+    }
+
+    pub fn hooks_mut(&mut self) -> &mut MemoryHooks {
+        &mut self.hooks
+    }
+
+    /// Calls `hook` whenever the instruction at `pc` is about to execute,
+    /// before the tracer sees it. The instruction is fetched from wherever
+    /// the hook leaves PC.
+    pub fn add_execute_hook(
+        &mut self,
+        pc: u16,
+        hook: impl FnMut(&mut Nise6502<B>) + Send + 'static,
+    ) -> HookId {
+        let id = self.hooks.next_id();
+        self.execute_hooks.push((id, pc, Box::new(hook)));
+        id
+    }
+
+    pub fn remove_execute_hook(&mut self, id: HookId) -> bool {
+        let before = self.execute_hooks.len();
+        self.execute_hooks.retain(|(hook, _, _)| *hook != id);
+        self.execute_hooks.len() != before
+    }
+
+    /// Runs the reset sequence: three suppressed stack pushes, then a jump
+    /// through the vector at $FFFC with interrupts disabled.
+    pub fn reset(&mut self) {
+        self.read(self.pc);
+        self.read(self.pc);
+        for _ in 0..3 {
+            self.read(0x100 + self.s as u16);
+            self.s = self.s.wrapping_sub(1);
+        }
+        self.p |= 0b0000_0100;
+        if self.variant == Variant::Wdc65C02 {
+            self.p &= 0b1111_0111;
+        }
+        self.pc = self.read_vector(0xFFFC);
+        self.waiting = false;
+        self.stopped = None;
+        self.call_stack.clear();
+    }
+
+    // The dispatch tables, which give each opcode the addressing mode that
+    // fetches its operand and the operation that acts on it
+    const RICOH_2A03: [Operation<B>; 256] = Self::operation_table(Variant::Ricoh2A03);
+    const NMOS_6502: [Operation<B>; 256] = Self::operation_table(Variant::Nmos6502);
+    const WDC_65C02: [Operation<B>; 256] = Self::operation_table(Variant::Wdc65C02);
+
+    fn operations(&self) -> &[Operation<B>; 256] {
+        match self.variant {
+            Variant::Ricoh2A03 => &Self::RICOH_2A03,
+            Variant::Nmos6502 => &Self::NMOS_6502,
+            Variant::Wdc65C02 => &Self::WDC_65C02,
+        }
+    }
+
+    const fn operation_table(variant: Variant) -> [Operation<B>; 256] {
+        let mut table = [Self::operation(variant, 0); 256];
+        let mut opcode = 1;
+        while opcode < 256 {
+            table[opcode] = Self::operation(variant, opcode as u8);
+            opcode += 1;
+        }
+        table
+    }
+
+    const fn operation(variant: Variant, opcode: u8) -> Operation<B> {
+        // Unofficial opcodes are starred, as traces show them
+        macro_rules! op {
+            (*$name:ident, $fetch:ident) => {
+                op!($name, $fetch)
+            };
+            ($name:ident, $fetch:ident) => {
+                (
+                    Self::$fetch as fn(&mut Self) -> Operand,
+                    Self::$name as fn(&mut Self, Operand),
                 )
+            };
+        }
+        match opcode {
+            0x69 => op!(adc, imm),
+            0x65 => op!(adc, zpa),
+            0x75 => op!(adc, zpx),
+            0x6D => op!(adc, abs),
+            0x7D => op!(adc, abx),
+            0x79 => op!(adc, aby),
+            0x61 => op!(adc, idx),
+            0x71 => op!(adc, idy),
+            0x29 => op!(and, imm),
+            0x25 => op!(and, zpa),
+            0x35 => op!(and, zpx),
+            0x2D => op!(and, abs),
+            0x3D => op!(and, abx),
+            0x39 => op!(and, aby),
+            0x21 => op!(and, idx),
+            0x31 => op!(and, idy),
+            0x0A => op!(asl_a, imp),
+            0x06 => op!(asl, zpa),
+            0x16 => op!(asl, zpx),
+            0x0E => op!(asl, abs),
+            0x1E => op!(asl, abx_m),
+            0x90 => op!(bcc, rel),
+            0xB0 => op!(bcs, rel),
+            0xF0 => op!(beq, rel),
+            0x24 => op!(bit, zpa),
+            0x2C => op!(bit, abs),
+            0x30 => op!(bmi, rel),
+            0xD0 => op!(bne, rel),
+            0x10 => op!(bpl, rel),
+            0x00 => op!(brk, imp),
+            0x50 => op!(bvc, rel),
+            0x70 => op!(bvs, rel),
+            0x18 => op!(clc, imp),
+            0xD8 => op!(cld, imp),
+            0x58 => op!(cli, imp),
+            0xB8 => op!(clv, imp),
+            0xC9 => op!(cmp, imm),
+            0xC5 => op!(cmp, zpa),
+            0xD5 => op!(cmp, zpx),
+            0xCD => op!(cmp, abs),
+            0xDD => op!(cmp, abx),
+            0xD9 => op!(cmp, aby),
+            0xC1 => op!(cmp, idx),
+            0xD1 => op!(cmp, idy),
+            0xE0 => op!(cpx, imm),
+            0xE4 => op!(cpx, zpa),
+            0xEC => op!(cpx, abs),
+            0xC0 => op!(cpy, imm),
+            0xC4 => op!(cpy, zpa),
+            0xCC => op!(cpy, abs),
+            0xC6 => op!(dec, zpa),
+            0xD6 => op!(dec, zpx),
+            0xCE => op!(dec, abs),
+            0xDE => op!(dec, abx_w),
+            0xCA => op!(dex, imp),
+            0x88 => op!(dey, imp),
+            0x49 => op!(eor, imm),
+            0x45 => op!(eor, zpa),
+            0x55 => op!(eor, zpx),
+            0x4D => op!(eor, abs),
+            0x5D => op!(eor, abx),
+            0x59 => op!(eor, aby),
+            0x41 => op!(eor, idx),
+            0x51 => op!(eor, idy),
+            0xE6 => op!(inc, zpa),
+            0xF6 => op!(inc, zpx),
+            0xEE => op!(inc, abs),
+            0xFE => op!(inc, abx_w),
+            0xE8 => op!(inx, imp),
+            0xC8 => op!(iny, imp),
+            0x4C => op!(jmp, abs),
+            0x6C => op!(jmp, ind),
+            0x20 => op!(jsr, imm),
+            0xA9 => op!(lda, imm),
+            0xA5 => op!(lda, zpa),
+            0xB5 => op!(lda, zpx),
+            0xAD => op!(lda, abs),
+            0xBD => op!(lda, abx),
+            0xB9 => op!(lda, aby),
+            0xA1 => op!(lda, idx),
+            0xB1 => op!(lda, idy),
+            0xA2 => op!(ldx, imm),
+            0xA6 => op!(ldx, zpa),
+            0xB6 => op!(ldx, zpy),
+            0xAE => op!(ldx, abs),
+            0xBE => op!(ldx, aby),
+            0xA0 => op!(ldy, imm),
+            0xA4 => op!(ldy, zpa),
+            0xB4 => op!(ldy, zpx),
+            0xAC => op!(ldy, abs),
+            0xBC => op!(ldy, abx),
+            0x4A => op!(lsr_a, imp),
+            0x46 => op!(lsr, zpa),
+            0x56 => op!(lsr, zpx),
+            0x4E => op!(lsr, abs),
+            0x5E => op!(lsr, abx_m),
+            0xEA => op!(nop, imp),
+            0x09 => op!(ora, imm),
+            0x05 => op!(ora, zpa),
+            0x15 => op!(ora, zpx),
+            0x0D => op!(ora, abs),
+            0x1D => op!(ora, abx),
+            0x19 => op!(ora, aby),
+            0x01 => op!(ora, idx),
+            0x11 => op!(ora, idy),
+            0x48 => op!(pha, imp),
+            0x08 => op!(php, imp),
+            0x68 => op!(pla, imp),
+            0x28 => op!(plp, imp),
+            0x2A => op!(rol_a, imp),
+            0x26 => op!(rol, zpa),
+            0x36 => op!(rol, zpx),
+            0x2E => op!(rol, abs),
+            0x3E => op!(rol, abx_m),
+            0x6A => op!(ror_a, imp),
+            0x66 => op!(ror, zpa),
+            0x76 => op!(ror, zpx),
+            0x6E => op!(ror, abs),
+            0x7E => op!(ror, abx_m),
+            0x40 => op!(rti, imp),
+            0x60 => op!(rts, imp),
+            0xE9 => op!(sbc, imm),
+            0xE5 => op!(sbc, zpa),
+            0xF5 => op!(sbc, zpx),
+            0xED => op!(sbc, abs),
+            0xFD => op!(sbc, abx),
+            0xF9 => op!(sbc, aby),
+            0xE1 => op!(sbc, idx),
+            0xF1 => op!(sbc, idy),
+            0x38 => op!(sec, imp),
+            0xF8 => op!(sed, imp),
+            0x78 => op!(sei, imp),
+            0x85 => op!(sta, zpa),
+            0x95 => op!(sta, zpx),
+            0x8D => op!(sta, abs),
+            0x9D => op!(sta, abx_w),
+            0x99 => op!(sta, aby_w),
+            0x81 => op!(sta, idx),
+            0x91 => op!(sta, idy_w),
+            0x86 => op!(stx, zpa),
+            0x96 => op!(stx, zpy),
+            0x8E => op!(stx, abs),
+            0x84 => op!(sty, zpa),
+            0x94 => op!(sty, zpx),
+            0x8C => op!(sty, abs),
+            0xAA => op!(tax, imp),
+            0xA8 => op!(tay, imp),
+            0xBA => op!(tsx, imp),
+            0x8A => op!(txa, imp),
+            0x9A => op!(txs, imp),
+            0x98 => op!(tya, imp),
+            _ => match variant {
+                Variant::Wdc65C02 => match opcode {
+                    0x72 => op!(adc, izp),
+                    0x32 => op!(and, izp),
+                    0x0F | 0x1F | 0x2F | 0x3F | 0x4F | 0x5F | 0x6F | 0x7F => op!(bbr, zpa),
+                    0x8F | 0x9F | 0xAF | 0xBF | 0xCF | 0xDF | 0xEF | 0xFF => op!(bbs, zpa),
+                    0x89 => op!(bit_imm, imm),
+                    0x34 => op!(bit, zpx),
+                    0x3C => op!(bit, abx),
+                    0x80 => op!(bra, rel),
+                    0xD2 => op!(cmp, izp),
+                    0x3A => op!(dec_a, imp),
+                    0x52 => op!(eor, izp),
+                    0x1A => op!(inc_a, imp),
+                    0x7C => op!(jmp, iax),
+                    0xB2 => op!(lda, izp),
+                    0x12 => op!(ora, izp),
+                    0xDA => op!(phx, imp),
+                    0x5A => op!(phy, imp),
+                    0xFA => op!(plx, imp),
+                    0x7A => op!(ply, imp),
+                    0x07 | 0x17 | 0x27 | 0x37 | 0x47 | 0x57 | 0x67 | 0x77 => op!(rmb, zpa),
+                    0xF2 => op!(sbc, izp),
+                    0x87 | 0x97 | 0xA7 | 0xB7 | 0xC7 | 0xD7 | 0xE7 | 0xF7 => op!(smb, zpa),
+                    0x92 => op!(sta, izp),
+                    0xDB => op!(stp, imp),
+                    0x64 => op!(stz, zpa),
+                    0x74 => op!(stz, zpx),
+                    0x9C => op!(stz, abs),
+                    0x9E => op!(stz, abx_w),
+                    0x14 => op!(trb, zpa),
+                    0x1C => op!(trb, abs),
+                    0x04 => op!(tsb, zpa),
+                    0x0C => op!(tsb, abs),
+                    0xCB => op!(wai, imp),
+                    // Every other opcode is a NOP, of one of several lengths
+                    0x02 | 0x22 | 0x42 | 0x62 | 0x82 | 0xC2 | 0xE2 => op!(nop_m, imm),
+                    0x44 => op!(nop_m, zpa),
+                    0x54 | 0xD4 | 0xF4 => op!(nop_m, zpx),
+                    0xDC | 0xFC => op!(nop_m, abs),
+                    0x5C => op!(nop_5c, abs),
+                    _ => op!(nop, none),
+                },
+                // Unofficial opcodes
+                _ => match opcode {
+                    0x0B | 0x2B => op!(*anc, imm),
+                    0x4B => op!(*alr, imm),
+                    0x6B => op!(*arr, imm),
+                    0xCB => op!(*axs, imm),
+                    0xC3 => op!(*dcp, idx),
+                    0xC7 => op!(*dcp, zpa),
+                    0xCF => op!(*dcp, abs),
+                    0xD3 => op!(*dcp, idy_w),
+                    0xD7 => op!(*dcp, zpx),
+                    0xDB => op!(*dcp, aby_w),
+                    0xDF => op!(*dcp, abx_w),
+                    0xE3 => op!(*isb, idx),
+                    0xE7 => op!(*isb, zpa),
+                    0xEF => op!(*isb, abs),
+                    0xF3 => op!(*isb, idy_w),
+                    0xF7 => op!(*isb, zpx),
+                    0xFB => op!(*isb, aby_w),
+                    0xFF => op!(*isb, abx_w),
+                    0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xB2 | 0xD2
+                    | 0xF2 => op!(*jam, imp),
+                    0xBB => op!(*las, aby),
+                    0xA3 => op!(*lax, idx),
+                    0xA7 => op!(*lax, zpa),
+                    0xAF => op!(*lax, abs),
+                    0xB3 => op!(*lax, idy),
+                    0xB7 => op!(*lax, zpy),
+                    0xBF => op!(*lax, aby),
+                    0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA => op!(*nop, imp),
+                    0x80 | 0x82 | 0x89 | 0xC2 | 0xE2 => op!(*nop_m, imm),
+                    0x04 | 0x44 | 0x64 => op!(*nop_m, zpa),
+                    0x14 | 0x34 | 0x54 | 0x74 | 0xD4 | 0xF4 => op!(*nop_m, zpx),
+                    0x0C => op!(*nop_m, abs),
+                    0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => op!(*nop_m, abx),
+                    0x23 => op!(*rla, idx),
+                    0x27 => op!(*rla, zpa),
+                    0x2F => op!(*rla, abs),
+                    0x33 => op!(*rla, idy_w),
+                    0x37 => op!(*rla, zpx),
+                    0x3B => op!(*rla, aby_w),
+                    0x3F => op!(*rla, abx_w),
+                    0x63 => op!(*rra, idx),
+                    0x67 => op!(*rra, zpa),
+                    0x6F => op!(*rra, abs),
+                    0x73 => op!(*rra, idy_w),
+                    0x77 => op!(*rra, zpx),
+                    0x7B => op!(*rra, aby_w),
+                    0x7F => op!(*rra, abx_w),
+                    0x83 => op!(*sax, idx),
+                    0x87 => op!(*sax, zpa),
+                    0x8F => op!(*sax, abs),
+                    0x97 => op!(*sax, zpy),
+                    0xEB => op!(*sbc, imm),
+                    0x03 => op!(*slo, idx),
+                    0x07 => op!(*slo, zpa),
+                    0x0F => op!(*slo, abs),
+                    0x13 => op!(*slo, idy_w),
+                    0x17 => op!(*slo, zpx),
+                    0x1B => op!(*slo, aby_w),
+                    0x1F => op!(*slo, abx_w),
+                    0x43 => op!(*sre, idx),
+                    0x47 => op!(*sre, zpa),
+                    0x4F => op!(*sre, abs),
+                    0x53 => op!(*sre, idy_w),
+                    0x57 => op!(*sre, zpx),
+                    0x5B => op!(*sre, aby_w),
+                    0x5F => op!(*sre, abx_w),
+                    _ => op!(unimplemented, none),
+                },
+            },
+        }
+    }
+
+    /// Runs one whole instruction, or the interrupt sequence if an interrupt
+    /// was polled during the previous one, and tells how that went. A halted
+    /// CPU only lets a cycle go by.
+    pub fn step(&mut self) -> StepStatus {
+        let start = self.cycles;
+        let pc = self.pc;
+        self.interrupt_serviced = None;
+        self.accesses.clear();
+        self.status = StepStatus::Running;
+        let before =
+            (self.history.capacity() > 0 && self.stopped.is_none() && !self.waiting).then(|| {
+                let bytes = [0, 1, 2].map(|i| self.bus.peek(self.pc.wrapping_add(i)));
+                (self.state(), bytes)
+            });
+        if self.stopped.is_some() {
+            self.idle_cycle();
+        } else if self.waiting {
+            // WAI resumes on any interrupt, even an IRQ masked by the I flag
+            self.idle_cycle();
+            if self.nmi_pending || self.irq_line || self.bus.irq_line() {
+                self.waiting = false;
             }
-            "idx" => {
-                format!(
-                    "(${:02X},X) @ {:02X} = {:04X} = {:02X}",
-                    self.read(old_state.pc),
-                    self.read(old_state.pc).wrapping_add(old_state.x),
-                    operand.address,
-                    operand.value
-                )
+        } else if self.prev_nmi_pending || self.prev_irq_pending {
+            // The opcode fetch happens but is discarded, and PC is not incremented
+            self.read(self.pc);
+            self.read(self.pc);
+            self.interrupt(false);
+        } else {
+            if !self.execute_hooks.is_empty() {
+                self.run_execute_hooks();
             }
-            "idy" | "idy_w" => {
-                format!(
-                    "(${:02X}),Y = {:04X} @ {:04X} = {:02X}",
-                    self.read(old_state.pc),
-                    operand.address.wrapping_sub(old_state.y as u16),
-                    operand.address,
-                    operand.value
-                )
+            if let Some(mut tracer) = self.tracer.take() {
+                tracer.trace(self);
+                self.tracer = Some(tracer);
             }
-            "rel" => {
-                format!("${:04X}", self.pc + operand.value as u16)
+            let address = self.pc;
+            let opcode = self.fetch_opcode();
+            self.opcode = opcode;
+            self.pc = self.pc.wrapping_add(1);
+            let (fetch, operation) = self.operations()[opcode as usize];
+            let operand = fetch(self);
+            self.effective_address = operand.address;
+            operation(self, operand);
+            if self.log_usage {
+                self.log_instruction(address);
             }
-            "imp" => match name {
-                "rol_a" | "lsr_a" | "asl_a" | "ror_a" => "A".to_string(),
-                _ => "".to_string(),
-            },
-            _ => "".to_string(),
-        };
-        debug!(
-            "{:04X}  {:02X} {}  {} {}{}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
-            old_state.pc - 1,
-            self.read(old_state.pc - 1),
-            operand_bytecode,
-            name.to_uppercase().chars().take(3).collect::<String>(),
-            disassembly,
-            " ".repeat(27 - disassembly.len()),
-            old_state.a,
-            old_state.x,
-            old_state.y,
-            old_state.p,
-            old_state.s
-        );
-    }
-
-    pub fn tick(&mut self) {
-        macro_rules! ex {
-            ($name:ident, $fetch:ident) => {{
-                #[cfg(feature = "nestest")]
-                let p_state: Nise6502State = self.into();
-                let operand = self.$fetch();
-                #[cfg(feature = "nestest")]
-                self.nestest_dbgprint(p_state, stringify!($name), stringify!($fetch), &operand);
-                self.$name(operand)
-            }};
         }
+        if let Some((state, bytes)) = before {
+            self.history.push(Executed {
+                state,
+                bytes,
+                interrupt: self.interrupt_serviced,
+            });
+        }
+        let cycles = (self.cycles - start) as u32;
+        if let Some(profiler) = &mut self.profiler {
+            // Interrupt entries count towards their handlers
+            let address = match self.interrupt_serviced {
+                Some(_) => self.pc,
+                None => pc,
+            };
+            profiler.record(address, cycles, &self.call_stack, self.interrupt_serviced);
+        }
+        if let Some(address) = self.stopped {
+            self.status = StepStatus::Jammed { address };
+        }
+        self.status
+    }
+
+    /// Runs one cycle. Instructions run whole on their first cycle, and the
+    /// rest go by idle. Tells how the instruction of the cycle went.
+    pub fn tick(&mut self) -> StepStatus {
         if self.cycle_count == 0 {
-            let opcode = self.read(self.pc);
-            self.pc += 1;
-            match opcode {
-                0x69 => ex!(adc, imm),
-                0x65 => ex!(adc, zpa),
-                0x75 => ex!(adc, zpx),
-                0x6D => ex!(adc, abs),
-                0x7D => ex!(adc, abx),
-                0x79 => ex!(adc, aby),
-                0x61 => ex!(adc, idx),
-                0x71 => ex!(adc, idy),
-                0x29 => ex!(and, imm),
-                0x25 => ex!(and, zpa),
-                0x35 => ex!(and, zpx),
-                0x2D => ex!(and, abs),
-                0x3D => ex!(and, abx),
-                0x39 => ex!(and, aby),
-                0x21 => ex!(and, idx),
-                0x31 => ex!(and, idy),
-                0x0A => ex!(asl_a, imp),
-                0x06 => ex!(asl, zpa),
-                0x16 => ex!(asl, zpx),
-                0x0E => ex!(asl, abs),
-                0x1E => ex!(asl, abx_w),
-                0x90 => ex!(bcc, rel),
-                0xB0 => ex!(bcs, rel),
-                0xF0 => ex!(beq, rel),
-                0x24 => ex!(bit, zpa),
-                0x2C => ex!(bit, abs),
-                0x30 => ex!(bmi, rel),
-                0xD0 => ex!(bne, rel),
-                0x10 => ex!(bpl, rel),
-                0x00 => ex!(brk, imp),
-                0x50 => ex!(bvc, rel),
-                0x70 => ex!(bvs, rel),
-                0x18 => ex!(clc, imp),
-                0xD8 => ex!(cld, imp),
-                0x58 => ex!(cli, imp),
-                0xB8 => ex!(clv, imp),
-                0xC9 => ex!(cmp, imm),
-                0xC5 => ex!(cmp, zpa),
-                0xD5 => ex!(cmp, zpx),
-                0xCD => ex!(cmp, abs),
-                0xDD => ex!(cmp, abx),
-                0xD9 => ex!(cmp, aby),
-                0xC1 => ex!(cmp, idx),
-                0xD1 => ex!(cmp, idy),
-                0xE0 => ex!(cpx, imm),
-                0xE4 => ex!(cpx, zpa),
-                0xEC => ex!(cpx, abs),
-                0xC0 => ex!(cpy, imm),
-                0xC4 => ex!(cpy, zpa),
-                0xCC => ex!(cpy, abs),
-                0xC6 => ex!(dec, zpa),
-                0xD6 => ex!(dec, zpx),
-                0xCE => ex!(dec, abs),
-                0xDE => ex!(dec, abx_w),
-                0xCA => ex!(dex, imp),
-                0x88 => ex!(dey, imp),
-                0x49 => ex!(eor, imm),
-                0x45 => ex!(eor, zpa),
-                0x55 => ex!(eor, zpx),
-                0x4D => ex!(eor, abs),
-                0x5D => ex!(eor, abx),
-                0x59 => ex!(eor, aby),
-                0x41 => ex!(eor, idx),
-                0x51 => ex!(eor, idy),
-                0xE6 => ex!(inc, zpa),
-                0xF6 => ex!(inc, zpx),
-                0xEE => ex!(inc, abs),
-                0xFE => ex!(inc, abx),
-                0xE8 => ex!(inx, imp),
-                0xC8 => ex!(iny, imp),
-                0x4C => ex!(jmp, abs),
-                0x6C => ex!(jmp, ind),
-                0x20 => ex!(jsr, abs),
-                0xA9 => ex!(lda, imm),
-                0xA5 => ex!(lda, zpa),
-                0xB5 => ex!(lda, zpx),
-                0xAD => ex!(lda, abs),
-                0xBD => ex!(lda, abx),
-                0xB9 => ex!(lda, aby),
-                0xA1 => ex!(lda, idx),
-                0xB1 => ex!(lda, idy),
-                0xA2 => ex!(ldx, imm),
-                0xA6 => ex!(ldx, zpa),
-                0xB6 => ex!(ldx, zpy),
-                0xAE => ex!(ldx, abs),
-                0xBE => ex!(ldx, aby),
-                0xA0 => ex!(ldy, imm),
-                0xA4 => ex!(ldy, zpa),
-                0xB4 => ex!(ldy, zpx),
-                0xAC => ex!(ldy, abs),
-                0xBC => ex!(ldy, abx),
-                0x4A => ex!(lsr_a, imp),
-                0x46 => ex!(lsr, zpa),
-                0x56 => ex!(lsr, zpx),
-                0x4E => ex!(lsr, abs),
-                0x5E => ex!(lsr, abx_w),
-                0xEA => ex!(nop, imp),
-                0x09 => ex!(ora, imm),
-                0x05 => ex!(ora, zpa),
-                0x15 => ex!(ora, zpx),
-                0x0D => ex!(ora, abs),
-                0x1D => ex!(ora, abx),
-                0x19 => ex!(ora, aby),
-                0x01 => ex!(ora, idx),
-                0x11 => ex!(ora, idy),
-                0x48 => ex!(pha, imp),
-                0x08 => ex!(php, imp),
-                0x68 => ex!(pla, imp),
-                0x28 => ex!(plp, imp),
-                0x2A => ex!(rol_a, imp),
-                0x26 => ex!(rol, zpa),
-                0x36 => ex!(rol, zpx),
-                0x2E => ex!(rol, abs),
-                0x3E => ex!(rol, abx_w),
-                0x6A => ex!(ror_a, imp),
-                0x66 => ex!(ror, zpa),
-                0x76 => ex!(ror, zpx),
-                0x6E => ex!(ror, abs),
-                0x7E => ex!(ror, abx_w),
-                0x40 => ex!(rti, imp),
-                0x60 => ex!(rts, imp),
-                0xE9 => ex!(sbc, imm),
-                0xE5 => ex!(sbc, zpa),
-                0xF5 => ex!(sbc, zpx),
-                0xED => ex!(sbc, abs),
-                0xFD => ex!(sbc, abx),
-                0xF9 => ex!(sbc, aby),
-                0xE1 => ex!(sbc, idx),
-                0xF1 => ex!(sbc, idy),
-                0x38 => ex!(sec, imp),
-                0xF8 => ex!(sed, imp),
-                0x78 => ex!(sei, imp),
-                0x85 => ex!(sta, zpa),
-                0x95 => ex!(sta, zpx),
-                0x8D => ex!(sta, abs),
-                0x9D => ex!(sta, abx_w),
-                0x99 => ex!(sta, aby_w),
-                0x81 => ex!(sta, idx),
-                0x91 => ex!(sta, idy_w),
-                0x86 => ex!(stx, zpa),
-                0x96 => ex!(stx, zpy),
-                0x8E => ex!(stx, abs),
-                0x84 => ex!(sty, zpa),
-                0x94 => ex!(sty, zpx),
-                0x8C => ex!(sty, abs),
-                0xAA => ex!(tax, imp),
-                0xA8 => ex!(tay, imp),
-                0xBA => ex!(tsx, imp),
-                0x8A => ex!(txa, imp),
-                0x9A => ex!(txs, imp),
-                0x98 => ex!(tya, imp),
-                _ => {
-                    warn!("Unimplemented opcode: {:#04X}", opcode);
-                    return;
-                }
-            }
+            let start = self.cycles;
+            self.step();
+            self.cycle_count = (self.cycles - start) as u32 - 1;
         } else {
             self.cycle_count -= 1;
         }
+        self.status
+    }
+
+    // Every bus access takes exactly one CPU cycle.
+    fn start_cycle(&mut self) {
+        self.cycles += 1;
+        if self.cycle_accurate {
+            self.bus.tick();
+        }
+    }
+
+    // Interrupt lines are sampled at the end of every cycle, but an
+    // instruction only acts on what was seen before its final cycle.
+    fn end_cycle(&mut self) {
+        let nmi_line = self.nmi_line || self.bus.nmi_line();
+        let irq_line = self.irq_line || self.bus.irq_line();
+        self.prev_nmi_pending = self.nmi_pending;
+        if nmi_line && !self.prev_nmi_line {
+            self.nmi_pending = true;
+        }
+        self.prev_nmi_line = nmi_line;
+
+        self.prev_irq_pending = self.irq_pending;
+        self.irq_pending = irq_line && self.p & 0b0000_0100 == 0;
+    }
+
+    // A cycle in which the CPU leaves the bus alone
+    fn idle_cycle(&mut self) {
+        self.start_cycle();
+        self.end_cycle();
     }
 
     fn read(&mut self, address: u16) -> u8 {
-        self.bus.read(address)
+        if let Some(dma) = self.bus.take_dma() {
+            self.halt(address, dma);
+        }
+        self.read_cycle(address)
+    }
+
+    fn read_cycle(&mut self, address: u16) -> u8 {
+        self.start_cycle();
+        let value = self.bus.read(address);
+        let value = self.hooks.read(address, value);
+        self.record(address, value, AccessKind::Read);
+        self.end_cycle();
+        value
+    }
+
+    // Reads the opcode at PC, which fetch hooks may swap for another
+    fn fetch_opcode(&mut self) -> u8 {
+        if let Some(dma) = self.bus.take_dma() {
+            self.halt(self.pc, dma);
+        }
+        self.start_cycle();
+        let value = self.bus.read(self.pc);
+        let value = self.hooks.read(self.pc, value);
+        let value = self.hooks.fetch(self.pc, value);
+        self.record(self.pc, value, AccessKind::Read);
+        self.end_cycle();
+        value
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.start_cycle();
+        if self.hooks.write(address, value) {
+            self.bus.write(address, value);
+        }
+        self.record(address, value, AccessKind::Write);
+        self.end_cycle();
+    }
+
+    // Stays off the bus while DMA runs, along with any DMA asked for in the
+    // meantime. DMA reads fall on even cycles and its writes on odd ones.
+    // The CPU repeats the read it was about to make on each cycle the DMA
+    // has no use for: the first, in which it halts, any needed to line DMA
+    // up, and the DMC's dummy cycle. Reads with side effects, such as those
+    // of $2007 and $4016, have them again each time.
+    fn halt(&mut self, address: u16, dma: Dma) {
+        // The next byte to copy to OAM, and whether it has been read
+        let mut oam: Option<(u16, bool)> = None;
+        // The sample byte to fetch, and the cycles it waits for first
+        let mut dmc: Option<(u16, u8)> = None;
+        let mut request = Some(dma);
+        let mut byte = 0;
+        let mut halted = false;
+        loop {
+            match request {
+                Some(Dma::Oam(page)) => oam = Some((to_u16(0, page), false)),
+                Some(Dma::Dmc(sample)) => dmc = Some((sample, 2)),
+                None if oam.is_none() && dmc.is_none() => break,
+                None => {}
+            }
+            let get = self.cycles.is_multiple_of(2);
+            let dmc_ready = matches!(dmc, Some((_, 0)));
+            if let Some((_, wait)) = &mut dmc {
+                *wait = wait.saturating_sub(1);
+            }
+            match (oam, dmc) {
+                _ if !halted => {
+                    self.read_cycle(address);
+                    halted = true;
+                }
+                (_, Some((sample, _))) if get && dmc_ready => {
+                    if self.log_usage {
+                        self.bus.log_usage(sample, Usage::Pcm);
+                    }
+                    let value = self.read_cycle(sample);
+                    self.bus.dmc_fetched(value);
+                    dmc = None;
+                }
+                (Some((source, false)), _) if get => {
+                    byte = self.read_cycle(source);
+                    oam = Some((source, true));
+                }
+                (Some((source, true)), _) if !get => {
+                    self.write(0x2004, byte);
+                    oam = (source & 0xFF != 0xFF).then_some((source + 1, false));
+                }
+                _ => {
+                    self.read_cycle(address);
+                }
+            }
+            request = self.bus.take_dma();
+        }
+    }
+
+    // The hooks that run can add more, but not remove any
+    fn run_execute_hooks(&mut self) {
+        let mut hooks = std::mem::take(&mut self.execute_hooks);
+        for (_, pc, hook) in &mut hooks {
+            if *pc == self.pc {
+                hook(self);
+            }
+        }
+        hooks.append(&mut self.execute_hooks);
+        self.execute_hooks = hooks;
+    }
+
+    fn record(&mut self, address: u16, value: u8, kind: AccessKind) {
+        if self.record_accesses {
+            self.accesses.push(BusAccess {
+                address,
+                value,
+                kind,
+            });
+        }
     }
 
     fn read16(&mut self, address: u16) -> u16 {
         let low_byte = self.read(address);
-        let high_byte = self.read(address + 1);
+        let high_byte = self.read(address.wrapping_add(1));
+        to_u16(low_byte, high_byte)
+    }
+
+    fn read_vector(&mut self, vector: u16) -> u16 {
+        if self.log_usage {
+            self.bus.log_usage(vector, Usage::Data);
+            self.bus.log_usage(vector.wrapping_add(1), Usage::Data);
+        }
+        self.read16(vector)
+    }
+
+    // Marks the bytes of the instruction at `address` as code, and what it
+    // read as data, as FCEUX's code/data logger would
+    fn log_instruction(&mut self, address: u16) {
+        use AddressingMode::*;
+        let opcode = opcodes(self.variant)[self.opcode as usize];
+        let code = if self.jumped_indirect {
+            Usage::IndirectCode
+        } else {
+            Usage::Code
+        };
+        for offset in 0..=opcode.mode.operand_length() as u16 {
+            self.bus.log_usage(address.wrapping_add(offset), code);
+        }
+        self.jumped_indirect = matches!(opcode.mode, Indirect | AbsoluteIndexedIndirect);
+        let data = match opcode.mode {
+            ZeroPage | ZeroPageX | ZeroPageY | Absolute | AbsoluteX | AbsoluteY => Usage::Data,
+            IndexedIndirect | IndirectIndexed | ZeroPageIndirect => Usage::IndirectData,
+            _ => return,
+        };
+        if !NON_READING.contains(&opcode.mnemonic) {
+            self.bus.log_usage(self.effective_address, data);
+        }
+    }
+
+    fn read16_zp(&mut self, address: u8) -> u16 {
+        let low_byte = self.read(address as u16);
+        let high_byte = self.read(address.wrapping_add(1) as u16);
         to_u16(low_byte, high_byte)
     }
 
@@ -389,141 +1137,293 @@ impl Nise6502 {
     }
 
     fn push(&mut self, value: u8) {
-        self.bus.write(0x100 + self.s as u16, value);
-        self.s -= 1;
+        self.write(0x100 + self.s as u16, value);
+        self.s = self.s.wrapping_sub(1);
     }
 
     fn pop(&mut self) -> u8 {
-        self.s += 1;
-        let value = self.read(0x100 + self.s as u16);
-        value
+        self.s = self.s.wrapping_add(1);
+        self.read(0x100 + self.s as u16)
     }
 
-    fn adc(&mut self, operand: Operand) {
-        let result = self.a as usize + operand.value as usize + (self.p & 1) as usize;
+    // The NMOS 6502 writes the unmodified value back while the ALU is busy,
+    // and only then the result. The 65C02 reads it a second time instead.
+    fn read_modify_write(&mut self, address: u16, f: fn(&mut Self, u8) -> u8) -> u8 {
+        let value = self.read(address);
+        if self.variant == Variant::Wdc65C02 {
+            self.read(address);
+        } else {
+            self.write(address, value);
+        }
+        let result = f(self, value);
+        self.write(address, result);
+        result
+    }
+
+    fn decimal_mode(&self) -> bool {
+        self.p & 0b0000_1000 != 0 && self.variant != Variant::Ricoh2A03
+    }
+
+    fn add(&mut self, value: u8) {
+        if self.decimal_mode() {
+            self.add_decimal(value)
+        } else {
+            self.add_binary(value)
+        }
+    }
+
+    fn subtract(&mut self, value: u8) {
+        if self.decimal_mode() {
+            self.subtract_decimal(value)
+        } else {
+            self.add_binary(!value)
+        }
+    }
+
+    fn add_binary(&mut self, value: u8) {
+        let result = self.a as usize + value as usize + (self.p & 1) as usize;
         self.p &= 0b1011_1110;
         self.p |= ((result >> 8) & 1) as u8;
 
-        self.p |= (!(self.a ^ operand.value) & (self.a ^ result as u8) & 0x80) >> 1;
+        self.p |= (!(self.a ^ value) & (self.a ^ result as u8) & 0x80) >> 1;
 
         self.a = result as u8;
         self.set_nz(self.a)
     }
 
+    // The NMOS 6502 takes N, V and Z from intermediate results of the BCD
+    // adjustment. The 65C02 sets N and Z properly but needs an extra cycle.
+    fn add_decimal(&mut self, value: u8) {
+        let (a, b, carry) = (self.a as i16, value as i16, (self.p & 1) as i16);
+        let mut low = (a & 0x0F) + (b & 0x0F) + carry;
+        if low >= 0x0A {
+            low = ((low + 0x06) & 0x0F) + 0x10;
+        }
+        let mut result = (a & 0xF0) + (b & 0xF0) + low;
+        let signed = (a & 0xF0) as u8 as i8 as i16 + (b & 0xF0) as u8 as i8 as i16 + low;
+        if result >= 0xA0 {
+            result += 0x60;
+        }
+
+        self.p &= 0b0011_1100;
+        self.p |= (result >= 0x100) as u8;
+        if !(-128..=127).contains(&signed) {
+            self.p |= 0b0100_0000;
+        }
+        if self.variant == Variant::Wdc65C02 {
+            self.set_nz(result as u8);
+            self.read(self.pc);
+        } else {
+            self.p |= signed as u8 & 0b1000_0000;
+            if (a + b + carry) & 0xFF == 0 {
+                self.p |= 0b0000_0010;
+            }
+        }
+        self.a = result as u8;
+    }
+
+    // Flags come from the binary subtraction, except for N and Z on the 65C02
+    fn subtract_decimal(&mut self, value: u8) {
+        let (a, b, carry) = (self.a as i16, value as i16, (self.p & 1) as i16);
+        let low = (a & 0x0F) - (b & 0x0F) + carry - 1;
+        let result = if self.variant == Variant::Wdc65C02 {
+            let mut result = a - b + carry - 1;
+            if result < 0 {
+                result -= 0x60;
+            }
+            if low < 0 {
+                result -= 0x06;
+            }
+            result
+        } else {
+            let low = if low < 0 {
+                ((low - 0x06) & 0x0F) - 0x10
+            } else {
+                low
+            };
+            let mut result = (a & 0xF0) - (b & 0xF0) + low;
+            if result < 0 {
+                result -= 0x60;
+            }
+            result
+        };
+
+        self.add_binary(!value);
+        self.a = result as u8;
+        if self.variant == Variant::Wdc65C02 {
+            self.set_nz(self.a);
+            self.read(self.pc);
+        }
+    }
+
+    fn shift_left(&mut self, value: u8) -> u8 {
+        self.p &= 0b1111_1110;
+        self.p |= value >> 7;
+        value << 1
+    }
+
+    fn shift_right(&mut self, value: u8) -> u8 {
+        self.p &= 0b1111_1110;
+        self.p |= value & 1;
+        value >> 1
+    }
+
+    fn rotate_left(&mut self, value: u8) -> u8 {
+        let carry = self.p & 1;
+        self.shift_left(value) | carry
+    }
+
+    fn rotate_right(&mut self, value: u8) -> u8 {
+        let carry = self.p & 1;
+        self.shift_right(value) | (carry << 7)
+    }
+
+    fn adc(&mut self, operand: Operand) {
+        let value = self.read(operand.address);
+        self.add(value)
+    }
+
     fn and(&mut self, operand: Operand) {
-        self.a &= operand.value;
+        self.a &= self.read(operand.address);
 
         self.set_nz(self.a)
     }
 
     fn asl(&mut self, operand: Operand) {
-        self.cycle_count += 2;
-        self.p &= 0b1111_1110;
-        self.p |= operand.value >> 7;
-        let result = operand.value << 1;
-        self.bus.write(operand.address, result);
+        let result = self.read_modify_write(operand.address, Self::shift_left);
         self.set_nz(result)
     }
 
     fn asl_a(&mut self, _: Operand) {
-        self.cycle_count += 2;
-
-        self.p &= 0b1111_1110;
-        self.p |= self.a >> 7;
-        self.a <<= 1;
+        self.a = self.shift_left(self.a);
 
         self.set_nz(self.a)
     }
 
-    fn branch(&mut self, value: u8) {
-        self.cycle_count += 2;
-        self.pc = self.pc.wrapping_add(value as i8 as i16 as u16);
+    fn branch(&mut self, operand: Operand, condition: bool) {
+        let offset = self.read(operand.address);
+        if condition {
+            // Whether an interrupt was raised during the operand fetch
+            let nmi = self.nmi_pending && !self.prev_nmi_pending;
+            let irq = self.irq_pending && !self.prev_irq_pending;
+            self.read(self.pc);
+            let target = self.pc.wrapping_add(offset as i8 as i16 as u16);
+            if target & 0xFF00 != self.pc & 0xFF00 {
+                self.read((self.pc & 0xFF00) | (target & 0x00FF));
+            } else {
+                // Without a page to cross, interrupts are only polled before
+                // the operand fetch, so one raised during it waits until after
+                // the next instruction
+                self.prev_nmi_pending &= !nmi;
+                self.prev_irq_pending &= !irq;
+            }
+            self.pc = target;
+        }
     }
 
     fn bcc(&mut self, operand: Operand) {
-        if (self.p & 1) == 0 {
-            self.branch(operand.value)
-        }
+        self.branch(operand, (self.p & 1) == 0)
     }
 
     fn bcs(&mut self, operand: Operand) {
-        if (self.p & 1) == 1 {
-            self.branch(operand.value)
-        }
+        self.branch(operand, (self.p & 1) == 1)
     }
 
     fn beq(&mut self, operand: Operand) {
-        if ((self.p >> 1) & 1) == 1 {
-            self.branch(operand.value)
-        }
+        self.branch(operand, ((self.p >> 1) & 1) == 1)
     }
 
     fn bit(&mut self, operand: Operand) {
+        let value = self.read(operand.address);
         self.p &= 0b1111_1101;
-        if (self.a & operand.value) == 0 {
+        if (self.a & value) == 0 {
             self.p |= 0b0000_0010;
         }
         self.p &= 0b0011_1111;
-        self.p |= operand.value & 0b1100_0000
+        self.p |= value & 0b1100_0000
     }
 
     fn bmi(&mut self, operand: Operand) {
-        if ((self.p >> 7) & 1) == 1 {
-            self.branch(operand.value)
-        }
+        self.branch(operand, ((self.p >> 7) & 1) == 1)
     }
 
     fn bne(&mut self, operand: Operand) {
-        if ((self.p >> 1) & 1) == 0 {
-            self.branch(operand.value)
-        }
+        self.branch(operand, ((self.p >> 1) & 1) == 0)
     }
 
     fn bpl(&mut self, operand: Operand) {
-        if ((self.p >> 7) & 1) == 0 {
-            self.branch(operand.value)
-        }
+        self.branch(operand, ((self.p >> 7) & 1) == 0)
     }
 
-    fn brk(&mut self, _: Operand) {
-        self.cycle_count += 7;
-        self.pc += 1;
+    // Shared by BRK, IRQ and NMI. The vector is only chosen after PC has been
+    // pushed, so an NMI arriving by then hijacks a BRK or IRQ in progress.
+    fn interrupt(&mut self, brk: bool) {
+        let return_address = self.pc;
         self.push((self.pc >> 8) as u8);
-        self.push((self.pc | 0x00ff) as u8);
-        self.push(self.p | 0x30);
-        self.pc = self.read16(0xFFFE);
+        self.push((self.pc & 0x00ff) as u8);
+        let (vector, interrupt) = if self.nmi_pending {
+            self.nmi_pending = false;
+            (0xFFFA, Interrupt::Nmi)
+        } else {
+            (0xFFFE, Interrupt::Irq)
+        };
+        if !brk {
+            self.interrupt_serviced = Some(interrupt);
+        }
+        if brk {
+            self.push(self.p | 0x30);
+        } else {
+            self.push((self.p | 0x20) & 0xEF);
+        }
         self.p |= 0b0000_0100;
+        if self.variant == Variant::Wdc65C02 {
+            self.p &= 0b1111_0111;
+        }
+        self.pc = self.read_vector(vector);
+        let kind = match interrupt {
+            Interrupt::Nmi => FrameKind::Nmi,
+            Interrupt::Irq if brk => FrameKind::Brk,
+            Interrupt::Irq => FrameKind::Irq,
+        };
+        let frame = Frame {
+            kind,
+            // BRK pushes the address past its padding byte
+            call_site: return_address.wrapping_sub(if brk { 2 } else { 0 }),
+            entry: self.pc,
+            return_address,
+            stack_pointer: self.s,
+        };
+        self.call_stack.call(frame, 3);
+    }
+
+    fn brk(&mut self, _: Operand) {
+        self.pc = self.pc.wrapping_add(1);
+        self.interrupt(true);
+        // The first instruction of the handler always runs before an NMI
+        self.prev_nmi_pending = false;
     }
 
     fn bvc(&mut self, operand: Operand) {
-        if ((self.p >> 6) & 1) == 0 {
-            self.branch(operand.value);
-        }
+        self.branch(operand, ((self.p >> 6) & 1) == 0)
     }
 
     fn bvs(&mut self, operand: Operand) {
-        if ((self.p >> 6) & 1) == 1 {
-            self.branch(operand.value);
-        }
+        self.branch(operand, ((self.p >> 6) & 1) == 1)
     }
 
     fn clc(&mut self, _: Operand) {
-        self.cycle_count += 2;
         self.p &= 0b1111_1110;
     }
 
     fn cld(&mut self, _: Operand) {
-        self.cycle_count += 2;
         self.p &= 0b1111_0111;
     }
 
     fn cli(&mut self, _: Operand) {
-        self.cycle_count += 2;
         self.p &= 0b1111_1011;
     }
 
     fn clv(&mut self, _: Operand) {
-        self.cycle_count += 2;
         self.p &= 0b1011_1111;
     }
 
@@ -543,346 +1443,519 @@ impl Nise6502 {
     }
 
     fn cmp(&mut self, operand: Operand) {
-        self.compare(self.a, operand.value)
+        let value = self.read(operand.address);
+        self.compare(self.a, value)
     }
 
     fn cpx(&mut self, operand: Operand) {
-        self.compare(self.x, operand.value)
+        let value = self.read(operand.address);
+        self.compare(self.x, value)
     }
 
     fn cpy(&mut self, operand: Operand) {
-        self.compare(self.y, operand.value)
+        let value = self.read(operand.address);
+        self.compare(self.y, value)
     }
 
     fn dec(&mut self, operand: Operand) {
-        self.cycle_count += 2;
-        let result = operand.value.wrapping_sub(1);
-        self.bus.write(operand.address, result);
+        let result = self.read_modify_write(operand.address, |_, value| value.wrapping_sub(1));
         self.set_nz(result)
     }
 
     fn dex(&mut self, _: Operand) {
-        self.cycle_count += 2;
         self.x = self.x.wrapping_sub(1);
         self.set_nz(self.x)
     }
 
     fn dey(&mut self, _: Operand) {
-        self.cycle_count += 2;
         self.y = self.y.wrapping_sub(1);
         self.set_nz(self.y)
     }
 
     fn eor(&mut self, operand: Operand) {
-        self.a ^= operand.value;
+        self.a ^= self.read(operand.address);
         self.set_nz(self.a)
     }
 
     fn inc(&mut self, operand: Operand) {
-        self.cycle_count += 2;
-        let result = operand.value.wrapping_add(1);
-        self.bus.write(operand.address, result);
+        let result = self.read_modify_write(operand.address, |_, value| value.wrapping_add(1));
         self.set_nz(result)
     }
 
     fn inx(&mut self, _: Operand) {
-        self.cycle_count += 2;
         self.x = self.x.wrapping_add(1);
         self.set_nz(self.x)
     }
 
     fn iny(&mut self, _: Operand) {
-        self.cycle_count += 2;
         self.y = self.y.wrapping_add(1);
         self.set_nz(self.y)
     }
 
+    // Reads the byte after the opcode and locks up
+    fn jam(&mut self, _: Operand) {
+        self.stopped = Some(self.pc.wrapping_sub(1));
+    }
+
     fn jmp(&mut self, operand: Operand) {
-        self.cycle_count -= 1;
         self.pc = operand.address;
     }
 
+    // The high byte of the target is only fetched after the return address
+    // (which points at it) has been pushed.
     fn jsr(&mut self, operand: Operand) {
-        self.cycle_count += 2;
-        self.pc -= 1;
+        let low_byte = self.read(operand.address);
+        self.read(0x100 + self.s as u16);
         self.push((self.pc >> 8) as u8);
         self.push((self.pc & 0x00ff) as u8);
-        self.pc = operand.address;
+        let high_byte = self.read(self.pc);
+        let call_site = self.pc.wrapping_sub(2);
+        self.pc = to_u16(low_byte, high_byte);
+        let frame = Frame {
+            kind: FrameKind::Jsr,
+            call_site,
+            entry: self.pc,
+            return_address: call_site.wrapping_add(3),
+            stack_pointer: self.s,
+        };
+        self.call_stack.call(frame, 2);
     }
 
     fn lda(&mut self, operand: Operand) {
-        self.a = operand.value;
+        self.a = self.read(operand.address);
         self.set_nz(self.a)
     }
 
     fn ldx(&mut self, operand: Operand) {
-        self.x = operand.value;
+        self.x = self.read(operand.address);
         self.set_nz(self.x)
     }
 
     fn ldy(&mut self, operand: Operand) {
-        self.y = operand.value;
+        self.y = self.read(operand.address);
         self.set_nz(self.y)
     }
 
     fn lsr(&mut self, operand: Operand) {
-        self.cycle_count += 2;
-        self.p &= 0b1111_1110;
-        self.p |= operand.value & 1;
-
-        let result = operand.value >> 1;
-        self.bus.write(operand.address, result);
+        let result = self.read_modify_write(operand.address, Self::shift_right);
 
         self.set_nz(result)
     }
 
     fn lsr_a(&mut self, _: Operand) {
-        self.cycle_count += 2;
-        self.p &= 0b1111_1110;
-        self.p |= self.a & 1;
-        self.a >>= 1;
+        self.a = self.shift_right(self.a);
 
         self.set_nz(self.a)
     }
 
-    fn nop(&mut self, _: Operand) {
-        self.cycle_count += 2;
+    fn nop(&mut self, _: Operand) {}
+
+    fn unimplemented(&mut self, _: Operand) {
+        self.status = StepStatus::Unimplemented {
+            address: self.pc.wrapping_sub(1),
+            opcode: self.opcode,
+        };
     }
 
     fn ora(&mut self, operand: Operand) {
-        self.a |= operand.value;
+        self.a |= self.read(operand.address);
         self.set_nz(self.a);
     }
 
     fn pha(&mut self, _: Operand) {
-        self.cycle_count += 3;
         self.push(self.a);
     }
 
     fn php(&mut self, _: Operand) {
-        self.cycle_count += 3;
         self.push(self.p | 0x30);
     }
 
     fn pla(&mut self, _: Operand) {
-        self.cycle_count += 4;
+        self.read(0x100 + self.s as u16);
         self.a = self.pop();
         self.set_nz(self.a);
     }
 
     fn plp(&mut self, _: Operand) {
-        self.cycle_count += 4;
+        self.read(0x100 + self.s as u16);
         self.p = (self.pop() | 0x30) & 0xEF;
     }
 
     fn rol(&mut self, operand: Operand) {
-        self.cycle_count += 2;
-        let result = operand.value << 1 | (self.p & 1);
-        self.p &= 0b1111_1110;
-        self.p |= operand.value >> 7;
-        self.bus.write(operand.address, result);
+        let result = self.read_modify_write(operand.address, Self::rotate_left);
 
         self.set_nz(result);
     }
 
     fn rol_a(&mut self, _: Operand) {
-        self.cycle_count += 2;
-        let result = self.a << 1 | (self.p & 1);
-        self.p &= 0b1111_1110;
-        self.p |= self.a >> 7;
-        self.a = result;
+        self.a = self.rotate_left(self.a);
 
-        self.set_nz(result);
+        self.set_nz(self.a);
     }
 
     fn ror(&mut self, operand: Operand) {
-        self.cycle_count += 2;
-        let result = operand.value >> 1 | ((self.p & 1) << 7);
-        self.p &= 0b1111_1110;
-        self.p |= self.a & 0b0000_0001;
-        self.bus.write(operand.address, result);
+        let result = self.read_modify_write(operand.address, Self::rotate_right);
 
         self.set_nz(result);
     }
 
     fn ror_a(&mut self, _: Operand) {
-        self.cycle_count += 2;
-        let result = self.a >> 1 | ((self.p & 1) << 7);
-        self.p &= 0b1111_1110;
-        self.p |= self.a & 0b0000_0001;
-        self.a = result;
+        self.a = self.rotate_right(self.a);
 
-        self.set_nz(result);
+        self.set_nz(self.a);
     }
 
     fn rti(&mut self, _: Operand) {
-        self.cycle_count += 6;
-        self.p = self.pop() | 0x30 & 0xEF;
+        let (from, stack_pointer) = (self.pc.wrapping_sub(1), self.s);
+        self.read(0x100 + self.s as u16);
+        self.p = (self.pop() | 0x30) & 0xEF;
         let pcl = self.pop();
         let pch = self.pop();
-        self.pc = to_u16(pcl, pch)
+        self.pc = to_u16(pcl, pch);
+        self.call_stack.ret(from, stack_pointer, self.pc);
     }
 
     fn rts(&mut self, _: Operand) {
-        self.cycle_count += 6;
+        let (from, stack_pointer) = (self.pc.wrapping_sub(1), self.s);
+        self.read(0x100 + self.s as u16);
         let pcl = self.pop();
         let pch = self.pop();
-        self.pc = to_u16(pcl, pch) + 1
+        self.pc = to_u16(pcl, pch);
+        self.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        self.call_stack.ret(from, stack_pointer, self.pc);
     }
 
     fn sbc(&mut self, operand: Operand) {
-        self.adc(Operand {
-            value: !operand.value,
-            address: operand.address,
-        })
+        let value = self.read(operand.address);
+        self.subtract(value)
     }
 
     fn sec(&mut self, _: Operand) {
-        self.cycle_count += 2;
         self.p |= 1
     }
 
     fn sed(&mut self, _: Operand) {
-        self.cycle_count += 2;
         self.p |= 0b0000_1000
     }
 
     fn sei(&mut self, _: Operand) {
-        self.cycle_count += 2;
         self.p |= 0b0000_0100
     }
 
     fn sta(&mut self, operand: Operand) {
-        self.bus.write(operand.address, self.a)
+        self.write(operand.address, self.a)
     }
 
     fn stx(&mut self, operand: Operand) {
-        self.bus.write(operand.address, self.x)
+        self.write(operand.address, self.x)
     }
 
     fn sty(&mut self, operand: Operand) {
-        self.bus.write(operand.address, self.y)
+        self.write(operand.address, self.y)
     }
 
     fn tax(&mut self, _: Operand) {
-        self.cycle_count += 2;
         self.x = self.a;
         self.set_nz(self.x);
     }
 
     fn tay(&mut self, _: Operand) {
-        self.cycle_count += 2;
         self.y = self.a;
         self.set_nz(self.y)
     }
 
     fn tsx(&mut self, _: Operand) {
-        self.cycle_count += 2;
         self.x = self.s;
         self.set_nz(self.x)
     }
 
     fn txa(&mut self, _: Operand) {
-        self.cycle_count += 2;
         self.a = self.x;
         self.set_nz(self.a)
     }
 
     fn txs(&mut self, _: Operand) {
-        self.cycle_count += 2;
         self.s = self.x;
+        self.call_stack.unwind(self.pc.wrapping_sub(1), self.s);
     }
 
     fn tya(&mut self, _: Operand) {
-        self.cycle_count += 2;
         self.a = self.y;
         self.set_nz(self.a)
     }
 
+    // Unofficial opcodes
+    fn alr(&mut self, operand: Operand) {
+        self.a &= self.read(operand.address);
+        self.a = self.shift_right(self.a);
+        self.set_nz(self.a)
+    }
+
+    fn anc(&mut self, operand: Operand) {
+        self.a &= self.read(operand.address);
+        self.set_nz(self.a);
+        self.p &= 0b1111_1110;
+        self.p |= self.a >> 7;
+    }
+
+    fn arr(&mut self, operand: Operand) {
+        let value = self.read(operand.address);
+        self.a = (self.a & value) >> 1 | ((self.p & 1) << 7);
+        self.set_nz(self.a);
+        self.p &= 0b1011_1110;
+        self.p |= (self.a >> 6) & 1;
+        self.p |= (((self.a >> 6) ^ (self.a >> 5)) & 1) << 6;
+    }
+
+    fn axs(&mut self, operand: Operand) {
+        let value = self.a & self.x;
+        let operand_value = self.read(operand.address);
+        self.x = value.wrapping_sub(operand_value);
+        self.p &= 0b1111_1110;
+        if value >= operand_value {
+            self.p |= 1;
+        }
+        self.set_nz(self.x)
+    }
+
+    fn dcp(&mut self, operand: Operand) {
+        let result = self.read_modify_write(operand.address, |_, value| value.wrapping_sub(1));
+        self.compare(self.a, result)
+    }
+
+    fn isb(&mut self, operand: Operand) {
+        let result = self.read_modify_write(operand.address, |_, value| value.wrapping_add(1));
+        self.subtract(result)
+    }
+
+    fn las(&mut self, operand: Operand) {
+        self.s &= self.read(operand.address);
+        self.a = self.s;
+        self.x = self.s;
+        self.set_nz(self.s)
+    }
+
+    fn lax(&mut self, operand: Operand) {
+        self.a = self.read(operand.address);
+        self.x = self.a;
+        self.set_nz(self.a)
+    }
+
+    fn nop_m(&mut self, operand: Operand) {
+        self.read(operand.address);
+    }
+
+    fn rla(&mut self, operand: Operand) {
+        let result = self.read_modify_write(operand.address, Self::rotate_left);
+        self.a &= result;
+        self.set_nz(self.a)
+    }
+
+    fn rra(&mut self, operand: Operand) {
+        let result = self.read_modify_write(operand.address, Self::rotate_right);
+        self.add(result)
+    }
+
+    fn sax(&mut self, operand: Operand) {
+        self.write(operand.address, self.a & self.x)
+    }
+
+    fn slo(&mut self, operand: Operand) {
+        let result = self.read_modify_write(operand.address, Self::shift_left);
+        self.a |= result;
+        self.set_nz(self.a)
+    }
+
+    fn sre(&mut self, operand: Operand) {
+        let result = self.read_modify_write(operand.address, Self::shift_right);
+        self.a ^= result;
+        self.set_nz(self.a)
+    }
+
+    // 65C02 instructions
+    fn bbr(&mut self, operand: Operand) {
+        let value = self.read(operand.address);
+        self.read(operand.address);
+        let offset = self.rel();
+        self.branch(offset, value & (1 << ((self.opcode >> 4) & 7)) == 0)
+    }
+
+    fn bbs(&mut self, operand: Operand) {
+        let value = self.read(operand.address);
+        self.read(operand.address);
+        let offset = self.rel();
+        self.branch(offset, value & (1 << ((self.opcode >> 4) & 7)) != 0)
+    }
+
+    fn bit_imm(&mut self, operand: Operand) {
+        let value = self.read(operand.address);
+        self.test_bits(value)
+    }
+
+    fn bra(&mut self, operand: Operand) {
+        self.branch(operand, true)
+    }
+
+    fn dec_a(&mut self, _: Operand) {
+        self.a = self.a.wrapping_sub(1);
+        self.set_nz(self.a)
+    }
+
+    fn inc_a(&mut self, _: Operand) {
+        self.a = self.a.wrapping_add(1);
+        self.set_nz(self.a)
+    }
+
+    fn nop_5c(&mut self, operand: Operand) {
+        self.read(operand.address);
+        for _ in 0..4 {
+            self.read(0xFFFF);
+        }
+    }
+
+    fn phx(&mut self, _: Operand) {
+        self.push(self.x);
+    }
+
+    fn phy(&mut self, _: Operand) {
+        self.push(self.y);
+    }
+
+    fn plx(&mut self, _: Operand) {
+        self.read(0x100 + self.s as u16);
+        self.x = self.pop();
+        self.set_nz(self.x);
+    }
+
+    fn ply(&mut self, _: Operand) {
+        self.read(0x100 + self.s as u16);
+        self.y = self.pop();
+        self.set_nz(self.y);
+    }
+
+    fn rmb(&mut self, operand: Operand) {
+        self.read_modify_write(operand.address, |cpu, value| {
+            value & !(1 << ((cpu.opcode >> 4) & 7))
+        });
+    }
+
+    fn smb(&mut self, operand: Operand) {
+        self.read_modify_write(operand.address, |cpu, value| {
+            value | (1 << ((cpu.opcode >> 4) & 7))
+        });
+    }
+
+    fn stp(&mut self, _: Operand) {
+        self.read(self.pc);
+        self.stopped = Some(self.pc.wrapping_sub(1));
+    }
+
+    fn stz(&mut self, operand: Operand) {
+        self.write(operand.address, 0)
+    }
+
+    fn test_bits(&mut self, value: u8) {
+        self.p &= 0b1111_1101;
+        if (self.a & value) == 0 {
+            self.p |= 0b0000_0010;
+        }
+    }
+
+    fn trb(&mut self, operand: Operand) {
+        self.read_modify_write(operand.address, |cpu, value| {
+            cpu.test_bits(value);
+            value & !cpu.a
+        });
+    }
+
+    fn tsb(&mut self, operand: Operand) {
+        self.read_modify_write(operand.address, |cpu, value| {
+            cpu.test_bits(value);
+            value | cpu.a
+        });
+    }
+
+    fn wai(&mut self, _: Operand) {
+        self.read(self.pc);
+        self.waiting = true;
+    }
+
     // Addressing modes
     fn imm(&mut self) -> Operand {
-        self.cycle_count += 2;
-        let value = self.pc;
-        self.pc += 1;
-        Operand {
-            value: self.read(value),
-            address: 0,
-        }
+        let address = self.pc;
+        self.pc = self.pc.wrapping_add(1);
+        Operand { address }
     }
 
     fn abs(&mut self) -> Operand {
-        self.cycle_count += 4;
         let effective_address = self.read16(self.pc);
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
         Operand {
-            value: self.read(effective_address),
             address: effective_address,
         }
     }
 
-    fn absolute_indexed(&mut self, index: u8) -> (Operand, bool) {
-        let (low_byte, crossed) = self.read(self.pc).overflowing_add(index);
-        let high_byte = self.read(self.pc.wrapping_add(1));
-        let mut effective_address = to_u16(low_byte, high_byte);
-        if crossed {
-            effective_address = effective_address.wrapping_add(0x100);
-            self.cycle_count += 5
-        } else {
-            self.cycle_count += 4;
+    // Indexing only adds to the low byte at first. The high byte is fixed on
+    // the next cycle, after a read from the possibly wrong address, which
+    // reads can skip when no page was crossed but writes never do.
+    fn indexed(&mut self, base_address: u16, index: u8, write: bool) -> Operand {
+        let effective_address = base_address.wrapping_add(index as u16);
+        if write || effective_address & 0xFF00 != base_address & 0xFF00 {
+            // The 65C02 rereads the last instruction byte instead
+            if self.variant == Variant::Wdc65C02 {
+                self.read(self.pc.wrapping_sub(1));
+            } else {
+                self.read((base_address & 0xFF00) | (effective_address & 0x00FF));
+            }
+        }
+        Operand {
+            address: effective_address,
         }
-        self.pc += 2;
-        (
-            Operand {
-                value: self.read(effective_address),
-                address: effective_address,
-            },
-            crossed,
-        )
     }
 
-    fn abx(&mut self) -> Operand {
-        let (operand, _) = self.absolute_indexed(self.x);
-        operand
+    fn absolute_indexed(&mut self, index: u8, write: bool) -> Operand {
+        let base_address = self.read16(self.pc);
+        self.pc = self.pc.wrapping_add(2);
+        self.indexed(base_address, index, write)
     }
 
-    fn aby(&mut self) -> Operand {
-        let (operand, _) = self.absolute_indexed(self.y);
-        operand
+    fn abx(&mut self) -> Operand {
+        self.absolute_indexed(self.x, false)
     }
 
-    fn absolute_indexed_w(&mut self, index: u8) -> Operand {
-        let (operand, crossed) = self.absolute_indexed(index);
-        if !crossed {
-            self.cycle_count += 1
-        };
-        operand
+    fn aby(&mut self) -> Operand {
+        self.absolute_indexed(self.y, false)
     }
 
     fn abx_w(&mut self) -> Operand {
-        self.absolute_indexed_w(self.x)
+        self.absolute_indexed(self.x, true)
     }
 
     fn aby_w(&mut self) -> Operand {
-        self.absolute_indexed_w(self.y)
+        self.absolute_indexed(self.y, true)
+    }
+
+    // Shifts and rotates, which the 65C02 only slows down on a page cross
+    fn abx_m(&mut self) -> Operand {
+        let write = self.variant != Variant::Wdc65C02;
+        self.absolute_indexed(self.x, write)
     }
 
     fn zpa(&mut self) -> Operand {
-        self.cycle_count += 3;
         let effective_address = self.read(self.pc) as u16;
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         Operand {
-            value: self.read(effective_address),
             address: effective_address,
         }
     }
 
     fn zero_page_indexed(&mut self, index: u8) -> Operand {
-        self.cycle_count += 4;
-        let effective_address = (self.read(self.pc).wrapping_add(index)) as u16;
-        self.pc += 1;
+        let base_address = self.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        self.read(base_address as u16);
         Operand {
-            value: self.read(effective_address),
-            address: effective_address,
+            address: base_address.wrapping_add(index) as u16,
         }
     }
 
@@ -894,81 +1967,84 @@ impl Nise6502 {
         self.zero_page_indexed(self.y)
     }
 
+    // The NMOS 6502 never carries into the high byte of the pointer. The
+    // 65C02 fixes that at the cost of an extra cycle.
     fn ind(&mut self) -> Operand {
-        self.cycle_count += 6;
         let pointer_address = self.read16(self.pc);
+        self.pc = self.pc.wrapping_add(2);
+        let high_address = if self.variant == Variant::Wdc65C02 {
+            self.read(self.pc.wrapping_sub(1));
+            pointer_address.wrapping_add(1)
+        } else {
+            to_u16(
+                ((pointer_address & 0xFF) as u8).wrapping_add(1),
+                (pointer_address >> 8) as u8,
+            )
+        };
         let low_byte = self.read(pointer_address);
-        let high_byte = self.read(to_u16(
-            ((pointer_address & 0xFF) as u8).wrapping_add(1),
-            (pointer_address >> 8) as u8,
-        ));
-        self.pc += 2;
+        let high_byte = self.read(high_address);
         Operand {
-            value: 0,
             address: to_u16(low_byte, high_byte),
         }
     }
 
+    fn iax(&mut self) -> Operand {
+        let base_address = self.read16(self.pc);
+        self.pc = self.pc.wrapping_add(2);
+        self.read(self.pc.wrapping_sub(1));
+        let pointer_address = base_address.wrapping_add(self.x as u16);
+        Operand {
+            address: self.read16(pointer_address),
+        }
+    }
+
+    fn izp(&mut self) -> Operand {
+        let pointer_address = self.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        Operand {
+            address: self.read16_zp(pointer_address),
+        }
+    }
+
     fn idx(&mut self) -> Operand {
-        self.cycle_count += 6;
         let pointer_address = self.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        self.read(pointer_address as u16);
         let indexed_address = pointer_address.wrapping_add(self.x);
-        let effective_address = self.read16(indexed_address as u16);
-        self.pc += 1;
         Operand {
-            value: self.read(effective_address),
-            address: effective_address,
+            address: self.read16_zp(indexed_address),
         }
     }
 
+    fn indirect_indexed(&mut self, write: bool) -> Operand {
+        let pointer_address = self.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        let base_address = self.read16_zp(pointer_address);
+        self.indexed(base_address, self.y, write)
+    }
+
     fn idy(&mut self) -> Operand {
-        let (operand, _) = self.indirected_indexed();
-        operand
-    }
-
-    fn indirected_indexed(&mut self) -> (Operand, bool) {
-        let pointer_address = self.read(self.pc) as u16;
-        self.pc += 1;
-        let (low_byte, crossed) = (self.read(pointer_address)).overflowing_add(self.y);
-        let high_byte = self.read(pointer_address.wrapping_add(1)) & 0xFF;
-        let mut effective_address = to_u16(low_byte, high_byte);
-        if crossed {
-            effective_address = effective_address.wrapping_add(0x100);
-            self.cycle_count += 6
-        } else {
-            self.cycle_count += 5;
-        }
-        (
-            Operand {
-                value: self.read(effective_address),
-                address: effective_address,
-            },
-            crossed,
-        )
+        self.indirect_indexed(false)
     }
 
     fn idy_w(&mut self) -> Operand {
-        let (operand, crossed) = self.indirected_indexed();
-        if !crossed {
-            self.cycle_count += 1;
-        }
-        operand
+        self.indirect_indexed(true)
     }
 
     fn rel(&mut self) -> Operand {
         let address = self.pc;
-        self.cycle_count += 2;
-        self.pc += 1;
-        Operand {
-            value: self.read(address),
-            address,
-        }
+        self.pc = self.pc.wrapping_add(1);
+        Operand { address }
     }
 
+    // Single byte instructions still read the byte after the opcode
     fn imp(&mut self) -> Operand {
-        Operand {
-            value: 0,
-            address: 0,
-        }
+        self.read(self.pc);
+        Operand { address: self.pc }
+    }
+
+    // Except for the single cycle NOPs of the 65C02
+    fn none(&mut self) -> Operand {
+        Operand { address: self.pc }
     }
 }
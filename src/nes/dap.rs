@@ -0,0 +1,841 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, TryRecvError};
+
+use serde_json::{json, Value};
+
+use crate::common::bus::Bus;
+use crate::nes::bus::NiseBus;
+use crate::nes::cpu::{Flag, Nise6502};
+use crate::nes::dbginfo::{DebugInfo, SourceLine};
+use crate::nes::debugger::{Debugger, StopReason};
+use crate::nes::disasm::decode_bus;
+use crate::nes::rom::Rom;
+
+// How many cycles `continue` runs between checks for new requests
+const CONTINUE_CHUNK: u64 = 10_000;
+
+// How long a step may take: ten seconds of NTSC time
+const STEP_BUDGET: u64 = 1_789_773 * 10;
+
+const BRK: u8 = 0x00;
+
+// There is a single thread
+const THREAD: u64 = 1;
+
+// Variable references for each scope, and for the flags inside P
+const REGISTERS: u64 = 1;
+const FLAGS: u64 = 2;
+const PPU: u64 = 3;
+
+const FLAG_NAMES: [(&str, Flag); 7] = [
+    ("N", Flag::Negative),
+    ("V", Flag::Overflow),
+    ("B", Flag::Break),
+    ("D", Flag::Decimal),
+    ("I", Flag::InterruptDisable),
+    ("Z", Flag::Zero),
+    ("C", Flag::Carry),
+];
+
+/// A Debug Adapter Protocol server, for debugging a ROM from an editor.
+///
+/// The `launch` request takes the path of the ROM as `program`, and
+/// optionally the path of its ld65 debug file as `debugInfo`, which
+/// otherwise defaults to the ROM's path with a `.dbg` extension. With debug
+/// information, breakpoints can be set on source lines and steps go by
+/// source line. Without it, everything works on instructions.
+///
+/// Registers, flags and PPU registers are shown as variables, memory
+/// references are CPU addresses like `0xC000`, and the exception filters
+/// stop on BRK and on NMI or IRQ entry.
+#[derive(Default)]
+pub struct DapServer {
+    session: Option<Session>,
+    seq: u64,
+    // Events to send after the response to the current request
+    events: Vec<Value>,
+    running: bool,
+    // Addresses of breakpoints by source path, and instruction breakpoints
+    source_breakpoints: BTreeMap<String, Vec<u16>>,
+    instruction_breakpoints: Vec<u16>,
+}
+
+struct Session {
+    debugger: Debugger<NiseBus>,
+    debug_info: Option<DebugInfo>,
+    // Where relative paths in the debug information start from
+    source_root: PathBuf,
+    stop_on_entry: bool,
+}
+
+impl Session {
+    fn cpu(&self) -> &Nise6502<NiseBus> {
+        self.debugger.cpu()
+    }
+
+    fn line(&self, address: u16) -> Option<SourceLine> {
+        self.debug_info.as_ref()?.line(address)
+    }
+
+    fn source(&self, line: SourceLine) -> Value {
+        let name = &self.debug_info.as_ref().unwrap().files()[line.file];
+        let path = self.source_root.join(name);
+        json!({ "name": name, "path": path })
+    }
+
+    // A number, in hex with `$` or `0x` or in decimal, or a symbol
+    fn address(&self, text: &str) -> Option<u16> {
+        let text = text.trim();
+        let number = if let Some(hex) = text.strip_prefix('$').or(text.strip_prefix("0x")) {
+            u16::from_str_radix(hex, 16).ok()
+        } else {
+            text.parse().ok()
+        };
+        let cpu = self.cpu();
+        number
+            .or_else(|| cpu.symbols().resolve(cpu.bus(), text))
+            .or_else(|| self.constant(text))
+    }
+
+    // Constants are not labels, but can be addresses all the same
+    fn constant(&self, name: &str) -> Option<u16> {
+        let symbols = self.debug_info.as_ref()?.symbols();
+        let symbol = symbols.iter().find(|symbol| symbol.name == name)?;
+        Some(symbol.value)
+    }
+
+    fn describe(&self, address: u16) -> String {
+        self.cpu()
+            .describe(address)
+            .unwrap_or_else(|| format!("${:04X}", address))
+    }
+}
+
+impl DapServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The debugger of the launched program, if there is one.
+    pub fn debugger(&mut self) -> Option<&mut Debugger<NiseBus>> {
+        self.session.as_mut().map(|session| &mut session.debugger)
+    }
+
+    /// Waits for a client on `address` and serves it until it disconnects.
+    pub fn listen(&mut self, address: impl ToSocketAddrs) -> io::Result<()> {
+        let listener = TcpListener::bind(address)?;
+        self.accept(&listener)
+    }
+
+    /// Serves the next client of `listener` until it disconnects.
+    pub fn accept(&mut self, listener: &TcpListener) -> io::Result<()> {
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        self.serve(stream.try_clone()?, stream)
+    }
+
+    /// Serves requests from `input` until the client disconnects, e.g. over
+    /// stdin and stdout.
+    pub fn serve(
+        &mut self,
+        input: impl Read + Send + 'static,
+        mut output: impl Write,
+    ) -> io::Result<()> {
+        // Reading blocks, but a running CPU has to notice requests to pause
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut input = BufReader::new(input);
+            while let Ok(Some(message)) = receive(&mut input) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        loop {
+            let message = if self.running {
+                match receiver.try_recv() {
+                    Ok(message) => message,
+                    Err(TryRecvError::Empty) => {
+                        self.resume();
+                        self.flush(&mut output)?;
+                        continue;
+                    }
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            } else {
+                match receiver.recv() {
+                    Ok(message) => message,
+                    Err(_) => break,
+                }
+            };
+            if message["type"] != "request" {
+                continue;
+            }
+            let command = message["command"].as_str().unwrap_or_default();
+            let arguments = &message["arguments"];
+            let result = self.request(command, arguments);
+            let mut response = json!({
+                "type": "response",
+                "request_seq": message["seq"],
+                "command": command,
+                "success": result.is_ok(),
+            });
+            match result {
+                Ok(body) => response["body"] = body,
+                Err(error) => response["message"] = error.into(),
+            }
+            self.send(&mut output, response)?;
+            self.flush(&mut output)?;
+            if command == "disconnect" {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, output: &mut impl Write, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = self.seq.into();
+        let text = message.to_string();
+        write!(output, "Content-Length: {}\r\n\r\n{}", text.len(), text)?;
+        output.flush()
+    }
+
+    // Sends the events queued by the last request or run
+    fn flush(&mut self, output: &mut impl Write) -> io::Result<()> {
+        for event in std::mem::take(&mut self.events) {
+            self.send(output, event)?;
+        }
+        Ok(())
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.events
+            .push(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn stopped(&mut self, reason: &str, description: Option<String>) {
+        self.running = false;
+        self.event(
+            "stopped",
+            json!({
+                "reason": reason,
+                "description": description,
+                "threadId": THREAD,
+                "allThreadsStopped": true,
+            }),
+        );
+    }
+
+    fn stopped_by(&mut self, reason: StopReason) {
+        match reason {
+            StopReason::Breakpoint(_) => self.stopped("breakpoint", None),
+            StopReason::Opcode(_) => self.stopped("exception", Some("BRK".to_string())),
+            StopReason::Interrupt(interrupt) => {
+                self.stopped("exception", Some(format!("{:?}", interrupt).to_uppercase()))
+            }
+            StopReason::HistoryStart => {
+                self.stopped("step", Some("start of the history kept".to_string()))
+            }
+            StopReason::Fault(status) => self.stopped("exception", Some(status.to_string())),
+            _ => self.stopped("step", None),
+        }
+    }
+
+    fn session(&mut self) -> Result<&mut Session, String> {
+        self.session
+            .as_mut()
+            .ok_or_else(|| "no program has been launched".to_string())
+    }
+
+    // Runs the CPU for a while after a `continue`
+    fn resume(&mut self) {
+        let Some(session) = self.session.as_mut() else {
+            self.running = false;
+            return;
+        };
+        match session.debugger.run(CONTINUE_CHUNK) {
+            StopReason::CycleLimit => {}
+            reason => self.stopped_by(reason),
+        }
+    }
+
+    fn request(&mut self, command: &str, arguments: &Value) -> Result<Value, String> {
+        match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsEvaluateForHovers": true,
+                "supportsSetVariable": true,
+                "supportsReadMemoryRequest": true,
+                "supportsWriteMemoryRequest": true,
+                "supportsDisassembleRequest": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsSteppingGranularity": true,
+                "supportsTerminateRequest": true,
+                "supportsStepBack": true,
+                "exceptionBreakpointFilters": [
+                    { "filter": "brk", "label": "BRK" },
+                    { "filter": "interrupt", "label": "NMI and IRQ" },
+                ],
+            })),
+            "launch" => self.launch(arguments),
+            "setBreakpoints" => self.set_breakpoints(arguments),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(arguments),
+            "setExceptionBreakpoints" => {
+                let filters = arguments["filters"].as_array().cloned().unwrap_or_default();
+                let debugger = &mut self.session()?.debugger;
+                debugger.set_break_on_opcode(BRK, filters.contains(&json!("brk")));
+                debugger.set_break_on_interrupt(filters.contains(&json!("interrupt")));
+                Ok(json!({}))
+            }
+            "configurationDone" => {
+                if self.session()?.stop_on_entry {
+                    self.stopped("entry", None);
+                } else {
+                    self.running = true;
+                }
+                Ok(json!({}))
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD, "name": "6502" }] })),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(json!({
+                "scopes": [
+                    { "name": "Registers", "variablesReference": REGISTERS, "expensive": false },
+                    { "name": "PPU", "variablesReference": PPU, "expensive": false },
+                ],
+            })),
+            "variables" => self.variables(arguments),
+            "setVariable" => self.set_variable(arguments),
+            "evaluate" => self.evaluate(arguments),
+            "readMemory" => self.read_memory(arguments),
+            "writeMemory" => self.write_memory(arguments),
+            "disassemble" => self.disassemble(arguments),
+            "continue" => {
+                self.session()?;
+                self.running = true;
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "pause" => {
+                self.session()?;
+                self.stopped("pause", None);
+                Ok(json!({}))
+            }
+            "next" | "stepIn" | "stepOut" => {
+                let by_instruction = arguments["granularity"] == "instruction";
+                let session = self.session()?;
+                let reason = match command {
+                    "stepOut" => session.debugger.step_out(STEP_BUDGET),
+                    _ => step(session, command == "stepIn", by_instruction),
+                };
+                self.stopped_by(reason);
+                Ok(json!({}))
+            }
+            "stepBack" | "reverseContinue" => {
+                let debugger = &mut self.session()?.debugger;
+                let reason = match command {
+                    "stepBack" => debugger.step_back(),
+                    _ => debugger.reverse_continue(),
+                };
+                self.stopped_by(reason);
+                Ok(json!({}))
+            }
+            "terminate" => {
+                self.running = false;
+                self.event("terminated", json!({}));
+                Ok(json!({}))
+            }
+            "disconnect" => {
+                self.running = false;
+                Ok(json!({}))
+            }
+            _ => Err(format!("unsupported request `{}`", command)),
+        }
+    }
+
+    fn launch(&mut self, arguments: &Value) -> Result<Value, String> {
+        let program = arguments["program"]
+            .as_str()
+            .ok_or("`program` must be the path of a ROM")?;
+        let data = std::fs::read(program).map_err(|error| format!("{}: {}", program, error))?;
+        let rom = Rom::new(&data).ok_or_else(|| format!("{}: not an iNES ROM", program))?;
+        let debug_path = match arguments["debugInfo"].as_str() {
+            Some(path) => Some(PathBuf::from(path)),
+            None => Some(Path::new(program).with_extension("dbg")).filter(|path| path.exists()),
+        };
+        let debug_info = debug_path.as_ref().map(DebugInfo::load).transpose()?;
+        let source_root = debug_path
+            .as_ref()
+            .and_then(|path| path.parent())
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+
+        let mut cpu = Nise6502::new(NiseBus::new(rom));
+        cpu.set_cycle_accurate(true);
+        if let Some(info) = &debug_info {
+            cpu.symbols_mut().add_debug_info(info);
+        }
+        cpu.reset();
+        self.session = Some(Session {
+            debugger: Debugger::new(cpu),
+            debug_info,
+            source_root,
+            stop_on_entry: arguments["stopOnEntry"].as_bool().unwrap_or(false),
+        });
+        self.source_breakpoints.clear();
+        self.instruction_breakpoints.clear();
+        // Breakpoints can only be resolved once the debug information is in
+        self.event("initialized", json!({}));
+        Ok(json!({}))
+    }
+
+    // Every kind of breakpoint ends up as an execute breakpoint
+    fn sync_breakpoints(&mut self) {
+        let Some(session) = self.session.as_mut() else {
+            return;
+        };
+        let debugger = &mut session.debugger;
+        for address in debugger.breakpoints().collect::<Vec<_>>() {
+            debugger.remove_breakpoint(address);
+        }
+        let sources = self.source_breakpoints.values().flatten();
+        for address in sources.chain(&self.instruction_breakpoints) {
+            debugger.add_breakpoint(*address);
+        }
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Value) -> Result<Value, String> {
+        let path = arguments["source"]["path"]
+            .as_str()
+            .ok_or("`source` needs a `path`")?;
+        let session = self.session()?;
+        let file = session
+            .debug_info
+            .as_ref()
+            .and_then(|info| info.file(Path::new(path)));
+        let mut addresses = vec![];
+        let mut breakpoints = vec![];
+        for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+            let line = breakpoint["line"].as_u64().unwrap_or(0) as u32;
+            let resolved = file.and_then(|file| {
+                let info = session.debug_info.as_ref()?;
+                info.addresses(SourceLine { file, line })
+            });
+            breakpoints.push(match resolved {
+                Some((line, found)) => {
+                    addresses.extend_from_slice(found);
+                    json!({ "verified": true, "line": line.line })
+                }
+                None => json!({
+                    "verified": false,
+                    "line": line,
+                    "message": "no code was assembled from this line",
+                }),
+            });
+        }
+        self.source_breakpoints.insert(path.to_string(), addresses);
+        self.sync_breakpoints();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn set_instruction_breakpoints(&mut self, arguments: &Value) -> Result<Value, String> {
+        let session = self.session()?;
+        let mut addresses = vec![];
+        let mut breakpoints = vec![];
+        for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+            let reference = breakpoint["instructionReference"].as_str().unwrap_or("");
+            let offset = breakpoint["offset"].as_i64().unwrap_or(0);
+            breakpoints.push(match session.address(reference) {
+                Some(address) => {
+                    let address = (address as i64 + offset) as u16;
+                    addresses.push(address);
+                    json!({ "verified": true, "instructionReference": reference })
+                }
+                None => json!({ "verified": false, "message": "not an address" }),
+            });
+        }
+        self.instruction_breakpoints = addresses;
+        self.sync_breakpoints();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn stack_trace(&mut self) -> Result<Value, String> {
+        let session = self.session()?;
+        let cpu = session.cpu();
+        // The frames that called in are shown at their call sites
+        let call_sites = cpu.call_stack().frames().iter().rev();
+        let addresses = std::iter::once(cpu.pc()).chain(call_sites.map(|frame| frame.call_site));
+        let frames = addresses
+            .enumerate()
+            .map(|(index, address)| {
+                let mut frame = json!({
+                    "id": index + 1,
+                    "name": session.describe(address),
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": format!("0x{:04X}", address),
+                });
+                if let Some(line) = session.line(address) {
+                    frame["line"] = line.line.into();
+                    frame["column"] = 1.into();
+                    frame["source"] = session.source(line);
+                }
+                frame
+            })
+            .collect::<Vec<_>>();
+        Ok(json!({ "totalFrames": frames.len(), "stackFrames": frames }))
+    }
+
+    fn variables(&mut self, arguments: &Value) -> Result<Value, String> {
+        let session = self.session()?;
+        let cpu = session.cpu();
+        let byte = |name: &str, value: u8| json!({ "name": name, "value": format!("${:02X}", value), "variablesReference": 0 });
+        let variables = match arguments["variablesReference"].as_u64() {
+            Some(REGISTERS) => vec![
+                byte("A", cpu.a()),
+                byte("X", cpu.x()),
+                byte("Y", cpu.y()),
+                json!({
+                    "name": "P",
+                    "value": format!("${:02X}", cpu.p()),
+                    "variablesReference": FLAGS,
+                }),
+                byte("SP", cpu.s()),
+                json!({
+                    "name": "PC",
+                    "value": format!("${:04X}", cpu.pc()),
+                    "memoryReference": format!("0x{:04X}", cpu.pc()),
+                    "variablesReference": 0,
+                }),
+                json!({
+                    "name": "Cycles",
+                    "value": cpu.cycles().to_string(),
+                    "variablesReference": 0,
+                }),
+            ],
+            Some(FLAGS) => FLAG_NAMES
+                .iter()
+                .map(|(name, flag)| {
+                    json!({
+                        "name": name,
+                        "value": (cpu.flag(*flag) as u8).to_string(),
+                        "variablesReference": 0,
+                    })
+                })
+                .collect(),
+            Some(PPU) => {
+                let ppu = cpu.bus().ppu();
+                let number = |name: &str, value: usize| json!({ "name": name, "value": value.to_string(), "variablesReference": 0 });
+                vec![
+                    byte("PPUCTRL", ppu.ppuctrl),
+                    byte("PPUMASK", ppu.ppumask),
+                    byte("PPUSTATUS", ppu.ppustatus),
+                    byte("OAMADDR", ppu.oamaddr),
+                    json!({ "name": "V", "value": format!("${:04X}", ppu.v), "variablesReference": 0 }),
+                    json!({ "name": "T", "value": format!("${:04X}", ppu.t), "variablesReference": 0 }),
+                    number("X", ppu.x as usize),
+                    number("W", ppu.w as usize),
+                    number("Scanline", ppu.scanline()),
+                    number("Dot", ppu.dot()),
+                ]
+            }
+            _ => vec![],
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn set_variable(&mut self, arguments: &Value) -> Result<Value, String> {
+        let name = arguments["name"].as_str().unwrap_or_default();
+        let text = arguments["value"].as_str().unwrap_or_default();
+        let session = self.session()?;
+        let value = session
+            .address(text)
+            .ok_or_else(|| format!("`{}` is not a number", text))?;
+        let cpu = session.debugger.cpu_mut();
+        match (arguments["variablesReference"].as_u64(), name) {
+            (Some(REGISTERS), "PC") => {
+                cpu.set_pc(value);
+                return Ok(json!({ "value": format!("${:04X}", value) }));
+            }
+            (Some(FLAGS), name) => {
+                let (_, flag) = FLAG_NAMES
+                    .iter()
+                    .find(|(flag, _)| *flag == name)
+                    .ok_or_else(|| format!("no flag `{}`", name))?;
+                cpu.set_flag(*flag, value != 0);
+                return Ok(json!({ "value": ((value != 0) as u8).to_string() }));
+            }
+            (Some(REGISTERS), "A") => cpu.set_a(value as u8),
+            (Some(REGISTERS), "X") => cpu.set_x(value as u8),
+            (Some(REGISTERS), "Y") => cpu.set_y(value as u8),
+            (Some(REGISTERS), "SP") => cpu.set_s(value as u8),
+            (Some(REGISTERS), "P") => cpu.set_p(value as u8),
+            _ => return Err(format!("`{}` cannot be changed", name)),
+        }
+        Ok(json!({ "value": format!("${:02X}", value as u8) }))
+    }
+
+    // Registers by name, or the byte at an address or symbol
+    fn evaluate(&mut self, arguments: &Value) -> Result<Value, String> {
+        let expression = arguments["expression"].as_str().unwrap_or_default().trim();
+        let session = self.session()?;
+        let cpu = session.cpu();
+        let register = match expression.to_uppercase().as_str() {
+            "A" => Some(cpu.a()),
+            "X" => Some(cpu.x()),
+            "Y" => Some(cpu.y()),
+            "P" => Some(cpu.p()),
+            "S" | "SP" => Some(cpu.s()),
+            "PC" => {
+                let pc = cpu.pc();
+                return Ok(json!({
+                    "result": format!("${:04X}", pc),
+                    "memoryReference": format!("0x{:04X}", pc),
+                    "variablesReference": 0,
+                }));
+            }
+            _ => None,
+        };
+        if let Some(value) = register {
+            return Ok(json!({ "result": format!("${:02X}", value), "variablesReference": 0 }));
+        }
+        let address = session
+            .address(expression)
+            .ok_or_else(|| format!("`{}` is not a register, symbol or address", expression))?;
+        Ok(json!({
+            "result": format!("${:04X}: ${:02X}", address, cpu.bus().peek(address)),
+            "memoryReference": format!("0x{:04X}", address),
+            "variablesReference": 0,
+        }))
+    }
+
+    fn read_memory(&mut self, arguments: &Value) -> Result<Value, String> {
+        let session = self.session()?;
+        let start = memory_reference(session, arguments)?;
+        let count = arguments["count"].as_u64().unwrap_or(0) as usize;
+        let bus = session.cpu().bus();
+        let bytes = (start..0x10000)
+            .take(count)
+            .map(|address| bus.peek(address as u16))
+            .collect::<Vec<_>>();
+        Ok(json!({
+            "address": format!("0x{:04X}", start),
+            "data": base64(&bytes),
+            "unreadableBytes": count - bytes.len(),
+        }))
+    }
+
+    fn write_memory(&mut self, arguments: &Value) -> Result<Value, String> {
+        let session = self.session()?;
+        let start = memory_reference(session, arguments)?;
+        let data = arguments["data"].as_str().unwrap_or_default();
+        let bytes = unbase64(data).ok_or("`data` is not base64")?;
+        let bus = session.debugger.cpu_mut().bus_mut();
+        let mut written = 0;
+        for (address, byte) in (start..0x10000).zip(bytes) {
+            bus.poke(address as u16, byte);
+            written += 1;
+        }
+        Ok(json!({ "bytesWritten": written }))
+    }
+
+    fn disassemble(&mut self, arguments: &Value) -> Result<Value, String> {
+        let session = self.session()?;
+        // $10000 is the end of the address space
+        let start = memory_reference(session, arguments)?;
+        let skip = arguments["instructionOffset"].as_i64().unwrap_or(0);
+        let count = arguments["instructionCount"].as_u64().unwrap_or(0) as usize;
+        let resolve = arguments["resolveSymbols"].as_bool().unwrap_or(false);
+        let variant = session.cpu().variant();
+        let bus = session.cpu().bus();
+        let length = |address: u32| decode_bus(variant, bus, address as u16).bytes().len() as u32;
+
+        // Instructions have no fixed size, so going backwards means finding
+        // a start that decodes forwards into `start`. Offsets past either end
+        // of the address space give invalid instructions, as the
+        // specification asks, and any more than $10000 of them reach past it
+        // anyway.
+        let mut address = start;
+        let mut padding = 0;
+        if skip < 0 {
+            let back = skip.unsigned_abs().min(0x10001) as u32;
+            let starts = (0..3)
+                .map(|slack| start.saturating_sub(back * 3 - slack))
+                .find_map(|from| {
+                    let mut starts = vec![];
+                    let mut address = from;
+                    while address < start {
+                        starts.push(address);
+                        address += length(address);
+                    }
+                    let enough = starts.len() as u32 >= back || from == 0;
+                    (address == start && enough).then_some(starts)
+                })
+                .unwrap_or_else(|| (start.saturating_sub(back)..start).collect());
+            let available = starts.len() as u32;
+            padding = back.saturating_sub(available);
+            address = match back.min(available) {
+                0 => start,
+                back => starts[(available - back) as usize],
+            };
+        } else {
+            for _ in 0..skip.min(0x10001) {
+                if address > 0xFFFF {
+                    break;
+                }
+                address += length(address);
+            }
+        }
+
+        let mut instructions = vec![];
+        let mut previous_line = None;
+        for _ in 0..count {
+            if padding > 0 || address > 0xFFFF {
+                padding = padding.saturating_sub(1);
+                instructions.push(json!({
+                    "address": format!("0x{:04X}", address.min(0xFFFF)),
+                    "instruction": "",
+                    "presentationHint": "invalid",
+                }));
+                continue;
+            }
+            let instruction = decode_bus(variant, bus, address as u16);
+            let bytes = instruction
+                .bytes()
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect::<Vec<_>>();
+            let mut entry = json!({
+                "address": format!("0x{:04X}", address),
+                "instructionBytes": bytes.join(" "),
+                "instruction": instruction.to_string_with(|address| {
+                    let symbols = session.cpu().symbols();
+                    resolve.then(|| symbols.label(bus, address))?.map(str::to_string)
+                }),
+            });
+            if let Some(label) = session.cpu().symbols().label(bus, address as u16) {
+                entry["symbol"] = label.into();
+            }
+            let line = session.line(address as u16);
+            if let Some(line) = line.filter(|_| line != previous_line) {
+                entry["location"] = session.source(line);
+                entry["line"] = line.line.into();
+            }
+            previous_line = line;
+            instructions.push(entry);
+            address += instruction.bytes().len() as u32;
+        }
+        Ok(json!({ "instructions": instructions }))
+    }
+}
+
+// Steps one instruction, or one source line when there is debug
+// information for it. A line ends at a different line, or on looping back to
+// its start.
+fn step(session: &mut Session, into: bool, by_instruction: bool) -> StopReason {
+    let debugger = &mut session.debugger;
+    let start = debugger.cpu().pc();
+    let line = session
+        .debug_info
+        .as_ref()
+        .and_then(|info| info.line(start));
+    let limit = debugger.cpu().cycles() + STEP_BUDGET;
+    loop {
+        let reason = if into {
+            debugger.step_into()
+        } else {
+            let budget = limit.saturating_sub(debugger.cpu().cycles());
+            debugger.step_over(budget)
+        };
+        if reason != StopReason::Step || by_instruction || line.is_none() {
+            return reason;
+        }
+        let pc = debugger.cpu().pc();
+        let here = session.debug_info.as_ref().and_then(|info| info.line(pc));
+        if (here.is_some() && here != line) || pc == start || debugger.cpu().cycles() >= limit {
+            return reason;
+        }
+    }
+}
+
+// `memoryReference` plus `offset`, as an address that may be just past the
+// end of memory
+fn memory_reference(session: &Session, arguments: &Value) -> Result<u32, String> {
+    let reference = arguments["memoryReference"].as_str().unwrap_or_default();
+    let address = session
+        .address(reference)
+        .ok_or_else(|| format!("`{}` is not an address", reference))?;
+    let offset = arguments["offset"].as_i64().unwrap_or(0);
+    Ok((address as i64 + offset).clamp(0, 0x10000) as u32)
+}
+
+// Reads one message, framed by a `Content-Length` header. Returns None once
+// the input has ended.
+fn receive(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = length
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64(bytes: &[u8]) -> String {
+    let mut text = String::new();
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (index, byte)| {
+            group | (*byte as u32) << (16 - 8 * index)
+        });
+        for index in 0..4 {
+            if index <= chunk.len() {
+                text.push(BASE64[(group >> (18 - 6 * index) & 0x3F) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+fn unbase64(text: &str) -> Option<Vec<u8>> {
+    let digits = text
+        .trim_end_matches('=')
+        .bytes()
+        .map(|digit| {
+            BASE64
+                .iter()
+                .position(|c| *c == digit)
+                .map(|value| value as u32)
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let mut bytes = vec![];
+    for chunk in digits.chunks(4) {
+        let group = chunk
+            .iter()
+            .enumerate()
+            .fold(0, |group, (index, digit)| group | digit << (18 - 6 * index));
+        for index in 0..chunk.len().saturating_sub(1) {
+            bytes.push((group >> (16 - 8 * index)) as u8);
+        }
+    }
+    Some(bytes)
+}
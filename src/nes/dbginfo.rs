@@ -0,0 +1,301 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+/// Debug information from ld65's `--dbgfile` option, which maps addresses to
+/// the assembly source lines they were built from, and names to addresses.
+///
+/// Only the CPU address space is modelled, so in a ROM with several banks at
+/// the same addresses the last segment listed wins.
+#[derive(Clone, Debug, Default)]
+pub struct DebugInfo {
+    files: Vec<String>,
+    lines: Vec<SourceLine>,
+    // An index into `lines` for every address, if any line covers it
+    by_address: Vec<Option<u32>>,
+    // Where the code of each line starts, for breakpoints
+    by_line: BTreeMap<SourceLine, Vec<u16>>,
+    symbols: Vec<Symbol>,
+}
+
+/// A line in one of the files of `DebugInfo::files`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SourceLine {
+    pub file: usize,
+    pub line: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    /// The name, qualified by its scopes as in `Main::loop`, or by its
+    /// parent as in `Main@loop` for a cheap local.
+    pub name: String,
+    pub value: u16,
+    /// Whether this is a label, rather than a constant made with `=`.
+    pub label: bool,
+    /// Where a label in ROM is in PRG ROM, which tells apart labels in
+    /// banks that share addresses.
+    pub prg_offset: Option<u32>,
+}
+
+// One line of the file, as `kind key=value,...`
+struct Record<'a> {
+    kind: &'a str,
+    fields: HashMap<&'a str, &'a str>,
+}
+
+impl<'a> Record<'a> {
+    fn parse(text: &'a str) -> Option<Self> {
+        let (kind, rest) = text.split_once(char::is_whitespace)?;
+        let mut fields = HashMap::new();
+        let mut rest = rest.trim();
+        while !rest.is_empty() {
+            let (key, value) = rest.split_once('=')?;
+            // Quoted values may contain commas
+            let end = match value.strip_prefix('"') {
+                Some(quoted) => quoted.find('"')? + 2,
+                None => value.find(',').unwrap_or(value.len()),
+            };
+            fields.insert(key, value[..end].trim_matches('"'));
+            rest = value[end..].strip_prefix(',').unwrap_or(&value[end..]);
+        }
+        Some(Record { kind, fields })
+    }
+
+    fn text(&self, key: &str) -> Option<&'a str> {
+        self.fields.get(key).copied()
+    }
+
+    fn number(&self, key: &str) -> Option<u32> {
+        let text = self.text(key)?;
+        match text.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16).ok(),
+            None => text.parse().ok(),
+        }
+    }
+
+    // A list of ids joined by `+`
+    fn ids(&self, key: &str) -> Vec<u32> {
+        self.text(key)
+            .map(|text| text.split('+').filter_map(|id| id.parse().ok()).collect())
+            .unwrap_or_default()
+    }
+}
+
+struct Span {
+    segment: u32,
+    start: u32,
+    size: u32,
+}
+
+struct Segment {
+    start: u32,
+    size: u32,
+    // Where the segment starts in PRG ROM, if it is in ROM
+    prg_offset: Option<u32>,
+}
+
+struct Scope {
+    name: String,
+    parent: Option<u32>,
+}
+
+impl DebugInfo {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|error| format!("{}: {}", path.display(), error))?;
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut records = vec![];
+        for (number, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record = Record::parse(line.trim())
+                .ok_or_else(|| format!("line {}: malformed record", number + 1))?;
+            if record.kind == "version" && record.number("major") != Some(2) {
+                return Err(format!("line {}: unsupported version", number + 1));
+            }
+            records.push(record);
+        }
+
+        // Records refer to each other by id, in any order
+        let id = |record: &Record| record.number("id");
+        let mut files = HashMap::new();
+        let mut segments = HashMap::new();
+        let mut spans = HashMap::new();
+        let mut scopes = HashMap::new();
+        for record in &records {
+            match (record.kind, id(record)) {
+                ("file", Some(id)) => {
+                    files.insert(id, record.text("name").unwrap_or_default().to_string());
+                }
+                ("seg", Some(id)) => {
+                    // The output file offset counts the iNES header too
+                    let in_rom = record.text("type") == Some("ro")
+                        && record
+                            .text("oname")
+                            .is_some_and(|name| name.to_lowercase().ends_with(".nes"));
+                    let segment = Segment {
+                        start: record.number("start").unwrap_or(0),
+                        size: record.number("size").unwrap_or(0),
+                        prg_offset: record
+                            .number("ooffs")
+                            .filter(|_| in_rom)
+                            .and_then(|offset| offset.checked_sub(16)),
+                    };
+                    segments.insert(id, segment);
+                }
+                ("span", Some(id)) => {
+                    let span = Span {
+                        segment: record.number("seg").unwrap_or(0),
+                        start: record.number("start").unwrap_or(0),
+                        size: record.number("size").unwrap_or(0),
+                    };
+                    spans.insert(id, span);
+                }
+                ("scope", Some(id)) => {
+                    let scope = Scope {
+                        name: record.text("name").unwrap_or_default().to_string(),
+                        parent: record.number("parent"),
+                    };
+                    scopes.insert(id, scope);
+                }
+                _ => {}
+            }
+        }
+
+        let mut info = DebugInfo::default();
+        let mut file_ids = files.into_iter().collect::<Vec<_>>();
+        file_ids.sort();
+        let file_index = file_ids
+            .iter()
+            .enumerate()
+            .map(|(index, (id, _))| (*id, index))
+            .collect::<HashMap<_, _>>();
+        info.files = file_ids.into_iter().map(|(_, name)| name).collect();
+
+        // Lines from the source itself beat lines inside macro definitions,
+        // and the narrowest line covering an address beats wider ones
+        let mut ranges = vec![];
+        for record in records.iter().filter(|record| record.kind == "line") {
+            let (Some(file), Some(line)) = (
+                record.number("file").and_then(|file| file_index.get(&file)),
+                record.number("line"),
+            ) else {
+                continue;
+            };
+            let line = SourceLine { file: *file, line };
+            let from_macro = record.number("type") == Some(2);
+            for span in record.ids("span") {
+                let Some(span) = spans.get(&span) else {
+                    continue;
+                };
+                let start = segments
+                    .get(&span.segment)
+                    .map_or(0, |segment| segment.start)
+                    + span.start;
+                if span.size == 0 || start > 0xFFFF {
+                    continue;
+                }
+                let end = (start + span.size - 1).min(0xFFFF);
+                info.by_line.entry(line).or_default().push(start as u16);
+                ranges.push((from_macro, span.size, start, end, line));
+            }
+        }
+        ranges.sort_by_key(|&(from_macro, size, ..)| (!from_macro, std::cmp::Reverse(size)));
+        info.by_address = vec![None; 0x10000];
+        for (_, _, start, end, line) in ranges {
+            let index = info.lines.len() as u32;
+            info.lines.push(line);
+            for address in start..=end {
+                info.by_address[address as usize] = Some(index);
+            }
+        }
+        for addresses in info.by_line.values_mut() {
+            addresses.sort();
+            addresses.dedup();
+        }
+
+        let symbols = records
+            .iter()
+            .filter(|record| record.kind == "sym")
+            .filter_map(|record| Some((id(record)?, record)))
+            .collect::<HashMap<_, _>>();
+        for record in symbols.values() {
+            let Some(value) = record.number("val").filter(|value| *value <= 0xFFFF) else {
+                continue;
+            };
+            let mut name = record.text("name").unwrap_or_default().to_string();
+            if let Some(parent) = record.number("parent").and_then(|id| symbols.get(&id)) {
+                name = format!("{}{}", parent.text("name").unwrap_or_default(), name);
+            }
+            let mut scope = record.number("scope").and_then(|id| scopes.get(&id));
+            while let Some(Scope {
+                name: outer,
+                parent,
+            }) = scope
+            {
+                if !outer.is_empty() {
+                    name = format!("{}::{}", outer, name);
+                }
+                scope = parent.and_then(|id| scopes.get(&id));
+            }
+            let prg_offset = record
+                .number("seg")
+                .and_then(|id| segments.get(&id))
+                .filter(|segment| (segment.start..segment.start + segment.size).contains(&value))
+                .and_then(|segment| Some(segment.prg_offset? + value - segment.start));
+            info.symbols.push(Symbol {
+                name,
+                value: value as u16,
+                label: record.text("type") == Some("lab"),
+                prg_offset,
+            });
+        }
+        info.symbols
+            .sort_by(|a, b| (a.value, &a.name).cmp(&(b.value, &b.name)));
+        Ok(info)
+    }
+
+    /// Source file names, as given to the assembler. A file's index is the
+    /// `file` of its `SourceLine`s.
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    /// Finds the source file at `path`, which may be given in full even if
+    /// the debug information only has a path relative to the project.
+    pub fn file(&self, path: &Path) -> Option<usize> {
+        self.files
+            .iter()
+            .position(|name| path.ends_with(name) || Path::new(name).ends_with(path))
+    }
+
+    /// The source line that the byte at `address` was assembled from.
+    pub fn line(&self, address: u16) -> Option<SourceLine> {
+        let index = (*self.by_address.get(address as usize)?)?;
+        Some(self.lines[index as usize])
+    }
+
+    /// The first line at or after `line` that produced code, and the
+    /// addresses where that code starts. There can be several, for a line in
+    /// a macro or an included file.
+    pub fn addresses(&self, line: SourceLine) -> Option<(SourceLine, &[u16])> {
+        let end = SourceLine {
+            file: line.file,
+            line: u32::MAX,
+        };
+        self.by_line
+            .range(line..=end)
+            .next()
+            .map(|(line, addresses)| (*line, &addresses[..]))
+    }
+
+    /// Every symbol, ordered by value.
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+}
@@ -0,0 +1,427 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::ops::RangeInclusive;
+
+use crate::common::bus::{AccessKind, Bus, BusAccess};
+use crate::nes::bus::NiseBus;
+use crate::nes::cpu::{Interrupt, Nise6502, Nise6502State, Snapshot, StepStatus};
+
+const JSR: u8 = 0x20;
+const RTI: u8 = 0x40;
+const RTS: u8 = 0x60;
+
+// How many instructions are kept for post-mortem dumps, unless the CPU was
+// already set up to keep some
+const HISTORY_LEN: usize = 256;
+
+// How often the machine is saved to run backwards from, in cycles, and how
+// many saves are kept: one a frame, for five seconds
+const SNAPSHOT_INTERVAL: u64 = 29_781;
+const SNAPSHOTS: usize = 300;
+
+/// Which accesses a watchpoint triggers on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl WatchKind {
+    pub fn matches(self, kind: AccessKind) -> bool {
+        matches!(
+            (self, kind),
+            (WatchKind::ReadWrite, _)
+                | (WatchKind::Read, AccessKind::Read)
+                | (WatchKind::Write, AccessKind::Write)
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub range: RangeInclusive<u16>,
+    pub kind: WatchKind,
+}
+
+/// Why execution stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// A step command completed.
+    Step,
+    /// `run_to` reached its address.
+    RunTo(u16),
+    /// PC reached an execute breakpoint. The instruction has not run yet.
+    Breakpoint(u16),
+    /// The instruction at PC has an opcode being watched. It has not run yet.
+    Opcode(u8),
+    /// The last instruction made this access to a watched address.
+    Watchpoint(BusAccess),
+    /// An interrupt was taken, and PC is at its handler.
+    Interrupt(Interrupt),
+    /// The cycle budget given to the command ran out.
+    CycleLimit,
+    /// Running backwards reached the oldest state kept.
+    HistoryStart,
+    /// The CPU jammed, or met an opcode it does not implement.
+    Fault(StepStatus),
+}
+
+/// Runs a CPU under the control of breakpoints and watchpoints.
+///
+/// Breakpoints are checked before each instruction, except the first one of
+/// a command, so that resuming from a breakpoint does not hit it again.
+/// Watchpoints and interrupts are checked after each instruction.
+///
+/// Going backwards restores a snapshot saved on the way forwards and replays
+/// from there. Changes made through the debugger, such as writes to memory,
+/// are not replayed, so going back past them undoes them.
+pub struct Debugger<B: Bus = NiseBus> {
+    cpu: Nise6502<B>,
+    breakpoints: BTreeSet<u16>,
+    watchpoints: Vec<Watchpoint>,
+    opcodes: BTreeSet<u8>,
+    break_on_interrupt: bool,
+    reverse: bool,
+    snapshots: VecDeque<Snapshot<B>>,
+}
+
+impl<B: Bus> Debugger<B> {
+    pub fn new(mut cpu: Nise6502<B>) -> Self {
+        cpu.set_record_accesses(true);
+        if cpu.history().capacity() == 0 {
+            cpu.set_history_len(HISTORY_LEN);
+        }
+        let mut debugger = Self {
+            cpu,
+            breakpoints: BTreeSet::new(),
+            watchpoints: vec![],
+            opcodes: BTreeSet::new(),
+            break_on_interrupt: false,
+            reverse: false,
+            snapshots: VecDeque::new(),
+        };
+        debugger.set_reverse(true);
+        debugger
+    }
+
+    pub fn cpu(&self) -> &Nise6502<B> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Nise6502<B> {
+        &mut self.cpu
+    }
+
+    pub fn into_cpu(mut self) -> Nise6502<B> {
+        self.cpu.set_record_accesses(false);
+        self.cpu
+    }
+
+    /// Returns false if there already was a breakpoint at `address`.
+    pub fn add_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.insert(address)
+    }
+
+    /// Returns false if there was no breakpoint at `address`.
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn add_watchpoint(&mut self, range: RangeInclusive<u16>, kind: WatchKind) {
+        self.watchpoints.push(Watchpoint { range, kind });
+    }
+
+    /// Removes the watchpoints that match `range` and `kind` exactly, and
+    /// returns whether there were any.
+    pub fn remove_watchpoint(&mut self, range: &RangeInclusive<u16>, kind: WatchKind) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints
+            .retain(|watchpoint| watchpoint.range != *range || watchpoint.kind != kind);
+        self.watchpoints.len() != count
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Stops before executing any instruction with this opcode.
+    pub fn set_break_on_opcode(&mut self, opcode: u8, enabled: bool) {
+        if enabled {
+            self.opcodes.insert(opcode);
+        } else {
+            self.opcodes.remove(&opcode);
+        }
+    }
+
+    /// Stops on entry to NMI and IRQ handlers. BRK is an instruction, and
+    /// can be caught with `set_break_on_opcode` instead.
+    pub fn set_break_on_interrupt(&mut self, enabled: bool) {
+        self.break_on_interrupt = enabled;
+    }
+
+    /// Saves the machine as it runs, so that it can be run backwards. On by
+    /// default. Returns false if the bus cannot save its state.
+    pub fn set_reverse(&mut self, enabled: bool) -> bool {
+        self.snapshots.clear();
+        self.reverse = false;
+        if enabled {
+            let Some(snapshot) = self.cpu.snapshot() else {
+                return false;
+            };
+            self.snapshots.push_back(snapshot);
+            self.reverse = true;
+        }
+        true
+    }
+
+    /// Removes every breakpoint and watchpoint.
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+        self.watchpoints.clear();
+        self.opcodes.clear();
+        self.break_on_interrupt = false;
+    }
+
+    /// Executes one instruction, or the entry into an interrupt handler.
+    pub fn step_into(&mut self) -> StopReason {
+        self.run_until(u64::MAX, |_, _| Some(StopReason::Step))
+    }
+
+    /// Like `step_into`, but runs a whole subroutine when the next
+    /// instruction is a JSR.
+    pub fn step_over(&mut self, max_cycles: u64) -> StopReason {
+        let pc = self.cpu.pc();
+        if self.cpu.bus().peek(pc) != JSR {
+            return self.step_into();
+        }
+        let return_address = pc.wrapping_add(3);
+        let stack = self.cpu.s();
+        // Recursive calls pass through the same address deeper in the stack
+        self.run_until(max_cycles, |cpu, _| {
+            (cpu.pc() == return_address && cpu.s() >= stack).then_some(StopReason::Step)
+        })
+    }
+
+    /// Runs until the current subroutine or interrupt handler returns.
+    pub fn step_out(&mut self, max_cycles: u64) -> StopReason {
+        let stack = self.cpu.s();
+        self.run_until(max_cycles, |_, executed| {
+            let returned = matches!(executed, Some((RTS | RTI, s)) if s >= stack);
+            returned.then_some(StopReason::Step)
+        })
+    }
+
+    pub fn run_to(&mut self, address: u16, max_cycles: u64) -> StopReason {
+        self.run_until(max_cycles, |cpu, _| {
+            (cpu.pc() == address).then_some(StopReason::RunTo(address))
+        })
+    }
+
+    /// Runs until a breakpoint, watchpoint or the cycle limit.
+    pub fn run(&mut self, max_cycles: u64) -> StopReason {
+        self.run_until(max_cycles, |_, _| None)
+    }
+
+    /// Goes back to before the last instruction or interrupt entry.
+    pub fn step_back(&mut self) -> StopReason {
+        let now = self.cpu.cycles();
+        if self.rewind_before(now).is_none() {
+            return StopReason::HistoryStart;
+        }
+        let mut last = None;
+        self.replay(now, |_, before| last = Some(before.cycles));
+        self.go_to(last.unwrap_or(now));
+        StopReason::Step
+    }
+
+    /// Runs backwards to the last stop that running forwards would have
+    /// made, or to the oldest state kept. Watchpoints stop after the
+    /// instruction that hit them, as they do going forwards.
+    pub fn reverse_continue(&mut self) -> StopReason {
+        let now = self.cpu.cycles();
+        let mut end = now;
+        while let Some(index) = self.rewind_before(end) {
+            let mut hit = None;
+            self.replay(end, |debugger, _| {
+                let cycles = debugger.cpu.cycles();
+                if cycles < now {
+                    if let Some(reason) = debugger.check_after().or(debugger.check_before()) {
+                        hit = Some((cycles, reason));
+                    }
+                }
+            });
+            if let Some((cycles, reason)) = hit {
+                self.go_to(cycles);
+                return reason;
+            }
+            end = self.snapshots[index].cycles();
+        }
+        if let Some(oldest) = self.snapshots.front() {
+            if oldest.cycles() <= now {
+                self.cpu.restore(oldest);
+            }
+        }
+        StopReason::HistoryStart
+    }
+
+    /// Finds the last write to `address` in the history kept, without
+    /// moving. Returns the registers before the instruction or interrupt
+    /// entry that made it, and the write.
+    pub fn last_write(&mut self, address: u16) -> Option<(Nise6502State, BusAccess)> {
+        let now = self.cpu.cycles();
+        let mut end = now;
+        let mut found = None;
+        while let Some(index) = self.rewind_before(end) {
+            self.replay(end, |debugger, before| {
+                let write =
+                    debugger.cpu.accesses().iter().rev().find(|access| {
+                        access.address == address && access.kind == AccessKind::Write
+                    });
+                if let Some(write) = write {
+                    found = Some((before, *write));
+                }
+            });
+            if found.is_some() {
+                break;
+            }
+            end = self.snapshots[index].cycles();
+        }
+        self.go_to(now);
+        found
+    }
+
+    // `done` sees the CPU after each step, and the opcode and stack pointer
+    // of the instruction it executed, if it was not an interrupt entry
+    fn run_until(
+        &mut self,
+        max_cycles: u64,
+        mut done: impl FnMut(&Nise6502<B>, Option<(u8, u8)>) -> Option<StopReason>,
+    ) -> StopReason {
+        let limit = self.cpu.cycles().saturating_add(max_cycles);
+        let mut first = true;
+        loop {
+            if !first {
+                if let Some(reason) = self.check_before() {
+                    return reason;
+                }
+            }
+            first = false;
+            if self.cpu.cycles() >= limit {
+                return StopReason::CycleLimit;
+            }
+
+            let opcode = self.cpu.bus().peek(self.cpu.pc());
+            let stack = self.cpu.s();
+            self.save();
+            let status = self.cpu.step();
+            if status != StepStatus::Running {
+                return StopReason::Fault(status);
+            }
+            if let Some(reason) = self.check_after() {
+                return reason;
+            }
+            let executed = match self.cpu.interrupt_serviced() {
+                Some(_) => None,
+                None => Some((opcode, stack)),
+            };
+            if let Some(reason) = done(&self.cpu, executed) {
+                return reason;
+            }
+        }
+    }
+
+    fn check_before(&self) -> Option<StopReason> {
+        let pc = self.cpu.pc();
+        if self.breakpoints.contains(&pc) {
+            return Some(StopReason::Breakpoint(pc));
+        }
+        let opcode = self.cpu.bus().peek(pc);
+        if self.opcodes.contains(&opcode) {
+            return Some(StopReason::Opcode(opcode));
+        }
+        None
+    }
+
+    fn check_after(&self) -> Option<StopReason> {
+        if let Some(interrupt) = self.cpu.interrupt_serviced() {
+            if self.break_on_interrupt {
+                return Some(StopReason::Interrupt(interrupt));
+            }
+        }
+        self.cpu
+            .accesses()
+            .iter()
+            .find(|access| {
+                self.watchpoints.iter().any(|watchpoint| {
+                    watchpoint.range.contains(&access.address)
+                        && watchpoint.kind.matches(access.kind)
+                })
+            })
+            .map(|access| StopReason::Watchpoint(*access))
+    }
+
+    // Saves the machine every so often while it runs forwards
+    fn save(&mut self) {
+        if !self.reverse {
+            return;
+        }
+        // Whatever was saved after now belongs to a future that was undone
+        let cycles = self.cpu.cycles();
+        while self
+            .snapshots
+            .back()
+            .is_some_and(|snapshot| snapshot.cycles() > cycles)
+        {
+            self.snapshots.pop_back();
+        }
+        if let Some(last) = self.snapshots.back() {
+            if cycles - last.cycles() < SNAPSHOT_INTERVAL {
+                return;
+            }
+        }
+        if let Some(snapshot) = self.cpu.snapshot() {
+            if self.snapshots.len() == SNAPSHOTS {
+                self.snapshots.pop_front();
+            }
+            self.snapshots.push_back(snapshot);
+        }
+    }
+
+    // Restores the last snapshot from before `cycles`, and returns its index
+    fn rewind_before(&mut self, cycles: u64) -> Option<usize> {
+        let index = self
+            .snapshots
+            .iter()
+            .rposition(|snapshot| snapshot.cycles() < cycles)?;
+        self.cpu.restore(&self.snapshots[index]);
+        Some(index)
+    }
+
+    // Goes to the moment `cycles` into the history kept, which has to be
+    // the start of a step
+    fn go_to(&mut self, cycles: u64) {
+        if self.rewind_before(cycles + 1).is_some() {
+            self.replay(cycles, |_, _| {});
+        }
+    }
+
+    // Steps up to `end`, showing `each` the debugger after every step and
+    // the registers before it. The tracer and profiler are set aside, as
+    // they have seen all of this before. Hooks are not, as they can change
+    // what the program does.
+    fn replay(&mut self, end: u64, mut each: impl FnMut(&Self, Nise6502State)) {
+        let tracer = self.cpu.set_tracer(None);
+        let profiler = self.cpu.stop_profiling();
+        while self.cpu.cycles() < end {
+            let before = self.cpu.state();
+            self.cpu.step();
+            each(self, before);
+        }
+        self.cpu.set_tracer(tracer);
+        self.cpu.set_profiler(profiler);
+    }
+}
@@ -0,0 +1,957 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use crate::common::bus::Bus;
+use crate::common::to_u16;
+use crate::nes::cpu::Variant;
+use AddressingMode::*;
+
+/// How an instruction finds its operand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressingMode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Relative,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    /// `($1234)`, only used by JMP
+    Indirect,
+    /// `($12,X)`
+    IndexedIndirect,
+    /// `($12),Y`
+    IndirectIndexed,
+    /// `($12)`, 65C02 only
+    ZeroPageIndirect,
+    /// `($1234,X)`, 65C02 JMP only
+    AbsoluteIndexedIndirect,
+    /// `$12,label`, the 65C02's BBR and BBS
+    ZeroPageRelative,
+}
+
+impl AddressingMode {
+    /// Number of bytes following the opcode.
+    pub fn operand_length(self) -> u8 {
+        match self {
+            Implied | Accumulator => 0,
+            Immediate | ZeroPage | ZeroPageX | ZeroPageY | Relative | IndexedIndirect
+            | IndirectIndexed | ZeroPageIndirect => 1,
+            Absolute
+            | AbsoluteX
+            | AbsoluteY
+            | Indirect
+            | AbsoluteIndexedIndirect
+            | ZeroPageRelative => 2,
+        }
+    }
+}
+
+/// An entry in the opcode table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Opcode {
+    pub mnemonic: &'static str,
+    pub mode: AddressingMode,
+    /// Cycles taken, not counting a taken branch, a page cross or the
+    /// 65C02's extra decimal mode cycle.
+    pub cycles: u8,
+    /// Takes an extra cycle when indexing crosses a page. For branches, the
+    /// penalty applies to the branch being taken.
+    pub page_penalty: bool,
+    /// Undocumented, and assembled by few tools.
+    pub unofficial: bool,
+}
+
+impl Opcode {
+    const fn page(mut self) -> Self {
+        self.page_penalty = true;
+        self
+    }
+
+    const fn unofficial(mut self) -> Self {
+        self.unofficial = true;
+        self
+    }
+}
+
+const fn op(mnemonic: &'static str, mode: AddressingMode, cycles: u8) -> Opcode {
+    Opcode {
+        mnemonic,
+        mode,
+        cycles,
+        page_penalty: false,
+        unofficial: false,
+    }
+}
+
+/// The opcode table of `variant`. The 2A03 decodes like any NMOS 6502.
+pub fn opcodes(variant: Variant) -> &'static [Opcode; 256] {
+    match variant {
+        Variant::Ricoh2A03 | Variant::Nmos6502 => &NMOS_OPCODES,
+        Variant::Wdc65C02 => &CMOS_OPCODES,
+    }
+}
+
+/// A decoded instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    pub address: u16,
+    pub opcode: u8,
+    pub mnemonic: &'static str,
+    pub mode: AddressingMode,
+    /// The bytes after the opcode, of which only the first `length - 1` are
+    /// meaningful.
+    pub operand_bytes: [u8; 2],
+    pub length: u8,
+    pub cycles: u8,
+    pub page_penalty: bool,
+    pub unofficial: bool,
+}
+
+impl Instruction {
+    fn new(variant: Variant, address: u16, opcode: u8, operand_bytes: [u8; 2]) -> Self {
+        let entry = opcodes(variant)[opcode as usize];
+        Instruction {
+            address,
+            opcode,
+            mnemonic: entry.mnemonic,
+            mode: entry.mode,
+            operand_bytes,
+            length: 1 + entry.mode.operand_length(),
+            cycles: entry.cycles,
+            page_penalty: entry.page_penalty,
+            unofficial: entry.unofficial,
+        }
+    }
+
+    /// The opcode followed by its operand bytes.
+    pub fn bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.opcode];
+        bytes.extend_from_slice(&self.operand_bytes[..self.length as usize - 1]);
+        bytes
+    }
+
+    /// The operand as a little endian number: a byte, an address, or a
+    /// zero page address and branch offset for BBR/BBS.
+    pub fn operand(&self) -> u16 {
+        match self.length {
+            1 => 0,
+            2 => self.operand_bytes[0] as u16,
+            _ => to_u16(self.operand_bytes[0], self.operand_bytes[1]),
+        }
+    }
+
+    /// Address of the instruction that follows this one.
+    pub fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.length as u16)
+    }
+
+    /// Where a branch goes when taken, or an absolute JMP or JSR goes.
+    pub fn target(&self) -> Option<u16> {
+        let offset = match self.mode {
+            Relative => self.operand_bytes[0],
+            ZeroPageRelative => self.operand_bytes[1],
+            Absolute if matches!(self.mnemonic, "JMP" | "JSR") => return Some(self.operand()),
+            _ => return None,
+        };
+        Some(self.next_address().wrapping_add(offset as i8 as u16))
+    }
+
+    /// The operand as written in assembly, e.g. `($12),Y`.
+    pub fn operand_text(&self) -> String {
+        self.operand_text_with(|_| None)
+    }
+
+    /// Like `operand_text`, but writes addresses as the names that `name`
+    /// gives them, where it does, e.g. `(pointer),Y`.
+    pub fn operand_text_with(&self, name: impl Fn(u16) -> Option<String>) -> String {
+        let byte = self.operand_bytes[0];
+        let word = self.operand();
+        let zero_page = name(byte as u16).unwrap_or_else(|| format!("${:02X}", byte));
+        let absolute = name(word).unwrap_or_else(|| format!("${:04X}", word));
+        let target = self
+            .target()
+            .map(|target| name(target).unwrap_or_else(|| format!("${:04X}", target)));
+        match self.mode {
+            Implied => String::new(),
+            Accumulator => "A".to_string(),
+            Immediate => format!("#${:02X}", byte),
+            ZeroPage => zero_page,
+            ZeroPageX => format!("{},X", zero_page),
+            ZeroPageY => format!("{},Y", zero_page),
+            Relative => target.unwrap(),
+            Absolute => absolute,
+            AbsoluteX => format!("{},X", absolute),
+            AbsoluteY => format!("{},Y", absolute),
+            Indirect => format!("({})", absolute),
+            IndexedIndirect => format!("({},X)", zero_page),
+            IndirectIndexed => format!("({}),Y", zero_page),
+            ZeroPageIndirect => format!("({})", zero_page),
+            AbsoluteIndexedIndirect => format!("({},X)", absolute),
+            ZeroPageRelative => format!("{},{}", zero_page, target.unwrap()),
+        }
+    }
+
+    /// Like `to_string`, but with the operand as `operand_text_with` writes
+    /// it.
+    pub fn to_string_with(&self, name: impl Fn(u16) -> Option<String>) -> String {
+        match self.mode {
+            Implied => self.mnemonic.to_string(),
+            _ => format!("{} {}", self.mnemonic, self.operand_text_with(name)),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_string_with(|_| None))
+    }
+}
+
+/// Decodes the instruction at the start of `bytes`, which are located at
+/// `address`. Returns `None` if `bytes` ends partway through it.
+pub fn decode(variant: Variant, bytes: &[u8], address: u16) -> Option<Instruction> {
+    let opcode = *bytes.first()?;
+    let length = 1 + opcodes(variant)[opcode as usize].mode.operand_length() as usize;
+    if bytes.len() < length {
+        return None;
+    }
+    let mut operand_bytes = [0; 2];
+    operand_bytes[..length - 1].copy_from_slice(&bytes[1..length]);
+    Some(Instruction::new(variant, address, opcode, operand_bytes))
+}
+
+/// Decodes the instruction at `address` without side effects on the bus.
+pub fn decode_bus<B: Bus + ?Sized>(variant: Variant, bus: &B, address: u16) -> Instruction {
+    let opcode = bus.peek(address);
+    let operand_bytes = [
+        bus.peek(address.wrapping_add(1)),
+        bus.peek(address.wrapping_add(2)),
+    ];
+    Instruction::new(variant, address, opcode, operand_bytes)
+}
+
+/// Decodes `bytes` as a straight run of code loaded at `address`, stopping
+/// at a truncated final instruction.
+pub fn disassemble(variant: Variant, bytes: &[u8], address: u16) -> Vec<Instruction> {
+    let mut instructions = vec![];
+    let mut offset = 0;
+    while let Some(instruction) = decode(
+        variant,
+        &bytes[offset..],
+        address.wrapping_add(offset as u16),
+    ) {
+        offset += instruction.length as usize;
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+/// Decodes every instruction that starts within `range` on the bus.
+pub fn disassemble_range<B: Bus + ?Sized>(
+    variant: Variant,
+    bus: &B,
+    range: RangeInclusive<u16>,
+) -> Vec<Instruction> {
+    let mut instructions = vec![];
+    let mut address = *range.start() as u32;
+    while address <= *range.end() as u32 {
+        let instruction = decode_bus(variant, bus, address as u16);
+        address += instruction.length as u32;
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+/// Renders instructions as a listing, one per line, with their address and
+/// bytes: `C000  4C F5 C5  JMP $C5F5`.
+pub fn listing(instructions: &[Instruction]) -> String {
+    let mut text = String::new();
+    for instruction in instructions {
+        let bytes = instruction
+            .bytes()
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+        text += &format!(
+            "{:04X}  {:<8}  {}\n",
+            instruction.address, bytes, instruction
+        );
+    }
+    text
+}
+
+pub static NMOS_OPCODES: [Opcode; 256] = [
+    op("BRK", Implied, 7),                             // 00
+    op("ORA", IndexedIndirect, 6),                     // 01
+    op("JAM", Implied, 1).unofficial(),                // 02
+    op("SLO", IndexedIndirect, 8).unofficial(),        // 03
+    op("NOP", ZeroPage, 3).unofficial(),               // 04
+    op("ORA", ZeroPage, 3),                            // 05
+    op("ASL", ZeroPage, 5),                            // 06
+    op("SLO", ZeroPage, 5).unofficial(),               // 07
+    op("PHP", Implied, 3),                             // 08
+    op("ORA", Immediate, 2),                           // 09
+    op("ASL", Accumulator, 2),                         // 0A
+    op("ANC", Immediate, 2).unofficial(),              // 0B
+    op("NOP", Absolute, 4).unofficial(),               // 0C
+    op("ORA", Absolute, 4),                            // 0D
+    op("ASL", Absolute, 6),                            // 0E
+    op("SLO", Absolute, 6).unofficial(),               // 0F
+    op("BPL", Relative, 2).page(),                     // 10
+    op("ORA", IndirectIndexed, 5).page(),              // 11
+    op("JAM", Implied, 1).unofficial(),                // 12
+    op("SLO", IndirectIndexed, 8).unofficial(),        // 13
+    op("NOP", ZeroPageX, 4).unofficial(),              // 14
+    op("ORA", ZeroPageX, 4),                           // 15
+    op("ASL", ZeroPageX, 6),                           // 16
+    op("SLO", ZeroPageX, 6).unofficial(),              // 17
+    op("CLC", Implied, 2),                             // 18
+    op("ORA", AbsoluteY, 4).page(),                    // 19
+    op("NOP", Implied, 2).unofficial(),                // 1A
+    op("SLO", AbsoluteY, 7).unofficial(),              // 1B
+    op("NOP", AbsoluteX, 4).page().unofficial(),       // 1C
+    op("ORA", AbsoluteX, 4).page(),                    // 1D
+    op("ASL", AbsoluteX, 7),                           // 1E
+    op("SLO", AbsoluteX, 7).unofficial(),              // 1F
+    op("JSR", Absolute, 6),                            // 20
+    op("AND", IndexedIndirect, 6),                     // 21
+    op("JAM", Implied, 1).unofficial(),                // 22
+    op("RLA", IndexedIndirect, 8).unofficial(),        // 23
+    op("BIT", ZeroPage, 3),                            // 24
+    op("AND", ZeroPage, 3),                            // 25
+    op("ROL", ZeroPage, 5),                            // 26
+    op("RLA", ZeroPage, 5).unofficial(),               // 27
+    op("PLP", Implied, 4),                             // 28
+    op("AND", Immediate, 2),                           // 29
+    op("ROL", Accumulator, 2),                         // 2A
+    op("ANC", Immediate, 2).unofficial(),              // 2B
+    op("BIT", Absolute, 4),                            // 2C
+    op("AND", Absolute, 4),                            // 2D
+    op("ROL", Absolute, 6),                            // 2E
+    op("RLA", Absolute, 6).unofficial(),               // 2F
+    op("BMI", Relative, 2).page(),                     // 30
+    op("AND", IndirectIndexed, 5).page(),              // 31
+    op("JAM", Implied, 1).unofficial(),                // 32
+    op("RLA", IndirectIndexed, 8).unofficial(),        // 33
+    op("NOP", ZeroPageX, 4).unofficial(),              // 34
+    op("AND", ZeroPageX, 4),                           // 35
+    op("ROL", ZeroPageX, 6),                           // 36
+    op("RLA", ZeroPageX, 6).unofficial(),              // 37
+    op("SEC", Implied, 2),                             // 38
+    op("AND", AbsoluteY, 4).page(),                    // 39
+    op("NOP", Implied, 2).unofficial(),                // 3A
+    op("RLA", AbsoluteY, 7).unofficial(),              // 3B
+    op("NOP", AbsoluteX, 4).page().unofficial(),       // 3C
+    op("AND", AbsoluteX, 4).page(),                    // 3D
+    op("ROL", AbsoluteX, 7),                           // 3E
+    op("RLA", AbsoluteX, 7).unofficial(),              // 3F
+    op("RTI", Implied, 6),                             // 40
+    op("EOR", IndexedIndirect, 6),                     // 41
+    op("JAM", Implied, 1).unofficial(),                // 42
+    op("SRE", IndexedIndirect, 8).unofficial(),        // 43
+    op("NOP", ZeroPage, 3).unofficial(),               // 44
+    op("EOR", ZeroPage, 3),                            // 45
+    op("LSR", ZeroPage, 5),                            // 46
+    op("SRE", ZeroPage, 5).unofficial(),               // 47
+    op("PHA", Implied, 3),                             // 48
+    op("EOR", Immediate, 2),                           // 49
+    op("LSR", Accumulator, 2),                         // 4A
+    op("ALR", Immediate, 2).unofficial(),              // 4B
+    op("JMP", Absolute, 3),                            // 4C
+    op("EOR", Absolute, 4),                            // 4D
+    op("LSR", Absolute, 6),                            // 4E
+    op("SRE", Absolute, 6).unofficial(),               // 4F
+    op("BVC", Relative, 2).page(),                     // 50
+    op("EOR", IndirectIndexed, 5).page(),              // 51
+    op("JAM", Implied, 1).unofficial(),                // 52
+    op("SRE", IndirectIndexed, 8).unofficial(),        // 53
+    op("NOP", ZeroPageX, 4).unofficial(),              // 54
+    op("EOR", ZeroPageX, 4),                           // 55
+    op("LSR", ZeroPageX, 6),                           // 56
+    op("SRE", ZeroPageX, 6).unofficial(),              // 57
+    op("CLI", Implied, 2),                             // 58
+    op("EOR", AbsoluteY, 4).page(),                    // 59
+    op("NOP", Implied, 2).unofficial(),                // 5A
+    op("SRE", AbsoluteY, 7).unofficial(),              // 5B
+    op("NOP", AbsoluteX, 4).page().unofficial(),       // 5C
+    op("EOR", AbsoluteX, 4).page(),                    // 5D
+    op("LSR", AbsoluteX, 7),                           // 5E
+    op("SRE", AbsoluteX, 7).unofficial(),              // 5F
+    op("RTS", Implied, 6),                             // 60
+    op("ADC", IndexedIndirect, 6),                     // 61
+    op("JAM", Implied, 1).unofficial(),                // 62
+    op("RRA", IndexedIndirect, 8).unofficial(),        // 63
+    op("NOP", ZeroPage, 3).unofficial(),               // 64
+    op("ADC", ZeroPage, 3),                            // 65
+    op("ROR", ZeroPage, 5),                            // 66
+    op("RRA", ZeroPage, 5).unofficial(),               // 67
+    op("PLA", Implied, 4),                             // 68
+    op("ADC", Immediate, 2),                           // 69
+    op("ROR", Accumulator, 2),                         // 6A
+    op("ARR", Immediate, 2).unofficial(),              // 6B
+    op("JMP", Indirect, 5),                            // 6C
+    op("ADC", Absolute, 4),                            // 6D
+    op("ROR", Absolute, 6),                            // 6E
+    op("RRA", Absolute, 6).unofficial(),               // 6F
+    op("BVS", Relative, 2).page(),                     // 70
+    op("ADC", IndirectIndexed, 5).page(),              // 71
+    op("JAM", Implied, 1).unofficial(),                // 72
+    op("RRA", IndirectIndexed, 8).unofficial(),        // 73
+    op("NOP", ZeroPageX, 4).unofficial(),              // 74
+    op("ADC", ZeroPageX, 4),                           // 75
+    op("ROR", ZeroPageX, 6),                           // 76
+    op("RRA", ZeroPageX, 6).unofficial(),              // 77
+    op("SEI", Implied, 2),                             // 78
+    op("ADC", AbsoluteY, 4).page(),                    // 79
+    op("NOP", Implied, 2).unofficial(),                // 7A
+    op("RRA", AbsoluteY, 7).unofficial(),              // 7B
+    op("NOP", AbsoluteX, 4).page().unofficial(),       // 7C
+    op("ADC", AbsoluteX, 4).page(),                    // 7D
+    op("ROR", AbsoluteX, 7),                           // 7E
+    op("RRA", AbsoluteX, 7).unofficial(),              // 7F
+    op("NOP", Immediate, 2).unofficial(),              // 80
+    op("STA", IndexedIndirect, 6),                     // 81
+    op("NOP", Immediate, 2).unofficial(),              // 82
+    op("SAX", IndexedIndirect, 6).unofficial(),        // 83
+    op("STY", ZeroPage, 3),                            // 84
+    op("STA", ZeroPage, 3),                            // 85
+    op("STX", ZeroPage, 3),                            // 86
+    op("SAX", ZeroPage, 3).unofficial(),               // 87
+    op("DEY", Implied, 2),                             // 88
+    op("NOP", Immediate, 2).unofficial(),              // 89
+    op("TXA", Implied, 2),                             // 8A
+    op("XAA", Immediate, 2).unofficial(),              // 8B
+    op("STY", Absolute, 4),                            // 8C
+    op("STA", Absolute, 4),                            // 8D
+    op("STX", Absolute, 4),                            // 8E
+    op("SAX", Absolute, 4).unofficial(),               // 8F
+    op("BCC", Relative, 2).page(),                     // 90
+    op("STA", IndirectIndexed, 6),                     // 91
+    op("JAM", Implied, 1).unofficial(),                // 92
+    op("SHA", IndirectIndexed, 6).unofficial(),        // 93
+    op("STY", ZeroPageX, 4),                           // 94
+    op("STA", ZeroPageX, 4),                           // 95
+    op("STX", ZeroPageY, 4),                           // 96
+    op("SAX", ZeroPageY, 4).unofficial(),              // 97
+    op("TYA", Implied, 2),                             // 98
+    op("STA", AbsoluteY, 5),                           // 99
+    op("TXS", Implied, 2),                             // 9A
+    op("TAS", AbsoluteY, 5).unofficial(),              // 9B
+    op("SHY", AbsoluteX, 5).unofficial(),              // 9C
+    op("STA", AbsoluteX, 5),                           // 9D
+    op("SHX", AbsoluteY, 5).unofficial(),              // 9E
+    op("SHA", AbsoluteY, 5).unofficial(),              // 9F
+    op("LDY", Immediate, 2),                           // A0
+    op("LDA", IndexedIndirect, 6),                     // A1
+    op("LDX", Immediate, 2),                           // A2
+    op("LAX", IndexedIndirect, 6).unofficial(),        // A3
+    op("LDY", ZeroPage, 3),                            // A4
+    op("LDA", ZeroPage, 3),                            // A5
+    op("LDX", ZeroPage, 3),                            // A6
+    op("LAX", ZeroPage, 3).unofficial(),               // A7
+    op("TAY", Implied, 2),                             // A8
+    op("LDA", Immediate, 2),                           // A9
+    op("TAX", Implied, 2),                             // AA
+    op("LXA", Immediate, 2).unofficial(),              // AB
+    op("LDY", Absolute, 4),                            // AC
+    op("LDA", Absolute, 4),                            // AD
+    op("LDX", Absolute, 4),                            // AE
+    op("LAX", Absolute, 4).unofficial(),               // AF
+    op("BCS", Relative, 2).page(),                     // B0
+    op("LDA", IndirectIndexed, 5).page(),              // B1
+    op("JAM", Implied, 1).unofficial(),                // B2
+    op("LAX", IndirectIndexed, 5).page().unofficial(), // B3
+    op("LDY", ZeroPageX, 4),                           // B4
+    op("LDA", ZeroPageX, 4),                           // B5
+    op("LDX", ZeroPageY, 4),                           // B6
+    op("LAX", ZeroPageY, 4).unofficial(),              // B7
+    op("CLV", Implied, 2),                             // B8
+    op("LDA", AbsoluteY, 4).page(),                    // B9
+    op("TSX", Implied, 2),                             // BA
+    op("LAS", AbsoluteY, 4).page().unofficial(),       // BB
+    op("LDY", AbsoluteX, 4).page(),                    // BC
+    op("LDA", AbsoluteX, 4).page(),                    // BD
+    op("LDX", AbsoluteY, 4).page(),                    // BE
+    op("LAX", AbsoluteY, 4).page().unofficial(),       // BF
+    op("CPY", Immediate, 2),                           // C0
+    op("CMP", IndexedIndirect, 6),                     // C1
+    op("NOP", Immediate, 2).unofficial(),              // C2
+    op("DCP", IndexedIndirect, 8).unofficial(),        // C3
+    op("CPY", ZeroPage, 3),                            // C4
+    op("CMP", ZeroPage, 3),                            // C5
+    op("DEC", ZeroPage, 5),                            // C6
+    op("DCP", ZeroPage, 5).unofficial(),               // C7
+    op("INY", Implied, 2),                             // C8
+    op("CMP", Immediate, 2),                           // C9
+    op("DEX", Implied, 2),                             // CA
+    op("AXS", Immediate, 2).unofficial(),              // CB
+    op("CPY", Absolute, 4),                            // CC
+    op("CMP", Absolute, 4),                            // CD
+    op("DEC", Absolute, 6),                            // CE
+    op("DCP", Absolute, 6).unofficial(),               // CF
+    op("BNE", Relative, 2).page(),                     // D0
+    op("CMP", IndirectIndexed, 5).page(),              // D1
+    op("JAM", Implied, 1).unofficial(),                // D2
+    op("DCP", IndirectIndexed, 8).unofficial(),        // D3
+    op("NOP", ZeroPageX, 4).unofficial(),              // D4
+    op("CMP", ZeroPageX, 4),                           // D5
+    op("DEC", ZeroPageX, 6),                           // D6
+    op("DCP", ZeroPageX, 6).unofficial(),              // D7
+    op("CLD", Implied, 2),                             // D8
+    op("CMP", AbsoluteY, 4).page(),                    // D9
+    op("NOP", Implied, 2).unofficial(),                // DA
+    op("DCP", AbsoluteY, 7).unofficial(),              // DB
+    op("NOP", AbsoluteX, 4).page().unofficial(),       // DC
+    op("CMP", AbsoluteX, 4).page(),                    // DD
+    op("DEC", AbsoluteX, 7),                           // DE
+    op("DCP", AbsoluteX, 7).unofficial(),              // DF
+    op("CPX", Immediate, 2),                           // E0
+    op("SBC", IndexedIndirect, 6),                     // E1
+    op("NOP", Immediate, 2).unofficial(),              // E2
+    op("ISB", IndexedIndirect, 8).unofficial(),        // E3
+    op("CPX", ZeroPage, 3),                            // E4
+    op("SBC", ZeroPage, 3),                            // E5
+    op("INC", ZeroPage, 5),                            // E6
+    op("ISB", ZeroPage, 5).unofficial(),               // E7
+    op("INX", Implied, 2),                             // E8
+    op("SBC", Immediate, 2),                           // E9
+    op("NOP", Implied, 2),                             // EA
+    op("SBC", Immediate, 2).unofficial(),              // EB
+    op("CPX", Absolute, 4),                            // EC
+    op("SBC", Absolute, 4),                            // ED
+    op("INC", Absolute, 6),                            // EE
+    op("ISB", Absolute, 6).unofficial(),               // EF
+    op("BEQ", Relative, 2).page(),                     // F0
+    op("SBC", IndirectIndexed, 5).page(),              // F1
+    op("JAM", Implied, 1).unofficial(),                // F2
+    op("ISB", IndirectIndexed, 8).unofficial(),        // F3
+    op("NOP", ZeroPageX, 4).unofficial(),              // F4
+    op("SBC", ZeroPageX, 4),                           // F5
+    op("INC", ZeroPageX, 6),                           // F6
+    op("ISB", ZeroPageX, 6).unofficial(),              // F7
+    op("SED", Implied, 2),                             // F8
+    op("SBC", AbsoluteY, 4).page(),                    // F9
+    op("NOP", Implied, 2).unofficial(),                // FA
+    op("ISB", AbsoluteY, 7).unofficial(),              // FB
+    op("NOP", AbsoluteX, 4).page().unofficial(),       // FC
+    op("SBC", AbsoluteX, 4).page(),                    // FD
+    op("INC", AbsoluteX, 7),                           // FE
+    op("ISB", AbsoluteX, 7).unofficial(),              // FF
+];
+
+pub static CMOS_OPCODES: [Opcode; 256] = [
+    op("BRK", Implied, 7),                  // 00
+    op("ORA", IndexedIndirect, 6),          // 01
+    op("NOP", Immediate, 2).unofficial(),   // 02
+    op("NOP", Implied, 1).unofficial(),     // 03
+    op("TSB", ZeroPage, 5),                 // 04
+    op("ORA", ZeroPage, 3),                 // 05
+    op("ASL", ZeroPage, 5),                 // 06
+    op("RMB0", ZeroPage, 5),                // 07
+    op("PHP", Implied, 3),                  // 08
+    op("ORA", Immediate, 2),                // 09
+    op("ASL", Accumulator, 2),              // 0A
+    op("NOP", Implied, 1).unofficial(),     // 0B
+    op("TSB", Absolute, 6),                 // 0C
+    op("ORA", Absolute, 4),                 // 0D
+    op("ASL", Absolute, 6),                 // 0E
+    op("BBR0", ZeroPageRelative, 5).page(), // 0F
+    op("BPL", Relative, 2).page(),          // 10
+    op("ORA", IndirectIndexed, 5).page(),   // 11
+    op("ORA", ZeroPageIndirect, 5),         // 12
+    op("NOP", Implied, 1).unofficial(),     // 13
+    op("TRB", ZeroPage, 5),                 // 14
+    op("ORA", ZeroPageX, 4),                // 15
+    op("ASL", ZeroPageX, 6),                // 16
+    op("RMB1", ZeroPage, 5),                // 17
+    op("CLC", Implied, 2),                  // 18
+    op("ORA", AbsoluteY, 4).page(),         // 19
+    op("INC", Accumulator, 2),              // 1A
+    op("NOP", Implied, 1).unofficial(),     // 1B
+    op("TRB", Absolute, 6),                 // 1C
+    op("ORA", AbsoluteX, 4).page(),         // 1D
+    op("ASL", AbsoluteX, 6).page(),         // 1E
+    op("BBR1", ZeroPageRelative, 5).page(), // 1F
+    op("JSR", Absolute, 6),                 // 20
+    op("AND", IndexedIndirect, 6),          // 21
+    op("NOP", Immediate, 2).unofficial(),   // 22
+    op("NOP", Implied, 1).unofficial(),     // 23
+    op("BIT", ZeroPage, 3),                 // 24
+    op("AND", ZeroPage, 3),                 // 25
+    op("ROL", ZeroPage, 5),                 // 26
+    op("RMB2", ZeroPage, 5),                // 27
+    op("PLP", Implied, 4),                  // 28
+    op("AND", Immediate, 2),                // 29
+    op("ROL", Accumulator, 2),              // 2A
+    op("NOP", Implied, 1).unofficial(),     // 2B
+    op("BIT", Absolute, 4),                 // 2C
+    op("AND", Absolute, 4),                 // 2D
+    op("ROL", Absolute, 6),                 // 2E
+    op("BBR2", ZeroPageRelative, 5).page(), // 2F
+    op("BMI", Relative, 2).page(),          // 30
+    op("AND", IndirectIndexed, 5).page(),   // 31
+    op("AND", ZeroPageIndirect, 5),         // 32
+    op("NOP", Implied, 1).unofficial(),     // 33
+    op("BIT", ZeroPageX, 4),                // 34
+    op("AND", ZeroPageX, 4),                // 35
+    op("ROL", ZeroPageX, 6),                // 36
+    op("RMB3", ZeroPage, 5),                // 37
+    op("SEC", Implied, 2),                  // 38
+    op("AND", AbsoluteY, 4).page(),         // 39
+    op("DEC", Accumulator, 2),              // 3A
+    op("NOP", Implied, 1).unofficial(),     // 3B
+    op("BIT", AbsoluteX, 4).page(),         // 3C
+    op("AND", AbsoluteX, 4).page(),         // 3D
+    op("ROL", AbsoluteX, 6).page(),         // 3E
+    op("BBR3", ZeroPageRelative, 5).page(), // 3F
+    op("RTI", Implied, 6),                  // 40
+    op("EOR", IndexedIndirect, 6),          // 41
+    op("NOP", Immediate, 2).unofficial(),   // 42
+    op("NOP", Implied, 1).unofficial(),     // 43
+    op("NOP", ZeroPage, 3).unofficial(),    // 44
+    op("EOR", ZeroPage, 3),                 // 45
+    op("LSR", ZeroPage, 5),                 // 46
+    op("RMB4", ZeroPage, 5),                // 47
+    op("PHA", Implied, 3),                  // 48
+    op("EOR", Immediate, 2),                // 49
+    op("LSR", Accumulator, 2),              // 4A
+    op("NOP", Implied, 1).unofficial(),     // 4B
+    op("JMP", Absolute, 3),                 // 4C
+    op("EOR", Absolute, 4),                 // 4D
+    op("LSR", Absolute, 6),                 // 4E
+    op("BBR4", ZeroPageRelative, 5).page(), // 4F
+    op("BVC", Relative, 2).page(),          // 50
+    op("EOR", IndirectIndexed, 5).page(),   // 51
+    op("EOR", ZeroPageIndirect, 5),         // 52
+    op("NOP", Implied, 1).unofficial(),     // 53
+    op("NOP", ZeroPageX, 4).unofficial(),   // 54
+    op("EOR", ZeroPageX, 4),                // 55
+    op("LSR", ZeroPageX, 6),                // 56
+    op("RMB5", ZeroPage, 5),                // 57
+    op("CLI", Implied, 2),                  // 58
+    op("EOR", AbsoluteY, 4).page(),         // 59
+    op("PHY", Implied, 3),                  // 5A
+    op("NOP", Implied, 1).unofficial(),     // 5B
+    op("NOP", Absolute, 8).unofficial(),    // 5C
+    op("EOR", AbsoluteX, 4).page(),         // 5D
+    op("LSR", AbsoluteX, 6).page(),         // 5E
+    op("BBR5", ZeroPageRelative, 5).page(), // 5F
+    op("RTS", Implied, 6),                  // 60
+    op("ADC", IndexedIndirect, 6),          // 61
+    op("NOP", Immediate, 2).unofficial(),   // 62
+    op("NOP", Implied, 1).unofficial(),     // 63
+    op("STZ", ZeroPage, 3),                 // 64
+    op("ADC", ZeroPage, 3),                 // 65
+    op("ROR", ZeroPage, 5),                 // 66
+    op("RMB6", ZeroPage, 5),                // 67
+    op("PLA", Implied, 4),                  // 68
+    op("ADC", Immediate, 2),                // 69
+    op("ROR", Accumulator, 2),              // 6A
+    op("NOP", Implied, 1).unofficial(),     // 6B
+    op("JMP", Indirect, 6),                 // 6C
+    op("ADC", Absolute, 4),                 // 6D
+    op("ROR", Absolute, 6),                 // 6E
+    op("BBR6", ZeroPageRelative, 5).page(), // 6F
+    op("BVS", Relative, 2).page(),          // 70
+    op("ADC", IndirectIndexed, 5).page(),   // 71
+    op("ADC", ZeroPageIndirect, 5),         // 72
+    op("NOP", Implied, 1).unofficial(),     // 73
+    op("STZ", ZeroPageX, 4),                // 74
+    op("ADC", ZeroPageX, 4),                // 75
+    op("ROR", ZeroPageX, 6),                // 76
+    op("RMB7", ZeroPage, 5),                // 77
+    op("SEI", Implied, 2),                  // 78
+    op("ADC", AbsoluteY, 4).page(),         // 79
+    op("PLY", Implied, 4),                  // 7A
+    op("NOP", Implied, 1).unofficial(),     // 7B
+    op("JMP", AbsoluteIndexedIndirect, 6),  // 7C
+    op("ADC", AbsoluteX, 4).page(),         // 7D
+    op("ROR", AbsoluteX, 6).page(),         // 7E
+    op("BBR7", ZeroPageRelative, 5).page(), // 7F
+    op("BRA", Relative, 3).page(),          // 80
+    op("STA", IndexedIndirect, 6),          // 81
+    op("NOP", Immediate, 2).unofficial(),   // 82
+    op("NOP", Implied, 1).unofficial(),     // 83
+    op("STY", ZeroPage, 3),                 // 84
+    op("STA", ZeroPage, 3),                 // 85
+    op("STX", ZeroPage, 3),                 // 86
+    op("SMB0", ZeroPage, 5),                // 87
+    op("DEY", Implied, 2),                  // 88
+    op("BIT", Immediate, 2),                // 89
+    op("TXA", Implied, 2),                  // 8A
+    op("NOP", Implied, 1).unofficial(),     // 8B
+    op("STY", Absolute, 4),                 // 8C
+    op("STA", Absolute, 4),                 // 8D
+    op("STX", Absolute, 4),                 // 8E
+    op("BBS0", ZeroPageRelative, 5).page(), // 8F
+    op("BCC", Relative, 2).page(),          // 90
+    op("STA", IndirectIndexed, 6),          // 91
+    op("STA", ZeroPageIndirect, 5),         // 92
+    op("NOP", Implied, 1).unofficial(),     // 93
+    op("STY", ZeroPageX, 4),                // 94
+    op("STA", ZeroPageX, 4),                // 95
+    op("STX", ZeroPageY, 4),                // 96
+    op("SMB1", ZeroPage, 5),                // 97
+    op("TYA", Implied, 2),                  // 98
+    op("STA", AbsoluteY, 5),                // 99
+    op("TXS", Implied, 2),                  // 9A
+    op("NOP", Implied, 1).unofficial(),     // 9B
+    op("STZ", Absolute, 4),                 // 9C
+    op("STA", AbsoluteX, 5),                // 9D
+    op("STZ", AbsoluteX, 5),                // 9E
+    op("BBS1", ZeroPageRelative, 5).page(), // 9F
+    op("LDY", Immediate, 2),                // A0
+    op("LDA", IndexedIndirect, 6),          // A1
+    op("LDX", Immediate, 2),                // A2
+    op("NOP", Implied, 1).unofficial(),     // A3
+    op("LDY", ZeroPage, 3),                 // A4
+    op("LDA", ZeroPage, 3),                 // A5
+    op("LDX", ZeroPage, 3),                 // A6
+    op("SMB2", ZeroPage, 5),                // A7
+    op("TAY", Implied, 2),                  // A8
+    op("LDA", Immediate, 2),                // A9
+    op("TAX", Implied, 2),                  // AA
+    op("NOP", Implied, 1).unofficial(),     // AB
+    op("LDY", Absolute, 4),                 // AC
+    op("LDA", Absolute, 4),                 // AD
+    op("LDX", Absolute, 4),                 // AE
+    op("BBS2", ZeroPageRelative, 5).page(), // AF
+    op("BCS", Relative, 2).page(),          // B0
+    op("LDA", IndirectIndexed, 5).page(),   // B1
+    op("LDA", ZeroPageIndirect, 5),         // B2
+    op("NOP", Implied, 1).unofficial(),     // B3
+    op("LDY", ZeroPageX, 4),                // B4
+    op("LDA", ZeroPageX, 4),                // B5
+    op("LDX", ZeroPageY, 4),                // B6
+    op("SMB3", ZeroPage, 5),                // B7
+    op("CLV", Implied, 2),                  // B8
+    op("LDA", AbsoluteY, 4).page(),         // B9
+    op("TSX", Implied, 2),                  // BA
+    op("NOP", Implied, 1).unofficial(),     // BB
+    op("LDY", AbsoluteX, 4).page(),         // BC
+    op("LDA", AbsoluteX, 4).page(),         // BD
+    op("LDX", AbsoluteY, 4).page(),         // BE
+    op("BBS3", ZeroPageRelative, 5).page(), // BF
+    op("CPY", Immediate, 2),                // C0
+    op("CMP", IndexedIndirect, 6),          // C1
+    op("NOP", Immediate, 2).unofficial(),   // C2
+    op("NOP", Implied, 1).unofficial(),     // C3
+    op("CPY", ZeroPage, 3),                 // C4
+    op("CMP", ZeroPage, 3),                 // C5
+    op("DEC", ZeroPage, 5),                 // C6
+    op("SMB4", ZeroPage, 5),                // C7
+    op("INY", Implied, 2),                  // C8
+    op("CMP", Immediate, 2),                // C9
+    op("DEX", Implied, 2),                  // CA
+    op("WAI", Implied, 3),                  // CB
+    op("CPY", Absolute, 4),                 // CC
+    op("CMP", Absolute, 4),                 // CD
+    op("DEC", Absolute, 6),                 // CE
+    op("BBS4", ZeroPageRelative, 5).page(), // CF
+    op("BNE", Relative, 2).page(),          // D0
+    op("CMP", IndirectIndexed, 5).page(),   // D1
+    op("CMP", ZeroPageIndirect, 5),         // D2
+    op("NOP", Implied, 1).unofficial(),     // D3
+    op("NOP", ZeroPageX, 4).unofficial(),   // D4
+    op("CMP", ZeroPageX, 4),                // D5
+    op("DEC", ZeroPageX, 6),                // D6
+    op("SMB5", ZeroPage, 5),                // D7
+    op("CLD", Implied, 2),                  // D8
+    op("CMP", AbsoluteY, 4).page(),         // D9
+    op("PHX", Implied, 3),                  // DA
+    op("STP", Implied, 3),                  // DB
+    op("NOP", Absolute, 4).unofficial(),    // DC
+    op("CMP", AbsoluteX, 4).page(),         // DD
+    op("DEC", AbsoluteX, 7),                // DE
+    op("BBS5", ZeroPageRelative, 5).page(), // DF
+    op("CPX", Immediate, 2),                // E0
+    op("SBC", IndexedIndirect, 6),          // E1
+    op("NOP", Immediate, 2).unofficial(),   // E2
+    op("NOP", Implied, 1).unofficial(),     // E3
+    op("CPX", ZeroPage, 3),                 // E4
+    op("SBC", ZeroPage, 3),                 // E5
+    op("INC", ZeroPage, 5),                 // E6
+    op("SMB6", ZeroPage, 5),                // E7
+    op("INX", Implied, 2),                  // E8
+    op("SBC", Immediate, 2),                // E9
+    op("NOP", Implied, 2),                  // EA
+    op("NOP", Implied, 1).unofficial(),     // EB
+    op("CPX", Absolute, 4),                 // EC
+    op("SBC", Absolute, 4),                 // ED
+    op("INC", Absolute, 6),                 // EE
+    op("BBS6", ZeroPageRelative, 5).page(), // EF
+    op("BEQ", Relative, 2).page(),          // F0
+    op("SBC", IndirectIndexed, 5).page(),   // F1
+    op("SBC", ZeroPageIndirect, 5),         // F2
+    op("NOP", Implied, 1).unofficial(),     // F3
+    op("NOP", ZeroPageX, 4).unofficial(),   // F4
+    op("SBC", ZeroPageX, 4),                // F5
+    op("INC", ZeroPageX, 6),                // F6
+    op("SMB7", ZeroPage, 5),                // F7
+    op("SED", Implied, 2),                  // F8
+    op("SBC", AbsoluteY, 4).page(),         // F9
+    op("PLX", Implied, 4),                  // FA
+    op("NOP", Implied, 1).unofficial(),     // FB
+    op("NOP", Absolute, 4).unofficial(),    // FC
+    op("SBC", AbsoluteX, 4).page(),         // FD
+    op("INC", AbsoluteX, 7),                // FE
+    op("BBS7", ZeroPageRelative, 5).page(), // FF
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_every_addressing_mode() {
+        let cases: [(Variant, &[u8], AddressingMode, &str); 16] = [
+            (Variant::Nmos6502, &[0xEA], Implied, "NOP"),
+            (Variant::Nmos6502, &[0x0A], Accumulator, "ASL A"),
+            (Variant::Nmos6502, &[0xA9, 0x12], Immediate, "LDA #$12"),
+            (Variant::Nmos6502, &[0xA5, 0x12], ZeroPage, "LDA $12"),
+            (Variant::Nmos6502, &[0xB5, 0x12], ZeroPageX, "LDA $12,X"),
+            (Variant::Nmos6502, &[0xB6, 0x12], ZeroPageY, "LDX $12,Y"),
+            (Variant::Nmos6502, &[0xD0, 0xFE], Relative, "BNE $8000"),
+            (
+                Variant::Nmos6502,
+                &[0xAD, 0x34, 0x12],
+                Absolute,
+                "LDA $1234",
+            ),
+            (
+                Variant::Nmos6502,
+                &[0xBD, 0x34, 0x12],
+                AbsoluteX,
+                "LDA $1234,X",
+            ),
+            (
+                Variant::Nmos6502,
+                &[0xB9, 0x34, 0x12],
+                AbsoluteY,
+                "LDA $1234,Y",
+            ),
+            (
+                Variant::Nmos6502,
+                &[0x6C, 0x34, 0x12],
+                Indirect,
+                "JMP ($1234)",
+            ),
+            (
+                Variant::Nmos6502,
+                &[0xA1, 0x12],
+                IndexedIndirect,
+                "LDA ($12,X)",
+            ),
+            (
+                Variant::Nmos6502,
+                &[0xB1, 0x12],
+                IndirectIndexed,
+                "LDA ($12),Y",
+            ),
+            (
+                Variant::Wdc65C02,
+                &[0xB2, 0x12],
+                ZeroPageIndirect,
+                "LDA ($12)",
+            ),
+            (
+                Variant::Wdc65C02,
+                &[0x7C, 0x34, 0x12],
+                AbsoluteIndexedIndirect,
+                "JMP ($1234,X)",
+            ),
+            (
+                Variant::Wdc65C02,
+                &[0x0F, 0x12, 0x05],
+                ZeroPageRelative,
+                "BBR0 $12,$8008",
+            ),
+        ];
+        for (variant, bytes, mode, text) in cases {
+            let instruction = decode(variant, bytes, 0x8000).unwrap();
+            assert_eq!(instruction.mode, mode);
+            assert_eq!(instruction.length as usize, bytes.len(), "{}", text);
+            assert_eq!(instruction.bytes(), bytes);
+            assert_eq!(instruction.to_string(), text);
+            assert_eq!(instruction.next_address(), 0x8000 + bytes.len() as u16);
+        }
+    }
+
+    #[test]
+    fn takes_lengths_from_the_mode() {
+        for variant in [Variant::Nmos6502, Variant::Wdc65C02] {
+            for (opcode, entry) in opcodes(variant).iter().enumerate() {
+                let length = 1 + entry.mode.operand_length() as usize;
+                let bytes = [opcode as u8, 0, 0];
+                let instruction = decode(variant, &bytes, 0).unwrap();
+                assert_eq!(instruction.length as usize, length);
+                assert_eq!(decode(variant, &bytes[..length - 1], 0), None);
+            }
+        }
+    }
+
+    #[test]
+    fn wraps_branch_targets_around_the_address_space() {
+        let target =
+            |bytes: &[u8], address| decode(Variant::Wdc65C02, bytes, address).unwrap().target();
+        assert_eq!(target(&[0xD0, 0x10], 0xFFFE), Some(0x0010));
+        assert_eq!(target(&[0xD0, 0xF0], 0x0000), Some(0xFFF2));
+        assert_eq!(target(&[0x80, 0x7F], 0xFFF0), Some(0x0071));
+        assert_eq!(target(&[0x0F, 0x12, 0x05], 0xFFFD), Some(0x0005));
+        assert_eq!(target(&[0x8F, 0x12, 0xFB], 0x0001), Some(0xFFFF));
+        // Absolute jumps go to their operand, and nothing else has a target
+        assert_eq!(target(&[0x20, 0x34, 0x12], 0x8000), Some(0x1234));
+        assert_eq!(target(&[0x6C, 0x34, 0x12], 0x8000), None);
+        assert_eq!(target(&[0xAD, 0x34, 0x12], 0x8000), None);
+    }
+
+    #[test]
+    fn decodes_65c02_opcodes_in_place_of_unofficial_ones() {
+        let text = |variant, bytes: &[u8]| {
+            let instruction = decode(variant, bytes, 0x8000).unwrap();
+            (instruction.to_string(), instruction.unofficial)
+        };
+        let cases: [(&[u8], &str, &str); 8] = [
+            (&[0x02, 0x12], "JAM", "NOP #$12"),
+            (&[0x1A], "NOP", "INC A"),
+            (&[0x3A], "NOP", "DEC A"),
+            (&[0x64, 0x12], "NOP $12", "STZ $12"),
+            (&[0x80, 0x02], "NOP #$02", "BRA $8004"),
+            (&[0x9C, 0x34, 0x12], "SHY $1234,X", "STZ $1234"),
+            (&[0xB2, 0x12], "JAM", "LDA ($12)"),
+            (&[0xCB, 0x12], "AXS #$12", "WAI"),
+        ];
+        for (bytes, nmos, cmos) in cases {
+            assert_eq!(text(Variant::Nmos6502, bytes), (nmos.to_string(), true));
+            assert_eq!(text(Variant::Ricoh2A03, bytes), (nmos.to_string(), true));
+            let cmos_unofficial = bytes[0] == 0x02;
+            assert_eq!(
+                text(Variant::Wdc65C02, bytes),
+                (cmos.to_string(), cmos_unofficial)
+            );
+        }
+        // The 65C02 takes a cycle more to fix JMP ($xxFF)
+        assert_eq!(NMOS_OPCODES[0x6C].cycles, 5);
+        assert_eq!(CMOS_OPCODES[0x6C].cycles, 6);
+    }
+
+    #[test]
+    fn needs_every_byte_of_an_instruction() {
+        assert_eq!(decode(Variant::Nmos6502, &[], 0x8000), None);
+        assert_eq!(decode(Variant::Nmos6502, &[0xA9], 0x8000), None);
+        assert_eq!(decode(Variant::Nmos6502, &[0xAD, 0x34], 0x8000), None);
+        assert_eq!(decode(Variant::Wdc65C02, &[0x0F, 0x12], 0x8000), None);
+        // A truncated instruction ends a disassembly
+        let instructions = disassemble(Variant::Nmos6502, &[0xEA, 0xA9, 0x12, 0xAD, 0x34], 0x8000);
+        let text = instructions
+            .iter()
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(text, ["NOP", "LDA #$12"]);
+    }
+}
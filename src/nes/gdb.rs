@@ -0,0 +1,472 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::common::bus::{AccessKind, Bus};
+use crate::nes::bus::NiseBus;
+use crate::nes::debugger::{Debugger, StopReason, WatchKind};
+
+// How many cycles `continue` runs between checks for an interrupt request
+const CONTINUE_CHUNK: u64 = 10_000;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+// There is no 6502 in GDB itself, so describe the registers to the client
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.nise.m6502">
+    <reg name="a" bitsize="8" regnum="0"/>
+    <reg name="x" bitsize="8"/>
+    <reg name="y" bitsize="8"/>
+    <reg name="p" bitsize="8"/>
+    <reg name="sp" bitsize="8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+/// A GDB remote serial protocol server for one client at a time.
+///
+/// Registers are numbered A, X, Y, P, SP and PC, in that order, with PC
+/// being 16 bits wide and every other register 8 bits. Breakpoints of both
+/// kinds map onto execute breakpoints, and memory accesses go through
+/// `Bus::peek` and `Bus::poke`, so they have no side effects but can patch
+/// ROM.
+pub struct GdbStub<B: Bus = NiseBus> {
+    debugger: Debugger<B>,
+}
+
+// The result of handling a packet
+enum Reply {
+    Packet(String),
+    // Continue and step reply once the CPU stops, which Ctrl-C can hurry
+    Resume(Resume),
+    Close,
+}
+
+enum Resume {
+    Continue,
+    Step,
+    ReverseContinue,
+    StepBack,
+}
+
+impl<B: Bus> GdbStub<B> {
+    pub fn new(debugger: Debugger<B>) -> Self {
+        Self { debugger }
+    }
+
+    pub fn debugger(&mut self) -> &mut Debugger<B> {
+        &mut self.debugger
+    }
+
+    pub fn into_debugger(self) -> Debugger<B> {
+        self.debugger
+    }
+
+    /// Waits for a client on `address` and serves it until it detaches.
+    pub fn listen(&mut self, address: impl ToSocketAddrs) -> io::Result<()> {
+        let listener = TcpListener::bind(address)?;
+        self.accept(&listener)
+    }
+
+    /// Serves the next client of `listener` until it detaches or kills the
+    /// session.
+    pub fn accept(&mut self, listener: &TcpListener) -> io::Result<()> {
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        let mut connection = Connection {
+            stream,
+            buffer: vec![],
+            acknowledge: true,
+        };
+        while let Some(packet) = connection.receive()? {
+            let Packet::Command(command) = packet else {
+                // Ctrl-C while the CPU is already stopped
+                connection.send(&stop_reply(SIGINT))?;
+                continue;
+            };
+            match self.command(&command) {
+                Reply::Packet(reply) => {
+                    connection.send(&reply)?;
+                    if command == "QStartNoAckMode" {
+                        connection.acknowledge = false;
+                    }
+                }
+                Reply::Resume(resume) => {
+                    let reply = self.resume(resume, &mut connection)?;
+                    connection.send(&reply)?;
+                }
+                Reply::Close => {
+                    connection.send("OK")?;
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn command(&mut self, command: &str) -> Reply {
+        let (kind, body) = command.split_at(1.min(command.len()));
+        let reply = match kind {
+            "?" => stop_reply(SIGTRAP),
+            "g" => {
+                let registers = self.registers();
+                hex(&registers)
+            }
+            "G" => match unhex(body) {
+                Some(registers) if registers.len() == 7 => {
+                    self.set_registers(&registers);
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            "p" => match usize::from_str_radix(body, 16) {
+                Ok(number @ 0..=4) => hex(&[self.registers()[number]]),
+                Ok(5) => hex(&self.registers()[5..]),
+                _ => "E01".to_string(),
+            },
+            "P" => self
+                .write_register(body)
+                .unwrap_or_else(|| "E01".to_string()),
+            "m" => self.read_memory(body).unwrap_or_else(|| "E01".to_string()),
+            "M" => self.write_memory(body).unwrap_or_else(|| "E01".to_string()),
+            "c" | "s" => {
+                // An address to resume from is optional
+                if let Ok(address) = u16::from_str_radix(body, 16) {
+                    self.debugger.cpu_mut().set_pc(address);
+                }
+                return Reply::Resume(if kind == "c" {
+                    Resume::Continue
+                } else {
+                    Resume::Step
+                });
+            }
+            "b" => match body {
+                "c" => return Reply::Resume(Resume::ReverseContinue),
+                "s" => return Reply::Resume(Resume::StepBack),
+                _ => String::new(),
+            },
+            "Z" | "z" => self
+                .breakpoint(kind == "Z", body)
+                .unwrap_or_else(|| "E01".to_string()),
+            "H" | "T" => "OK".to_string(),
+            "D" | "k" => return Reply::Close,
+            "q" | "Q" => self.query(command),
+            _ => String::new(),
+        };
+        Reply::Packet(reply)
+    }
+
+    fn query(&self, query: &str) -> String {
+        if query.starts_with("qSupported") {
+            return "PacketSize=4000;QStartNoAckMode+;qXfer:features:read+;ReverseStep+;ReverseContinue+"
+                .to_string();
+        }
+        if let Some(range) = query.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, length)) = range.split_once(',') else {
+                return "E01".to_string();
+            };
+            let (Ok(offset), Ok(length)) = (
+                usize::from_str_radix(offset, 16),
+                usize::from_str_radix(length, 16),
+            ) else {
+                return "E01".to_string();
+            };
+            let offset = offset.min(TARGET_XML.len());
+            let end = (offset + length).min(TARGET_XML.len());
+            let marker = if end == TARGET_XML.len() { 'l' } else { 'm' };
+            return format!("{}{}", marker, &TARGET_XML[offset..end]);
+        }
+        match query {
+            "QStartNoAckMode" => "OK",
+            "qAttached" => "1",
+            "qC" => "QC1",
+            "qfThreadInfo" => "m1",
+            "qsThreadInfo" => "l",
+            _ => "",
+        }
+        .to_string()
+    }
+
+    // Runs until the debugger stops or the client sends Ctrl-C
+    fn resume(&mut self, resume: Resume, connection: &mut Connection) -> io::Result<String> {
+        let reason = match resume {
+            Resume::Step => self.debugger.step_into(),
+            Resume::StepBack => self.debugger.step_back(),
+            Resume::ReverseContinue => self.debugger.reverse_continue(),
+            Resume::Continue => loop {
+                match self.debugger.run(CONTINUE_CHUNK) {
+                    StopReason::CycleLimit => {
+                        if connection.interrupted()? {
+                            return Ok(stop_reply(SIGINT));
+                        }
+                    }
+                    reason => break reason,
+                }
+            },
+        };
+        Ok(match reason {
+            StopReason::Watchpoint(access) => {
+                let kind = match self.watch_kind(access.address, access.kind) {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::ReadWrite => "awatch",
+                };
+                format!("T{:02x}{}:{:04x};", SIGTRAP, kind, access.address)
+            }
+            StopReason::HistoryStart => format!("T{:02x}replaylog:begin;", SIGTRAP),
+            StopReason::Fault(_) => stop_reply(SIGILL),
+            _ => stop_reply(SIGTRAP),
+        })
+    }
+
+    // The kind of the watchpoint that an access hit, to report it as such
+    fn watch_kind(&self, address: u16, access: AccessKind) -> WatchKind {
+        self.debugger
+            .watchpoints()
+            .iter()
+            .find(|watchpoint| {
+                watchpoint.range.contains(&address) && watchpoint.kind.matches(access)
+            })
+            .map_or(WatchKind::ReadWrite, |watchpoint| watchpoint.kind)
+    }
+
+    fn registers(&self) -> [u8; 7] {
+        let cpu = self.debugger.cpu();
+        let pc = cpu.pc();
+        [
+            cpu.a(),
+            cpu.x(),
+            cpu.y(),
+            cpu.p(),
+            cpu.s(),
+            pc as u8,
+            (pc >> 8) as u8,
+        ]
+    }
+
+    fn set_registers(&mut self, registers: &[u8]) {
+        let cpu = self.debugger.cpu_mut();
+        cpu.set_a(registers[0]);
+        cpu.set_x(registers[1]);
+        cpu.set_y(registers[2]);
+        cpu.set_p(registers[3]);
+        cpu.set_s(registers[4]);
+        cpu.set_pc(u16::from_le_bytes([registers[5], registers[6]]));
+    }
+
+    fn write_register(&mut self, body: &str) -> Option<String> {
+        let (number, value) = body.split_once('=')?;
+        let number = usize::from_str_radix(number, 16).ok()?;
+        let value = unhex(value)?;
+        let mut registers = self.registers();
+        match (number, &value[..]) {
+            (0..=4, [byte]) => registers[number] = *byte,
+            (5, [low, high]) => registers[5..].copy_from_slice(&[*low, *high]),
+            _ => return None,
+        }
+        self.set_registers(&registers);
+        Some("OK".to_string())
+    }
+
+    fn read_memory(&self, body: &str) -> Option<String> {
+        let (address, length) = parse_range(body)?;
+        let bus = self.debugger.cpu().bus();
+        let bytes = (0..length)
+            .map(|offset| bus.peek(address.wrapping_add(offset)))
+            .collect::<Vec<_>>();
+        Some(hex(&bytes))
+    }
+
+    fn write_memory(&mut self, body: &str) -> Option<String> {
+        let (range, data) = body.split_once(':')?;
+        let (address, length) = parse_range(range)?;
+        let data = unhex(data)?;
+        if data.len() != length as usize {
+            return None;
+        }
+        let bus = self.debugger.cpu_mut().bus_mut();
+        for (offset, byte) in data.into_iter().enumerate() {
+            bus.poke(address.wrapping_add(offset as u16), byte);
+        }
+        Some("OK".to_string())
+    }
+
+    // `type,address,kind`, where kind is a length for watchpoints
+    fn breakpoint(&mut self, insert: bool, body: &str) -> Option<String> {
+        let mut fields = body.split(',');
+        let kind = fields.next()?;
+        let address = u16::from_str_radix(fields.next()?, 16).ok()?;
+        let length = u32::from_str_radix(fields.next()?, 16).ok()?;
+        let watch = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.debugger.add_breakpoint(address);
+                } else {
+                    self.debugger.remove_breakpoint(address);
+                }
+                return Some("OK".to_string());
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::ReadWrite,
+            _ => return Some(String::new()),
+        };
+        // A watch running past $FFFF carries on from $0000, as two ranges
+        if !(1..=0x10000).contains(&length) {
+            return None;
+        }
+        let end = address as u32 + length - 1;
+        let ranges = match end {
+            0..=0xFFFF => vec![address..=end as u16],
+            _ => vec![address..=0xFFFF, 0..=(end - 0x10000) as u16],
+        };
+        for range in ranges {
+            if insert {
+                self.debugger.add_watchpoint(range, watch);
+            } else {
+                self.debugger.remove_watchpoint(&range, watch);
+            }
+        }
+        Some("OK".to_string())
+    }
+}
+
+enum Packet {
+    Command(String),
+    Interrupt,
+}
+
+struct Connection {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    acknowledge: bool,
+}
+
+impl Connection {
+    // Returns None once the client has disconnected
+    fn receive(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            if let Some(packet) = self.parse()? {
+                return Ok(Some(packet));
+            }
+            let mut chunk = [0; 1024];
+            let count = self.stream.read(&mut chunk)?;
+            if count == 0 {
+                return Ok(None);
+            }
+            self.buffer.extend_from_slice(&chunk[..count]);
+        }
+    }
+
+    // Takes the first complete packet out of the buffer
+    fn parse(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            match self.buffer.first() {
+                None => return Ok(None),
+                Some(0x03) => {
+                    self.buffer.remove(0);
+                    return Ok(Some(Packet::Interrupt));
+                }
+                Some(b'$') => break,
+                // Acknowledgements, and noise between packets
+                Some(_) => {
+                    self.buffer.remove(0);
+                }
+            }
+        }
+        let Some(end) = self.buffer.iter().position(|byte| *byte == b'#') else {
+            return Ok(None);
+        };
+        if self.buffer.len() < end + 3 {
+            return Ok(None);
+        }
+        let packet = self.buffer.drain(..end + 3).collect::<Vec<_>>();
+        let data = unescape(&packet[1..end]);
+        let checksum = std::str::from_utf8(&packet[end + 1..])
+            .ok()
+            .and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+        if self.acknowledge {
+            let valid = checksum == Some(checksum_of(&packet[1..end]));
+            self.stream.write_all(if valid { b"+" } else { b"-" })?;
+            if !valid {
+                return self.parse();
+            }
+        }
+        Ok(Some(Packet::Command(
+            String::from_utf8_lossy(&data).into_owned(),
+        )))
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())
+    }
+
+    // Polls for Ctrl-C without blocking
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut chunk = [0; 1024];
+        let result = self.stream.read(&mut chunk);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(count) => self.buffer.extend_from_slice(&chunk[..count]),
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => {}
+            Err(error) => return Err(error),
+        }
+        match self.buffer.iter().position(|byte| *byte == 0x03) {
+            Some(index) => {
+                self.buffer.remove(index);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+// `}` escapes the byte after it, XORed with 0x20
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![];
+    let mut iter = data.iter();
+    while let Some(byte) = iter.next() {
+        match byte {
+            b'}' => bytes.extend(iter.next().map(|byte| byte ^ 0x20)),
+            _ => bytes.push(*byte),
+        }
+    }
+    bytes
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+// `address,length`
+fn parse_range(text: &str) -> Option<(u16, u16)> {
+    let (address, length) = text.split_once(',')?;
+    Some((
+        u16::from_str_radix(address, 16).ok()?,
+        u16::from_str_radix(length, 16).ok()?,
+    ))
+}
@@ -0,0 +1,184 @@
+use std::collections::VecDeque;
+
+use crate::nes::cpu::{Interrupt, Nise6502State, Variant};
+use crate::nes::disasm::{decode, Instruction};
+
+// How many anomalies the call stack remembers
+const ANOMALY_LOG: usize = 64;
+
+/// How a frame of the call stack was entered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameKind {
+    Jsr,
+    Brk,
+    Nmi,
+    Irq,
+}
+
+/// A subroutine or interrupt handler that has been entered but not yet
+/// returned from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    /// The JSR or BRK, or the instruction that an interrupt came before.
+    pub call_site: u16,
+    /// The start of the subroutine or handler.
+    pub entry: u16,
+    /// Where an RTS or RTI from this frame should go.
+    pub return_address: u16,
+    /// S after the return address, and P for interrupts, were pushed. The
+    /// frame is gone once S moves above it.
+    pub stack_pointer: u8,
+}
+
+/// Control flow that did not keep to the call stack.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Anomaly {
+    /// An RTS or RTI at `from` did not return from any frame, but pulled an
+    /// address pushed since, as jump tables of pushed addresses do.
+    ReturnAsJump { from: u16, to: u16 },
+    /// An RTS or RTI at `from` returned from a frame to somewhere other than
+    /// after its call, as when skipping data placed after a JSR.
+    ReturnElsewhere { from: u16, to: u16, expected: u16 },
+    /// The instruction at `at` revealed that S had moved past `frames`
+    /// frames, which were dropped without being returned from.
+    Unwound { at: u16, frames: usize },
+}
+
+/// A shadow of the stack that follows JSR, RTS, BRK, RTI and interrupts, to
+/// tell how execution got where it is.
+#[derive(Clone, Debug, Default)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+    anomalies: VecDeque<Anomaly>,
+}
+
+impl CallStack {
+    /// The frames, from the outermost to the innermost.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// The most recent anomalies, oldest first.
+    pub fn anomalies(&self) -> impl Iterator<Item = &Anomaly> {
+        self.anomalies.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.anomalies.clear();
+    }
+
+    /// Enters a frame, whose return address and P are already pushed.
+    pub(crate) fn call(&mut self, frame: Frame, pushed: u8) {
+        self.unwind(frame.call_site, frame.stack_pointer.wrapping_add(pushed));
+        self.frames.push(frame);
+    }
+
+    /// Returns from the frame that an RTS or RTI at `from` pulled from with
+    /// S at `stack_pointer`, to `to`.
+    pub(crate) fn ret(&mut self, from: u16, stack_pointer: u8, to: u16) {
+        self.unwind(from, stack_pointer);
+        match self.frames.last() {
+            Some(frame) if frame.stack_pointer == stack_pointer => {
+                if to != frame.return_address {
+                    self.log(Anomaly::ReturnElsewhere {
+                        from,
+                        to,
+                        expected: frame.return_address,
+                    });
+                }
+                self.frames.pop();
+            }
+            _ => self.log(Anomaly::ReturnAsJump { from, to }),
+        }
+    }
+
+    /// Drops the frames whose return addresses S has moved above, as after
+    /// TXS or pulling a return address to discard it.
+    pub(crate) fn unwind(&mut self, at: u16, stack_pointer: u8) {
+        let live = self
+            .frames
+            .iter()
+            .rposition(|frame| frame.stack_pointer >= stack_pointer)
+            .map_or(0, |index| index + 1);
+        let frames = self.frames.len() - live;
+        if frames > 0 {
+            self.frames.truncate(live);
+            self.log(Anomaly::Unwound { at, frames });
+        }
+    }
+
+    fn log(&mut self, anomaly: Anomaly) {
+        if self.anomalies.len() == ANOMALY_LOG {
+            self.anomalies.pop_front();
+        }
+        self.anomalies.push_back(anomaly);
+    }
+}
+
+/// An instruction, or an interrupt entry, as it was executed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Executed {
+    /// The registers beforehand, with PC at the instruction.
+    pub state: Nise6502State,
+    /// The bytes at PC at the time, which bank switching may since have
+    /// replaced.
+    pub bytes: [u8; 3],
+    /// Set when an interrupt was taken instead of running the instruction.
+    pub interrupt: Option<Interrupt>,
+}
+
+impl Executed {
+    pub fn instruction(&self, variant: Variant) -> Instruction {
+        decode(variant, &self.bytes, self.state.pc).unwrap()
+    }
+}
+
+/// A ring buffer of the last instructions executed, for post-mortem dumps.
+#[derive(Clone, Debug, Default)]
+pub struct History {
+    entries: VecDeque<Executed>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// How many instructions are kept. Zero keeps none.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The instructions, oldest first.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Executed> + ExactSizeIterator {
+        self.entries.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub(crate) fn push(&mut self, executed: Executed) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(executed);
+    }
+}
@@ -0,0 +1,155 @@
+use std::ops::RangeInclusive;
+
+use crate::nes::cpu::Nise6502;
+
+/// Names a hook, to remove it by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct HookId(u32);
+
+/// Sees the address and value of a read, and returns a value to read
+/// instead, if any.
+pub type ReadHook = Box<dyn FnMut(u16, u8) -> Option<u8> + Send>;
+
+/// Sees the address and value of a write, and returns false to keep it from
+/// reaching memory.
+pub type WriteHook = Box<dyn FnMut(u16, u8) -> bool + Send>;
+
+/// Sees the CPU about to execute the instruction at an address, and may
+/// change it.
+pub type ExecuteHook<B> = Box<dyn FnMut(&mut Nise6502<B>) + Send>;
+
+// A hook and the addresses it watches
+type Hook<H> = (HookId, RangeInclusive<u16>, H);
+
+/// Callbacks on the accesses made to one address space, each over a range
+/// of addresses. Hooks are called in the order they were added. When none
+/// are, checking for them costs a length comparison per access.
+#[derive(Default)]
+pub struct MemoryHooks {
+    reads: Vec<Hook<ReadHook>>,
+    writes: Vec<Hook<WriteHook>>,
+    fetches: Vec<Hook<ReadHook>>,
+    next_id: u32,
+}
+
+impl MemoryHooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Calls `hook` on every read in `range`, opcode fetches included. Each
+    /// hook sees the value the one before it left.
+    pub fn add_read(
+        &mut self,
+        range: RangeInclusive<u16>,
+        hook: impl FnMut(u16, u8) -> Option<u8> + Send + 'static,
+    ) -> HookId {
+        let id = self.next_id();
+        self.reads.push((id, range, Box::new(hook)));
+        id
+    }
+
+    /// Calls `hook` on every write in `range`. A write any hook vetoes still
+    /// takes its cycle, but leaves memory alone.
+    pub fn add_write(
+        &mut self,
+        range: RangeInclusive<u16>,
+        hook: impl FnMut(u16, u8) -> bool + Send + 'static,
+    ) -> HookId {
+        let id = self.next_id();
+        self.writes.push((id, range, Box::new(hook)));
+        id
+    }
+
+    /// Calls `hook` on opcode fetches in `range`, after the read hooks. Only
+    /// the CPU fetches instructions.
+    pub fn add_fetch(
+        &mut self,
+        range: RangeInclusive<u16>,
+        hook: impl FnMut(u16, u8) -> Option<u8> + Send + 'static,
+    ) -> HookId {
+        let id = self.next_id();
+        self.fetches.push((id, range, Box::new(hook)));
+        id
+    }
+
+    pub fn remove(&mut self, id: HookId) -> bool {
+        let before = self.len();
+        self.reads.retain(|(hook, _, _)| *hook != id);
+        self.writes.retain(|(hook, _, _)| *hook != id);
+        self.fetches.retain(|(hook, _, _)| *hook != id);
+        self.len() != before
+    }
+
+    pub fn clear(&mut self) {
+        self.reads.clear();
+        self.writes.clear();
+        self.fetches.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn len(&self) -> usize {
+        self.reads.len() + self.writes.len() + self.fetches.len()
+    }
+
+    // Hands out ids for other kinds of hooks too, so that all the hooks of
+    // one owner can be told apart
+    pub(crate) fn next_id(&mut self) -> HookId {
+        self.next_id += 1;
+        HookId(self.next_id)
+    }
+
+    // The value a read of `address` gives, once the hooks have seen it
+    #[inline]
+    pub(crate) fn read(&mut self, address: u16, value: u8) -> u8 {
+        if self.reads.is_empty() {
+            return value;
+        }
+        run_reads(&mut self.reads, address, value)
+    }
+
+    #[inline]
+    pub(crate) fn fetch(&mut self, address: u16, value: u8) -> u8 {
+        if self.fetches.is_empty() {
+            return value;
+        }
+        run_reads(&mut self.fetches, address, value)
+    }
+
+    // Whether the write should go ahead. Every hook in range sees it, even
+    // once one has vetoed it.
+    #[inline]
+    pub(crate) fn write(&mut self, address: u16, value: u8) -> bool {
+        if self.writes.is_empty() {
+            return true;
+        }
+        run_writes(&mut self.writes, address, value)
+    }
+}
+
+// The hooks themselves are called out of line, to keep the checks for them
+// small enough to inline into every access
+#[cold]
+#[inline(never)]
+fn run_reads(hooks: &mut [Hook<ReadHook>], address: u16, value: u8) -> u8 {
+    hooks
+        .iter_mut()
+        .filter(|(_, range, _)| range.contains(&address))
+        .fold(value, |value, (_, _, hook)| {
+            hook(address, value).unwrap_or(value)
+        })
+}
+
+#[cold]
+#[inline(never)]
+fn run_writes(hooks: &mut [Hook<WriteHook>], address: u16, value: u8) -> bool {
+    hooks
+        .iter_mut()
+        .filter(|(_, range, _)| range.contains(&address))
+        .fold(true, |allowed, (_, _, hook)| {
+            hook(address, value) && allowed
+        })
+}
@@ -0,0 +1,813 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, TryRecvError};
+use std::sync::Arc;
+
+use crate::common::bus::Bus;
+use crate::nes::bus::NiseBus;
+use crate::nes::cdl::{CODE, DATA, RENDERED};
+use crate::nes::cpu::{Flag, Nise6502State};
+use crate::nes::debugger::{Debugger, StopReason, WatchKind};
+use crate::nes::disasm::{decode_bus, disassemble_range, Instruction};
+use crate::nes::history::FrameKind;
+use crate::nes::symbols::{Location, Symbols};
+use crate::nes::trace::{TraceFormat, TraceWriter};
+
+// How long a command that runs the CPU may take: ten seconds of NTSC time
+const RUN_BUDGET: u64 = 1_789_773 * 10;
+
+// How many cycles an attached machine runs between checks for input: a frame
+const ATTACHED_CHUNK: u64 = 29_781;
+
+const HELP: &str = "\
+r [reg=value ...]     show or set registers (A X Y S P PC)
+d [start [end]]       disassemble, to an end address rather than a count
+m [start [end]]       dump memory, to an end address
+> address byte ...    write memory, ROM included
+bk [address]          set a breakpoint, or list breakpoints and watchpoints
+watch [r|w] start [end]
+                      set a watchpoint on reads, writes or both
+del [address]         delete a breakpoint, or all of them and all watchpoints
+z [count]             step into
+n                     step over
+ret                   step out
+until address         run to an address
+g [address]           continue, from an address if given
+bz [count]            step back
+bg                    continue backwards, to the last breakpoint or watchpoint
+lw address            show the instruction that last wrote to an address
+reset                 reset the CPU
+ppu                   show PPU registers and position
+stack                 show the stack
+bt                    show the subroutines and interrupt handlers entered
+hist [count]          show the last instructions executed
+ll file               load labels from a .dbg, .nl or .mlb file
+al address label      add a label
+shl                   show labels
+cdl [start|stop]      show, start or stop the code/data log
+cdl save|load file    save or carry on from an FCEUX .cdl file
+prof [start|stop]     start or stop profiling
+prof [count]          show the routines and addresses that took longest
+prof folded file      save folded stacks for flame graphs
+trace file [format]   log each instruction, as nestest (default), fceux or mesen
+trace off             stop logging instructions
+x                     leave the monitor, resuming the machine when attached
+quit                  quit
+Addresses and bytes are in hex, with an optional $. Addresses can also be
+labels, as in ReadJoypad or ReadJoypad+3 with a decimal offset, or .cafe for a
+label that would otherwise read as hex. Counts are in decimal, or in hex after
+a $, so z 10 steps ten times.
+While an attached machine runs, typing a command breaks into the monitor.";
+
+/// Asks an attached machine to stop and enter the monitor, from another
+/// thread, or from a signal handler. Clones share the request.
+#[derive(Clone, Default)]
+pub struct BreakIn(Arc<AtomicBool>);
+
+impl BreakIn {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn request(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    // Whether a break was asked for since the last call
+    fn take(&self) -> bool {
+        self.0.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// A VICE style machine monitor. Each line of input is one command, and all
+/// output goes to the writer given to `run` or `execute`.
+pub struct Monitor {
+    debugger: Debugger<NiseBus>,
+    // Where `d` and `m` without arguments carry on from
+    next_disassembly: Option<u16>,
+    next_dump: Option<u16>,
+    break_in: BreakIn,
+    // Whether the command that left the monitor asked to quit altogether
+    quit: bool,
+}
+
+impl Monitor {
+    pub fn new(debugger: Debugger<NiseBus>) -> Self {
+        Self {
+            debugger,
+            next_disassembly: None,
+            next_dump: None,
+            break_in: BreakIn::new(),
+            quit: false,
+        }
+    }
+
+    /// Whether the command that last left the monitor was `quit`, rather
+    /// than `x`.
+    pub fn has_quit(&self) -> bool {
+        self.quit
+    }
+
+    /// A handle to stop the machine with while it runs attached.
+    pub fn break_in(&self) -> BreakIn {
+        self.break_in.clone()
+    }
+
+    pub fn debugger(&mut self) -> &mut Debugger<NiseBus> {
+        &mut self.debugger
+    }
+
+    pub fn into_debugger(self) -> Debugger<NiseBus> {
+        self.debugger
+    }
+
+    /// Executes commands from `input` until it runs out or one of them quits.
+    /// With `echo`, each command is written out after the prompt, as when
+    /// reading a script. Returns false if a command left the monitor.
+    pub fn run(
+        &mut self,
+        input: impl BufRead,
+        output: &mut impl Write,
+        echo: bool,
+    ) -> io::Result<bool> {
+        write!(output, "{}", self.prompt())?;
+        output.flush()?;
+        for line in input.lines() {
+            let line = line?;
+            if echo {
+                writeln!(output, "{}", line)?;
+            }
+            if !self.execute(&line, output)? {
+                return Ok(false);
+            }
+            write!(output, "{}", self.prompt())?;
+            output.flush()?;
+        }
+        Ok(true)
+    }
+
+    /// Attaches to the machine, as the main loop of the emulator. It runs,
+    /// starting out stopped unless `running`, until a breakpoint or a
+    /// watchpoint, a request through `break_in`, or a line of `input`, which
+    /// is then executed as the first command. `x` lets it run again. Returns
+    /// on `quit`, or once `input`, read on a thread of its own, runs out.
+    pub fn attach(
+        &mut self,
+        input: impl Read + Send + 'static,
+        output: &mut impl Write,
+        mut running: bool,
+    ) -> io::Result<()> {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(input).lines() {
+                let Ok(line) = line else { break };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        if !running {
+            write!(output, "{}", self.prompt())?;
+            output.flush()?;
+        }
+        loop {
+            let line = if running {
+                let line = match receiver.try_recv() {
+                    Ok(line) => Some(line),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                };
+                let text = match line.is_some() || self.break_in.take() {
+                    true => {
+                        self.next_disassembly = None;
+                        format!("(Break)\n{}", self.position())
+                    }
+                    false => match self.debugger.run(ATTACHED_CHUNK) {
+                        StopReason::CycleLimit => continue,
+                        reason => self.report(reason),
+                    },
+                };
+                running = false;
+                writeln!(output, "{}", text)?;
+                match line {
+                    Some(line) => line,
+                    None => {
+                        write!(output, "{}", self.prompt())?;
+                        output.flush()?;
+                        continue;
+                    }
+                }
+            } else {
+                match receiver.recv() {
+                    Ok(line) => line,
+                    Err(_) => return Ok(()),
+                }
+            };
+            if !self.execute(&line, output)? {
+                if self.quit {
+                    return Ok(());
+                }
+                running = true;
+                continue;
+            }
+            write!(output, "{}", self.prompt())?;
+            output.flush()?;
+        }
+    }
+
+    /// Executes one command. Returns false if it left the monitor.
+    pub fn execute(&mut self, line: &str, output: &mut impl Write) -> io::Result<bool> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Ok(true);
+        };
+        let args = words.collect::<Vec<_>>();
+        let result = match command.to_lowercase().as_str() {
+            "x" | "exit" => {
+                self.quit = false;
+                return Ok(false);
+            }
+            "q" | "quit" => {
+                self.quit = true;
+                return Ok(false);
+            }
+            "help" | "?" => Ok(HELP.to_string()),
+            "r" | "registers" => self.registers(&args.join(" ")),
+            "d" | "disass" => self.disassemble(&args),
+            "m" | "mem" => self.dump(&args),
+            ">" => self.write_memory(&args),
+            "bk" | "break" => self.add_breakpoint(&args),
+            "watch" | "w" => self.add_watchpoint(&args),
+            "del" | "delete" => self.delete(&args),
+            "z" | "step" => self.step(&args),
+            "n" | "next" => self.resume(|debugger| debugger.step_over(RUN_BUDGET)),
+            "ret" => self.resume(|debugger| debugger.step_out(RUN_BUDGET)),
+            "until" | "un" => match args.first().map(|arg| self.address(arg)) {
+                Some(Ok(address)) => self.resume(|debugger| debugger.run_to(address, RUN_BUDGET)),
+                Some(Err(error)) => Err(error),
+                None => Err("missing address".to_string()),
+            },
+            "g" | "goto" => self.go(&args),
+            "bz" | "back" => self.step_back(&args),
+            "bg" | "reverse" => self.resume(|debugger| debugger.reverse_continue()),
+            "lw" | "lastwrite" => self.last_write(&args),
+            "reset" => {
+                self.debugger.cpu_mut().reset();
+                Ok(self.position())
+            }
+            "ppu" => Ok(self.ppu()),
+            "stack" => Ok(self.stack()),
+            "bt" | "backtrace" => Ok(self.backtrace()),
+            "hist" | "history" => self.history(&args),
+            "ll" | "load_labels" => self.load_labels(&args),
+            "al" | "add_label" => self.add_label(&args),
+            "shl" | "show_labels" => Ok(self.show_labels()),
+            "cdl" => self.code_data_log(&args),
+            "prof" | "profile" => self.profile(&args),
+            "trace" | "tr" => self.trace(&args),
+            _ => Err(format!("unknown command `{}`, try `help`", command)),
+        };
+        match result {
+            Ok(text) if text.is_empty() => {}
+            Ok(text) => writeln!(output, "{}", text.trim_end())?,
+            Err(error) => writeln!(output, "error: {}", error)?,
+        }
+        Ok(true)
+    }
+
+    fn prompt(&self) -> String {
+        format!("(C:${:04X}) ", self.debugger.cpu().pc())
+    }
+
+    fn registers(&mut self, assignments: &str) -> Result<String, String> {
+        // Accept both `a=10` and `a = 10`
+        let assignments = assignments.replace('=', " = ");
+        let words = assignments.split_whitespace().collect::<Vec<_>>();
+        for assignment in words.chunks(3) {
+            let [register, "=", value] = assignment else {
+                return Err("expected register=value".to_string());
+            };
+            let value = self.address(value)?;
+            let cpu = self.debugger.cpu_mut();
+            match register.to_lowercase().as_str() {
+                "pc" => cpu.set_pc(value),
+                register => {
+                    let value = u8::try_from(value)
+                        .map_err(|_| format!("${:X} does not fit in {}", value, register))?;
+                    match register {
+                        "a" => cpu.set_a(value),
+                        "x" => cpu.set_x(value),
+                        "y" => cpu.set_y(value),
+                        "s" | "sp" => cpu.set_s(value),
+                        "p" => cpu.set_p(value),
+                        _ => return Err(format!("unknown register `{}`", register)),
+                    }
+                }
+            }
+        }
+        let cpu = self.debugger.cpu();
+        let flags = [
+            (Flag::Negative, 'N'),
+            (Flag::Overflow, 'V'),
+            (Flag::Break, 'B'),
+            (Flag::Decimal, 'D'),
+            (Flag::InterruptDisable, 'I'),
+            (Flag::Zero, 'Z'),
+            (Flag::Carry, 'C'),
+        ]
+        .iter()
+        .map(|(flag, name)| if cpu.flag(*flag) { *name } else { '.' })
+        .collect::<String>();
+        Ok(format!(
+            "  ADDR A  X  Y  SP NV-BDIZC CYCLES\n.;{:04X} {:02X} {:02X} {:02X} {:02X} {}-{} {}",
+            cpu.pc(),
+            cpu.a(),
+            cpu.x(),
+            cpu.y(),
+            cpu.s(),
+            &flags[..2],
+            &flags[2..],
+            cpu.cycles()
+        ))
+    }
+
+    fn disassemble(&mut self, args: &[&str]) -> Result<String, String> {
+        let cpu = self.debugger.cpu();
+        let start = match args.first() {
+            Some(start) => self.address(start)?,
+            None => self.next_disassembly.unwrap_or(cpu.pc()),
+        };
+        let instructions = match args.get(1) {
+            Some(end) => {
+                let end = end_address(start, self.address(end)?)?;
+                disassemble_range(cpu.variant(), cpu.bus(), start..=end)
+            }
+            None => {
+                let mut address = start;
+                (0..16)
+                    .map(|_| {
+                        let instruction = decode_bus(cpu.variant(), cpu.bus(), address);
+                        address = instruction.next_address();
+                        instruction
+                    })
+                    .collect()
+            }
+        };
+        let mut text = String::new();
+        for instruction in &instructions {
+            let bytes = instruction
+                .bytes()
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let marker = if instruction.address == cpu.pc() {
+                '>'
+            } else {
+                '.'
+            };
+            if let Some(label) = cpu.symbols().label(cpu.bus(), instruction.address) {
+                text += &format!("{}:\n", label);
+            }
+            text += &format!(
+                "{}C:{:04X}  {:<8}  {}\n",
+                marker,
+                instruction.address,
+                bytes,
+                self.instruction_text(instruction)
+            );
+        }
+        self.next_disassembly = instructions.last().map(|last| last.next_address());
+        Ok(text)
+    }
+
+    fn dump(&mut self, args: &[&str]) -> Result<String, String> {
+        let start = match args.first() {
+            Some(start) => self.address(start)?,
+            None => self.next_dump.unwrap_or(0),
+        };
+        let end = match args.get(1) {
+            Some(end) => end_address(start, self.address(end)?)?,
+            None => start.saturating_add(0x7F),
+        };
+        let bus = self.debugger.cpu().bus();
+        let mut text = String::new();
+        let mut line = start as u32;
+        while line <= end as u32 {
+            let bytes = (line..=(line + 15).min(end as u32))
+                .map(|address| bus.peek(address as u16))
+                .collect::<Vec<_>>();
+            let hex = bytes
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let ascii = bytes
+                .iter()
+                .map(|byte| match byte {
+                    0x20..=0x7E => *byte as char,
+                    _ => '.',
+                })
+                .collect::<String>();
+            text += &format!(">C:{:04X}  {:<47}  {}\n", line, hex, ascii);
+            line += 16;
+        }
+        self.next_dump = Some(end.wrapping_add(1));
+        Ok(text)
+    }
+
+    fn write_memory(&mut self, args: &[&str]) -> Result<String, String> {
+        let [address, bytes @ ..] = args else {
+            return Err("missing address".to_string());
+        };
+        let address = self.address(address)?;
+        for (offset, byte) in bytes.iter().enumerate() {
+            let byte = parse_number(byte)?;
+            let byte = u8::try_from(byte).map_err(|_| format!("${:X} is not a byte", byte))?;
+            self.debugger
+                .cpu_mut()
+                .bus_mut()
+                .poke(address.wrapping_add(offset as u16), byte);
+        }
+        Ok(String::new())
+    }
+
+    fn add_breakpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        if let Some(address) = args.first() {
+            let address = self.address(address)?;
+            self.debugger.add_breakpoint(address);
+            return Ok(String::new());
+        }
+        let mut text = String::new();
+        for address in self.debugger.breakpoints() {
+            text += &format!("BREAK: C:${:04X}{}\n", address, self.label_suffix(address));
+        }
+        for watchpoint in self.debugger.watchpoints() {
+            text += &format!(
+                "WATCH: C:${:04X}-${:04X} {:?}\n",
+                watchpoint.range.start(),
+                watchpoint.range.end(),
+                watchpoint.kind
+            );
+        }
+        Ok(text)
+    }
+
+    fn add_watchpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        let (kind, args) = match args.first().map(|arg| arg.to_lowercase()).as_deref() {
+            Some("r") => (WatchKind::Read, &args[1..]),
+            Some("w") => (WatchKind::Write, &args[1..]),
+            Some("rw") => (WatchKind::ReadWrite, &args[1..]),
+            _ => (WatchKind::ReadWrite, args),
+        };
+        let start = self.address(args.first().ok_or("missing address")?)?;
+        let end = match args.get(1) {
+            Some(end) => end_address(start, self.address(end)?)?,
+            None => start,
+        };
+        self.debugger.add_watchpoint(start..=end, kind);
+        Ok(String::new())
+    }
+
+    fn delete(&mut self, args: &[&str]) -> Result<String, String> {
+        match args.first() {
+            Some(address) => {
+                let address = self.address(address)?;
+                if !self.debugger.remove_breakpoint(address) {
+                    return Err(format!("no breakpoint at ${:04X}", address));
+                }
+            }
+            None => self.debugger.clear(),
+        }
+        Ok(String::new())
+    }
+
+    fn step(&mut self, args: &[&str]) -> Result<String, String> {
+        let count = match args.first() {
+            Some(count) => parse_count(count)?,
+            None => 1,
+        };
+        let mut reason = StopReason::Step;
+        for _ in 0..count {
+            reason = self.debugger.step_into();
+            if reason != StopReason::Step {
+                break;
+            }
+        }
+        Ok(self.report(reason))
+    }
+
+    fn step_back(&mut self, args: &[&str]) -> Result<String, String> {
+        let count = match args.first() {
+            Some(count) => parse_count(count)?,
+            None => 1,
+        };
+        let mut reason = StopReason::Step;
+        for _ in 0..count {
+            reason = self.debugger.step_back();
+            if reason != StopReason::Step {
+                break;
+            }
+        }
+        Ok(self.report(reason))
+    }
+
+    fn last_write(&mut self, args: &[&str]) -> Result<String, String> {
+        let address = self.address(args.first().ok_or("missing address")?)?;
+        let Some((state, write)) = self.debugger.last_write(address) else {
+            return Ok(format!("No write to {:04X} in the history kept", address));
+        };
+        Ok(format!(
+            "{:04X} = {:02X} written at cycle {} by {:04X}{}",
+            address,
+            write.value,
+            state.cycles,
+            state.pc,
+            self.label_suffix(state.pc)
+        ))
+    }
+
+    fn go(&mut self, args: &[&str]) -> Result<String, String> {
+        if let Some(address) = args.first() {
+            let address = self.address(address)?;
+            self.debugger.cpu_mut().set_pc(address);
+        }
+        self.resume(|debugger| debugger.run(RUN_BUDGET))
+    }
+
+    fn resume(
+        &mut self,
+        command: impl FnOnce(&mut Debugger<NiseBus>) -> StopReason,
+    ) -> Result<String, String> {
+        let reason = command(&mut self.debugger);
+        Ok(self.report(reason))
+    }
+
+    // Why execution stopped and where
+    fn report(&mut self, reason: StopReason) -> String {
+        self.next_disassembly = None;
+        let reason = match reason {
+            StopReason::Step | StopReason::RunTo(_) => String::new(),
+            StopReason::Breakpoint(address) => format!(
+                "#1 (Stop on exec {:04X}{})\n",
+                address,
+                self.label_suffix(address)
+            ),
+            StopReason::Opcode(opcode) => format!("#1 (Stop on opcode {:02X})\n", opcode),
+            StopReason::Watchpoint(access) => format!(
+                "#1 (Stop on {:?} {:04X} = {:02X})\n",
+                access.kind, access.address, access.value
+            ),
+            StopReason::Interrupt(interrupt) => format!("#1 (Stop on {:?})\n", interrupt),
+            StopReason::CycleLimit => "(Stopped after the cycle limit)\n".to_string(),
+            StopReason::HistoryStart => "(Stopped at the start of the history kept)\n".to_string(),
+            StopReason::Fault(status) => format!("(CPU {})\n", status),
+        };
+        reason + &self.position()
+    }
+
+    // The next instruction and the registers, on one line
+    fn position(&self) -> String {
+        let cpu = self.debugger.cpu();
+        let instruction = decode_bus(cpu.variant(), cpu.bus(), cpu.pc());
+        state_line(&self.instruction_text(&instruction), &cpu.state())
+    }
+
+    fn ppu(&self) -> String {
+        let ppu = self.debugger.cpu().bus().ppu();
+        format!(
+            "PPUCTRL:{:02X} PPUMASK:{:02X} PPUSTATUS:{:02X} OAMADDR:{:02X}\n\
+             V:{:04X} T:{:04X} X:{} W:{}\n\
+             SCANLINE:{} DOT:{}",
+            ppu.ppuctrl,
+            ppu.ppumask,
+            ppu.ppustatus,
+            ppu.oamaddr,
+            ppu.v,
+            ppu.t,
+            ppu.x,
+            ppu.w,
+            ppu.scanline(),
+            ppu.dot()
+        )
+    }
+
+    fn load_labels(&mut self, args: &[&str]) -> Result<String, String> {
+        let path = args.first().ok_or("missing file name")?;
+        let count = self.debugger.cpu_mut().symbols_mut().load(path)?;
+        Ok(format!("Loaded {} labels", count))
+    }
+
+    fn add_label(&mut self, args: &[&str]) -> Result<String, String> {
+        let [address, label] = args else {
+            return Err("expected an address and a label".to_string());
+        };
+        let address = self.address(address)?;
+        let cpu = self.debugger.cpu_mut();
+        let location = Symbols::location(cpu.bus(), address);
+        cpu.symbols_mut().insert(location, *label);
+        Ok(String::new())
+    }
+
+    fn show_labels(&self) -> String {
+        let cpu = self.debugger.cpu();
+        let mut text = String::new();
+        for (location, label) in cpu.symbols().iter() {
+            let address = match location {
+                Location::Cpu(address) => format!("C:${:04X}", address),
+                Location::Prg(offset) => match cpu.bus().prg_address(offset) {
+                    Some(address) => format!("C:${:04X}", address),
+                    None => format!("PRG:${:05X}", offset),
+                },
+            };
+            text += &format!("{} .{}\n", address, label);
+        }
+        text
+    }
+
+    fn code_data_log(&mut self, args: &[&str]) -> Result<String, String> {
+        let cpu = self.debugger.cpu_mut();
+        match *args {
+            [] => {}
+            ["start"] => {
+                cpu.bus_mut().start_code_data_log();
+                cpu.set_log_usage(true);
+            }
+            ["stop"] => {
+                cpu.bus_mut().stop_code_data_log();
+                cpu.set_log_usage(false);
+                return Ok(String::new());
+            }
+            ["load", path] => {
+                cpu.bus_mut().load_code_data_log(path)?;
+                cpu.set_log_usage(true);
+            }
+            ["save", path] => {
+                let log = cpu.bus().code_data_log().ok_or("not logging")?;
+                log.save(path)?;
+            }
+            _ => return Err("expected start, stop, save file or load file".to_string()),
+        }
+        let log = cpu.bus().code_data_log().ok_or("not logging")?;
+        Ok(format!(
+            "PRG: {} of {} bytes code, {} data\nCHR: {} of {} bytes rendered",
+            log.prg_count(CODE),
+            log.prg.len(),
+            log.prg_count(DATA),
+            log.chr_count(RENDERED),
+            log.chr.len()
+        ))
+    }
+
+    fn profile(&mut self, args: &[&str]) -> Result<String, String> {
+        let cpu = self.debugger.cpu_mut();
+        match *args {
+            ["start"] => cpu.start_profiling(),
+            ["stop"] => {
+                cpu.stop_profiling();
+            }
+            ["folded", path] => {
+                let profiler = cpu.profiler().ok_or("not profiling")?;
+                let folded = profiler.folded_with(|address| cpu.describe(address));
+                std::fs::write(path, folded).map_err(|e| format!("{}: {}", path, e))?;
+            }
+            [] | [_] => {
+                let count = match args.first() {
+                    Some(count) => parse_count(count)?,
+                    None => 10,
+                };
+                let profiler = cpu.profiler().ok_or("not profiling")?;
+                return Ok(profiler.report_with(count, |address| cpu.describe(address)));
+            }
+            _ => return Err("expected start, stop, a count or folded file".to_string()),
+        }
+        Ok(String::new())
+    }
+
+    fn trace(&mut self, args: &[&str]) -> Result<String, String> {
+        let cpu = self.debugger.cpu_mut();
+        let (path, format) = match *args {
+            ["off"] => {
+                cpu.set_tracer(None);
+                return Ok(String::new());
+            }
+            [path] => (path, TraceFormat::Nestest),
+            [path, "nestest"] => (path, TraceFormat::Nestest),
+            [path, "fceux"] => (path, TraceFormat::Fceux),
+            [path, "mesen"] => (path, TraceFormat::Mesen),
+            _ => return Err("expected off, or a file and nestest, fceux or mesen".to_string()),
+        };
+        let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        cpu.set_tracer(Some(Box::new(TraceWriter::new(
+            BufWriter::new(file),
+            format,
+        ))));
+        Ok(String::new())
+    }
+
+    // A number, or a label with an optional offset
+    fn address(&self, text: &str) -> Result<u16, String> {
+        let cpu = self.debugger.cpu();
+        let label = |text| cpu.symbols().resolve(cpu.bus(), text);
+        match text.strip_prefix('.') {
+            Some(text) => label(text).ok_or_else(|| format!("no label `{}`", text)),
+            None => parse_number(text).or_else(|_| {
+                label(text).ok_or(format!("`{}` is not a hex number or a label", text))
+            }),
+        }
+    }
+
+    fn label_suffix(&self, address: u16) -> String {
+        match self.debugger.cpu().describe(address) {
+            Some(label) => format!(" ({})", label),
+            None => String::new(),
+        }
+    }
+
+    fn instruction_text(&self, instruction: &Instruction) -> String {
+        instruction.to_string_with(|address| self.debugger.cpu().describe(address))
+    }
+
+    // The innermost frame first, each shown by where it was entered from
+    fn backtrace(&self) -> String {
+        let cpu = self.debugger.cpu();
+        let mut text = format!("#0  C:{:04X}{}\n", cpu.pc(), self.label_suffix(cpu.pc()));
+        for (depth, frame) in cpu.call_stack().frames().iter().rev().enumerate() {
+            let kind = match frame.kind {
+                FrameKind::Jsr => "JSR",
+                FrameKind::Brk => "BRK",
+                FrameKind::Nmi => "NMI",
+                FrameKind::Irq => "IRQ",
+            };
+            text += &format!(
+                "#{:<2} C:{:04X}{}  {} ${:04X}{}\n",
+                depth + 1,
+                frame.call_site,
+                self.label_suffix(frame.call_site),
+                kind,
+                frame.entry,
+                self.label_suffix(frame.entry)
+            );
+        }
+        text
+    }
+
+    fn history(&self, args: &[&str]) -> Result<String, String> {
+        let count = match args.first() {
+            Some(count) => parse_count(count)?,
+            None => 16,
+        };
+        let cpu = self.debugger.cpu();
+        let history = cpu.history();
+        let mut text = String::new();
+        for executed in history.iter().skip(history.len().saturating_sub(count)) {
+            let instruction = match executed.interrupt {
+                Some(interrupt) => format!("({:?})", interrupt).to_uppercase(),
+                None => self.instruction_text(&executed.instruction(cpu.variant())),
+            };
+            text += &state_line(&instruction, &executed.state);
+            text += "\n";
+        }
+        Ok(text)
+    }
+
+    fn stack(&self) -> String {
+        let cpu = self.debugger.cpu();
+        let mut text = format!("SP:{:02X}\n", cpu.s());
+        for s in (cpu.s() as u16 + 1)..=0xFF {
+            let address = 0x100 + s;
+            text += &format!(">C:{:04X}  {:02X}\n", address, cpu.bus().peek(address));
+        }
+        text
+    }
+}
+
+fn state_line(instruction: &str, state: &Nise6502State) -> String {
+    format!(
+        ".C:{:04X}  {:<14} - A:{:02X} X:{:02X} Y:{:02X} SP:{:02X} P:{:02X} CYC:{}",
+        state.pc, instruction, state.a, state.x, state.y, state.s, state.p, state.cycles
+    )
+}
+
+fn parse_number(text: &str) -> Result<u16, String> {
+    let digits = text.strip_prefix('$').unwrap_or(text);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("`{}` is not a hex number", text))
+}
+
+// The end of a range, which VICE takes as an address and not a length
+fn end_address(start: u16, end: u16) -> Result<u16, String> {
+    match end >= start {
+        true => Ok(end),
+        false => Err(format!("the end, {:04X}, is before the start", end)),
+    }
+}
+
+// Counts are decimal, as in VICE, unless they start with a $
+fn parse_count(text: &str) -> Result<usize, String> {
+    match text.strip_prefix('$') {
+        Some(digits) => usize::from_str_radix(digits, 16),
+        None => text.parse(),
+    }
+    .map_err(|_| format!("`{}` is not a count", text))
+}
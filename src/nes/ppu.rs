@@ -1,5 +1,8 @@
 use crate::common::to_u16;
+use crate::nes::cdl::{READ, RENDERED};
+use crate::nes::hooks::MemoryHooks;
 
+#[derive(Clone)]
 pub struct NisePPU {
     pub ppuctrl: u8,
     pub ppumask: u8,
@@ -8,14 +11,20 @@ pub struct NisePPU {
     pub oamdata: u8,
     pub ppuscroll: u8,
     pub ppuaddr: u8,
+    /// The PPUDATA read buffer, which a read returns before refilling it.
     pub ppudata: u8,
     pub oamdma: u8,
     chr_rom: Vec<u8>,
+    // 8 KiB of pattern tables in RAM, in place of CHR ROM if there is none
+    chr_ram: Vec<u8>,
+    // Code/data log flags for each byte of CHR ROM, while logging
+    pub(crate) chr_log: Option<Vec<u8>>,
     video_buffer: [u8; 240],
     oam: [u8; 256],
     internal_oam: [u8; 32],
     found_sprites: usize,
     vram: [u8; 2048],
+    palette: [u8; 32],
     mirroring: fn(u16) -> usize,
     pub v: u16,
     pub t: u16,
@@ -38,12 +47,19 @@ impl NisePPU {
             ppuaddr,
             ppudata,
             oamdma,
+            chr_ram: if chr_rom.is_empty() {
+                vec![0; 0x2000]
+            } else {
+                vec![]
+            },
             chr_rom,
+            chr_log: None,
             video_buffer: [0; 240],
             oam: [0; 256],
             internal_oam: [0; 32],
             found_sprites: 0,
             vram: [0; 2048],
+            palette: [0; 32],
             mirroring,
             v: 0,
             t: 0,
@@ -52,14 +68,14 @@ impl NisePPU {
             cycle_count: 0,
         }
     }
-    pub fn tick(&mut self) {
+    pub fn tick(&mut self, hooks: &mut MemoryHooks) {
         // TODO: Improve cycle accuracy?
         let current_scanline = self.cycle_count / 341;
         let _current_cycle = self.cycle_count % 341;
         match current_scanline {
             1..=239 => {
                 for _byte_num in 0..32 {
-                    let nametable_entry = self.read(0x2000 + self.v);
+                    let nametable_entry = self.read(0x2000 + self.v, hooks);
 
                     let palette_index = self.v % 960;
 
@@ -68,42 +84,26 @@ impl NisePPU {
                             + 960 * (self.v / 960)
                             + palette_index % 8
                             + (palette_index / 64 * 8),
-                    ) >> (palette_index / 2) % 2 + 2 * (palette_index / 32) % 2)
+                        hooks,
+                    ) >> ((palette_index / 2) % 2 + 2 * (palette_index / 32) % 2))
                         & 0x03;
 
                     let pattern_data =
-                        self.read16(((self.ppuctrl & 0x10) << 8 + nametable_entry) as u16);
+                        self.read16(((self.ppuctrl & 0x10) << (8 + nametable_entry)) as u16, hooks);
                     for k in 0..8 {
                         let pattern_index = ((pattern_data & (1 << k)) >> k)
                             & ((pattern_data & (1 << (8 + k))) >> (7 + k));
                         let pixel_color =
-                            self.read(0x3F00 + (bg_palette << 2) as u16 + pattern_index as u16);
+                            self.read(0x3F00 + (bg_palette << 2) as u16 + pattern_index, hooks);
                         self.video_buffer[(self.v * 8) as usize + k] = pixel_color;
                     }
 
                     self.v += 1;
                 }
 
-                for i in (self.found_sprites - 1)..=0 {
-                    let _attributes = self.internal_oam[4 * i + 2];
-                    let x_pos = self.internal_oam[4 * i + 3];
-                    let pattern_table_addr = if self.sprite_height() == 8 {
-                        ((self.ppuctrl & 0x08) << 9) + self.internal_oam[4 * i + 1]
-                    } else {
-                        ((self.internal_oam[4 * i + 1] & 0x01) << 12)
-                            + (self.internal_oam[4 * i + 1] >> 1)
-                    };
-
-                    for x in x_pos..=std::cmp::max(x_pos + 7, 0xFF) {
-                        let pixel_color =
-                            self.read(0x3F00 + (bg_palette << 2) as u16 + pattern_index as u16);
-
-                        self.video_buffer[256 * current_scanline + x as usize] = self.;
-                    }
-                }
+                // TODO: Draw the sprites found for this line
 
-                // Sprite 0 Hit
-                if self.internal_oam[0..4] == self.oam[0..4] {}
+                // TODO: Sprite 0 hit
 
                 (self.internal_oam, self.found_sprites) = self.sprite_evaluation(current_scanline);
             }
@@ -113,6 +113,158 @@ impl NisePPU {
         self.cycle_count += 341;
     }
 
+    // Advances the PPU by a single dot. Only frame timing is modelled here:
+    // the vblank flag and the NMI output it drives.
+    pub fn clock(&mut self) {
+        self.cycle_count = (self.cycle_count + 1) % (341 * 262);
+        match (self.cycle_count / 341, self.cycle_count % 341) {
+            (241, 1) => self.ppustatus |= 0x80,
+            (261, 1) => self.ppustatus &= 0x1F,
+            _ => {}
+        }
+    }
+
+    pub fn nmi_line(&self) -> bool {
+        self.ppuctrl & 0x80 != 0 && self.ppustatus & 0x80 != 0
+    }
+
+    pub fn scanline(&self) -> usize {
+        self.cycle_count / 341
+    }
+
+    pub fn dot(&self) -> usize {
+        self.cycle_count % 341
+    }
+
+    pub fn chr_rom(&self) -> &[u8] {
+        &self.chr_rom
+    }
+
+    /// Object attribute memory: four bytes for each of the 64 sprites.
+    pub fn oam(&self) -> &[u8; 256] {
+        &self.oam
+    }
+
+    // OAMDATA reads the byte at OAMADDR, and writes store there and move on
+    pub(crate) fn read_oam(&self) -> u8 {
+        self.oam[self.oamaddr as usize]
+    }
+
+    pub(crate) fn write_oam(&mut self, data: u8) {
+        self.oam[self.oamaddr as usize] = data;
+        self.oamaddr = self.oamaddr.wrapping_add(1);
+        self.oamdata = data;
+    }
+
+    // PPUCTRL also selects the nametable to start rendering from, in t
+    pub(crate) fn write_ctrl(&mut self, data: u8) {
+        self.ppuctrl = data;
+        self.t = (self.t & !0x0C00) | ((data as u16 & 0x03) << 10);
+    }
+
+    // Reading PPUSTATUS ends vblank and resets the write toggle
+    pub(crate) fn read_status(&mut self) -> u8 {
+        let status = self.ppustatus;
+        self.ppustatus &= 0x7F;
+        self.w = 0;
+        status
+    }
+
+    // PPUSCROLL takes coarse and fine X, then coarse and fine Y, into t and x
+    pub(crate) fn write_scroll(&mut self, data: u8) {
+        self.ppuscroll = data;
+        let data = data as u16;
+        if self.w == 0 {
+            self.t = (self.t & !0x001F) | (data >> 3);
+            self.x = data & 0x07;
+        } else {
+            self.t = (self.t & !0x73E0) | ((data & 0x07) << 12) | ((data & 0xF8) << 2);
+        }
+        self.w ^= 1;
+    }
+
+    // PPUADDR takes the high, then the low byte of t, and then copies t to v
+    pub(crate) fn write_addr(&mut self, data: u8) {
+        self.ppuaddr = data;
+        let data = data as u16;
+        if self.w == 0 {
+            self.t = (self.t & 0x00FF) | ((data & 0x3F) << 8);
+        } else {
+            self.t = (self.t & 0xFF00) | data;
+            self.v = self.t;
+        }
+        self.w ^= 1;
+    }
+
+    // PPUDATA reads return what the read before left in the buffer, except
+    // for palette entries, which come back at once while the nametable byte
+    // underneath fills the buffer. `hooks` see both reads.
+    pub(crate) fn read_data(&mut self, hooks: &mut MemoryHooks) -> u8 {
+        let address = self.v & 0x3FFF;
+        if let (0x0000..=0x1FFF, Some(log)) = (address, &mut self.chr_log) {
+            if let Some(flags) = log.get_mut(address as usize) {
+                *flags |= READ;
+            }
+        }
+        let value = hooks.read(address, self.memory(address));
+        let value = match address {
+            0x3F00..=0x3FFF => {
+                self.ppudata = hooks.read(address & 0x2FFF, self.memory(address & 0x2FFF));
+                value
+            }
+            _ => std::mem::replace(&mut self.ppudata, value),
+        };
+        self.increment();
+        value
+    }
+
+    pub(crate) fn write_data(&mut self, data: u8, hooks: &mut MemoryHooks) {
+        let address = self.v & 0x3FFF;
+        if hooks.write(address, data) {
+            self.write_memory(address, data);
+        }
+        self.increment();
+    }
+
+    // What a PPUDATA read would return, leaving everything as it was
+    pub(crate) fn peek_data(&self) -> u8 {
+        match self.v & 0x3FFF {
+            address @ 0x3F00..=0x3FFF => self.memory(address),
+            _ => self.ppudata,
+        }
+    }
+
+    // PPUDATA moves v on across, or down a row of 32 tiles
+    fn increment(&mut self) {
+        let step = if self.ppuctrl & 0x04 == 0 { 1 } else { 32 };
+        self.v = (self.v + step) & 0x7FFF;
+    }
+
+    /// The byte at `address` in the PPU address space, $0000-$3FFF.
+    pub fn memory(&self, address: u16) -> u8 {
+        match address & 0x3FFF {
+            address @ 0x0000..=0x1FFF => match self.chr_ram.is_empty() {
+                true => self.chr_rom[address as usize],
+                false => self.chr_ram[address as usize],
+            },
+            address @ 0x2000..=0x3EFF => self.vram[(self.mirroring)(address)],
+            address => self.palette[palette_index(address)],
+        }
+    }
+
+    // CHR ROM cannot be written to
+    fn write_memory(&mut self, address: u16, data: u8) {
+        match address & 0x3FFF {
+            address @ 0x0000..=0x1FFF => {
+                if let Some(byte) = self.chr_ram.get_mut(address as usize) {
+                    *byte = data;
+                }
+            }
+            address @ 0x2000..=0x3EFF => self.vram[(self.mirroring)(address)] = data,
+            address => self.palette[palette_index(address)] = data,
+        }
+    }
+
     fn sprite_height(&self) -> usize {
         if self.ppuctrl & 0b0001_0000 == 0 {
             8
@@ -121,18 +273,20 @@ impl NisePPU {
         }
     }
 
-    fn read(&self, address: u16) -> u8 {
-        let mirroring = self.mirroring;
-        match address {
-            0x0..=0x1FFF => self.chr_rom[address as usize],
-            0x2000..=0x3EFF => self.vram[mirroring(address)],
-            _ => 0,
+    // The fetches made while rendering will go past the hooks too, once
+    // something calls tick
+    fn read(&mut self, address: u16, hooks: &mut MemoryHooks) -> u8 {
+        if let (0x0..=0x1FFF, Some(log)) = (address, &mut self.chr_log) {
+            if let Some(flags) = log.get_mut(address as usize) {
+                *flags |= RENDERED;
+            }
         }
+        hooks.read(address, self.memory(address))
     }
 
-    fn read16(&mut self, address: u16) -> u16 {
-        let low_byte = self.read(address);
-        let high_byte = self.read(address + 1);
+    fn read16(&mut self, address: u16, hooks: &mut MemoryHooks) -> u16 {
+        let low_byte = self.read(address, hooks);
+        let high_byte = self.read(address + 1, hooks);
         to_u16(low_byte, high_byte)
     }
 
@@ -159,3 +313,13 @@ impl NisePPU {
         (internal_oam, found_sprites)
     }
 }
+
+// The backdrop colour of each sprite palette is that of the matching
+// background palette
+fn palette_index(address: u16) -> usize {
+    let index = address as usize & 0x1F;
+    match index {
+        0x10 | 0x14 | 0x18 | 0x1C => index - 0x10,
+        _ => index,
+    }
+}
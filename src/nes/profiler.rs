@@ -0,0 +1,246 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::nes::cpu::Interrupt;
+use crate::nes::history::{CallStack, FrameKind};
+
+/// Cycles spent between two NMIs, which is a video frame on the NES.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FrameCycles {
+    pub cycles: u64,
+    /// Cycles with the NMI handler on the call stack.
+    pub nmi: u64,
+}
+
+/// Cycles spent in a subroutine or interrupt handler, and in those it
+/// called.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Routine {
+    pub entry: u16,
+    pub calls: u64,
+    /// Cycles with the routine anywhere on the call stack.
+    pub inclusive: u64,
+    /// Cycles with the routine at the top of the call stack.
+    pub exclusive: u64,
+}
+
+/// Counts the cycles taken by each instruction, by the chain of routines it
+/// ran in, and by each frame. See `Nise6502::start_profiling`.
+#[derive(Clone, Debug)]
+pub struct Profiler {
+    cycles: Vec<u64>,
+    counts: Vec<u64>,
+    // Cycles by the entries of the routines on the call stack, outermost
+    // first, and the chain seen after the last step
+    stacks: HashMap<Vec<u16>, u64>,
+    stack: Vec<u16>,
+    in_nmi: bool,
+    calls: HashMap<u16, u64>,
+    frames: Vec<FrameCycles>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self {
+            cycles: vec![0; 0x10000],
+            counts: vec![0; 0x10000],
+            stacks: HashMap::new(),
+            stack: vec![],
+            in_nmi: false,
+            calls: HashMap::new(),
+            frames: vec![FrameCycles::default()],
+        }
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accounts for a step of `cycles` that ran the instruction at
+    /// `address`, or entered an interrupt handler at `address`, leaving the
+    /// call stack as it is now.
+    pub(crate) fn record(
+        &mut self,
+        address: u16,
+        cycles: u32,
+        call_stack: &CallStack,
+        interrupt: Option<Interrupt>,
+    ) {
+        let cycles = cycles as u64;
+        self.cycles[address as usize] += cycles;
+        self.counts[address as usize] += 1;
+
+        // Calls and returns both count towards the routine they enter or
+        // leave, so the deeper of the stacks before and after is charged
+        let returned = call_stack.frames().len() < self.stack.len();
+        if !returned {
+            self.follow(call_stack);
+        }
+        match self.stacks.get_mut(&self.stack) {
+            Some(total) => *total += cycles,
+            None => {
+                self.stacks.insert(self.stack.clone(), cycles);
+            }
+        }
+
+        if interrupt == Some(Interrupt::Nmi) {
+            self.frames.push(FrameCycles::default());
+        }
+        let frame = self.frames.last_mut().unwrap();
+        frame.cycles += cycles;
+        if self.in_nmi {
+            frame.nmi += cycles;
+        }
+        if returned {
+            self.follow(call_stack);
+        }
+    }
+
+    fn follow(&mut self, call_stack: &CallStack) {
+        let frames = call_stack.frames();
+        if frames
+            .iter()
+            .map(|frame| frame.entry)
+            .eq(self.stack.iter().copied())
+        {
+            return;
+        }
+        if frames.len() > self.stack.len() {
+            *self.calls.entry(frames.last().unwrap().entry).or_default() += 1;
+        }
+        self.stack.clear();
+        self.stack.extend(frames.iter().map(|frame| frame.entry));
+        self.in_nmi = frames.iter().any(|frame| frame.kind == FrameKind::Nmi);
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.cycles.iter().sum()
+    }
+
+    /// Cycles taken by the instruction at `address`, and how many times it ran.
+    pub fn address(&self, address: u16) -> (u64, u64) {
+        (self.cycles[address as usize], self.counts[address as usize])
+    }
+
+    /// The addresses that took the most cycles, most first.
+    pub fn hotspots(&self, count: usize) -> Vec<(u16, u64)> {
+        let mut hotspots = (0..=0xFFFF)
+            .filter(|&address| self.cycles[address as usize] > 0)
+            .map(|address| (address, self.cycles[address as usize]))
+            .collect::<Vec<_>>();
+        hotspots.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hotspots.truncate(count);
+        hotspots
+    }
+
+    /// Every routine entered, by the cycles spent in it and what it called,
+    /// most first.
+    pub fn routines(&self) -> Vec<Routine> {
+        let mut routines = BTreeMap::<u16, Routine>::new();
+        for (stack, &cycles) in &self.stacks {
+            for (depth, &entry) in stack.iter().enumerate() {
+                let routine = routines.entry(entry).or_insert(Routine {
+                    entry,
+                    calls: self.calls.get(&entry).copied().unwrap_or(0),
+                    ..Routine::default()
+                });
+                // Recursion counts once
+                if !stack[..depth].contains(&entry) {
+                    routine.inclusive += cycles;
+                }
+                if depth == stack.len() - 1 {
+                    routine.exclusive += cycles;
+                }
+            }
+        }
+        let mut routines = routines.into_values().collect::<Vec<_>>();
+        routines.sort_by_key(|routine| std::cmp::Reverse(routine.inclusive));
+        routines
+    }
+
+    /// Cycles by frame. The first frame runs from the start of profiling to
+    /// the first NMI, and the last one up to now.
+    pub fn frames(&self) -> &[FrameCycles] {
+        &self.frames
+    }
+
+    /// A plain text report of where the time went, naming addresses with
+    /// `name` where it can.
+    pub fn report_with(&self, count: usize, name: impl Fn(u16) -> Option<String>) -> String {
+        let total = self.total_cycles().max(1);
+        let percent = |cycles: u64| cycles as f64 * 100.0 / total as f64;
+        let describe = |address: u16| match name(address) {
+            Some(name) => format!("${:04X} {}", address, name),
+            None => format!("${:04X}", address),
+        };
+        let mut text = format!("{} cycles\n", self.total_cycles());
+
+        // Frames cut short by the start of profiling or by now are left out
+        let whole = match self.frames.len() {
+            0..=2 => &self.frames[..0],
+            length => &self.frames[1..length - 1],
+        };
+        if !whole.is_empty() {
+            let average = |cycles: fn(&FrameCycles) -> u64| {
+                whole.iter().map(cycles).sum::<u64>() / whole.len() as u64
+            };
+            let most = |cycles: fn(&FrameCycles) -> u64| whole.iter().map(cycles).max().unwrap();
+            text += &format!(
+                "{} frames: {} cycles on average, {} at most; NMI {} on average, {} at most\n",
+                whole.len(),
+                average(|frame| frame.cycles),
+                most(|frame| frame.cycles),
+                average(|frame| frame.nmi),
+                most(|frame| frame.nmi)
+            );
+        }
+
+        text += &format!(
+            "\n{:>16} {:>16} {:>9}  Routine\n",
+            "Inclusive", "Exclusive", "Calls"
+        );
+        for routine in self.routines().iter().take(count) {
+            text += &format!(
+                "{:>9} {:5.1}% {:>9} {:5.1}% {:>9}  {}\n",
+                routine.inclusive,
+                percent(routine.inclusive),
+                routine.exclusive,
+                percent(routine.exclusive),
+                routine.calls,
+                describe(routine.entry)
+            );
+        }
+
+        text += &format!("\n{:>16} {:>9}  Address\n", "Cycles", "Count");
+        for (address, cycles) in self.hotspots(count) {
+            text += &format!(
+                "{:>9} {:5.1}% {:>9}  {}\n",
+                cycles,
+                percent(cycles),
+                self.counts[address as usize],
+                describe(address)
+            );
+        }
+        text
+    }
+
+    /// The cycles of each chain of routines, in the folded format that
+    /// flamegraph.pl and inferno take: `main;NMI;ReadJoypad 1234`.
+    pub fn folded_with(&self, name: impl Fn(u16) -> Option<String>) -> String {
+        let mut lines = self
+            .stacks
+            .iter()
+            .map(|(stack, cycles)| {
+                let mut line = "main".to_string();
+                for &entry in stack {
+                    line.push(';');
+                    line += &name(entry).unwrap_or_else(|| format!("${:04X}", entry));
+                }
+                format!("{} {}\n", line, cycles)
+            })
+            .collect::<Vec<_>>();
+        lines.sort();
+        lines.concat()
+    }
+}
@@ -20,7 +20,7 @@ pub struct Rom {
 }
 
 impl Rom {
-    pub fn new(raw: &Vec<u8>) -> Option<Rom> {
+    pub fn new(raw: &[u8]) -> Option<Rom> {
         if raw[0..=3] != [0x4E, 0x45, 0x53, 0x1A] {
             return None;
         }
@@ -50,21 +50,17 @@ impl Rom {
     }
 }
 
+// Where a nametable address, $2000-$3EFF, is in the PPU's 2 KiB of VRAM
 pub fn horizontal_mirrored_addr(address: u16) -> usize {
-    match address {
-        0x2400..=0x27FF | 0x2C00..=0x2FFF => address as usize - 0x400,
-        _ => address as usize,
-    }
+    ((address as usize >> 1) & 0x400) | (address as usize & 0x3FF)
 }
 
 pub fn vertical_mirrored_addr(address: u16) -> usize {
-    match address {
-        0x2800..=0x2FFF => address as usize - 0x800,
-        _ => address as usize,
-    }
+    address as usize & 0x7FF
 }
 
-// TODO: IMPLEMENT FOUR SCREEN MIRRORING
+// TODO: IMPLEMENT FOUR SCREEN MIRRORING, which needs 2 KiB more on the
+// cartridge. Until then it is vertical.
 pub fn four_screen_mirrored_addr(address: u16) -> usize {
-    address as usize
+    vertical_mirrored_addr(address)
 }
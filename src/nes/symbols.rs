@@ -0,0 +1,235 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use crate::common::bus::Bus;
+use crate::nes::dbginfo::DebugInfo;
+
+// How far past a label outside of ROM an address can be and still be named
+// after it, as in `buffer+4`
+const MAX_DATA_OFFSET: u16 = 0xFF;
+
+/// What a symbol names.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Location {
+    /// A CPU address outside of PRG ROM, such as RAM or a register.
+    Cpu(u16),
+    /// A byte of PRG ROM, seen at whichever address its bank is mapped to.
+    Prg(u32),
+}
+
+/// A table of labels, from ca65 `.dbg`, FCEUX `.nl` and Mesen `.mlb` files.
+///
+/// Labels in ROM are kept by their offset in PRG ROM, so that banks sharing
+/// an address keep their own labels. Every lookup goes through the bus to see
+/// which bank is mapped in at the moment.
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    names: BTreeMap<Location, String>,
+    locations: HashMap<String, Location>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Names `location`, replacing any name it had.
+    pub fn insert(&mut self, location: Location, name: impl Into<String>) {
+        let name = name.into();
+        if let Some(old) = self.names.insert(location, name.clone()) {
+            self.locations.remove(&old);
+        }
+        if let Some(old) = self.locations.insert(name, location) {
+            if old != location {
+                self.names.remove(&old);
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Location, &str)> {
+        self.names
+            .iter()
+            .map(|(location, name)| (*location, &name[..]))
+    }
+
+    /// Loads a file of labels, telling its format from its name: `.dbg`,
+    /// `.mlb`, or `.nl` with FCEUX's naming of `game.nes.ram.nl` for RAM and
+    /// `game.nes.0.nl` for the first 16 KiB bank. Returns how many labels
+    /// it added.
+    pub fn load(&mut self, path: impl AsRef<Path>) -> Result<usize, String> {
+        let path = path.as_ref();
+        let error = |error: String| format!("{}: {}", path.display(), error);
+        let text = std::fs::read_to_string(path).map_err(|e| error(e.to_string()))?;
+        let count = self.len();
+        let name = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_lowercase();
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("dbg") => self.add_debug_info(&DebugInfo::parse(&text).map_err(error)?),
+            Some("mlb") => self.add_mlb(&text).map_err(error)?,
+            Some("nl") => {
+                let bank = match name.trim_end_matches(".nl").rsplit('.').next() {
+                    Some("ram") => None,
+                    bank => Some(bank.and_then(|bank| bank.parse().ok()).ok_or_else(|| {
+                        error("expected a name like game.nes.0.nl or game.nes.ram.nl".into())
+                    })?),
+                };
+                self.add_nl(&text, bank).map_err(error)?;
+            }
+            _ => return Err(error("not a .dbg, .nl or .mlb file".to_string())),
+        }
+        Ok(self.len() - count)
+    }
+
+    /// Adds the labels of ld65 debug information. Constants are left out,
+    /// as they need not be addresses.
+    pub fn add_debug_info(&mut self, info: &DebugInfo) {
+        for symbol in info.symbols().iter().filter(|symbol| symbol.label) {
+            let location = match symbol.prg_offset {
+                Some(offset) => Location::Prg(offset),
+                None => Location::Cpu(symbol.value),
+            };
+            self.insert(location, symbol.name.clone());
+        }
+    }
+
+    /// Adds the labels of an FCEUX `.nl` file, in lines like
+    /// `$C000#Reset#comment`, for a 16 KiB bank of PRG ROM, or for RAM when
+    /// `bank` is `None`.
+    pub fn add_nl(&mut self, text: &str, bank: Option<u32>) -> Result<(), String> {
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = || format!("line {}: expected $address#name#comment", number + 1);
+            let mut fields = line.split('#');
+            let address = fields.next().and_then(|address| address.strip_prefix('$'));
+            // Arrays are written `$0300/10`
+            let address = address.map(|address| address.split('/').next().unwrap());
+            let address = address
+                .and_then(|address| u16::from_str_radix(address, 16).ok())
+                .ok_or_else(error)?;
+            let name = fields.next().ok_or_else(error)?.trim();
+            if name.is_empty() {
+                continue;
+            }
+            let location = match bank {
+                Some(bank) => Location::Prg(bank * 0x4000 + (address & 0x3FFF) as u32),
+                None => Location::Cpu(address),
+            };
+            self.insert(location, name);
+        }
+        Ok(())
+    }
+
+    /// Adds the labels of a Mesen `.mlb` file, in lines like
+    /// `P:0010:Reset:comment` or `NesPrgRom:0010:Reset`. The address may be
+    /// a range, of which the label names the start.
+    pub fn add_mlb(&mut self, text: &str) -> Result<(), String> {
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = || format!("line {}: expected type:address:name", number + 1);
+            let mut fields = line.splitn(4, ':');
+            let (Some(kind), Some(address), Some(name)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                return Err(error());
+            };
+            let address = address.split('-').next().unwrap();
+            let address = u32::from_str_radix(address, 16).map_err(|_| error())?;
+            let location = match kind {
+                "P" | "NesPrgRom" => Location::Prg(address),
+                "R" | "NesInternalRam" => Location::Cpu(address as u16 & 0x7FF),
+                "S" | "W" | "NesSaveRam" | "NesWorkRam" => {
+                    Location::Cpu(0x6000 + (address as u16 & 0x1FFF))
+                }
+                "G" | "NesMemory" => Location::Cpu(address as u16),
+                // Labels in CHR and the like are of no use to the CPU
+                _ => continue,
+            };
+            if !name.is_empty() {
+                self.insert(location, name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Where `address` is, with the banks mapped in at the moment.
+    pub fn location<B: Bus + ?Sized>(bus: &B, address: u16) -> Location {
+        match bus.prg_offset(address) {
+            Some(offset) => Location::Prg(offset),
+            None => Location::Cpu(address),
+        }
+    }
+
+    /// The label of exactly `address`.
+    pub fn label<B: Bus + ?Sized>(&self, bus: &B, address: u16) -> Option<&str> {
+        self.names
+            .get(&Self::location(bus, address))
+            .map(|name| &name[..])
+    }
+
+    /// Names `address` after the closest label at or before it, as in
+    /// `ReadJoypad+3`. Labels in ROM name the rest of their bank, and other
+    /// labels only the next 255 bytes.
+    pub fn describe<B: Bus + ?Sized>(&self, bus: &B, address: u16) -> Option<String> {
+        let (name, offset) = match Self::location(bus, address) {
+            Location::Prg(offset) => {
+                let range = Location::Prg(0)..=Location::Prg(offset);
+                let (&Location::Prg(label), name) = self.names.range(range).next_back()? else {
+                    return None;
+                };
+                // The label has to be mapped in along with the address
+                let start = address.checked_sub(u16::try_from(offset - label).ok()?)?;
+                if bus.prg_offset(start) != Some(label) {
+                    return None;
+                }
+                (name, offset - label)
+            }
+            Location::Cpu(address) => {
+                let start = address.saturating_sub(MAX_DATA_OFFSET);
+                let range = Location::Cpu(start)..=Location::Cpu(address);
+                let (&Location::Cpu(label), name) = self.names.range(range).next_back()? else {
+                    return None;
+                };
+                (name, (address - label) as u32)
+            }
+        };
+        Some(match offset {
+            0 => name.clone(),
+            _ => format!("{}+{}", name, offset),
+        })
+    }
+
+    /// The address of a label, where its bank is mapped in at the moment.
+    pub fn address<B: Bus + ?Sized>(&self, bus: &B, name: &str) -> Option<u16> {
+        match *self.locations.get(name)? {
+            Location::Cpu(address) => Some(address),
+            Location::Prg(offset) => bus.prg_address(offset),
+        }
+    }
+
+    /// Reads what `describe` writes: a label, optionally followed by a
+    /// decimal offset as in `ReadJoypad+3`.
+    pub fn resolve<B: Bus + ?Sized>(&self, bus: &B, text: &str) -> Option<u16> {
+        let (name, offset) = match text.rsplit_once('+') {
+            Some((name, offset)) => (name, offset.parse::<u16>().ok()?),
+            None => (text, 0),
+        };
+        Some(self.address(bus, name)?.wrapping_add(offset))
+    }
+}
@@ -0,0 +1,306 @@
+use std::io::Write;
+
+use log::warn;
+
+use crate::common::bus::Bus;
+use crate::common::to_u16;
+use crate::nes::cpu::{Nise6502, Variant};
+use crate::nes::disasm::{decode_bus, AddressingMode, Instruction};
+use AddressingMode::*;
+
+/// Sees the CPU before each instruction it executes. Attach one with
+/// `Nise6502::set_tracer`. Closures taking the CPU are tracers too.
+pub trait Tracer<B: Bus> {
+    fn trace(&mut self, cpu: &Nise6502<B>);
+}
+
+impl<B: Bus, F: FnMut(&Nise6502<B>)> Tracer<B> for F {
+    fn trace(&mut self, cpu: &Nise6502<B>) {
+        self(cpu)
+    }
+}
+
+/// The trace log formats of other emulators, to compare traces against.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// As in the reference log of nestest.nes, including its `PPU:` and
+    /// `CYC:` columns.
+    Nestest,
+    /// As FCEUX's trace logger writes it, registers first.
+    Fceux,
+    /// As Mesen's default trace format.
+    Mesen,
+}
+
+impl TraceFormat {
+    /// The line for the instruction at PC, which is about to execute.
+    pub fn line<B: Bus>(self, cpu: &Nise6502<B>) -> String {
+        let instruction = decode_bus(cpu.variant(), cpu.bus(), cpu.pc());
+        let bytes = instruction
+            .bytes()
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>();
+        let (scanline, dot) = cpu.bus().video_position().unwrap_or((0, 0));
+        match self {
+            TraceFormat::Nestest => {
+                let text = format!(
+                    "{}{} {}",
+                    if instruction.unofficial { '*' } else { ' ' },
+                    instruction.mnemonic,
+                    nestest_operand(cpu, &instruction)
+                );
+                format!(
+                    "{:04X}  {:<8} {:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+                    cpu.pc(),
+                    bytes.join(" "),
+                    text.trim_end(),
+                    cpu.a(),
+                    cpu.x(),
+                    cpu.y(),
+                    cpu.p(),
+                    cpu.s(),
+                    scanline,
+                    dot,
+                    cpu.cycles()
+                )
+            }
+            TraceFormat::Fceux => {
+                let mut text = instruction.to_string_with(|address| cpu.describe(address));
+                if let Some(address) = effective_address(cpu, &instruction) {
+                    if !direct(instruction.mode) {
+                        text += &format!(" @ ${:04X}", address);
+                    }
+                    text += &format!(" = #${:02X}", cpu.bus().peek(address));
+                }
+                format!(
+                    "A:{:02X} X:{:02X} Y:{:02X} S:{:02X} P:{}  ${:04X}:{:<9} {}",
+                    cpu.a(),
+                    cpu.x(),
+                    cpu.y(),
+                    cpu.s(),
+                    flags(cpu.p()),
+                    cpu.pc(),
+                    bytes.join(" "),
+                    text
+                )
+            }
+            TraceFormat::Mesen => {
+                let mut text = instruction.to_string_with(|address| cpu.describe(address));
+                if let Some(address) = effective_address(cpu, &instruction) {
+                    if !direct(instruction.mode) {
+                        text += &format!(" [${:04X}]", address);
+                    }
+                    text += &format!(" = ${:02X}", cpu.bus().peek(address));
+                }
+                let bytes = bytes
+                    .iter()
+                    .map(|byte| format!("${} ", byte))
+                    .collect::<String>();
+                format!(
+                    "{:04X}  {:<12} {:<33} A:{:02X} X:{:02X} Y:{:02X} S:{:02X} P:{} V:{:<3} H:{:<3} Cycle:{}",
+                    cpu.pc(),
+                    bytes,
+                    text,
+                    cpu.a(),
+                    cpu.x(),
+                    cpu.y(),
+                    cpu.s(),
+                    flags(cpu.p()),
+                    scanline,
+                    dot,
+                    cpu.cycles()
+                )
+            }
+        }
+    }
+}
+
+/// When a `TraceWriter` starts or stops writing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceCondition {
+    /// PC reaches an address.
+    Address(u16),
+    /// The cycle counter reaches a count.
+    Cycle(u64),
+}
+
+impl TraceCondition {
+    fn met<B: Bus>(self, cpu: &Nise6502<B>) -> bool {
+        match self {
+            TraceCondition::Address(address) => cpu.pc() == address,
+            TraceCondition::Cycle(cycles) => cpu.cycles() >= cycles,
+        }
+    }
+}
+
+/// A tracer that writes a line in `format` for each instruction, from the
+/// start condition, if any, up to the stop condition.
+pub struct TraceWriter<W: Write> {
+    output: W,
+    format: TraceFormat,
+    start: Option<TraceCondition>,
+    stop: Option<TraceCondition>,
+    tracing: bool,
+    done: bool,
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(output: W, format: TraceFormat) -> Self {
+        Self {
+            output,
+            format,
+            start: None,
+            stop: None,
+            tracing: false,
+            done: false,
+        }
+    }
+
+    /// Waits for `condition` before writing anything.
+    pub fn set_start(&mut self, condition: Option<TraceCondition>) {
+        self.start = condition;
+    }
+
+    /// Stops writing for good once `condition` is met. The instruction that
+    /// meets it is left out.
+    pub fn set_stop(&mut self, condition: Option<TraceCondition>) {
+        self.stop = condition;
+    }
+}
+
+impl<B: Bus, W: Write> Tracer<B> for TraceWriter<W> {
+    fn trace(&mut self, cpu: &Nise6502<B>) {
+        if self.done {
+            return;
+        }
+        if !self.tracing {
+            self.tracing = self.start.is_none_or(|start| start.met(cpu));
+        }
+        if self.tracing && self.stop.is_some_and(|stop| stop.met(cpu)) {
+            self.done = true;
+        } else if self.tracing {
+            if let Err(error) = writeln!(self.output, "{}", self.format.line(cpu)) {
+                warn!("Trace stopped: {}", error);
+                self.done = true;
+            }
+        }
+    }
+}
+
+// Modes whose operand is the address itself
+fn direct(mode: AddressingMode) -> bool {
+    matches!(mode, ZeroPage | Absolute)
+}
+
+// The address the instruction at PC reads or writes as data, if any
+fn effective_address<B: Bus>(cpu: &Nise6502<B>, instruction: &Instruction) -> Option<u16> {
+    let bus = cpu.bus();
+    let operand = instruction.operand();
+    let pointer = |address: u8| {
+        to_u16(
+            bus.peek(address as u16),
+            bus.peek(address.wrapping_add(1) as u16),
+        )
+    };
+    match instruction.mode {
+        ZeroPage => Some(operand),
+        ZeroPageX => Some((operand as u8).wrapping_add(cpu.x()) as u16),
+        ZeroPageY => Some((operand as u8).wrapping_add(cpu.y()) as u16),
+        Absolute => (!matches!(instruction.mnemonic, "JMP" | "JSR")).then_some(operand),
+        AbsoluteX => Some(operand.wrapping_add(cpu.x() as u16)),
+        AbsoluteY => Some(operand.wrapping_add(cpu.y() as u16)),
+        IndexedIndirect => Some(pointer((operand as u8).wrapping_add(cpu.x()))),
+        IndirectIndexed => Some(pointer(operand as u8).wrapping_add(cpu.y() as u16)),
+        ZeroPageIndirect => Some(pointer(operand as u8)),
+        _ => None,
+    }
+}
+
+// The operand as nestest.log shows it, with the address and value it leads to
+fn nestest_operand<B: Bus>(cpu: &Nise6502<B>, instruction: &Instruction) -> String {
+    let bus = cpu.bus();
+    let operand = instruction.operand();
+    let label = |address: u16, text: String| cpu.describe(address).unwrap_or(text);
+    let Some(address) = effective_address(cpu, instruction) else {
+        return match instruction.mode {
+            Accumulator => "A".to_string(),
+            Immediate => format!("#${:02X}", operand),
+            Absolute | Relative => {
+                let target = instruction.target().unwrap();
+                label(target, format!("${:04X}", target))
+            }
+            // The NMOS 6502 fetches the high byte of the target without
+            // carrying into the high byte of the pointer
+            Indirect => {
+                let high = match cpu.variant() {
+                    Variant::Wdc65C02 => operand.wrapping_add(1),
+                    _ => (operand & 0xFF00) | (operand.wrapping_add(1) & 0x00FF),
+                };
+                let target = to_u16(bus.peek(operand), bus.peek(high));
+                format!("(${:04X}) = {:04X}", operand, target)
+            }
+            AbsoluteIndexedIndirect => {
+                let pointer = operand.wrapping_add(cpu.x() as u16);
+                let target = to_u16(bus.peek(pointer), bus.peek(pointer.wrapping_add(1)));
+                format!("(${:04X},X) = {:04X}", operand, target)
+            }
+            _ => instruction.operand_text(),
+        };
+    };
+    let value = bus.peek(address);
+    match instruction.mode {
+        ZeroPage => format!(
+            "{} = {:02X}",
+            label(address, format!("${:02X}", address)),
+            value
+        ),
+        Absolute => format!(
+            "{} = {:02X}",
+            label(address, format!("${:04X}", address)),
+            value
+        ),
+        ZeroPageX | ZeroPageY | AbsoluteX | AbsoluteY => {
+            let width = if instruction.length == 3 { 4 } else { 2 };
+            format!(
+                "${:0width$X},{} @ {:0width$X} = {:02X}",
+                operand,
+                if matches!(instruction.mode, ZeroPageX | AbsoluteX) {
+                    'X'
+                } else {
+                    'Y'
+                },
+                address,
+                value,
+                width = width
+            )
+        }
+        IndexedIndirect => format!(
+            "(${:02X},X) @ {:02X} = {:04X} = {:02X}",
+            operand,
+            (operand as u8).wrapping_add(cpu.x()),
+            address,
+            value
+        ),
+        IndirectIndexed => format!(
+            "(${:02X}),Y = {:04X} @ {:04X} = {:02X}",
+            operand,
+            address.wrapping_sub(cpu.y() as u16),
+            address,
+            value
+        ),
+        _ => format!("(${:02X}) = {:04X} = {:02X}", operand, address, value),
+    }
+}
+
+// P as in `nvUbdIzc`, capitals for the flags that are set
+fn flags(p: u8) -> String {
+    "nvubdizc"
+        .chars()
+        .enumerate()
+        .map(|(bit, flag)| match p & (0x80 >> bit) {
+            0 => flag,
+            _ => flag.to_ascii_uppercase(),
+        })
+        .collect()
+}
@@ -0,0 +1,130 @@
+use nise::common::bus::{Bus, FlatBus};
+use nise::nes::asm::assemble;
+use nise::nes::cpu::{Flag, Nise6502, Variant};
+use nise::nes::disasm::{decode, disassemble};
+use nise::nes::rom::Rom;
+
+// Every opcode should assemble back from its own disassembly. Unofficial
+// duplicates may come back as a different opcode with the same meaning.
+#[test]
+fn round_trips_disassembly() {
+    for variant in [Variant::Nmos6502, Variant::Wdc65C02] {
+        for opcode in 0..=0xFF {
+            let instruction = decode(variant, &[opcode, 0x34, 0x12], 0x8000).unwrap();
+            let source = format!(".org $8000\n{}", instruction);
+            let assembly = assemble(variant, &source).unwrap_or_else(|error| {
+                panic!("{:?} `{}`: {}", variant, instruction, error);
+            });
+            let bytes = &assembly.segments[0].bytes;
+            let reassembled = decode(variant, bytes, 0x8000).unwrap();
+            assert_eq!(reassembled.to_string(), instruction.to_string());
+            assert_eq!(reassembled.length as usize, bytes.len());
+        }
+    }
+}
+
+#[test]
+fn resolves_labels_and_expressions() {
+    let source = "
+        PPUCTRL = $2000
+        ptr = $10
+        .org $C000
+        start:  lda #<message       ; a forward reference
+                ldx #>message
+                sta PPUCTRL
+                lda ptr             ; zero page, as ptr is already known
+        @loop:  dex
+                bne @loop
+                jmp (vector)
+        next:   beq @loop
+        @loop:  rts
+        message: .byte \"HI\", 0, 'x'
+        vector: .word start, next + 2 * 3
+    ";
+    let assembly = assemble(Variant::Ricoh2A03, source).unwrap();
+    assert_eq!(assembly.symbols["start"], 0xC000);
+    assert_eq!(assembly.symbols["start@loop"], 0xC009);
+    assert_eq!(assembly.symbols["next@loop"], 0xC011);
+    assert_eq!(assembly.symbols["message"], 0xC012);
+    let listing = disassemble(
+        Variant::Ricoh2A03,
+        &assembly.segments[0].bytes[..0x12],
+        0xC000,
+    )
+    .iter()
+    .map(|instruction| instruction.to_string())
+    .collect::<Vec<_>>();
+    assert_eq!(
+        listing,
+        [
+            "LDA #$12",
+            "LDX #$C0",
+            "STA $2000",
+            "LDA $10",
+            "DEX",
+            "BNE $C009",
+            "JMP ($C016)",
+            "BEQ $C011",
+            "RTS"
+        ]
+    );
+    assert_eq!(
+        assembly.segments[0].bytes[0x12..],
+        [b'H', b'I', 0, b'x', 0x00, 0xC0, 0x15, 0xC0]
+    );
+}
+
+#[test]
+fn reports_errors_by_line() {
+    let error = assemble(Variant::Ricoh2A03, "nop\nbne far\n.res 200\nfar:").unwrap_err();
+    assert_eq!(error.line, 2);
+    let error = assemble(Variant::Ricoh2A03, "lda ($12)").unwrap_err();
+    assert_eq!(error.line, 1);
+    assert!(assemble(Variant::Wdc65C02, "lda ($12)").is_ok());
+}
+
+#[test]
+fn reports_overflowing_expressions() {
+    for expression in [
+        "$7FFFFFFFFFFFFFFF*2",
+        "$7FFFFFFFFFFFFFFF+1",
+        "-$7FFFFFFFFFFFFFFF-2",
+        "-(-$7FFFFFFFFFFFFFFF-1)",
+        "(-$7FFFFFFFFFFFFFFF-1)/-1",
+    ] {
+        let source = format!("nop\nvalue = {}", expression);
+        let error = assemble(Variant::Ricoh2A03, &source).unwrap_err();
+        assert_eq!((error.line, &error.message[..]), (2, "overflow"));
+    }
+    let error = assemble(Variant::Ricoh2A03, "value = 1/0").unwrap_err();
+    assert_eq!(error.message, "division by zero");
+}
+
+#[test]
+fn builds_a_runnable_rom() {
+    let source = "
+        .org $8000
+        reset:  ldx #5
+        @loop:  inx
+                cpx #$10
+                bne @loop
+                stx $00
+        halt:   jmp halt
+        .org $FFFA
+        .word 0, reset, 0
+    ";
+    let assembly = assemble(Variant::Ricoh2A03, source).unwrap();
+    let rom = Rom::new(&assembly.ines(2)).unwrap();
+    assert_eq!(rom.prg_rom.len(), 0x8000);
+
+    let mut bus = FlatBus::new();
+    bus.load(0x8000, &rom.prg_rom);
+    let mut cpu = Nise6502::new(bus);
+    cpu.reset();
+    while cpu.pc() != assembly.symbols["halt"] {
+        cpu.step();
+    }
+    assert_eq!(cpu.x(), 0x10);
+    assert_eq!(cpu.bus().peek(0x00), 0x10);
+    assert!(cpu.flag(Flag::Zero) && cpu.flag(Flag::Carry));
+}
@@ -0,0 +1,60 @@
+use nise::nes::asm::assemble;
+use nise::nes::bus::NiseBus;
+use nise::nes::cdl::{CodeDataLog, CODE, DATA, INDIRECT_CODE, INDIRECT_DATA};
+use nise::nes::cpu::{Nise6502, Variant};
+use nise::nes::rom::Rom;
+
+const PROGRAM: &str = "
+    .org $8000
+    reset:  ldx #0
+            lda table,x
+            lda #<table
+            sta $00
+            lda #>table
+            sta $01
+            ldy #1
+            lda ($00),y
+            jmp (vector)
+    .org $A000
+    target: nop
+    halt:   jmp halt
+    table:  .byte 1, 2, 3
+    vector: .word target
+    .org $FFFA
+    .word reset, reset, reset
+";
+
+#[test]
+fn logs_code_and_data_like_fceux() {
+    let assembly = assemble(Variant::Ricoh2A03, PROGRAM).unwrap();
+    let offset = |name: &str| assembly.symbols[name] as usize - 0x8000;
+    let mut cpu = Nise6502::new(NiseBus::new(Rom::new(&assembly.ines(2)).unwrap()));
+    assert_eq!(cpu.bus().code_data_log(), None);
+    cpu.bus_mut().start_code_data_log();
+    cpu.set_log_usage(true);
+    cpu.reset();
+    for _ in 0..12 {
+        cpu.step();
+    }
+
+    let log = cpu.bus().code_data_log().unwrap();
+    assert_eq!((log.prg.len(), log.chr.len()), (0x8000, 0x2000));
+    // The window of $8000-$FFFF each byte was seen through is in bits 2-3
+    assert_eq!(log.prg[offset("reset")], CODE);
+    assert_eq!(log.prg[offset("reset") + 3], CODE);
+    assert_eq!(log.prg[offset("table")], DATA | 0x04);
+    assert_eq!(log.prg[offset("table") + 1], DATA | INDIRECT_DATA | 0x04);
+    assert_eq!(log.prg[offset("table") + 2], 0);
+    assert_eq!(log.prg[offset("target")], CODE | INDIRECT_CODE | 0x04);
+    assert_eq!(log.prg[offset("halt") + 2], CODE | 0x04);
+    assert_eq!(log.prg[0x7FFC..], [DATA | 0x0C, DATA | 0x0C, 0, 0]);
+    assert_eq!(log.prg_count(CODE), 24);
+
+    let path = std::env::temp_dir().join(format!("nise-cdl-{}.cdl", std::process::id()));
+    log.save(&path).unwrap();
+    assert_eq!(std::fs::read(&path).unwrap().len(), 0xA000);
+    let mut cpu = Nise6502::new(NiseBus::new(Rom::new(&assembly.ines(1)).unwrap()));
+    assert!(cpu.bus_mut().load_code_data_log(&path).is_err());
+    assert_eq!(CodeDataLog::load(&path, 0x8000, 0x2000), Ok(log));
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,243 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use serde_json::{json, Value};
+
+use nise::nes::asm::assemble;
+use nise::nes::cpu::Variant;
+use nise::nes::dap::DapServer;
+use nise::nes::dbginfo::{DebugInfo, SourceLine};
+
+// Lines 3 to 8 of main.s
+const PROGRAM: &str = "
+        .org $C000
+reset:  lda #$42
+        sta $10
+        jsr sub
+halt:   jmp halt
+sub:    inc $10
+        rts
+        .org $FFFA
+        .word reset, reset, reset
+";
+
+// What ld65 would write for PROGRAM, trimmed down
+const DEBUG_INFO: &str = "\
+version\tmajor=2,minor=0
+file\tid=0,name=\"src/main.s\",size=140,mtime=0x00000000,mod=0
+line\tid=0,file=0,line=3,span=0
+line\tid=1,file=0,line=4,span=1
+line\tid=2,file=0,line=5,span=2
+line\tid=3,file=0,line=6,span=3
+line\tid=4,file=0,line=7,span=4
+line\tid=5,file=0,line=8,span=5
+seg\tid=0,name=\"CODE\",start=0x00C000,size=0x000D,addrsize=absolute,type=ro,oname=\"test.nes\",ooffs=16
+span\tid=0,seg=0,start=0,size=2
+span\tid=1,seg=0,start=2,size=2
+span\tid=2,seg=0,start=4,size=3
+span\tid=3,seg=0,start=7,size=3
+span\tid=4,seg=0,start=10,size=2
+span\tid=5,seg=0,start=12,size=1
+scope\tid=0,name=\"\",mod=0,size=13
+scope\tid=1,name=\"Sub\",mod=0,parent=0,size=3
+sym\tid=0,name=\"reset\",addrsize=absolute,scope=0,def=0,val=0xC000,seg=0,type=lab
+sym\tid=2,name=\"counter\",addrsize=zeropage,scope=0,def=1,val=0x10,type=equ
+sym\tid=3,name=\"@done\",addrsize=absolute,scope=1,def=5,val=0xC00C,parent=4,seg=0,type=lab
+sym\tid=4,name=\"inner\",addrsize=absolute,scope=1,def=5,val=0xC00A,seg=0,type=lab
+sym\tid=5,name=\"halt\",addrsize=absolute,scope=0,def=3,val=0xC007,seg=0,type=lab
+";
+
+struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    seq: u64,
+}
+
+impl Client {
+    fn receive(&mut self) -> Value {
+        let mut header = String::new();
+        self.reader.read_line(&mut header).unwrap();
+        let length = header
+            .trim()
+            .strip_prefix("Content-Length: ")
+            .unwrap()
+            .parse()
+            .unwrap();
+        self.reader.read_line(&mut header).unwrap();
+        let mut body = vec![0; length];
+        self.reader.read_exact(&mut body).unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    // Returns the body of the response, skipping events
+    fn request(&mut self, command: &str, arguments: Value) -> Value {
+        self.seq += 1;
+        let message = json!({
+            "seq": self.seq,
+            "type": "request",
+            "command": command,
+            "arguments": arguments,
+        })
+        .to_string();
+        write!(
+            self.writer,
+            "Content-Length: {}\r\n\r\n{}",
+            message.len(),
+            message
+        )
+        .unwrap();
+        loop {
+            let message = self.receive();
+            if message["type"] == "response" {
+                assert_eq!(message["request_seq"], self.seq);
+                assert_eq!(message["success"], true, "{}", message);
+                return message["body"].clone();
+            }
+        }
+    }
+
+    fn event(&mut self, event: &str) -> Value {
+        loop {
+            let message = self.receive();
+            if message["event"] == event {
+                return message["body"].clone();
+            }
+        }
+    }
+
+    fn stack_frame(&mut self) -> Value {
+        let trace = self.request("stackTrace", json!({ "threadId": 1 }));
+        trace["stackFrames"][0].clone()
+    }
+}
+
+fn write_files() -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("nise-dap-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let assembly = assemble(Variant::Ricoh2A03, PROGRAM).unwrap();
+    std::fs::write(directory.join("test.nes"), assembly.ines(1)).unwrap();
+    std::fs::write(directory.join("test.dbg"), DEBUG_INFO).unwrap();
+    directory
+}
+
+#[test]
+fn debugs_by_source_line() {
+    let directory = write_files();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = thread::spawn(move || DapServer::new().accept(&listener).unwrap());
+    let stream = TcpStream::connect(address).unwrap();
+    stream.set_nodelay(true).unwrap();
+    let mut client = Client {
+        reader: BufReader::new(stream.try_clone().unwrap()),
+        writer: stream,
+        seq: 0,
+    };
+
+    let capabilities = client.request("initialize", json!({ "adapterID": "nise" }));
+    assert_eq!(capabilities["supportsDisassembleRequest"], true);
+    client.request(
+        "launch",
+        json!({ "program": directory.join("test.nes"), "stopOnEntry": true }),
+    );
+    client.event("initialized");
+    let source = directory.join("src").join("main.s");
+    let breakpoints = client.request(
+        "setBreakpoints",
+        json!({ "source": { "path": source }, "breakpoints": [{ "line": 7 }, { "line": 20 }] }),
+    );
+    assert_eq!(breakpoints["breakpoints"][0]["verified"], true);
+    assert_eq!(breakpoints["breakpoints"][1]["verified"], false);
+    client.request("configurationDone", json!({}));
+    assert_eq!(client.event("stopped")["reason"], "entry");
+    let frame = client.stack_frame();
+    assert_eq!(frame["name"], "reset");
+    assert_eq!(frame["line"], 3);
+    assert_eq!(frame["source"]["path"], json!(source));
+
+    client.request("continue", json!({ "threadId": 1 }));
+    assert_eq!(client.event("stopped")["reason"], "breakpoint");
+    assert_eq!(client.stack_frame()["line"], 7);
+    client.request("next", json!({ "threadId": 1 }));
+    assert_eq!(client.stack_frame()["name"], "Sub::inner@done");
+    let trace = client.request("stackTrace", json!({ "threadId": 1 }));
+    assert_eq!(trace["totalFrames"], 2);
+    assert_eq!(trace["stackFrames"][1]["line"], 5);
+    client.request("stepOut", json!({ "threadId": 1 }));
+    assert_eq!(client.stack_frame()["line"], 6);
+
+    let registers = client.request("variables", json!({ "variablesReference": 1 }));
+    assert_eq!(
+        registers["variables"][0],
+        json!({ "name": "A", "value": "$42", "variablesReference": 0 })
+    );
+    let counter = client.request("evaluate", json!({ "expression": "counter" }));
+    assert_eq!(counter["result"], "$0010: $43");
+    client.request(
+        "writeMemory",
+        json!({ "memoryReference": "counter", "offset": 1, "data": "AQI=" }),
+    );
+    let memory = client.request(
+        "readMemory",
+        json!({ "memoryReference": "0x0010", "count": 3 }),
+    );
+    assert_eq!(memory["data"], "QwEC");
+    let disassembly = client.request(
+        "disassemble",
+        json!({ "memoryReference": "0xC007", "instructionOffset": -2, "instructionCount": 3, "resolveSymbols": true }),
+    );
+    let instructions = &disassembly["instructions"];
+    assert_eq!(instructions[0]["instruction"], "STA $10");
+    assert_eq!(instructions[1]["instruction"], "JSR Sub::inner");
+    assert_eq!(instructions[2]["symbol"], "halt");
+    assert_eq!(instructions[2]["address"], "0xC007");
+    // Far past either end of the address space
+    let disassembly = client.request(
+        "disassemble",
+        json!({ "memoryReference": "0xC000", "instructionOffset": -100_000_000_000i64, "instructionCount": 2 }),
+    );
+    let invalid = json!({ "address": "0x0000", "instruction": "", "presentationHint": "invalid" });
+    assert_eq!(disassembly["instructions"], json!([invalid, invalid]));
+    let disassembly = client.request(
+        "disassemble",
+        json!({ "memoryReference": "0xFFFF", "instructionCount": 2 }),
+    );
+    let instructions = &disassembly["instructions"];
+    assert_eq!(instructions[0]["address"], "0xFFFF");
+    assert_eq!(instructions[1]["presentationHint"], "invalid");
+
+    // The CPU spins at `halt` until paused
+    client.request(
+        "setBreakpoints",
+        json!({ "source": { "path": source }, "breakpoints": [] }),
+    );
+    client.request("continue", json!({ "threadId": 1 }));
+    client.request("pause", json!({ "threadId": 1 }));
+    assert_eq!(client.event("stopped")["reason"], "pause");
+    assert_eq!(client.stack_frame()["line"], 6);
+    client.request("disconnect", json!({}));
+    server.join().unwrap();
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn maps_lines_and_symbols() {
+    let info = DebugInfo::parse(DEBUG_INFO).unwrap();
+    let file = info.file(Path::new("/home/me/game/src/main.s")).unwrap();
+    assert_eq!(info.line(0xC005), Some(SourceLine { file, line: 5 }));
+    assert_eq!(info.line(0xC00D), None);
+    let (line, addresses) = info.addresses(SourceLine { file, line: 1 }).unwrap();
+    assert_eq!((line.line, addresses), (3, &[0xC000][..]));
+    let done = info
+        .symbols()
+        .iter()
+        .find(|symbol| symbol.name == "Sub::inner@done")
+        .unwrap();
+    assert_eq!((done.value, done.prg_offset), (0xC00C, Some(0xC)));
+    assert!(!info
+        .symbols()
+        .iter()
+        .any(|symbol| symbol.name == "counter" && symbol.label));
+}
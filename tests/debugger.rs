@@ -0,0 +1,248 @@
+use nise::common::bus::{AccessKind, Bus, BusAccess, FlatBus};
+use nise::nes::asm::{assemble, Assembly};
+use nise::nes::cpu::{Interrupt, Nise6502, StepStatus, Variant};
+use nise::nes::debugger::{Debugger, StopReason, WatchKind};
+
+const PROGRAM: &str = "
+    counter = $10
+    .org $8000
+    reset:  ldx #$FF
+            txs
+            jsr outer
+    after:  inc counter
+    halt:   jmp halt
+    outer:  jsr inner
+    back:   lda #1
+            rts
+    inner:  sta counter
+            rts
+    nmi:    rti
+    .org $FFFA
+    .word nmi, reset, 0
+";
+
+fn load(program: &str) -> (Nise6502<FlatBus>, Assembly) {
+    let assembly = assemble(Variant::Ricoh2A03, program).unwrap();
+    let mut cpu = Nise6502::new(assembly.flat_bus());
+    cpu.reset();
+    (cpu, assembly)
+}
+
+fn debug(program: &str) -> (Debugger<FlatBus>, Assembly) {
+    let (cpu, assembly) = load(program);
+    (Debugger::new(cpu), assembly)
+}
+
+#[test]
+fn steps_over_into_and_out_of_subroutines() {
+    let (mut debugger, assembly) = debug(PROGRAM);
+    let symbol = |name: &str| assembly.symbols[name];
+    assert_eq!(debugger.step_into(), StopReason::Step);
+    assert_eq!(debugger.step_into(), StopReason::Step);
+    assert_eq!(debugger.step_over(1000), StopReason::Step);
+    assert_eq!(debugger.cpu().pc(), symbol("after"));
+
+    let (mut debugger, _) = debug(PROGRAM);
+    assert_eq!(
+        debugger.run_to(symbol("inner"), 1000),
+        StopReason::RunTo(symbol("inner"))
+    );
+    assert_eq!(debugger.step_out(1000), StopReason::Step);
+    assert_eq!(debugger.cpu().pc(), symbol("back"));
+    assert_eq!(debugger.step_out(1000), StopReason::Step);
+    assert_eq!(debugger.cpu().pc(), symbol("after"));
+}
+
+#[test]
+fn stops_on_breakpoints_and_watchpoints() {
+    let (mut debugger, assembly) = debug(PROGRAM);
+    debugger.add_breakpoint(assembly.symbols["back"]);
+    debugger.add_watchpoint(0x10..=0x10, WatchKind::Write);
+    assert_eq!(
+        debugger.run(1000),
+        StopReason::Watchpoint(BusAccess {
+            address: 0x10,
+            value: 0,
+            kind: AccessKind::Write
+        })
+    );
+    assert_eq!(debugger.cpu().pc(), assembly.symbols["inner"] + 2);
+    assert_eq!(
+        debugger.run(1000),
+        StopReason::Breakpoint(assembly.symbols["back"])
+    );
+
+    // Resuming runs the instruction under the breakpoint
+    debugger.set_break_on_opcode(0x60, true);
+    assert_eq!(debugger.run(1000), StopReason::Opcode(0x60));
+    debugger.clear();
+    assert_eq!(debugger.run(1000), StopReason::CycleLimit);
+    assert_eq!(debugger.cpu().bus().peek(0x10), 1);
+}
+
+#[test]
+fn stops_on_interrupt_entry() {
+    let (mut debugger, assembly) = debug(PROGRAM);
+    debugger.set_break_on_interrupt(true);
+    debugger.run_to(assembly.symbols["halt"], 1000);
+    debugger.cpu_mut().set_nmi(true);
+    assert_eq!(debugger.run(1000), StopReason::Interrupt(Interrupt::Nmi));
+    assert_eq!(debugger.cpu().pc(), assembly.symbols["nmi"]);
+}
+
+// Counts up at $10, with a delay between counts long enough for a run of
+// a few frames to cross several snapshots
+const COUNTER: &str = "
+    counter = $10
+    .org $8000
+    reset:  ldx #$FF
+            txs
+    loop:   inc counter
+            ldy #0
+    wait:   dey
+            bne wait
+            jmp loop
+    .org $FFFA
+    .word 0, reset, 0
+";
+
+#[test]
+fn steps_back_across_snapshots() {
+    let (mut debugger, _) = debug(COUNTER);
+    assert_eq!(debugger.run(59_000), StopReason::CycleLimit);
+    let mut states = vec![];
+    for _ in 0..400 {
+        let cpu = debugger.cpu();
+        states.push((cpu.state(), cpu.bus().peek(0x10)));
+        debugger.step_into();
+    }
+    assert!(debugger.cpu().cycles() > 60_000);
+    for expected in states.iter().rev() {
+        assert_eq!(debugger.step_back(), StopReason::Step);
+        let cpu = debugger.cpu();
+        assert_eq!((cpu.state(), cpu.bus().peek(0x10)), *expected);
+    }
+}
+
+#[test]
+fn runs_backwards_to_breakpoints_and_watchpoints() {
+    let (mut debugger, assembly) = debug(COUNTER);
+    let (loop_start, wait) = (assembly.symbols["loop"], assembly.symbols["wait"]);
+    assert_eq!(debugger.run(40_000), StopReason::CycleLimit);
+    let count = debugger.cpu().bus().peek(0x10);
+
+    debugger.add_breakpoint(loop_start);
+    assert_eq!(
+        debugger.reverse_continue(),
+        StopReason::Breakpoint(loop_start)
+    );
+    assert_eq!(debugger.cpu().pc(), loop_start);
+    assert_eq!(debugger.cpu().bus().peek(0x10), count - 1);
+    assert_eq!(
+        debugger.reverse_continue(),
+        StopReason::Breakpoint(loop_start)
+    );
+    assert_eq!(debugger.cpu().bus().peek(0x10), count - 2);
+
+    // INC writes the old value back before the new one
+    debugger.clear();
+    debugger.add_watchpoint(0x10..=0x10, WatchKind::Write);
+    let reason = debugger.reverse_continue();
+    let write = BusAccess {
+        address: 0x10,
+        value: count - 3,
+        kind: AccessKind::Write,
+    };
+    assert_eq!(reason, StopReason::Watchpoint(write));
+    assert_eq!(debugger.cpu().pc(), loop_start + 2);
+    assert_eq!(debugger.cpu().bus().peek(0x10), count - 2);
+
+    // Going forwards again replays the same run
+    debugger.clear();
+    debugger.add_breakpoint(wait);
+    assert_eq!(debugger.run(10_000), StopReason::Breakpoint(wait));
+    assert_eq!(debugger.cpu().bus().peek(0x10), count - 2);
+
+    debugger.clear();
+    assert_eq!(debugger.reverse_continue(), StopReason::HistoryStart);
+    assert_eq!(debugger.cpu().pc(), loop_start - 3);
+    assert_eq!(debugger.step_back(), StopReason::HistoryStart);
+}
+
+#[test]
+fn finds_the_last_write() {
+    let (mut debugger, assembly) = debug(COUNTER);
+    assert_eq!(debugger.run(40_000), StopReason::CycleLimit);
+    let before = debugger.cpu().state();
+    let count = debugger.cpu().bus().peek(0x10);
+
+    let (state, write) = debugger.last_write(0x10).unwrap();
+    assert_eq!(state.pc, assembly.symbols["loop"]);
+    assert_eq!(write.value, count);
+    assert!(state.cycles < before.cycles);
+    assert_eq!(debugger.cpu().state(), before);
+    assert_eq!(debugger.last_write(0x11), None);
+
+    // Without snapshots there is nothing to go back to
+    debugger.set_reverse(false);
+    assert_eq!(debugger.step_back(), StopReason::HistoryStart);
+    assert_eq!(debugger.last_write(0x10), None);
+}
+
+#[test]
+fn stays_jammed_until_reset() {
+    let (mut cpu, _) = load(
+        "
+        .org $8000
+        reset:  lda #1
+                .byte $02
+                lda #2
+        nmi:    rti
+        .org $FFFA
+        .word nmi, reset, 0
+        ",
+    );
+    assert_eq!(cpu.step(), StepStatus::Running);
+    let jammed = StepStatus::Jammed { address: 0x8002 };
+    assert_eq!(cpu.step(), jammed);
+    // Not even an NMI wakes it up
+    cpu.set_nmi(true);
+    for _ in 0..10 {
+        assert_eq!(cpu.tick(), jammed);
+    }
+    assert_eq!((cpu.pc(), cpu.a()), (0x8003, 1));
+
+    cpu.set_nmi(false);
+    cpu.reset();
+    assert_eq!(cpu.step(), StepStatus::Running);
+    let mut debugger = Debugger::new(cpu);
+    assert_eq!(debugger.run(1000), StopReason::Fault(jammed));
+    assert_eq!(debugger.step_into(), StopReason::Fault(jammed));
+}
+
+#[test]
+fn reports_unimplemented_opcodes() {
+    let (mut cpu, _) = load(
+        "
+        .org $8000
+        reset:  .byte $8B
+                lda #2
+        .org $FFFC
+        .word reset
+        ",
+    );
+    let unimplemented = StepStatus::Unimplemented {
+        address: 0x8000,
+        opcode: 0x8B,
+    };
+    assert_eq!(cpu.tick(), unimplemented);
+    assert_eq!(cpu.tick(), StepStatus::Running);
+    assert_eq!(cpu.pc(), 0x8003);
+
+    cpu.reset();
+    let mut debugger = Debugger::new(cpu);
+    assert_eq!(debugger.run(1000), StopReason::Fault(unimplemented));
+    // Carries on after the opcode, if asked to
+    assert_eq!(debugger.step_into(), StopReason::Step);
+    assert_eq!(debugger.cpu().a(), 2);
+}
@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+
+use nise::common::bus::{AccessKind, Bus, BusAccess, Dma, FlatBus};
+use nise::nes::asm::assemble;
+use nise::nes::bus::NiseBus;
+use nise::nes::cdl::PCM;
+use nise::nes::cpu::{Nise6502, Variant};
+use nise::nes::rom::Rom;
+
+const PROGRAM: &str = "
+    .org $C000
+    reset:  ldx #$00
+    fill:   txa
+            eor #$FF
+            sta $0200,x
+            inx
+            bne fill
+            stx $2003
+            lda #$02
+    dma:    sta $4014
+            nop
+    halt:   jmp halt
+    sample: .byte $5A
+    .org $FFFC
+    .word reset
+";
+
+fn nes() -> (Nise6502<NiseBus>, BTreeMap<String, u16>) {
+    let assembly = assemble(Variant::Ricoh2A03, PROGRAM).unwrap();
+    let rom = Rom::new(&assembly.ines(1)).unwrap();
+    let mut cpu = Nise6502::new(NiseBus::new(rom));
+    cpu.set_cycle_accurate(true);
+    cpu.reset();
+    (cpu, assembly.symbols)
+}
+
+#[test]
+fn copies_a_page_to_oam() {
+    let mut stalls = vec![];
+    for parity in 0..2 {
+        let (mut cpu, symbols) = nes();
+        while cpu.pc() != symbols["dma"] {
+            cpu.step();
+        }
+        cpu.set_cycles(cpu.cycles() + parity);
+        cpu.step();
+        assert_eq!(cpu.pc(), symbols["dma"] + 3);
+        let start = cpu.cycles();
+        cpu.step();
+        // Less the two cycles of the NOP
+        stalls.push(cpu.cycles() - start - 2);
+        let expected = (0..=255).map(|i: u8| !i).collect::<Vec<_>>();
+        assert_eq!(cpu.bus().ppu().oam()[..], expected[..]);
+    }
+    stalls.sort();
+    assert_eq!(stalls, [513, 514]);
+}
+
+#[test]
+fn fetches_dmc_samples_into_the_log() {
+    let (mut cpu, symbols) = nes();
+    cpu.set_log_usage(true);
+    cpu.bus_mut().start_code_data_log();
+    cpu.bus_mut().request_dmc_fetch(symbols["sample"]);
+    let start = cpu.cycles();
+    cpu.step();
+    assert!([5, 6].contains(&(cpu.cycles() - start)));
+    assert_eq!(cpu.bus().dmc_sample(), Some(0x5A));
+    let log = cpu.bus().code_data_log().unwrap();
+    let offset = cpu.bus().prg_offset(symbols["sample"]).unwrap();
+    assert_eq!(log.prg[offset as usize] & PCM, PCM);
+}
+
+// Memory that starts DMA once it has been clocked for some cycles
+struct ScriptedBus {
+    memory: FlatBus,
+    cycles: u64,
+    script: Vec<(u64, Dma)>,
+    fetched: Vec<u8>,
+}
+
+impl Bus for ScriptedBus {
+    fn read(&mut self, address: u16) -> u8 {
+        self.memory.read(address)
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        self.memory.write(address, data)
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        self.memory.peek(address)
+    }
+
+    fn tick(&mut self) {
+        self.cycles += 1;
+    }
+
+    fn take_dma(&mut self) -> Option<Dma> {
+        match self.script.first() {
+            Some(&(cycles, dma)) if cycles <= self.cycles => {
+                self.script.remove(0);
+                Some(dma)
+            }
+            _ => None,
+        }
+    }
+
+    fn dmc_fetched(&mut self, value: u8) {
+        self.fetched.push(value);
+    }
+}
+
+fn scripted(script: Vec<(u64, Dma)>) -> Nise6502<ScriptedBus> {
+    let mut memory = FlatBus::new();
+    // LDA $4016 forever, with a sample at $C000 and sprites at $0300
+    memory.load(0x8000, &[0xAD, 0x16, 0x40, 0x4C, 0x00, 0x80]);
+    memory.load(0xC000, &[0x77]);
+    memory.load(0x0300, &[0x11; 256]);
+    let mut cpu = Nise6502::new(ScriptedBus {
+        memory,
+        cycles: 0,
+        script,
+        fetched: vec![],
+    });
+    cpu.set_cycle_accurate(true);
+    cpu.set_record_accesses(true);
+    cpu.set_pc(0x8000);
+    cpu
+}
+
+fn read(address: u16, value: u8) -> BusAccess {
+    BusAccess {
+        address,
+        value,
+        kind: AccessKind::Read,
+    }
+}
+
+#[test]
+fn repeats_the_halted_read() {
+    for parity in 0..2 {
+        // Halts on the read of $4016, the last cycle of the first LDA
+        let mut cpu = scripted(vec![(3, Dma::Dmc(0xC000))]);
+        cpu.set_cycles(parity);
+        cpu.step();
+        let accesses = cpu.accesses();
+        let halted = &accesses[3..accesses.len() - 1];
+        let (fetch, repeated) = halted.split_last().unwrap();
+        assert_eq!(*fetch, read(0xC000, 0x77));
+        assert!(repeated.iter().all(|access| *access == read(0x4016, 0)));
+        assert_eq!(repeated.len() as u64, 3 - parity);
+        assert_eq!(cpu.bus().fetched, [0x77]);
+    }
+}
+
+#[test]
+fn fetches_samples_during_sprite_dma() {
+    let mut cpu = scripted(vec![(0, Dma::Oam(0x03)), (100, Dma::Dmc(0xC000))]);
+    cpu.step();
+    let accesses = cpu.accesses();
+    let writes = accesses
+        .iter()
+        .filter(|access| access.kind == AccessKind::Write)
+        .collect::<Vec<_>>();
+    assert_eq!(writes.len(), 256);
+    assert!(writes.iter().all(|write| write.address == 0x2004));
+    assert_eq!(cpu.bus().fetched, [0x77]);
+    // The halt, one to line up, 512 of copying and two for the sample, one
+    // of them to get back in step, then the four of the LDA
+    assert_eq!(cpu.cycles(), 1 + 1 + 512 + 2 + 4);
+}
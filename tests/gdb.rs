@@ -0,0 +1,148 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+use nise::common::bus::FlatBus;
+use nise::nes::asm::assemble;
+use nise::nes::cpu::{Nise6502, Variant};
+use nise::nes::debugger::Debugger;
+use nise::nes::gdb::GdbStub;
+
+const PROGRAM: &str = "
+    .org $0200
+    start:  lda #$11
+            sta $40
+            ldx $41
+    loop:   inx
+            jmp loop
+    .org $FFFC
+    .word start
+";
+
+struct Client {
+    stream: TcpStream,
+}
+
+impl Client {
+    fn send(&mut self, data: &str) {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        write!(self.stream, "${}#{:02x}", data, checksum).unwrap();
+    }
+
+    // Reads one packet, skipping acknowledgements
+    fn receive(&mut self) -> String {
+        let mut packet = vec![];
+        let mut byte = [0];
+        loop {
+            self.stream.read_exact(&mut byte).unwrap();
+            match byte[0] {
+                b'+' if packet.is_empty() => {}
+                b'#' => break,
+                _ => packet.push(byte[0]),
+            }
+        }
+        let mut checksum = [0; 2];
+        self.stream.read_exact(&mut checksum).unwrap();
+        String::from_utf8(packet[1..].to_vec()).unwrap()
+    }
+
+    fn request(&mut self, data: &str) -> String {
+        self.send(data);
+        self.receive()
+    }
+}
+
+fn connect() -> (Client, thread::JoinHandle<GdbStub<FlatBus>>) {
+    let assembly = assemble(Variant::Ricoh2A03, PROGRAM).unwrap();
+    let mut cpu = Nise6502::new(assembly.flat_bus());
+    cpu.reset();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let mut stub = GdbStub::new(Debugger::new(cpu));
+        stub.accept(&listener).unwrap();
+        stub
+    });
+    let stream = TcpStream::connect(address).unwrap();
+    stream.set_nodelay(true).unwrap();
+    (Client { stream }, server)
+}
+
+#[test]
+fn reads_and_writes_registers_and_memory() {
+    let (mut client, server) = connect();
+    assert!(client
+        .request("qSupported:swbreak+")
+        .contains("qXfer:features:read+"));
+    assert_eq!(client.request("QStartNoAckMode"), "OK");
+    assert_eq!(client.request("?"), "S05");
+    assert_eq!(client.request("g"), "00000024fd0002");
+    assert_eq!(client.request("P0=7f"), "OK");
+    assert_eq!(client.request("p0"), "7f");
+    assert_eq!(client.request("G01020304050403"), "OK");
+    assert_eq!(client.request("p5"), "0403");
+    assert_eq!(client.request("m0200,2"), "a911");
+    assert_eq!(client.request("M41,2:0506"), "OK");
+    assert_eq!(client.request("m41,2"), "0506");
+    assert!(client
+        .request("qXfer:features:read:target.xml:0,1000")
+        .starts_with("l<?xml"));
+    assert_eq!(client.request("D"), "OK");
+
+    let stub = server.join().unwrap();
+    let cpu = stub.into_debugger().into_cpu();
+    assert_eq!((cpu.a(), cpu.s(), cpu.pc()), (0x01, 0x05, 0x0304));
+}
+
+#[test]
+fn stops_on_breakpoints_watchpoints_and_ctrl_c() {
+    let (mut client, server) = connect();
+    assert_eq!(client.request("Z2,40,1"), "OK");
+    assert_eq!(client.request("c"), "T05watch:0040;");
+    assert_eq!(client.request("p5"), "0402");
+    assert_eq!(client.request("s"), "S05");
+    assert_eq!(client.request("p5"), "0602");
+    assert_eq!(client.request("z2,40,1"), "OK");
+    assert_eq!(client.request("Z0,207,1"), "OK");
+    assert_eq!(client.request("c"), "S05");
+    assert_eq!(client.request("p5"), "0702");
+    assert_eq!(client.request("z0,207,1"), "OK");
+
+    // The loop never ends, so only Ctrl-C stops it
+    client.send("c");
+    client.stream.write_all(&[0x03]).unwrap();
+    assert_eq!(client.receive(), "S02");
+    assert_eq!(client.request("k"), "OK");
+    server.join().unwrap();
+}
+
+#[test]
+fn splits_watches_that_wrap() {
+    let (mut client, server) = connect();
+    assert_eq!(client.request("Z2,40,0"), "E01");
+    assert_eq!(client.request("Z3,40,10001"), "E01");
+    // $FFFF and $0000 to $0040
+    assert_eq!(client.request("Z2,ffff,42"), "OK");
+    assert_eq!(client.request("c"), "T05watch:0040;");
+    assert_eq!(client.request("z2,ffff,42"), "OK");
+    assert_eq!(client.request("D"), "OK");
+    let stub = server.join().unwrap();
+    assert!(stub.into_debugger().watchpoints().is_empty());
+}
+
+#[test]
+fn runs_backwards() {
+    let (mut client, server) = connect();
+    assert!(client
+        .request("qSupported:swbreak+")
+        .contains("ReverseStep+;ReverseContinue+"));
+    assert_eq!(client.request("s"), "S05");
+    assert_eq!(client.request("s"), "S05");
+    assert_eq!(client.request("p5"), "0402");
+    assert_eq!(client.request("bs"), "S05");
+    assert_eq!(client.request("p5"), "0202");
+    assert_eq!(client.request("bc"), "T05replaylog:begin;");
+    assert_eq!(client.request("p5"), "0002");
+    assert_eq!(client.request("k"), "OK");
+    server.join().unwrap();
+}
@@ -0,0 +1,129 @@
+use nise::nes::asm::assemble;
+use nise::nes::cpu::{Interrupt, Nise6502, Variant};
+use nise::nes::debugger::Debugger;
+use nise::nes::history::{Anomaly, FrameKind};
+
+const PROGRAM: &str = "
+    .org $8000
+    reset:  ldx #$FF
+            txs
+            jsr outer
+    after:  jsr skip
+            .byte $EA
+    halt:   jmp halt
+    outer:  jsr inner
+            rts
+    ; Jumps by pushing an address and returning to it
+    inner:  lda #>(target-1)
+            pha
+            lda #<(target-1)
+            pha
+    jump:   rts
+    target: brk
+            .byte 0
+            rts
+    ; Returns past the byte after its JSR
+    skip:   tsx
+            inc $0101,x
+            rts
+    irq:    rti
+    ; Throws away the stack
+    nmi:    ldx #$FF
+            txs
+            jmp halt
+    .org $FFFA
+    .word nmi, reset, irq
+";
+
+#[test]
+fn follows_calls_returns_and_interrupts() {
+    let assembly = assemble(Variant::Ricoh2A03, PROGRAM).unwrap();
+    let symbol = |name: &str| assembly.symbols[name];
+    let mut cpu = Nise6502::new(assembly.flat_bus());
+    cpu.reset();
+    let mut debugger = Debugger::new(cpu);
+
+    debugger.run_to(symbol("target"), 1000);
+    let cpu = debugger.cpu();
+    let frames = cpu.call_stack().frames();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].kind, FrameKind::Jsr);
+    assert_eq!(frames[0].call_site, symbol("reset") + 3);
+    assert_eq!(frames[0].return_address, symbol("after"));
+    assert_eq!(frames[1].entry, symbol("inner"));
+    assert_eq!(frames[1].stack_pointer, 0xFB);
+    assert_eq!(
+        cpu.call_stack().anomalies().collect::<Vec<_>>(),
+        [&Anomaly::ReturnAsJump {
+            from: symbol("jump"),
+            to: symbol("target"),
+        }]
+    );
+
+    debugger.step_into();
+    let frame = *debugger.cpu().call_stack().frames().last().unwrap();
+    assert_eq!(frame.kind, FrameKind::Brk);
+    assert_eq!(frame.call_site, symbol("target"));
+    assert_eq!(frame.entry, symbol("irq"));
+    assert_eq!(frame.return_address, symbol("target") + 2);
+
+    debugger.run_to(symbol("halt"), 1000);
+    let cpu = debugger.cpu();
+    assert!(cpu.call_stack().frames().is_empty());
+    assert_eq!(
+        cpu.call_stack().anomalies().last(),
+        Some(&Anomaly::ReturnElsewhere {
+            from: symbol("skip") + 4,
+            to: symbol("halt"),
+            expected: symbol("after") + 3,
+        })
+    );
+
+    // The NMI handler resets S, dropping its own frame
+    // It is polled during JMP and taken after
+    debugger.cpu_mut().set_nmi(true);
+    debugger.step_into();
+    debugger.step_into();
+    let cpu = debugger.cpu();
+    assert_eq!(
+        cpu.history().iter().last().unwrap().interrupt,
+        Some(Interrupt::Nmi)
+    );
+    assert_eq!(cpu.call_stack().frames()[0].kind, FrameKind::Nmi);
+    assert_eq!(cpu.call_stack().frames()[0].call_site, symbol("halt"));
+    debugger.step_into();
+    debugger.step_into();
+    let cpu = debugger.cpu();
+    assert!(cpu.call_stack().frames().is_empty());
+    assert_eq!(
+        cpu.call_stack().anomalies().last(),
+        Some(&Anomaly::Unwound {
+            at: symbol("nmi") + 2,
+            frames: 1,
+        })
+    );
+}
+
+#[test]
+fn keeps_the_last_instructions() {
+    let assembly = assemble(Variant::Ricoh2A03, PROGRAM).unwrap();
+    let mut cpu = Nise6502::new(assembly.flat_bus());
+    cpu.reset();
+    cpu.step();
+    assert!(cpu.history().is_empty());
+
+    cpu.set_history_len(3);
+    for _ in 0..4 {
+        cpu.step();
+    }
+    let history = cpu.history().iter().collect::<Vec<_>>();
+    assert_eq!(history.len(), 3);
+    assert_eq!(history[0].state.pc, assembly.symbols["reset"] + 3);
+    assert_eq!(history[0].state.s, 0xFF);
+    let instruction = history[1].instruction(Variant::Ricoh2A03);
+    assert_eq!(
+        instruction.to_string(),
+        format!("JSR ${:04X}", assembly.symbols["inner"])
+    );
+    assert_eq!(history[2].bytes[0], 0xA9);
+}
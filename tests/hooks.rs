@@ -0,0 +1,145 @@
+use std::sync::{Arc, Mutex};
+
+use nise::common::bus::{Bus, FlatBus};
+use nise::nes::asm::assemble;
+use nise::nes::bus::NiseBus;
+use nise::nes::cpu::{Nise6502, Variant};
+use nise::nes::rom::Rom;
+
+const PROGRAM: &str = "
+    .org $C000
+    reset:  lda $10
+            sta $11
+            lda #$20
+            sta $2006
+            lda #$00
+            sta $2006
+            lda #$55
+            sta $2007
+            lda $2007
+            lda $2007
+    halt:   jmp halt
+    .org $FFFC
+    .word reset
+";
+
+fn flat() -> Nise6502<FlatBus> {
+    let assembly = assemble(Variant::Ricoh2A03, PROGRAM).unwrap();
+    let mut bus = assembly.flat_bus();
+    bus.write(0x10, 0x42);
+    let mut cpu = Nise6502::new(bus);
+    cpu.reset();
+    cpu
+}
+
+#[test]
+fn overrides_reads_and_vetoes_writes() {
+    let mut cpu = flat();
+    let seen = Arc::new(Mutex::new(vec![]));
+    let log = seen.clone();
+    cpu.hooks_mut()
+        .add_read(0x10..=0x1F, move |address, value| {
+            log.lock().unwrap().push((address, value));
+            Some(value + 1)
+        });
+    let vetoed = cpu
+        .hooks_mut()
+        .add_write(0x11..=0x11, |_, value| value != 0x43);
+    cpu.step();
+    assert_eq!(cpu.a(), 0x43);
+    cpu.step();
+    assert_eq!(cpu.bus().peek(0x11), 0x00);
+    assert_eq!(*seen.lock().unwrap(), [(0x10, 0x42)]);
+
+    assert!(cpu.hooks_mut().remove(vetoed));
+    assert!(!cpu.hooks_mut().remove(vetoed));
+    cpu.set_pc(0xC002);
+    cpu.step();
+    assert_eq!(cpu.bus().peek(0x11), 0x43);
+}
+
+#[test]
+fn swaps_fetched_opcodes() {
+    let mut cpu = flat();
+    // Only the opcode fetch turns into LDX, not the read of the operand
+    cpu.hooks_mut()
+        .add_fetch(0xC000..=0xC000, |_, _| Some(0xA6));
+    cpu.hooks_mut()
+        .add_fetch(0xC001..=0xC001, |_, _| panic!("operands are not fetches"));
+    cpu.step();
+    assert_eq!((cpu.a(), cpu.x(), cpu.pc()), (0x00, 0x42, 0xC002));
+    assert_eq!(cpu.bus().peek(0xC000), 0xA5);
+}
+
+#[test]
+fn runs_execute_hooks_on_their_address() {
+    let mut cpu = flat();
+    let calls = Arc::new(Mutex::new(0));
+    let count = calls.clone();
+    // Skips the store
+    let hook = cpu.add_execute_hook(0xC002, move |cpu| {
+        *count.lock().unwrap() += 1;
+        cpu.set_pc(0xC004);
+    });
+    cpu.step();
+    cpu.step();
+    assert_eq!(*calls.lock().unwrap(), 1);
+    assert_eq!((cpu.pc(), cpu.a()), (0xC006, 0x20));
+    assert_eq!(cpu.bus().peek(0x11), 0x00);
+
+    assert!(cpu.remove_execute_hook(hook));
+    cpu.set_pc(0xC002);
+    cpu.step();
+    assert_eq!(cpu.bus().peek(0x11), 0x20);
+}
+
+#[test]
+fn sees_the_ppu_address_space() {
+    let assembly = assemble(Variant::Ricoh2A03, PROGRAM).unwrap();
+    let rom = Rom::new(&assembly.ines(1)).unwrap();
+    let mut cpu = Nise6502::new(NiseBus::new(rom));
+    cpu.reset();
+    let seen = Arc::new(Mutex::new(vec![]));
+    let log = seen.clone();
+    cpu.bus_mut()
+        .ppu_hooks_mut()
+        .add_write(0x0000..=0x3FFF, move |address, value| {
+            log.lock().unwrap().push((address, value));
+            true
+        });
+    cpu.bus_mut()
+        .ppu_hooks_mut()
+        .add_read(0x2000..=0x2FFF, |_, _| Some(0x99));
+    // CPU hooks see the register, and what the PPU hooks made of it once it
+    // comes out of the read buffer
+    let reads = Arc::new(Mutex::new(vec![]));
+    let log = reads.clone();
+    cpu.hooks_mut().add_read(0x2007..=0x2007, move |_, value| {
+        log.lock().unwrap().push(value);
+        None
+    });
+    while cpu.pc() != assembly.symbols["halt"] {
+        cpu.step();
+    }
+    assert_eq!(cpu.a(), 0x99);
+    assert_eq!(*reads.lock().unwrap(), [0x00, 0x99]);
+    assert_eq!(*seen.lock().unwrap(), [(0x2000, 0x55)]);
+    assert!(!cpu.bus().ppu_hooks().is_empty());
+}
+
+#[test]
+fn sees_the_nametable_read_behind_the_palette() {
+    let assembly = assemble(Variant::Ricoh2A03, PROGRAM).unwrap();
+    let mut bus = NiseBus::new(Rom::new(&assembly.ines(1)).unwrap());
+    let seen = Arc::new(Mutex::new(vec![]));
+    let log = seen.clone();
+    bus.ppu_hooks_mut()
+        .add_read(0x0000..=0x3FFF, move |address, _| {
+            log.lock().unwrap().push(address);
+            None
+        });
+    bus.write(0x2006, 0x3F);
+    bus.write(0x2006, 0x01);
+    bus.read(0x2007);
+    assert_eq!(*seen.lock().unwrap(), [0x3F01, 0x2F01]);
+}
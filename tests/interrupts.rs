@@ -0,0 +1,175 @@
+use nise::common::bus::{Bus, FlatBus};
+use nise::nes::cpu::{Flag, Interrupt, Nise6502};
+
+// Memory that raises an interrupt line once it has been clocked for some
+// cycles
+struct LineBus {
+    memory: FlatBus,
+    cycles: u64,
+    raise_at: u64,
+    interrupt: Interrupt,
+}
+
+impl Bus for LineBus {
+    fn read(&mut self, address: u16) -> u8 {
+        self.memory.read(address)
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        self.memory.write(address, data)
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        self.memory.peek(address)
+    }
+
+    fn tick(&mut self) {
+        self.cycles += 1;
+    }
+
+    fn nmi_line(&self) -> bool {
+        self.interrupt == Interrupt::Nmi && self.cycles >= self.raise_at
+    }
+
+    fn irq_line(&self) -> bool {
+        self.interrupt == Interrupt::Irq && self.cycles >= self.raise_at
+    }
+}
+
+// A CPU running `program` from `address`, with NOPs after it, at the NMI
+// handler at $9000 and at the IRQ handler at $A000. Interrupts are disabled
+// and `interrupt` is raised from cycle `raise_at` on.
+fn machine(address: u16, program: &[u8], interrupt: Interrupt, raise_at: u64) -> Nise6502<LineBus> {
+    let mut memory = FlatBus::new();
+    memory.load(address, program);
+    memory.load(address + program.len() as u16, &[0xEA; 8]);
+    memory.load(0x9000, &[0xEA; 8]);
+    memory.load(0xA000, &[0xEA; 8]);
+    memory.load(0xFFFA, &[0x00, 0x90, 0x00, 0x90, 0x00, 0xA0]);
+    let mut cpu = Nise6502::new(LineBus {
+        memory,
+        cycles: 0,
+        raise_at,
+        interrupt,
+    });
+    cpu.set_cycle_accurate(true);
+    cpu.set_pc(address);
+    cpu
+}
+
+// The interrupts serviced by the next `steps` steps
+fn run(cpu: &mut Nise6502<LineBus>, steps: usize) -> Vec<Option<Interrupt>> {
+    (0..steps)
+        .map(|_| {
+            cpu.step();
+            cpu.interrupt_serviced()
+        })
+        .collect()
+}
+
+// CLI, then BCC at `address + 1` to `target`. Returns the interrupts serviced
+// by the three steps after the branch.
+fn branch(
+    address: u16,
+    target: u16,
+    interrupt: Interrupt,
+    raise_at: u64,
+) -> Vec<Option<Interrupt>> {
+    let offset = target.wrapping_sub(address + 3) as u8;
+    let mut cpu = machine(address, &[0x58, 0x90, offset], interrupt, raise_at);
+    cpu.bus_mut().memory.load(target, &[0xEA; 8]);
+    run(&mut cpu, 2);
+    assert_eq!(cpu.pc(), target);
+    run(&mut cpu, 3)
+}
+
+#[test]
+fn services_nmi_once_per_edge() {
+    // Raised during the first instruction and held
+    let mut cpu = machine(0x8000, &[], Interrupt::Nmi, 0);
+    assert_eq!(
+        run(&mut cpu, 5),
+        [None, Some(Interrupt::Nmi), None, None, None]
+    );
+}
+
+#[test]
+fn services_irq_for_as_long_as_the_line_is_held_and_i_is_clear() {
+    let mut cpu = machine(0x8000, &[], Interrupt::Irq, 0);
+    assert_eq!(run(&mut cpu, 4), [None; 4]);
+
+    // The handler returns at once, and RTI lets the IRQ in straight after
+    let mut cpu = machine(0x8000, &[], Interrupt::Irq, 0);
+    cpu.set_flag(Flag::InterruptDisable, false);
+    cpu.bus_mut().memory.load(0xA000, &[0x40]);
+    assert_eq!(
+        run(&mut cpu, 4),
+        [None, Some(Interrupt::Irq), None, Some(Interrupt::Irq)]
+    );
+}
+
+#[test]
+fn delays_irq_by_an_instruction_after_cli_sei_and_plp() {
+    // CLI, and PLP of a clear I, let the IRQ in only after the next
+    // instruction
+    for program in [[0x58], [0x28]] {
+        let mut cpu = machine(0x8000, &program, Interrupt::Irq, 0);
+        cpu.bus_mut().memory.load(0x01FE, &[0x00]);
+        assert_eq!(run(&mut cpu, 3), [None, None, Some(Interrupt::Irq)]);
+    }
+    // SEI, and PLP of a set I, still let in an IRQ raised while they run
+    for program in [[0xEA, 0x78], [0xEA, 0x28]] {
+        let mut cpu = machine(0x8000, &program, Interrupt::Irq, 3);
+        cpu.set_flag(Flag::InterruptDisable, false);
+        cpu.bus_mut().memory.load(0x01FE, &[0x04]);
+        assert_eq!(run(&mut cpu, 4), [None, None, Some(Interrupt::Irq), None]);
+    }
+}
+
+#[test]
+fn lets_nmi_hijack_a_brk() {
+    // Raised before BRK reads its vector, NMI takes the vector over. The
+    // pushed status still has B set.
+    let mut cpu = machine(0x8000, &[0x00, 0x00], Interrupt::Nmi, 3);
+    run(&mut cpu, 1);
+    assert_eq!(cpu.pc(), 0x9000);
+    assert_eq!(cpu.bus().peek(0x0100 + cpu.s() as u16 + 1) & 0x10, 0x10);
+    assert_eq!(run(&mut cpu, 2), [None, None]);
+
+    // Raised later, it waits for the first instruction of the BRK handler
+    let mut cpu = machine(0x8000, &[0x00, 0x00], Interrupt::Nmi, 7);
+    run(&mut cpu, 1);
+    assert_eq!(cpu.pc(), 0xA000);
+    assert_eq!(run(&mut cpu, 2), [None, Some(Interrupt::Nmi)]);
+}
+
+// The CLI takes cycles 1 and 2, and the branch's opcode fetch cycle 3 and its
+// operand fetch cycle 4
+#[test]
+fn delays_interrupts_raised_in_a_branch_on_its_page() {
+    for interrupt in [Interrupt::Nmi, Interrupt::Irq] {
+        assert_eq!(
+            branch(0x8000, 0x8010, interrupt, 4),
+            [None, Some(interrupt), None]
+        );
+        // Raised before the operand fetch, it comes straight after the branch
+        assert_eq!(
+            branch(0x8000, 0x8010, interrupt, 3),
+            [Some(interrupt), None, None]
+        );
+    }
+}
+
+#[test]
+fn services_interrupts_after_a_branch_across_pages() {
+    for interrupt in [Interrupt::Nmi, Interrupt::Irq] {
+        assert_eq!(
+            branch(0x80F0, 0x8110, interrupt, 4),
+            [Some(interrupt), None, None]
+        );
+        assert_eq!(
+            branch(0x80F0, 0x8110, interrupt, 5),
+            [Some(interrupt), None, None]
+        );
+    }
+}
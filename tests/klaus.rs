@@ -0,0 +1,104 @@
+//! Klaus Dormann's 6502 functional and interrupt tests, from
+//! https://github.com/Klaus2m5/6502_65C02_functional_tests.
+//!
+//! The binaries are not distributed with nise. Copy
+//! `bin_files/6502_functional_test.bin` and `bin_files/6502_interrupt_test.bin`
+//! from that repository into `tests/roms/`, then run the tests, which are
+//! ignored by default, with `cargo test --test klaus -- --ignored`.
+
+use nise::common::bus::{Bus, FlatBus};
+use nise::nes::cpu::{Nise6502, Variant};
+
+// Trap addresses reached on success by the stock binaries
+const FUNCTIONAL_SUCCESS: u16 = 0x3469;
+const INTERRUPT_SUCCESS: u16 = 0x06F5;
+
+fn load_binary(name: &str) -> Vec<u8> {
+    let path = format!("{}/tests/roms/{}", env!("CARGO_MANIFEST_DIR"), name);
+    std::fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path, e))
+}
+
+// Both suites signal success and failure alike by looping on a single
+// instruction, so run until PC stops moving and report where.
+fn run_to_trap<B: Bus>(cpu: &mut Nise6502<B>, max_cycles: u64) -> u16 {
+    loop {
+        let pc = cpu.pc();
+        cpu.step();
+        if cpu.pc() == pc {
+            return pc;
+        }
+        assert!(
+            cpu.cycles() < max_cycles,
+            "no trap after {} cycles, PC=${:04X}",
+            max_cycles,
+            cpu.pc()
+        );
+    }
+}
+
+#[test]
+#[ignore = "needs tests/roms/6502_functional_test.bin"]
+fn functional_test() {
+    let binary = load_binary("6502_functional_test.bin");
+    let mut bus = FlatBus::new();
+    bus.load(0x0000, &binary);
+    let mut cpu = Nise6502::new(bus);
+    cpu.set_variant(Variant::Nmos6502);
+    cpu.reset();
+
+    let trap = run_to_trap(&mut cpu, 200_000_000);
+    assert_eq!(
+        trap, FUNCTIONAL_SUCCESS,
+        "trapped at ${:04X}, look it up in the test listing",
+        trap
+    );
+}
+
+// The interrupt test raises IRQ and NMI by writing a feedback register at
+// $BFFC. The stock binary is built for open collector outputs, so a 0 in
+// bit 0 asserts IRQ and a 0 in bit 1 asserts NMI.
+struct FeedbackBus {
+    ram: FlatBus,
+}
+
+const FEEDBACK_PORT: u16 = 0xBFFC;
+
+impl Bus for FeedbackBus {
+    fn read(&mut self, address: u16) -> u8 {
+        self.ram.read(address)
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        self.ram.write(address, data)
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        self.ram.peek(address)
+    }
+
+    fn irq_line(&self) -> bool {
+        self.ram.peek(FEEDBACK_PORT) & 0b01 == 0
+    }
+
+    fn nmi_line(&self) -> bool {
+        self.ram.peek(FEEDBACK_PORT) & 0b10 == 0
+    }
+}
+
+#[test]
+#[ignore = "needs tests/roms/6502_interrupt_test.bin"]
+fn interrupt_test() {
+    let binary = load_binary("6502_interrupt_test.bin");
+    let mut ram = FlatBus::new();
+    ram.load(0x0000, &binary);
+    ram.write(FEEDBACK_PORT, 0xFF);
+    let mut cpu = Nise6502::new(FeedbackBus { ram });
+    cpu.reset();
+
+    let trap = run_to_trap(&mut cpu, 10_000_000);
+    assert_eq!(
+        trap, INTERRUPT_SUCCESS,
+        "trapped at ${:04X}, look it up in the test listing",
+        trap
+    );
+}
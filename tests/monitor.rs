@@ -0,0 +1,264 @@
+use std::io::Write;
+use std::sync::mpsc::{self, Sender};
+
+use nise::nes::asm::assemble;
+use nise::nes::bus::NiseBus;
+use nise::nes::cpu::{Nise6502, Variant};
+use nise::nes::debugger::Debugger;
+use nise::nes::monitor::{BreakIn, Monitor};
+use nise::nes::rom::Rom;
+
+fn monitor() -> Monitor {
+    let source = "
+        .org $C000
+        reset:  lda #$42
+                sta $10
+                jsr sub
+        halt:   jmp halt
+        sub:    inc $10
+                rts
+        .org $FFFC
+        .word reset
+    ";
+    let assembly = assemble(Variant::Ricoh2A03, source).unwrap();
+    let rom = Rom::new(&assembly.ines(1)).unwrap();
+    let mut cpu = Nise6502::new(NiseBus::new(rom));
+    cpu.reset();
+    Monitor::new(Debugger::new(cpu))
+}
+
+fn run(monitor: &mut Monitor, commands: &str) -> String {
+    let mut output = vec![];
+    monitor.run(commands.as_bytes(), &mut output, true).unwrap();
+    String::from_utf8(output).unwrap()
+}
+
+// Output that lets the test know once some text has been written
+struct Watched {
+    text: Vec<u8>,
+    wait_for: &'static str,
+    seen: Option<Sender<()>>,
+}
+
+impl Write for Watched {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.text.extend_from_slice(buf);
+        if String::from_utf8_lossy(&self.text).contains(self.wait_for) {
+            if let Some(seen) = self.seen.take() {
+                seen.send(()).unwrap();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Attaches to the running machine and types `commands`, then quits once
+// `wait_for` has been written
+fn attach(monitor: &mut Monitor, commands: &'static str, wait_for: &'static str) -> String {
+    let (input, mut typing) = std::io::pipe().unwrap();
+    let (seen, done) = mpsc::channel();
+    let typist = std::thread::spawn(move || {
+        typing.write_all(commands.as_bytes()).unwrap();
+        done.recv().unwrap();
+        typing.write_all(b"quit\n").unwrap();
+    });
+    let mut output = Watched {
+        text: vec![],
+        wait_for,
+        seen: Some(seen),
+    };
+    monitor.attach(input, &mut output, true).unwrap();
+    typist.join().unwrap();
+    String::from_utf8(output.text).unwrap()
+}
+
+#[test]
+fn steps_and_inspects_state() {
+    let mut monitor = monitor();
+    let output = run(&mut monitor, "z 2\nm 10 10\nn\nr\nd c004 c004\n");
+    assert!(output.contains(">C:0010  42"), "{}", output);
+    assert!(output.contains(".C:C007  JMP $C007"), "{}", output);
+    assert!(output.contains(".;C007 42 00 00 FD"), "{}", output);
+    assert!(
+        output.contains(".C:C004  20 0A C0  JSR $C00A"),
+        "{}",
+        output
+    );
+}
+
+#[test]
+fn edits_registers_memory_and_breakpoints() {
+    let mut monitor = monitor();
+    let output = run(
+        &mut monitor,
+        "r a=1 x = $FF\n> c002 86\nbk c00a\nbk\ng\nm 10 10\nstack\nret\nbogus\n",
+    );
+    assert!(output.contains(".;C000 01 FF 00 FD"), "{}", output);
+    assert!(output.contains("BREAK: C:$C00A"), "{}", output);
+    assert!(output.contains("#1 (Stop on exec C00A)"), "{}", output);
+    // The patched STX stored X instead of A
+    assert!(output.contains(">C:0010  FF"), "{}", output);
+    assert!(output.contains(">C:01FC  06\n>C:01FD  C0"), "{}", output);
+    assert!(output.contains(".C:C007  JMP $C007"), "{}", output);
+    assert!(
+        output.contains("error: unknown command `bogus`"),
+        "{}",
+        output
+    );
+    assert!(!run(&mut monitor, "x\nr\n").contains("ADDR"));
+}
+
+#[test]
+fn accepts_and_shows_labels() {
+    let mut monitor = monitor();
+    let output = run(
+        &mut monitor,
+        "al c00a sub\nal 10 counter\nbk sub\ng\nd c004 c004\nm .counter .counter\nshl\nuntil sub+2\nbt\nhist 2\n",
+    );
+    assert!(
+        output.contains("#1 (Stop on exec C00A (sub))"),
+        "{}",
+        output
+    );
+    assert!(output.contains(".C:C00A  INC counter"), "{}", output);
+    assert!(output.contains(".C:C004  20 0A C0  JSR sub"), "{}", output);
+    assert!(output.contains(">C:0010  42"), "{}", output);
+    assert!(
+        output.contains("C:$0010 .counter\nC:$C00A .sub"),
+        "{}",
+        output
+    );
+    assert!(output.contains(".C:C00C  RTS"), "{}", output);
+    assert!(
+        output.contains("#0  C:C00C (sub+2)\n#1  C:C004  JSR $C00A (sub)\n"),
+        "{}",
+        output
+    );
+    assert!(
+        output.contains(".C:C004  JSR sub        - A:42 X:00 Y:00 SP:FD P:24 CYC:"),
+        "{}",
+        output
+    );
+    assert!(
+        output.contains(".C:C00A  INC counter    - A:42"),
+        "{}",
+        output
+    );
+    assert!(run(&mut monitor, "bk nowhere\n").contains("not a hex number or a label"));
+}
+
+#[test]
+fn keeps_a_code_data_log() {
+    let mut monitor = monitor();
+    let output = run(&mut monitor, "cdl\ncdl start\nz 3\ncdl\n");
+    assert!(output.contains("error: not logging"), "{}", output);
+    assert!(
+        output.contains("PRG: 7 of 16384 bytes code, 0 data\nCHR: 0 of 8192 bytes rendered"),
+        "{}",
+        output
+    );
+}
+
+#[test]
+fn profiles_routines() {
+    let mut monitor = monitor();
+    let output = run(&mut monitor, "prof\nprof start\nal c00a sub\nz 4\nprof 2\n");
+    assert!(output.contains("error: not profiling"), "{}", output);
+    assert!(output.contains("16 cycles\n"), "{}", output);
+    assert!(
+        output.contains("       11  68.8%        11  68.8%         1  $C00A sub\n"),
+        "{}",
+        output
+    );
+}
+
+#[test]
+fn traces_to_a_file() {
+    let path = std::env::temp_dir().join(format!("nise-trace-{}.log", std::process::id()));
+    let mut monitor = monitor();
+    let commands = format!("trace {} fceux\nz 2\ntrace off\nz\n", path.display());
+    let output = run(&mut monitor, &commands);
+    assert!(!output.contains("error"), "{}", output);
+    let log = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(log.lines().count(), 2, "{}", log);
+    assert!(log.contains("$C002:85 10     STA $10 = #$00"), "{}", log);
+    let output = run(&mut monitor, "trace\n");
+    assert!(output.contains("error: expected off"), "{}", output);
+}
+
+#[test]
+fn steps_back_and_finds_writes() {
+    let mut monitor = monitor();
+    let output = run(&mut monitor, "z 3\nlw 10\nlw 11\nbz 2\n");
+    assert!(
+        output.contains("0010 = 42 written at cycle 9 by C002\n"),
+        "{}",
+        output
+    );
+    assert!(output.contains("No write to 0011"), "{}", output);
+    assert!(output.contains("bz 2\n.C:C002  STA $10 "), "{}", output);
+    let output = run(&mut monitor, "bg\n");
+    assert!(
+        output.contains("(Stopped at the start of the history kept)"),
+        "{}",
+        output
+    );
+}
+
+#[test]
+fn attaches_to_the_running_machine() {
+    // Typing a command stops the machine, wherever it is, and runs it
+    let mut monitor = monitor();
+    let output = attach(&mut monitor, "r\n", "ADDR");
+    assert!(output.starts_with("(Break)\n.C:C0"), "{}", output);
+    assert!(output.contains(".;C0"), "{}", output);
+    assert!(monitor.has_quit());
+
+    // Breakpoints stop it too, and x lets it carry on from there
+    let output = attach(&mut monitor, "reset\nbk c00a\nx\n", "(C:$C00A)");
+    assert!(output.contains("#1 (Stop on exec C00A)"), "{}", output);
+}
+
+#[test]
+fn breaks_in_on_request() {
+    let mut monitor = monitor();
+    let break_in: BreakIn = monitor.break_in();
+    break_in.request();
+    let output = attach(&mut monitor, "", "(C:$");
+    assert!(output.starts_with("(Break)\n"), "{}", output);
+}
+
+#[test]
+fn takes_counts_in_decimal() {
+    let mut monitor = monitor();
+    // LDA, STA, JSR, INC and RTS, then five of the JMP looping at halt
+    run(&mut monitor, "z 10\n");
+    assert_eq!(monitor.debugger().cpu().cycles(), 7 + 22 + 5 * 3);
+    run(&mut monitor, "z $10\n");
+    assert_eq!(monitor.debugger().cpu().cycles(), 7 + 22 + 21 * 3);
+    let output = run(&mut monitor, "hist 3\nz 1f\nd c000 10\n");
+    assert_eq!(output.matches("JMP $C007").count(), 3, "{}", output);
+    assert!(output.contains("error: `1f` is not a count"), "{}", output);
+    assert!(
+        output.contains("error: the end, 0010, is before the start"),
+        "{}",
+        output
+    );
+}
+
+#[test]
+fn rejects_watches_that_end_before_they_start() {
+    let mut monitor = monitor();
+    let output = run(&mut monitor, "watch 20 10\nwatch w 10 20\n");
+    assert!(
+        output.contains("error: the end, 0010, is before the start"),
+        "{}",
+        output
+    );
+    assert_eq!(monitor.debugger().watchpoints().len(), 1);
+}
@@ -0,0 +1,169 @@
+//! kevtris's nestest, run from $C000 without a PPU to see, against the
+//! reference trace `nestest.log` that Nintendulator made of it.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use nise::common::bus::Bus;
+use nise::nes::bus::NiseBus;
+use nise::nes::cpu::{Nise6502, StepStatus};
+use nise::nes::rom::Rom;
+use nise::nes::trace::TraceFormat;
+
+// Where the instruction ends and the registers begin in each line
+const REGISTERS_COLUMN: usize = 47;
+
+// A line of the log, cut into the fields worth comparing one by one
+struct Line<'a> {
+    instruction: &'a str,
+    registers: Vec<(&'a str, String)>,
+}
+
+fn parse(line: &str) -> Line<'_> {
+    let (instruction, rest) = line.split_at(REGISTERS_COLUMN.min(line.len()));
+    let (rest, cycles) = rest.split_once(" CYC:").unwrap_or((rest, ""));
+    let (rest, ppu) = rest.split_once(" PPU:").unwrap_or((rest, ""));
+    let mut registers = rest
+        .split_whitespace()
+        .filter_map(|field| field.split_once(':'))
+        .map(|(name, value)| (name, value.to_string()))
+        .collect::<Vec<_>>();
+    registers.push(("PPU", ppu.replace(' ', "")));
+    registers.push(("CYC", cycles.to_string()));
+    Line {
+        instruction: instruction.trim_end(),
+        registers,
+    }
+}
+
+// Nintendulator shows $FF for the write-only APU and I/O registers, where
+// nise has nothing to show, so the values of those are not compared
+fn masked(instruction: &str) -> &str {
+    let Some((access, _)) = instruction.rsplit_once(" = ") else {
+        return instruction;
+    };
+    let operand = access.rsplit(' ').next().unwrap_or("");
+    let address = operand
+        .strip_prefix('$')
+        .and_then(|address| u16::from_str_radix(address, 16).ok());
+    match address {
+        Some(0x4000..=0x401F) => access,
+        _ => instruction,
+    }
+}
+
+// The differences between two lines, one field to a line
+fn differences(expected: &str, actual: &str) -> Vec<String> {
+    let (expected, actual) = (parse(expected), parse(actual));
+    let mut differences = vec![];
+    if masked(expected.instruction) != masked(actual.instruction) {
+        differences.push(format!(
+            "instruction: expected `{}`, got `{}`",
+            expected.instruction, actual.instruction
+        ));
+    }
+    for (name, value) in &expected.registers {
+        let other = actual
+            .registers
+            .iter()
+            .find(|(other, _)| other == name)
+            .map_or("missing", |(_, value)| &value[..]);
+        if value == other {
+            continue;
+        }
+        let mut difference = format!("{}: expected {}, got {}", name, value, other);
+        if *name == "P" {
+            if let (Ok(value), Ok(other)) =
+                (u8::from_str_radix(value, 16), u8::from_str_radix(other, 16))
+            {
+                let flags = "NV-BDIZC"
+                    .chars()
+                    .enumerate()
+                    .filter(|(bit, _)| (value ^ other) & (0x80 >> bit) != 0)
+                    .map(|(_, flag)| flag)
+                    .collect::<String>();
+                difference += &format!(" (flags {} differ)", flags);
+            }
+        }
+        differences.push(difference);
+    }
+    differences
+}
+
+#[test]
+fn matches_reference_trace() {
+    let root = env!("CARGO_MANIFEST_DIR");
+    let rom = std::fs::read(format!("{}/nestest.nes", root)).unwrap();
+    let reference = std::fs::read_to_string(format!("{}/nestest.log", root)).unwrap();
+    let reference = reference.lines().collect::<Vec<_>>();
+
+    let mut cpu = Nise6502::new(NiseBus::new(Rom::new(&rom).unwrap()));
+    cpu.set_cycle_accurate(true);
+    cpu.reset();
+    // The automated mode starts at $C000 rather than at the reset vector
+    cpu.set_pc(0xC000);
+    let lines = Arc::new(Mutex::new(VecDeque::new()));
+    let trace = lines.clone();
+    let tracer = move |cpu: &Nise6502<NiseBus>| {
+        trace
+            .lock()
+            .unwrap()
+            .push_back(TraceFormat::Nestest.line(cpu));
+    };
+    cpu.set_tracer(Some(Box::new(tracer)));
+
+    // Each line is checked as soon as it is traced, so that a divergence
+    // is reported where it starts even if the CPU goes on to jam
+    let mut status = StepStatus::Running;
+    for (number, expected) in reference.iter().enumerate() {
+        let actual = loop {
+            if let Some(line) = lines.lock().unwrap().pop_front() {
+                break line;
+            }
+            assert_eq!(
+                status,
+                StepStatus::Running,
+                "line {} is missing, as the CPU is {}\nexpected: {}",
+                number + 1,
+                status,
+                expected
+            );
+            status = cpu.step();
+        };
+        let differences = differences(expected, &actual);
+        assert!(
+            differences.is_empty(),
+            "line {} diverges, with the CPU {}:\n  {}\nexpected: {}\n     got: {}",
+            number + 1,
+            status,
+            differences.join("\n  "),
+            expected,
+            actual
+        );
+    }
+
+    // The ROM leaves the number of the first failing official opcode test
+    // at $02, and of the first failing unofficial one at $03
+    let results = (cpu.bus().peek(0x02), cpu.bus().peek(0x03));
+    assert_eq!(results, (0, 0), "nestest failed with codes $02, $03");
+}
+
+#[test]
+fn reports_fields_that_differ() {
+    let expected = "C72F  F0 04     BEQ $C735                       A:00 X:00 Y:00 P:26 SP:FB PPU:  0, 30 CYC:10";
+    let actual = "C72F  F0 04     BEQ $C735                       A:00 X:00 Y:00 P:A4 SP:FB PPU:  0, 33 CYC:11";
+    assert_eq!(
+        differences(expected, actual),
+        [
+            "P: expected 26, got A4 (flags NZ differ)",
+            "PPU: expected 0,30, got 0,33",
+            "CYC: expected 10, got 11",
+        ]
+    );
+    let expected = "C5F5  A2 00     LDX #$00                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10";
+    let actual = "C5F5  A2 00     LDX #$00                       A:01 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10";
+    assert_eq!(differences(expected, actual), ["A: expected 00, got 01"]);
+    let expected = "C68B  8D 15 40  STA $4015 = FF                  A:02 X:FF Y:15 P:25 SP:FB PPU:233,107 CYC:26520";
+    let actual = "C68B  8D 15 40  STA $4015 = 00                  A:02 X:FF Y:15 P:25 SP:FB PPU:233,107 CYC:26520";
+    assert!(differences(expected, actual).is_empty());
+}
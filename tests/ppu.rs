@@ -0,0 +1,158 @@
+use nise::common::bus::Bus;
+use nise::nes::asm::assemble;
+use nise::nes::bus::NiseBus;
+use nise::nes::cdl::READ;
+use nise::nes::cpu::Variant;
+use nise::nes::rom::Rom;
+
+// An NROM cartridge with horizontal mirroring, and with CHR ROM or RAM
+fn bus(chr_ram: bool) -> NiseBus {
+    let assembly = assemble(Variant::Ricoh2A03, ".org $C000\n.byte 0").unwrap();
+    let mut ines = assembly.ines(1);
+    if chr_ram {
+        ines[5] = 0;
+        ines.truncate(16 + 0x4000);
+    }
+    NiseBus::new(Rom::new(&ines).unwrap())
+}
+
+fn set_address(bus: &mut NiseBus, address: u16) {
+    bus.write(0x2006, (address >> 8) as u8);
+    bus.write(0x2006, address as u8);
+}
+
+#[test]
+fn latches_scroll_and_address_writes() {
+    // The example worked through on the NESdev wiki's PPU scrolling page
+    let mut bus = bus(false);
+    bus.write(0x2000, 0x00);
+    bus.read(0x2002);
+    bus.write(0x2005, 0x7D);
+    assert_eq!((bus.ppu().t, bus.ppu().x, bus.ppu().w), (0x000F, 5, 1));
+    bus.write(0x2005, 0x5E);
+    assert_eq!((bus.ppu().t, bus.ppu().w), (0x616F, 0));
+    bus.write(0x2006, 0x3D);
+    assert_eq!((bus.ppu().t, bus.ppu().v), (0x3D6F, 0x0000));
+    bus.write(0x2006, 0xF0);
+    assert_eq!((bus.ppu().t, bus.ppu().v, bus.ppu().w), (0x3DF0, 0x3DF0, 0));
+
+    // PPUCTRL's nametable bits go to t, and a PPUSTATUS read resets w
+    bus.write(0x2000, 0x03);
+    assert_eq!(bus.ppu().t, 0x3DF0 | 0x0C00);
+    bus.write(0x2005, 0x00);
+    bus.read(0x2002);
+    assert_eq!(bus.ppu().w, 0);
+}
+
+#[test]
+fn reads_and_writes_vram_through_ppudata() {
+    let mut bus = bus(false);
+    set_address(&mut bus, 0x2108);
+    bus.write(0x2007, 0x11);
+    bus.write(0x2007, 0x22);
+    assert_eq!(bus.ppu().v, 0x210A);
+    // $2500 is $2100 seen through the mirroring
+    assert_eq!(bus.ppu().memory(0x2509), 0x22);
+
+    // Reads come a byte late, through the buffer
+    set_address(&mut bus, 0x2108);
+    bus.read(0x2007);
+    assert_eq!(bus.peek(0x2007), 0x11);
+    assert_eq!(bus.read(0x2007), 0x11);
+    assert_eq!(bus.read(0x2007), 0x22);
+
+    // Going down a column
+    bus.write(0x2000, 0x04);
+    set_address(&mut bus, 0x2000);
+    bus.write(0x2007, 0x33);
+    bus.write(0x2007, 0x44);
+    assert_eq!(bus.ppu().v, 0x2040);
+    assert_eq!(bus.ppu().memory(0x2020), 0x44);
+
+    // CHR ROM cannot be written to
+    set_address(&mut bus, 0x0000);
+    bus.write(0x2007, 0x55);
+    assert_eq!(bus.ppu().memory(0x0000), 0x00);
+}
+
+#[test]
+fn reads_the_palette_at_once() {
+    let mut bus = bus(false);
+    set_address(&mut bus, 0x2F00);
+    bus.write(0x2007, 0x66);
+    set_address(&mut bus, 0x3F10);
+    bus.write(0x2007, 0x0F);
+    assert_eq!(bus.ppu().memory(0x3F00), 0x0F);
+
+    set_address(&mut bus, 0x3F00);
+    assert_eq!(bus.read(0x2007), 0x0F);
+    // The buffer has the nametable byte under the palette
+    set_address(&mut bus, 0x2000);
+    assert_eq!(bus.read(0x2007), 0x66);
+}
+
+#[test]
+fn clears_vblank_on_status_reads() {
+    let mut bus = bus(false);
+    while bus.peek(0x2002) & 0x80 == 0 {
+        bus.tick();
+    }
+    assert_eq!(bus.read(0x2002) & 0x80, 0x80);
+    assert_eq!(bus.read(0x2002) & 0x80, 0x00);
+}
+
+#[test]
+fn writes_oam_data_in_turn() {
+    let mut bus = bus(false);
+    bus.write(0x2003, 0x10);
+    bus.write(0x2004, 0x01);
+    bus.write(0x2004, 0x02);
+    assert_eq!(bus.ppu().oamaddr, 0x12);
+    assert_eq!(bus.ppu().oam()[0x10..0x12], [0x01, 0x02]);
+    bus.write(0x2003, 0x10);
+    assert_eq!((bus.read(0x2004), bus.read(0x2004)), (0x01, 0x01));
+}
+
+#[test]
+fn uploads_to_chr_ram_and_logs_chr_reads() {
+    let mut bus = bus(true);
+    set_address(&mut bus, 0x0010);
+    bus.write(0x2007, 0xAB);
+    assert_eq!(bus.ppu().memory(0x0010), 0xAB);
+
+    let mut bus = self::bus(false);
+    bus.start_code_data_log();
+    set_address(&mut bus, 0x0010);
+    bus.read(0x2007);
+    let log = bus.code_data_log().unwrap();
+    assert_eq!(log.chr[0x10], READ);
+    assert_eq!(log.chr[0x11], 0);
+}
+
+#[test]
+fn ignores_writes_to_status_and_rom() {
+    let mut bus = bus(false);
+    let status = bus.peek(0x2002);
+    bus.write(0x2002, 0xFF);
+    bus.write(0x3FFA, 0xFF);
+    assert_eq!(bus.peek(0x2002), status);
+    bus.write(0xC000, 0xFF);
+    bus.write(0xFFFF, 0x00);
+    assert_eq!((bus.peek(0xC000), bus.peek(0xFFFF)), (0x00, 0xFF));
+}
+
+#[test]
+fn leaves_registers_alone_when_poked() {
+    let mut bus = bus(false);
+    set_address(&mut bus, 0x2108);
+    bus.write(0x2005, 0x7D);
+    let registers = |bus: &NiseBus| (bus.ppu().t, bus.ppu().v, bus.ppu().x, bus.ppu().w);
+    let before = registers(&bus);
+    for address in [0x2000, 0x2005, 0x2006, 0x2006, 0x2007, 0x3FFD] {
+        bus.poke(address, 0x3F);
+    }
+    assert_eq!(registers(&bus), before);
+    assert_eq!(bus.ppu().memory(0x2108), 0x00);
+    bus.poke(0x4014, 0x02);
+    assert_eq!(bus.take_dma(), None);
+}
@@ -0,0 +1,115 @@
+use nise::common::bus::FlatBus;
+use nise::nes::asm::{assemble, Assembly};
+use nise::nes::cpu::{Nise6502, Variant};
+use nise::nes::profiler::Routine;
+
+const PROGRAM: &str = "
+    .org $8000
+    reset:  ldx #$FF
+            txs
+    loop:   jsr outer
+            jmp loop
+    outer:  jsr inner
+            rts
+    inner:  nop
+            rts
+    nmi:    jsr inner
+            rti
+    .org $FFFA
+    .word nmi, reset, 0
+";
+
+fn setup() -> (Nise6502<FlatBus>, Assembly) {
+    let assembly = assemble(Variant::Ricoh2A03, PROGRAM).unwrap();
+    let mut cpu = Nise6502::new(assembly.flat_bus());
+    cpu.reset();
+    cpu.step();
+    cpu.step();
+    (cpu, assembly)
+}
+
+#[test]
+fn counts_cycles_by_routine() {
+    let (mut cpu, assembly) = setup();
+    let symbol = |name: &str| assembly.symbols[name];
+    assert!(cpu.profiler().is_none());
+    cpu.start_profiling();
+    // Each time around: JSR, JSR, NOP, RTS, RTS, JMP
+    for _ in 0..60 {
+        cpu.step();
+    }
+
+    let profiler = cpu.profiler().unwrap();
+    assert_eq!(profiler.total_cycles(), 290);
+    assert_eq!(profiler.address(symbol("loop")), (60, 10));
+    assert_eq!(profiler.hotspots(1), [(symbol("loop"), 60)]);
+    // JSR and RTS count towards the routine they call and return from
+    assert_eq!(
+        profiler.routines(),
+        [
+            Routine {
+                entry: symbol("outer"),
+                calls: 10,
+                inclusive: 260,
+                exclusive: 120,
+            },
+            Routine {
+                entry: symbol("inner"),
+                calls: 10,
+                inclusive: 140,
+                exclusive: 140,
+            },
+        ]
+    );
+
+    let name = |address| {
+        let names = [("outer", symbol("outer")), ("inner", symbol("inner"))];
+        names
+            .iter()
+            .find(|(_, entry)| *entry == address)
+            .map(|(name, _)| name.to_string())
+    };
+    assert_eq!(
+        profiler.folded_with(name),
+        "main 30\nmain;outer 120\nmain;outer;inner 140\n"
+    );
+    let report = profiler.report_with(5, name);
+    assert!(report.starts_with("290 cycles\n"), "{}", report);
+    assert!(
+        report.contains("      260  89.7%       120  41.4%        10  $"),
+        "{}",
+        report
+    );
+}
+
+#[test]
+fn totals_frames_between_nmis() {
+    let (mut cpu, _) = setup();
+    cpu.start_profiling();
+    for frame in 1..=3 {
+        cpu.set_nmi(true);
+        while cpu.profiler().unwrap().frames().len() == frame {
+            cpu.step();
+        }
+        cpu.set_nmi(false);
+        for _ in 0..20 {
+            cpu.step();
+        }
+    }
+    let frames = cpu.profiler().unwrap().frames();
+    assert_eq!(frames.len(), 4);
+    // Entry, JSR, NOP, RTS and RTI
+    assert!(
+        frames[1..].iter().all(|frame| frame.nmi == 27),
+        "{:?}",
+        frames
+    );
+    assert_eq!(frames[0].nmi, 0);
+    let report = cpu.profiler().unwrap().report_with(5, |_| None);
+    assert!(report.contains("2 frames: "), "{}", report);
+    assert!(
+        report.contains("NMI 27 on average, 27 at most"),
+        "{}",
+        report
+    );
+}
@@ -0,0 +1,33 @@
+use nise::nes::rom::{four_screen_mirrored_addr, horizontal_mirrored_addr, vertical_mirrored_addr};
+
+// The first byte of each of the four nametables, and of their mirror at $3000
+const NAMETABLES: [u16; 8] = [
+    0x2000, 0x2400, 0x2800, 0x2C00, 0x3000, 0x3400, 0x3800, 0x3C00,
+];
+
+#[test]
+fn mirrors_nametables_horizontally() {
+    let addresses = NAMETABLES.map(horizontal_mirrored_addr);
+    assert_eq!(
+        addresses,
+        [0x000, 0x000, 0x400, 0x400, 0x000, 0x000, 0x400, 0x400]
+    );
+    assert_eq!(horizontal_mirrored_addr(0x27FF), 0x3FF);
+    assert_eq!(horizontal_mirrored_addr(0x2FFF), 0x7FF);
+}
+
+#[test]
+fn mirrors_nametables_vertically() {
+    let addresses = NAMETABLES.map(vertical_mirrored_addr);
+    assert_eq!(
+        addresses,
+        [0x000, 0x400, 0x000, 0x400, 0x000, 0x400, 0x000, 0x400]
+    );
+    assert_eq!(vertical_mirrored_addr(0x2BFF), 0x3FF);
+    assert_eq!(vertical_mirrored_addr(0x3EFF), 0x6FF);
+}
+
+#[test]
+fn stays_within_vram_for_four_screens() {
+    assert!((0x2000..=0x3EFF).all(|address| four_screen_mirrored_addr(address) < 0x800));
+}
@@ -0,0 +1,259 @@
+//! Tom Harte's single step processor tests, from
+//! https://github.com/SingleStepTests/65x02.
+//!
+//! The tests are not distributed with nise. Copy the JSON files of
+//! `nes6502/v1/`, `00.json` to `ff.json`, into `tests/roms/nes6502/`, then
+//! run them, as they are ignored by default, with
+//! `cargo test --release --test single_step -- --ignored --nocapture`.
+//!
+//! Every case gives the registers and the RAM an instruction starts with,
+//! those it should leave, and the address, value and direction of the bus
+//! access it should make on each cycle.
+
+use nise::common::bus::{AccessKind, Bus, BusAccess, FlatBus};
+use nise::nes::cpu::{Nise6502, Variant};
+use nise::nes::disasm::decode;
+use serde_json::Value;
+
+// Opcodes that are reported on but not held against the CPU: the ones that
+// jam it, whose bus activity once halted is not modelled, and the unstable
+// stores and immediates, which are not implemented
+const UNSUPPORTED: &[u8] = &[
+    0x02, 0x12, 0x22, 0x32, 0x42, 0x52, 0x62, 0x72, 0x92, 0xB2, 0xD2, 0xF2, 0x8B, 0xAB, 0x93, 0x9B,
+    0x9C, 0x9E, 0x9F,
+];
+
+// The registers and RAM of a case, before or after it runs
+struct State {
+    pc: u16,
+    s: u8,
+    a: u8,
+    x: u8,
+    y: u8,
+    p: u8,
+    ram: Vec<(u16, u8)>,
+}
+
+struct Case {
+    name: String,
+    initial: State,
+    expected: State,
+    accesses: Vec<BusAccess>,
+}
+
+fn number(value: &Value) -> Result<u64, String> {
+    value
+        .as_u64()
+        .ok_or_else(|| format!("expected a number, got {}", value))
+}
+
+fn parse_state(value: &Value) -> Result<State, String> {
+    let register = |name: &str| number(&value[name]);
+    let ram = value["ram"]
+        .as_array()
+        .ok_or("expected ram")?
+        .iter()
+        .map(|pair| Ok((number(&pair[0])? as u16, number(&pair[1])? as u8)))
+        .collect::<Result<_, String>>()?;
+    Ok(State {
+        pc: register("pc")? as u16,
+        s: register("s")? as u8,
+        a: register("a")? as u8,
+        x: register("x")? as u8,
+        y: register("y")? as u8,
+        p: register("p")? as u8,
+        ram,
+    })
+}
+
+fn parse_case(value: &Value) -> Result<Case, String> {
+    let accesses = value["cycles"]
+        .as_array()
+        .ok_or("expected cycles")?
+        .iter()
+        .map(|cycle| {
+            let kind = match cycle[2].as_str() {
+                Some("read") => AccessKind::Read,
+                Some("write") => AccessKind::Write,
+                _ => return Err(format!("expected read or write, got {}", cycle[2])),
+            };
+            Ok(BusAccess {
+                address: number(&cycle[0])? as u16,
+                value: number(&cycle[1])? as u8,
+                kind,
+            })
+        })
+        .collect::<Result<_, String>>()?;
+    Ok(Case {
+        name: value["name"].as_str().unwrap_or("?").to_string(),
+        initial: parse_state(&value["initial"])?,
+        expected: parse_state(&value["final"])?,
+        accesses,
+    })
+}
+
+fn parse_cases(text: &str) -> Result<Vec<Case>, String> {
+    let value = serde_json::from_str::<Value>(text).map_err(|e| e.to_string())?;
+    value
+        .as_array()
+        .ok_or("expected a list of cases")?
+        .iter()
+        .map(parse_case)
+        .collect()
+}
+
+// Runs one case on a CPU that may be left over from others, and describes
+// how it went wrong, if it did
+fn run_case(cpu: &mut Nise6502<FlatBus>, case: &Case) -> Result<(), String> {
+    let initial = &case.initial;
+    for &(address, value) in &initial.ram {
+        cpu.bus_mut().write(address, value);
+    }
+    cpu.set_pc(initial.pc);
+    cpu.set_s(initial.s);
+    cpu.set_a(initial.a);
+    cpu.set_x(initial.x);
+    cpu.set_y(initial.y);
+    cpu.set_p(initial.p);
+    cpu.step();
+
+    let expected = &case.expected;
+    let mut errors = vec![];
+    let registers = [
+        ("PC", expected.pc, cpu.pc()),
+        ("S", expected.s as u16, cpu.s() as u16),
+        ("A", expected.a as u16, cpu.a() as u16),
+        ("X", expected.x as u16, cpu.x() as u16),
+        ("Y", expected.y as u16, cpu.y() as u16),
+        ("P", expected.p as u16, cpu.p() as u16),
+    ];
+    for (name, expected, actual) in registers {
+        if expected != actual {
+            errors.push(format!(
+                "{} is ${:02X}, not ${:02X}",
+                name, actual, expected
+            ));
+        }
+    }
+    for &(address, value) in &expected.ram {
+        let actual = cpu.bus().peek(address);
+        if actual != value {
+            errors.push(format!(
+                "${:04X} is ${:02X}, not ${:02X}",
+                address, actual, value
+            ));
+        }
+    }
+    let access = |access: &BusAccess| {
+        let kind = match access.kind {
+            AccessKind::Read => "read",
+            AccessKind::Write => "write",
+        };
+        format!("{} ${:02X} at ${:04X}", kind, access.value, access.address)
+    };
+    let cycles = case.accesses.len().max(cpu.accesses().len());
+    for cycle in 0..cycles {
+        let expected = case.accesses.get(cycle);
+        let actual = cpu.accesses().get(cycle);
+        if expected != actual {
+            errors.push(format!(
+                "cycle {}: {}, not {}",
+                cycle + 1,
+                actual.map_or("nothing".to_string(), access),
+                expected.map_or("nothing".to_string(), access)
+            ));
+            break;
+        }
+    }
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors.join(", ")),
+    }
+}
+
+fn cpu() -> Nise6502<FlatBus> {
+    let mut cpu = Nise6502::new(FlatBus::new());
+    cpu.set_variant(Variant::Ricoh2A03);
+    cpu.set_cycle_accurate(true);
+    cpu.set_record_accesses(true);
+    cpu
+}
+
+#[test]
+#[ignore = "needs the nes6502 JSON files in tests/roms/nes6502"]
+fn single_step_tests() {
+    let directory = format!("{}/tests/roms/nes6502", env!("CARGO_MANIFEST_DIR"));
+    let mut cpu = cpu();
+    let mut failed = vec![];
+    for opcode in 0..=0xFFu8 {
+        let path = format!("{}/{:02x}.json", directory, opcode);
+        let text = std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {}", path, e));
+        let cases = parse_cases(&text).unwrap_or_else(|e| panic!("{}: {}", path, e));
+        let mut passed = 0;
+        let mut first_failure = None;
+        for case in &cases {
+            match run_case(&mut cpu, case) {
+                Ok(()) => passed += 1,
+                Err(error) => {
+                    first_failure.get_or_insert_with(|| format!("{}: {}", case.name, error));
+                }
+            }
+        }
+        let mnemonic = decode(Variant::Ricoh2A03, &[opcode, 0, 0], 0)
+            .map_or("???", |instruction| instruction.mnemonic);
+        let unsupported = UNSUPPORTED.contains(&opcode);
+        match first_failure {
+            None => println!(
+                "{:02X} {}  pass {}/{}",
+                opcode,
+                mnemonic,
+                passed,
+                cases.len()
+            ),
+            Some(failure) => {
+                println!(
+                    "{:02X} {}  FAIL {}/{}{}\n    {}",
+                    opcode,
+                    mnemonic,
+                    passed,
+                    cases.len(),
+                    if unsupported { " (unsupported)" } else { "" },
+                    failure
+                );
+                if !unsupported {
+                    failed.push(format!("{:02X}", opcode));
+                }
+            }
+        }
+    }
+    assert!(
+        failed.is_empty(),
+        "opcodes failed: {}, run with --nocapture for details",
+        failed.join(" ")
+    );
+}
+
+#[test]
+fn checks_registers_memory_and_cycles() {
+    // LDA $10,X with X = 2: a dummy read of $10 before the real one at $12
+    let case = r#"[{
+        "name": "b5 10 00",
+        "initial": {"pc": 1000, "s": 253, "a": 0, "x": 2, "y": 0, "p": 36,
+                    "ram": [[1000, 181], [1001, 16], [18, 66]]},
+        "final": {"pc": 1002, "s": 253, "a": 66, "x": 2, "y": 0, "p": 36,
+                  "ram": [[1000, 181], [1001, 16], [18, 66]]},
+        "cycles": [[1000, 181, "read"], [1001, 16, "read"], [16, 0, "read"], [18, 66, "read"]]
+    }]"#;
+    let cases = parse_cases(case).unwrap();
+    let mut cpu = cpu();
+    assert_eq!(run_case(&mut cpu, &cases[0]), Ok(()));
+
+    let wrong = case
+        .replace("\"a\": 66", "\"a\": 67")
+        .replace("[16, 0, \"read\"], ", "");
+    let cases = parse_cases(&wrong).unwrap();
+    assert_eq!(
+        run_case(&mut cpu, &cases[0]),
+        Err("A is $42, not $43, cycle 3: read $00 at $0010, not read $42 at $0012".to_string())
+    );
+}
@@ -0,0 +1,87 @@
+use nise::common::bus::Bus;
+use nise::nes::asm::assemble;
+use nise::nes::bus::NiseBus;
+use nise::nes::cpu::Variant;
+use nise::nes::disasm::decode_bus;
+use nise::nes::rom::Rom;
+use nise::nes::symbols::{Location, Symbols};
+
+fn bus(prg_banks: u8) -> NiseBus {
+    let source = "
+        .org $8000
+        far:    rts
+        .org $C000
+        reset:  lda $0305
+        loop:   jsr far
+                jmp loop
+    ";
+    let assembly = assemble(Variant::Ricoh2A03, source).unwrap();
+    NiseBus::new(Rom::new(&assembly.ines(prg_banks)).unwrap())
+}
+
+#[test]
+fn reads_fceux_and_mesen_labels_by_bank() {
+    let bus = bus(2);
+    let directory = std::env::temp_dir().join(format!("nise-symbols-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let files = [
+        (
+            "game.nes.ram.nl",
+            "$0010#counter#\n$0300/10#buffer#Sprite buffer\n",
+        ),
+        (
+            "game.nes.1.nl",
+            "$C000#Reset#Entry point\n$C003#Loop#\n$C006##Only a comment\n",
+        ),
+        (
+            "game.mlb",
+            "P:0000:Far:A comment: with colons\nR:0020-0021:pointer\nG:2002:PPUSTATUS\n",
+        ),
+    ];
+    let mut symbols = Symbols::new();
+    for (name, text) in files {
+        std::fs::write(directory.join(name), text).unwrap();
+        symbols.load(directory.join(name)).unwrap();
+    }
+    std::fs::remove_dir_all(directory).unwrap();
+    assert_eq!(symbols.len(), 7);
+    assert_eq!(
+        symbols.iter().next(),
+        Some((Location::Cpu(0x0010), "counter"))
+    );
+
+    assert_eq!(symbols.label(&bus, 0xC000), Some("Reset"));
+    assert_eq!(symbols.label(&bus, 0x8000), Some("Far"));
+    assert_eq!(symbols.address(&bus, "Loop"), Some(0xC003));
+    assert_eq!(symbols.address(&bus, "pointer"), Some(0x0020));
+    assert_eq!(symbols.describe(&bus, 0xC005).as_deref(), Some("Loop+2"));
+    assert_eq!(symbols.describe(&bus, 0x0305).as_deref(), Some("buffer+5"));
+    assert_eq!(symbols.describe(&bus, 0x0410), None);
+    assert_eq!(symbols.describe(&bus, 0x2002).as_deref(), Some("PPUSTATUS"));
+    assert_eq!(symbols.resolve(&bus, "Loop+2"), Some(0xC005));
+    assert_eq!(symbols.resolve(&bus, "Nowhere"), None);
+
+    let instruction = decode_bus(Variant::Ricoh2A03, &bus, 0xC000);
+    let text = instruction.to_string_with(|address| symbols.describe(&bus, address));
+    assert_eq!(text, "LDA buffer+5");
+    let instruction = decode_bus(Variant::Ricoh2A03, &bus, 0xC003);
+    let text = instruction.to_string_with(|address| symbols.describe(&bus, address));
+    assert_eq!(text, "JSR Far");
+}
+
+#[test]
+fn follows_mirrored_banks() {
+    // A single bank is seen at both $8000 and $C000
+    let bus = bus(1);
+    let mut symbols = Symbols::new();
+    symbols.add_mlb("P:0003:Loop\n").unwrap();
+    assert_eq!(symbols.address(&bus, "Loop"), Some(0xC003));
+    assert_eq!(symbols.describe(&bus, 0x8004).as_deref(), Some("Loop+1"));
+    assert_eq!(bus.peek(0x8003), bus.peek(0xC003));
+
+    // Renaming a location drops its old name
+    symbols.insert(Location::Prg(3), "Spin");
+    assert_eq!(symbols.address(&bus, "Loop"), None);
+    assert_eq!(symbols.label(&bus, 0xC003), Some("Spin"));
+    assert!(symbols.add_mlb("P:zz:Bad\n").is_err());
+}
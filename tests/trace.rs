@@ -0,0 +1,129 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use nise::common::bus::FlatBus;
+use nise::nes::asm::assemble;
+use nise::nes::bus::NiseBus;
+use nise::nes::cpu::{Nise6502, Variant};
+use nise::nes::rom::Rom;
+use nise::nes::trace::{TraceCondition, TraceFormat, TraceWriter};
+
+const PROGRAM: &str = "
+    .org $C000
+    reset:  lda #$42
+            sta $10
+            ldx #$02
+            lda $0E,x
+            jsr sub
+    halt:   jmp halt
+    sub:    inc $10
+            rts
+    .org $FFFC
+    .word reset
+";
+
+fn nes() -> Nise6502<NiseBus> {
+    let assembly = assemble(Variant::Ricoh2A03, PROGRAM).unwrap();
+    let rom = Rom::new(&assembly.ines(1)).unwrap();
+    let mut cpu = Nise6502::new(NiseBus::new(rom));
+    cpu.set_cycle_accurate(true);
+    cpu.reset();
+    cpu
+}
+
+// A sink that can still be read once the tracer owning it is attached
+#[derive(Clone, Default)]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(bytes)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Shared {
+    fn lines(&self) -> Vec<String> {
+        let text = String::from_utf8(self.0.lock().unwrap().clone()).unwrap();
+        text.lines().map(str::to_string).collect()
+    }
+}
+
+fn trace(format: TraceFormat, steps: usize) -> Vec<String> {
+    let mut cpu = nes();
+    let output = Shared::default();
+    cpu.set_tracer(Some(Box::new(TraceWriter::new(output.clone(), format))));
+    for _ in 0..steps {
+        cpu.step();
+    }
+    output.lines()
+}
+
+#[test]
+fn closures_trace() {
+    let assembly = assemble(Variant::Ricoh2A03, PROGRAM).unwrap();
+    let mut cpu = Nise6502::new(assembly.flat_bus());
+    cpu.reset();
+
+    let seen = Arc::new(Mutex::new(vec![]));
+    let log = seen.clone();
+    let tracer = move |cpu: &Nise6502<FlatBus>| log.lock().unwrap().push((cpu.pc(), cpu.a()));
+    assert!(cpu.set_tracer(Some(Box::new(tracer))).is_none());
+    cpu.step();
+    cpu.step();
+    assert!(cpu.set_tracer(None).is_some());
+    cpu.step();
+    assert_eq!(*seen.lock().unwrap(), [(0xC000, 0x00), (0xC002, 0x42)]);
+}
+
+#[test]
+fn writes_nestest_lines() {
+    let lines = trace(TraceFormat::Nestest, 6);
+    assert_eq!(
+        lines,
+        [
+            "C000  A9 42     LDA #$42                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7",
+            "C002  85 10     STA $10 = 00                   A:42 X:00 Y:00 P:24 SP:FD PPU:  0, 27 CYC:9",
+            "C004  A2 02     LDX #$02                       A:42 X:00 Y:00 P:24 SP:FD PPU:  0, 36 CYC:12",
+            "C006  B5 0E     LDA $0E,X @ 10 = 42            A:42 X:02 Y:00 P:24 SP:FD PPU:  0, 42 CYC:14",
+            "C008  20 0E C0  JSR $C00E                      A:42 X:02 Y:00 P:24 SP:FD PPU:  0, 54 CYC:18",
+            "C00E  E6 10     INC $10 = 42                   A:42 X:02 Y:00 P:24 SP:FB PPU:  0, 72 CYC:24",
+        ]
+    );
+}
+
+#[test]
+fn writes_fceux_and_mesen_lines() {
+    let fceux = trace(TraceFormat::Fceux, 4);
+    assert_eq!(
+        fceux[3],
+        "A:42 X:02 Y:00 S:FD P:nvUbdIzc  $C006:B5 0E     LDA $0E,X @ $0010 = #$42"
+    );
+    let mesen = trace(TraceFormat::Mesen, 4);
+    assert_eq!(
+        mesen[3],
+        "C006  $B5 $0E      LDA $0E,X [$0010] = $42           A:42 X:02 Y:00 S:FD P:nvUbdIzc V:0   H:42  Cycle:14"
+    );
+}
+
+#[test]
+fn starts_and_stops_on_conditions() {
+    let mut cpu = nes();
+    let output = Shared::default();
+    let mut writer = TraceWriter::new(output.clone(), TraceFormat::Nestest);
+    writer.set_start(Some(TraceCondition::Address(0xC004)));
+    writer.set_stop(Some(TraceCondition::Cycle(18)));
+    cpu.set_tracer(Some(Box::new(writer)));
+    for _ in 0..20 {
+        cpu.step();
+    }
+    let addresses = output
+        .lines()
+        .iter()
+        .map(|line| line[..4].to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(addresses, ["C004", "C006"]);
+}
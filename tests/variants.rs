@@ -0,0 +1,144 @@
+use nise::common::bus::{Bus, FlatBus};
+use nise::nes::asm::assemble;
+use nise::nes::cpu::{Flag, Nise6502, Variant};
+
+// A `variant` CPU reset into `source`, which is assembled at $8000 and
+// followed by a `halt` label
+fn load(variant: Variant, source: &str) -> (Nise6502<FlatBus>, u16) {
+    let source = format!(
+        ".org $8000\n{}\nhalt: jmp halt\n.org $FFFC\n.word $8000\n",
+        source
+    );
+    let assembly = assemble(variant, &source).unwrap();
+    let mut cpu = Nise6502::new(assembly.flat_bus());
+    cpu.set_variant(variant);
+    cpu.reset();
+    (cpu, assembly.symbols["halt"])
+}
+
+fn run(variant: Variant, source: &str) -> Nise6502<FlatBus> {
+    let (mut cpu, halt) = load(variant, source);
+    while cpu.pc() != halt {
+        cpu.step();
+    }
+    cpu
+}
+
+// The cycles taken by the instruction at PC
+fn cycles(cpu: &mut Nise6502<FlatBus>) -> u64 {
+    let start = cpu.cycles();
+    cpu.step();
+    cpu.cycles() - start
+}
+
+// A, then N, V, Z and C, after `op #b` in decimal mode with `a` in A
+fn decimal(variant: Variant, op: &str, a: u8, b: u8, carry: bool) -> (u8, [bool; 4]) {
+    let carry = if carry { "sec" } else { "clc" };
+    let source = format!("sed\n{}\nlda #${:02X}\n{} #${:02X}", carry, a, op, b);
+    let cpu = run(variant, &source);
+    let flags = [Flag::Negative, Flag::Overflow, Flag::Zero, Flag::Carry].map(|f| cpu.flag(f));
+    (cpu.a(), flags)
+}
+
+#[test]
+fn adds_and_subtracts_in_decimal_mode() {
+    for variant in [Variant::Nmos6502, Variant::Wdc65C02] {
+        assert_eq!(
+            decimal(variant, "adc", 0x12, 0x34, false),
+            (0x46, [false, false, false, false])
+        );
+        assert_eq!(
+            decimal(variant, "adc", 0x15, 0x27, false),
+            (0x42, [false, false, false, false])
+        );
+        // V comes from the sum of the tens, before they are adjusted
+        assert_eq!(
+            decimal(variant, "adc", 0x79, 0x00, true),
+            (0x80, [true, true, false, false])
+        );
+        assert_eq!(
+            decimal(variant, "sbc", 0x46, 0x12, true),
+            (0x34, [false, false, false, true])
+        );
+        assert_eq!(
+            decimal(variant, "sbc", 0x00, 0x01, true),
+            (0x99, [true, false, false, false])
+        );
+    }
+    // The 2A03 has no decimal mode
+    assert_eq!(
+        decimal(Variant::Ricoh2A03, "adc", 0x09, 0x01, false),
+        (0x0A, [false, false, false, false])
+    );
+}
+
+#[test]
+fn takes_decimal_n_and_z_from_the_binary_sum_on_nmos() {
+    // $99 + $01 is $00 with a carry, but $9A in binary, and $A0 before the
+    // tens are adjusted
+    assert_eq!(
+        decimal(Variant::Nmos6502, "adc", 0x99, 0x01, false),
+        (0x00, [true, false, false, true])
+    );
+    assert_eq!(
+        decimal(Variant::Wdc65C02, "adc", 0x99, 0x01, false),
+        (0x00, [false, false, true, true])
+    );
+}
+
+#[test]
+fn takes_an_extra_cycle_in_decimal_mode_on_the_65c02() {
+    for (variant, extra) in [(Variant::Nmos6502, 0), (Variant::Wdc65C02, 1)] {
+        let (mut cpu, _) = load(variant, "adc #1\nsbc #1\nsed\nadc #1\nsbc #1");
+        let taken = [0; 5].map(|_| cycles(&mut cpu));
+        assert_eq!(taken, [2, 2, 2, 2 + extra, 2 + extra]);
+    }
+}
+
+#[test]
+fn runs_65c02_instructions() {
+    let cpu = run(
+        Variant::Wdc65C02,
+        "
+        lda #$FF
+        sta $10
+        stz $10
+        lda #$0F
+        sta $11
+        lda #$3C
+        tsb $11     ; $3F
+        trb $11     ; $03
+        bra skip
+        lda #0
+    skip:
+        lda #$40
+        trb $12     ; $12 is 0, so Z is set
+        ",
+    );
+    assert_eq!(cpu.bus().peek(0x10), 0x00);
+    assert_eq!(cpu.bus().peek(0x11), 0x03);
+    assert_eq!(cpu.a(), 0x40);
+    assert!(cpu.flag(Flag::Zero));
+
+    // BRA takes 3 cycles, and one more across a page
+    let (mut cpu, _) = load(Variant::Wdc65C02, "bra next\nnext: bra $7FF0");
+    assert_eq!((cycles(&mut cpu), cycles(&mut cpu)), (3, 4));
+}
+
+#[test]
+fn fixes_jmp_indirect_across_a_page_on_the_65c02() {
+    // The pointer at $10FF is $9000 read within its page, as the NMOS chips
+    // do, and $A000 read across it
+    for (variant, target, taken) in [
+        (Variant::Ricoh2A03, 0x9000, 5),
+        (Variant::Nmos6502, 0x9000, 5),
+        (Variant::Wdc65C02, 0xA000, 6),
+    ] {
+        let (mut cpu, _) = load(variant, "jmp ($10FF)");
+        cpu.bus_mut().load(0x10FF, &[0x00]);
+        cpu.bus_mut().load(0x1000, &[0x90]);
+        cpu.bus_mut().load(0x1100, &[0xA0]);
+        assert_eq!(cycles(&mut cpu), taken);
+        assert_eq!(cpu.pc(), target);
+    }
+}